@@ -0,0 +1,66 @@
+use std::path::Path;
+
+use sandengine_lang::parser::{fields::SandField, materials::SandMaterial};
+
+use crate::save::WorldSave;
+use crate::simulation::{Params, SimModification};
+
+
+/// Something that can run the falling sand simulation, either on the GPU (`Simulation`)
+/// or on the CPU (`CpuSimulation`).
+///
+/// All buffers are stored row by row, starting at the top left cell
+pub trait SimulationBackend {
+    /// Width and height of the simulation in cells
+    fn size(&self) -> (u32, u32);
+
+    /// The materials and custom fields the simulation was created with
+    fn materials(&self) -> &[SandMaterial];
+    fn fields(&self) -> &[SandField];
+
+    /// The parameters (uniforms) of the simulation
+    fn params(&self) -> &Params;
+    fn params_mut(&mut self) -> &mut Params;
+
+    /// Queues modifications (e.g. the brush), which are applied during the next step. If more than
+    /// `MAX_MODIFICATIONS` are queued, the rest is applied in the steps after it, in the same order
+    fn apply_modifications(&mut self, modifications: &[SimModification]);
+
+    /// The modifications that were queued, but not applied yet
+    fn queued_modifications(&self) -> &[SimModification];
+
+    /// Runs the simulation for one step
+    fn step(&mut self);
+
+    /// Material id of every cell
+    fn read_cells(&self) -> Vec<u16>;
+
+    /// Replaces every cell with a new cell of the material, which has the field values of the material
+    fn write_cells(&mut self, cells: &[u16]);
+
+    /// Values of the custom fields of every cell, in the order of `fields()` (one value per field and cell)
+    fn read_fields(&self) -> Vec<f32>;
+
+    /// Overwrites the values of the custom fields of every cell, the cells keep their material
+    fn write_fields(&mut self, fields: &[f32]);
+
+    /// Color (RGBA) of every cell, as produced by the last step
+    fn read_color(&self) -> Vec<[f32; 4]>;
+
+    /// Light (RGB and falloff) of every cell, as produced by the last step
+    fn read_light(&self) -> Vec<[f32; 4]>;
+
+    /// Replaces the light of the last step, which the next step spreads further
+    fn write_light(&mut self, light: &[[f32; 4]]);
+
+    /// Saves the cells, their fields and the light to a world file (see `WorldSave`)
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        WorldSave::capture(self, true, true).save(path)
+    }
+
+    /// Loads a world file saved by `save`. Materials are matched by name, in case they changed since saving
+    fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        WorldSave::load(path)?.restore(self);
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+use sandengine_lang::parser::materials::SandMaterial;
+
+use crate::simulation::{SimModification, MODSHAPE_CIRCLE, MODSHAPE_SPRAY, MODSHAPE_SQUARE};
+
+
+
+const ID_EMPTY: u16 = 0;
+
+
+/// The shape the brush paints with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrushShape {
+    Circle,
+    Square,
+    /// A straight line of circles from where the mouse was pressed to where it was released
+    Line,
+    /// A circle, in which only some of the cells are painted (see `Brush::density`)
+    Spray,
+}
+impl BrushShape {
+    /// All shapes, in the order they are cycled through
+    pub const ALL: [BrushShape; 4] = [BrushShape::Circle, BrushShape::Square, BrushShape::Line, BrushShape::Spray];
+
+    /// The next shape after this one
+    pub fn next(self) -> Self {
+        let idx = BrushShape::ALL.iter().position(|s| *s == self).unwrap_or(0);
+        BrushShape::ALL[(idx + 1) % BrushShape::ALL.len()]
+    }
+}
+
+
+/// What the brush does with the cells it touches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrushMode {
+    /// Places the material of the brush
+    Paint,
+    /// Places EMPTY
+    Erase,
+    /// Places the material of the brush, but only over cells of the material with this id
+    Replace(u16),
+}
+
+
+/// Turns the movement of the mouse into modifications of the simulation.
+/// Between two frames, the brush is stamped along a line from the previous to the current
+/// position of the cursor, so fast strokes don't leave gaps
+#[derive(Debug, Clone)]
+pub struct Brush {
+    pub shape: BrushShape,
+    pub mode: BrushMode,
+    /// Radius (or half the width of a square) in cells
+    pub size: u32,
+    pub material: SandMaterial,
+    /// Fraction of the cells a spray paints (0-1)
+    pub density: f32,
+    /// Position of the cursor in the last frame, while the mouse is pressed
+    last_pos: Option<(i32, i32)>,
+    /// Where a line starts, while it is drawn
+    line_start: Option<(i32, i32)>,
+}
+impl Brush {
+    pub fn new(material: SandMaterial) -> Self {
+        Self {
+            shape: BrushShape::Circle,
+            mode: BrushMode::Paint,
+            size: 5,
+            material,
+            density: 0.1,
+            last_pos: None,
+            line_start: None,
+        }
+    }
+
+    /// Continues the stroke to the position of the cursor (in cells) and returns the modifications of this frame.
+    /// Call it once per frame, also when the mouse is not pressed, so that the stroke ends
+    pub fn stroke(&mut self, pos: (i32, i32), pressed: bool) -> Vec<SimModification> {
+        if self.shape == BrushShape::Line {
+            self.last_pos = None;
+            return match (pressed, self.line_start) {
+                (true, None) => {
+                    self.line_start = Some(pos);
+                    vec![]
+                },
+                (false, Some(start)) => {
+                    self.line_start = None;
+                    self.stamps(start, pos)
+                },
+                _ => vec![],
+            };
+        }
+        self.line_start = None;
+        if !pressed {
+            self.last_pos = None;
+            return vec![];
+        }
+        let from = self.last_pos.unwrap_or(pos);
+        self.last_pos = Some(pos);
+        self.stamps(from, pos)
+    }
+
+    /// Finds the material of the brush (and the replaced material) again after the materials changed.
+    /// `ids` are the new ids of the old ids (see `ParsingResult::remap_from`)
+    pub fn remap(&mut self, materials: &[SandMaterial], ids: &[u16]) {
+        self.material = materials.iter()
+            .find(|m| m.name == self.material.name)
+            .cloned()
+            .unwrap_or_default();
+        if let BrushMode::Replace(id) = self.mode {
+            self.mode = BrushMode::Replace(ids.get(id as usize).copied().unwrap_or(ID_EMPTY));
+        }
+    }
+
+    /// Stops the current stroke, e.g. when the brush changes
+    pub fn cancel(&mut self) {
+        self.last_pos = None;
+        self.line_start = None;
+    }
+
+    /// The brush stamped along the line. The stamps overlap, so that the line has no gaps
+    fn stamps(&self, from: (i32, i32), to: (i32, i32)) -> Vec<SimModification> {
+        let spacing = (self.size as usize / 2).max(1);
+        let points = line(from, to);
+        let last = points.len() - 1;
+        points.into_iter()
+            .enumerate()
+            .filter(|(idx, _)| idx % spacing == 0 || *idx == last)
+            .map(|(_, pos)| self.modification(pos))
+            .collect()
+    }
+
+    /// The brush at a single position
+    pub fn modification(&self, pos: (i32, i32)) -> SimModification {
+        let (material, replace) = match self.mode {
+            BrushMode::Paint => (self.material.id as u16, None),
+            BrushMode::Erase => (ID_EMPTY, None),
+            BrushMode::Replace(id) => (self.material.id as u16, Some(id)),
+        };
+        SimModification {
+            position: [pos.0, pos.1],
+            mod_shape: match self.shape {
+                BrushShape::Circle | BrushShape::Line => MODSHAPE_CIRCLE,
+                BrushShape::Square => MODSHAPE_SQUARE,
+                BrushShape::Spray => MODSHAPE_SPRAY,
+            },
+            mod_size: self.size.max(1) as i32,
+            mod_matID: material as i32,
+            mod_replaceID: replace.map_or(-1, |id| id as i32),
+            mod_density: self.density.clamp(0.0, 1.0),
+            ..Default::default()
+        }
+    }
+}
+
+
+/// All cells on the line between the two points, including both (Bresenham's algorithm)
+pub fn line(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+    let step = ((to.0 - from.0).signum(), (to.1 - from.1).signum());
+    let mut err = dx + dy;
+    let mut pos = from;
+    let mut points = vec![pos];
+    while pos != to {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            pos.0 += step.0;
+        }
+        if e2 <= dx {
+            err += dx;
+            pos.1 += step.1;
+        }
+        points.push(pos);
+    }
+    points
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brush(size: u32) -> Brush {
+        let mut brush = Brush::new(SandMaterial { id: 4, ..Default::default() });
+        brush.size = size;
+        brush
+    }
+
+    /// Neighbouring points of a line touch each other (also diagonally)
+    fn assert_connected(points: &[(i32, i32)]) {
+        for pair in points.windows(2) {
+            let diff = ((pair[1].0 - pair[0].0).abs(), (pair[1].1 - pair[0].1).abs());
+            assert!(diff.0 <= 1 && diff.1 <= 1 && diff != (0, 0), "gap between {:?} and {:?}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn line_endpoints() {
+        assert_eq!(line((3, 4), (3, 4)), [(3, 4)]);
+        for (from, to) in [((0, 0), (10, 3)), ((5, 5), (-2, 1)), ((1, -4), (1, 6)), ((0, 0), (-7, -7))] {
+            let points = line(from, to);
+            assert_eq!((points[0], points[points.len() - 1]), (from, to));
+        }
+    }
+
+    #[test]
+    fn line_without_gaps() {
+        // Shallow, steep and diagonal lines in every direction
+        for to in [(20, 3), (-20, 3), (3, 20), (3, -20), (-2, -17), (9, 9)] {
+            let points = line((0, 0), to);
+            assert_connected(&points);
+            // One point per step along the longer axis
+            assert_eq!(points.len() as i32, to.0.abs().max(to.1.abs()) + 1);
+        }
+    }
+
+    #[test]
+    fn stamps_along_line() {
+        // Every second cell of the line, the end is always stamped
+        let stamps = brush(4).stamps((0, 0), (5, 0));
+        let positions: Vec<[i32; 2]> = stamps.iter().map(|m| m.position).collect();
+        assert_eq!(positions, [[0, 0], [2, 0], [4, 0], [5, 0]]);
+        assert!(stamps.iter().all(|m| m.mod_shape == MODSHAPE_CIRCLE && m.mod_size == 4 && m.mod_matID == 4));
+        // Small brushes stamp every cell
+        assert_eq!(brush(1).stamps((0, 0), (0, 3)).len(), 4);
+        assert_eq!(brush(4).stamps((2, 2), (2, 2)).len(), 1);
+    }
+
+    #[test]
+    fn stroke_continues_from_last_frame() {
+        let mut brush = brush(1);
+        assert!(brush.stroke((0, 0), false).is_empty());
+        assert_eq!(brush.stroke((0, 0), true).len(), 1);
+        // A fast movement is filled in from the last position
+        let positions: Vec<(i32, i32)> = brush.stroke((0, 6), true).iter()
+            .map(|m| (m.position[0], m.position[1]))
+            .collect();
+        assert_eq!(positions.len(), 7);
+        assert_connected(&positions);
+        // Releasing ends the stroke, the next one starts fresh
+        assert!(brush.stroke((0, 6), false).is_empty());
+        assert_eq!(brush.stroke((10, 10), true).len(), 1);
+    }
+
+    #[test]
+    fn stroke_line_on_release() {
+        let mut brush = brush(1);
+        brush.shape = BrushShape::Line;
+        assert!(brush.stroke((0, 0), true).is_empty());
+        assert!(brush.stroke((3, 1), true).is_empty());
+        let stamps = brush.stroke((4, 0), false);
+        assert_eq!(stamps.len(), 5);
+        assert_eq!((stamps[0].position, stamps[4].position), ([0, 0], [4, 0]));
+        // Nothing more until the next press
+        assert!(brush.stroke((4, 0), false).is_empty());
+        // Cancelling drops the line
+        brush.stroke((0, 0), true);
+        brush.cancel();
+        assert!(brush.stroke((2, 0), false).is_empty());
+    }
+
+    #[test]
+    fn modes() {
+        let mut brush = brush(2);
+        let paint = brush.modification((1, 1));
+        assert_eq!((paint.mod_matID, paint.mod_replaceID), (4, -1));
+        brush.mode = BrushMode::Erase;
+        let erase = brush.modification((1, 1));
+        assert_eq!((erase.mod_matID, erase.mod_replaceID), (ID_EMPTY as i32, -1));
+        brush.mode = BrushMode::Replace(7);
+        let replace = brush.modification((1, 1));
+        assert_eq!((replace.mod_matID, replace.mod_replaceID), (4, 7));
+
+        brush.shape = BrushShape::Spray;
+        brush.density = 1.5;
+        let spray = brush.modification((1, 1));
+        assert_eq!((spray.mod_shape, spray.mod_density), (MODSHAPE_SPRAY, 1.0));
+        brush.shape = BrushShape::Square;
+        assert_eq!(brush.modification((1, 1)).mod_shape, MODSHAPE_SQUARE);
+    }
+}
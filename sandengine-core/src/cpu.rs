@@ -0,0 +1,301 @@
+use rayon::prelude::*;
+use sandengine_lang::{ParsingResult, parser::{fields::SandField, materials::SandMaterial}};
+use sandengine_lang::interpreter::{self, Cell, Grid, Interpreter};
+
+use crate::backend::SimulationBackend;
+use crate::modifications::{self, ModificationTiles};
+use crate::simulation::{self, Params, SimModification, MODSHAPE_CIRCLE, MODSHAPE_SPRAY, MODSHAPE_SQUARE};
+
+
+const ID_EMPTY: u16 = 0;
+const ID_NULL: u16 = 1;
+
+
+/// Runs the simulation on the CPU, using the rule interpreter of `sandengine-lang`.
+/// The Margolus blocks never overlap, so they are simulated in parallel.
+/// Does not need a window or GPU, so it can run on headless servers
+pub struct CpuSimulation {
+    interpreter: Interpreter,
+    materials: Vec<SandMaterial>,
+    /// Index inside of `materials` of every id
+    indices: Vec<usize>,
+    fields: Vec<SandField>,
+    grid: Grid,
+    color: Vec<[f32; 4]>,
+    light: Vec<[f32; 4]>,
+    pub params: Params,
+    /// The queued modifications. At most `MAX_MODIFICATIONS` of them are applied per frame,
+    /// the rest stay queued for the next frames
+    pub modifications: Vec<SimModification>,
+}
+impl CpuSimulation {
+    /// Creates an empty simulation. Seed 0 produces the same random values as the compute shader
+    pub fn new(parsing_result: &ParsingResult, size: (u32, u32), seed: u32) -> Self {
+        let interpreter = Interpreter::new(parsing_result, seed);
+        let grid = interpreter.new_grid(size.0 as usize, size.1 as usize);
+        let num_cells = (size.0 * size.1) as usize;
+        let mut params = Params::new();
+        // The GPU clears the world in the first frame, the grid already starts out empty
+        params.frame = grid.frame;
+        params.seed = seed;
+        Self {
+            interpreter,
+            materials: parsing_result.materials.clone(),
+            indices: parsing_result.material_indices(),
+            fields: parsing_result.fields.clone(),
+            grid,
+            color: vec![[0.0; 4]; num_cells],
+            light: vec![[0.0; 4]; num_cells],
+            params,
+            modifications: vec![],
+        }
+    }
+
+    /// The cells and their fields
+    pub fn grid(&self) -> &Grid {
+        &self.grid
+    }
+
+    /// Unknown ids are NULL, just like `getMaterialFromID`
+    fn material(&self, id: u16) -> &SandMaterial {
+        let idx = self.indices.get(id as usize).copied().unwrap_or(self.indices[ID_NULL as usize]);
+        &self.materials[idx]
+    }
+
+    /// The material the modifications of the frame place at the position, if any (the last one wins).
+    /// `old_cells` are the cells before the step, which `mod_replaceID` is compared with
+    fn modification_at(&self, pos: (i32, i32), old_cells: &[u16], batch: &[SimModification], tiles: &ModificationTiles) -> Option<u16> {
+        let mut result = None;
+        for modification in tiles.at(pos).iter().map(|idx| &batch[*idx as usize]) {
+            let old_id = old_cells[pos.1 as usize * self.grid.width + pos.0 as usize];
+            if modification.mod_replaceID >= 0 && old_id as i32 != modification.mod_replaceID {
+                continue;
+            }
+            let diff = ((modification.position[0] - pos.0).abs(), (modification.position[1] - pos.1).abs());
+            let size = modification.mod_size;
+            let hit = match modification.mod_shape {
+                MODSHAPE_CIRCLE => ((diff.0 * diff.0 + diff.1 * diff.1) as f32).sqrt() <= size as f32,
+                MODSHAPE_SQUARE => diff.0 <= size && diff.1 <= size,
+                // A different seed, so that the spray does not depend on RAND (like in GLSL)
+                MODSHAPE_SPRAY => ((diff.0 * diff.0 + diff.1 * diff.1) as f32).sqrt() <= size as f32
+                    && interpreter::hash43([pos.0 as u32, pos.1 as u32, self.grid.frame as u32], self.params.seed.wrapping_add(1))[0] < modification.mod_density,
+                _ => false,
+            };
+            if hit {
+                result = Some(self.material(modification.mod_matID.clamp(0, u16::MAX as i32) as u16).id as u16);
+            }
+        }
+        result.filter(|id| *id != ID_NULL)
+    }
+
+    /// Color of the cell, darkened by some noise (see `setCell` in GLSL)
+    fn cell_color(&self, cell: &Cell, pos: (i32, i32)) -> [f32; 4] {
+        let mut color = self.material(cell.id).color;
+        if cell.id != ID_EMPTY {
+            let rand = noise([pos.0 as f32, pos.1 as f32], 3, 2.0, 0.25) * 0.25;
+            for c in color.iter_mut().take(3) {
+                *c = (*c - rand).clamp(0.0, 1.0);
+            }
+        }
+        color
+    }
+
+    /// Light of the cell, spread from the light of the neighbours in the last frame (see `setCell` in GLSL)
+    fn cell_light(&self, cell: &Cell, pos: (i32, i32), old_cells: &[u16], old_light: &[[f32; 4]]) -> [f32; 4] {
+        let emission = self.material(cell.id).emission;
+        if emission[..3] != [0.0; 3] {
+            return emission;
+        }
+        if pos.1 == 0 {
+            return [1.0, 1.0, 1.0, 0.999999];
+        }
+
+        let (width, height) = (self.grid.width as i32, self.grid.height as i32);
+        let mut avg_light = [0.0; 4];
+        let mut max_light = [0.0f32; 4];
+        let mut max_falloff = 0.0f32;
+        let mut num_lightsources = 0;
+        // Same order as `getDiagonalNeighbours`
+        for (dx, dy) in [(0, 1), (0, -1), (-1, 1), (-1, -1), (1, 1), (1, -1), (1, 0), (-1, 0)] {
+            let (x, y) = (pos.0 + dx, pos.1 + dy);
+            if x < 0 || y < 0 || x >= width || y >= height {
+                continue;
+            }
+            let idx = (y * width + x) as usize;
+            let neigh = self.material(old_cells[idx]);
+            let obstacle = neigh.emission[..3] == [0.0; 3] && neigh.mattype != "EMPTY";
+            let mut light_data = old_light[idx];
+            if obstacle {
+                light_data[..3].fill(0.0);
+            }
+
+            let falloff = match light_data[3] == 0.0 {
+                true => max_falloff,
+                false => light_data[3],
+            };
+            let light = [light_data[0] * light_data[3], light_data[1] * light_data[3], light_data[2] * light_data[3], falloff];
+            for c in 0..4 {
+                avg_light[c] += light[c];
+                max_light[c] = max_light[c].max(light[c]);
+            }
+            max_falloff = falloff.max(max_falloff);
+            num_lightsources += 1;
+        }
+        if num_lightsources > 0 {
+            avg_light.iter_mut().for_each(|c| *c /= num_lightsources as f32);
+        }
+        // Max light is fast but produces star like patterns and average is too slow, so lerp
+        let mix = |c: usize| avg_light[c] + (max_light[c] - avg_light[c]) * 0.5;
+        [mix(0), mix(1), mix(2), avg_light[3]]
+    }
+}
+impl SimulationBackend for CpuSimulation {
+    fn size(&self) -> (u32, u32) {
+        (self.grid.width as u32, self.grid.height as u32)
+    }
+
+    fn materials(&self) -> &[SandMaterial] {
+        &self.materials
+    }
+
+    fn fields(&self) -> &[SandField] {
+        &self.fields
+    }
+
+    fn params(&self) -> &Params {
+        &self.params
+    }
+
+    fn params_mut(&mut self) -> &mut Params {
+        &mut self.params
+    }
+
+    fn apply_modifications(&mut self, modifications: &[SimModification]) {
+        self.modifications.extend_from_slice(modifications);
+    }
+
+    fn queued_modifications(&self) -> &[SimModification] {
+        &self.modifications
+    }
+
+    fn step(&mut self) {
+        let old_cells = self.grid.cells.clone();
+        let old_light = std::mem::take(&mut self.light);
+
+        // The frame and the seed can be changed through the params (e.g. when a world is loaded)
+        self.grid.frame = self.params.frame + 1;
+        self.interpreter.set_seed(self.params.seed);
+        let grid = &self.grid;
+        let interpreter = &self.interpreter;
+        let blocks: Vec<_> = interpreter.block_origins(grid)
+            .into_par_iter()
+            .map(|origin| (origin, interpreter.simulate_block(grid, origin)))
+            .collect();
+        for (origin, block) in blocks {
+            interpreter::write_block(&mut self.grid, origin, block);
+        }
+
+        // Modified cells are replaced, no matter what the simulation did with them.
+        // Modifications, that don't fit into this frame, stay queued (like on the GPU)
+        let width = self.grid.width;
+        let batch = modifications::next_batch(&mut self.modifications);
+        let tiles = ModificationTiles::new(&batch, self.size());
+        let modified: Vec<(i32, i32, u16)> = (0..self.grid.cells.len())
+            .into_par_iter()
+            .filter_map(|idx| {
+                let pos = ((idx % width) as i32, (idx / width) as i32);
+                self.modification_at(pos, &old_cells, &batch, &tiles).map(|id| (pos.0, pos.1, id))
+            })
+            .collect();
+        for (x, y, id) in modified {
+            self.interpreter.set(&mut self.grid, x, y, id);
+        }
+
+        let (color, light): (Vec<[f32; 4]>, Vec<[f32; 4]>) = (0..self.grid.cells.len())
+            .into_par_iter()
+            .map(|idx| {
+                let pos = ((idx % width) as i32, (idx / width) as i32);
+                let cell = self.grid.cell(pos.0, pos.1).unwrap();
+                (self.cell_color(&cell, pos), self.cell_light(&cell, pos, &old_cells, &old_light))
+            })
+            .unzip();
+        self.color = color;
+        self.light = light;
+        self.params.frame = self.grid.frame;
+        self.params.moveRight = simulation::move_right(self.params.seed, self.params.frame);
+    }
+
+    fn read_cells(&self) -> Vec<u16> {
+        self.grid.cells.clone()
+    }
+
+    fn write_cells(&mut self, cells: &[u16]) {
+        let width = self.grid.width;
+        for (idx, id) in cells.iter().enumerate().take(self.grid.cells.len()) {
+            self.interpreter.set(&mut self.grid, (idx % width) as i32, (idx / width) as i32, *id);
+        }
+    }
+
+    fn read_fields(&self) -> Vec<f32> {
+        self.grid.fields.clone()
+    }
+
+    fn write_fields(&mut self, fields: &[f32]) {
+        let len = fields.len().min(self.grid.fields.len());
+        self.grid.fields[..len].copy_from_slice(&fields[..len]);
+    }
+
+    fn read_color(&self) -> Vec<[f32; 4]> {
+        self.color.clone()
+    }
+
+    fn read_light(&self) -> Vec<[f32; 4]> {
+        self.light.clone()
+    }
+
+    fn write_light(&mut self, light: &[[f32; 4]]) {
+        let num_cells = self.grid.cells.len();
+        self.light = light.iter().copied().chain(std::iter::repeat([0.0; 4])).take(num_cells).collect();
+    }
+}
+
+
+/// `old_hash2` in GLSL
+fn hash2(p: [f32; 2]) -> [f32; 2] {
+    let p = [p[0] * 127.1 + p[1] * 311.7, p[0] * 269.5 + p[1] * 183.3];
+    p.map(|v| -1.0 + 2.0 * ((v.sin() * 43758.547).fract().rem_euclid(1.0)))
+}
+
+
+/// `_noise` in GLSL (2D simplex noise)
+fn simplex_noise(p: [f32; 2]) -> f32 {
+    const K1: f32 = 0.36602542;
+    const K2: f32 = 0.21132487;
+    let dot = |a: [f32; 2], b: [f32; 2]| a[0] * b[0] + a[1] * b[1];
+
+    let i = [(p[0] + (p[0] + p[1]) * K1).floor(), (p[1] + (p[0] + p[1]) * K1).floor()];
+    let a = [p[0] - i[0] + (i[0] + i[1]) * K2, p[1] - i[1] + (i[0] + i[1]) * K2];
+    let m = if a[0] >= a[1] { 1.0 } else { 0.0 };
+    let o = [m, 1.0 - m];
+    let b = [a[0] - o[0] + K2, a[1] - o[1] + K2];
+    let c = [a[0] - 1.0 + 2.0 * K2, a[1] - 1.0 + 2.0 * K2];
+    let h = [dot(a, a), dot(b, b), dot(c, c)].map(|d| (0.5 - d).max(0.0));
+    let n = [
+        h[0].powi(4) * dot(a, hash2(i)),
+        h[1].powi(4) * dot(b, hash2([i[0] + o[0], i[1] + o[1]])),
+        h[2].powi(4) * dot(c, hash2([i[0] + 1.0, i[1] + 1.0])),
+    ];
+    0.25 + 0.5 * (n[0] + n[1] + n[2]) * 70.0
+}
+
+
+/// `noise` in GLSL, multiple octaves of simplex noise
+fn noise(p: [f32; 2], octaves: i32, lacunarity: f32, frequency: f32) -> f32 {
+    let mut f = 0.0;
+    let mut p2 = p;
+    for o in 1..octaves + 1 {
+        f += 1.0 / o as f32 * simplex_noise([p2[0] * frequency, p2[1] * frequency]);
+        p2 = [p2[0] * lacunarity, p2[1] * lacunarity];
+    }
+    f
+}
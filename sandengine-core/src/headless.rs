@@ -0,0 +1,221 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context};
+use sandengine_lang::ParsingResult;
+
+use crate::backend::SimulationBackend;
+use crate::cpu::CpuSimulation;
+use crate::replay::{Player, Replay};
+use crate::save::WorldSave;
+use crate::world::{self, ImportedWorld};
+
+
+/// Size of the simulation, if neither a size nor a world is given
+pub const DEFAULT_SIZE: (u32, u32) = (64, 64);
+
+
+/// Options for running the simulation without a window
+#[derive(Debug, Clone)]
+pub struct HeadlessOptions {
+    /// Number of frames to simulate. A replay is always played to its end, even if it has more frames
+    pub frames: u32,
+    /// Size of the simulation. Defaults to the size of the world (or `DEFAULT_SIZE`)
+    pub size: Option<(u32, u32)>,
+    /// Seed for the random values of the rules
+    pub seed: u32,
+    /// Image the world is loaded from (see `world::load_png`)
+    pub world: Option<PathBuf>,
+    /// Replay to play, it brings its own world and seed (see `replay::Replay`)
+    pub replay: Option<PathBuf>,
+    /// Directory the snapshots and the final world are written to
+    pub out: PathBuf,
+    /// A snapshot of the colors is written every n frames (0 means only after the last frame)
+    pub snapshot_every: u32,
+}
+impl Default for HeadlessOptions {
+    fn default() -> Self {
+        Self {
+            frames: 100,
+            size: None,
+            seed: 0,
+            world: None,
+            replay: None,
+            out: PathBuf::from("out"),
+            snapshot_every: 10,
+        }
+    }
+}
+
+
+/// What a simulation starts with: an imported world or a replay (which brings its own world), and its size
+pub(crate) struct Start {
+    pub world: Option<ImportedWorld>,
+    pub replay: Option<Replay>,
+    pub size: (u32, u32),
+}
+
+
+/// Loads the world image or the replay. The size defaults to their size (or `DEFAULT_SIZE`),
+/// a different size is an error
+pub(crate) fn load_start(parsing_result: &ParsingResult, world: Option<&Path>, replay: Option<&Path>, size: Option<(u32, u32)>) -> anyhow::Result<Start> {
+    let world = match world {
+        Some(path) => {
+            let world = world::load_png(path, parsing_result)?;
+            world.print_unmatched(path);
+            Some(world)
+        },
+        None => None,
+    };
+    let replay = match replay {
+        Some(path) if world.is_some() => {
+            return Err(anyhow!("The replay '{}' brings its own world, it can't be combined with a world", path.display()));
+        },
+        Some(path) => Some(Replay::load(path)?),
+        None => None,
+    };
+    let size = match (&replay, &world, size) {
+        (Some(replay), _, Some(size)) if replay.world.size != size => {
+            return Err(anyhow!("The replay is {}x{}, but the size is {}x{}", replay.world.size.0, replay.world.size.1, size.0, size.1));
+        },
+        (Some(replay), _, _) => replay.world.size,
+        (None, Some(world), Some(size)) if world.size != size => {
+            return Err(anyhow!("The world is {}x{}, but the size is {}x{}", world.size.0, world.size.1, size.0, size.1));
+        },
+        (None, Some(world), _) => world.size,
+        (None, None, size) => size.unwrap_or(DEFAULT_SIZE),
+    };
+    if size.0 == 0 || size.1 == 0 {
+        return Err(anyhow!("The size needs to be at least 1x1"));
+    }
+    Ok(Start { world, replay, size })
+}
+
+
+/// Runs the simulation on the CPU, without opening a window. Writes the color buffer to
+/// `frame_<n>.png` in the output directory every `snapshot_every` frames and the final world to `world.png`
+/// and `world.sandworld` (with the fields and the light, see `WorldSave`)
+pub fn run_headless(parsing_result: &ParsingResult, options: &HeadlessOptions) -> anyhow::Result<()> {
+    let Start { world, replay, size } = load_start(parsing_result, options.world.as_deref(), options.replay.as_deref(), options.size)?;
+
+    std::fs::create_dir_all(&options.out)
+        .with_context(|| format!("Could not create the output directory '{}'", options.out.display()))?;
+
+    let mut sim = CpuSimulation::new(parsing_result, size, options.seed);
+    if let Some(world) = world {
+        sim.write_cells(&world.cells);
+    }
+    let mut player = replay.map(|replay| Player::start(replay, &mut sim));
+    let frames = options.frames.max(player.as_ref().map_or(0, |p| p.len() as u32));
+
+    for frame in 1..=frames {
+        if let Some(player) = &mut player {
+            player.play_next(&mut sim);
+        }
+        sim.step();
+        let snapshot = match options.snapshot_every {
+            0 => frame == frames,
+            every => frame % every == 0 || frame == frames,
+        };
+        if snapshot {
+            let pixels = sim.read_color().into_iter().flat_map(world::to_rgba8).collect();
+            world::save_rgba(&options.out.join(format!("frame_{:06}.png", frame)), size, pixels)?;
+        }
+    }
+
+    world::save_png(&options.out.join("world.png"), size, &sim.read_cells(), &parsing_result.materials)?;
+    WorldSave::capture(&sim, true, true).save(&options.out.join("world.sandworld"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sandengine_lang::parse_string;
+
+    use super::*;
+    use crate::replay::Recorder;
+
+    fn parse() -> ParsingResult {
+        parse_string("
+rules:
+    fall:
+        if: SELF.density > DOWN.density
+        do: SWAP SELF DOWN
+types:
+    movable_solid:
+        base_rules: [fall]
+materials:
+    sand:
+        type: movable_solid
+        color: [255, 255, 0]
+        density: 1.5
+", None).unwrap()
+    }
+
+    /// An empty directory for the files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sandengine_test_headless_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn start_conflicts() {
+        let result = parse();
+        let dir = test_dir("start");
+        let world = dir.join("world.png");
+        let sand = result.materials.iter().find(|m| m.name == "sand").unwrap().id as u16;
+        world::save_png(&world, (4, 3), &[sand; 12], &result.materials).unwrap();
+        let replay = dir.join("recording.sandreplay");
+        Recorder::start(&CpuSimulation::new(&result, (5, 2), 0)).finish().save(&replay).unwrap();
+        let size = |world: Option<&Path>, replay: Option<&Path>, size| {
+            load_start(&result, world, replay, size).map(|start| start.size)
+        };
+
+        assert_eq!(size(None, None, None).unwrap(), DEFAULT_SIZE);
+        assert_eq!(size(None, None, Some((8, 2))).unwrap(), (8, 2));
+        assert!(size(None, None, Some((0, 5))).is_err());
+        // The world and the replay bring their own size, a different one is an error
+        assert_eq!(size(Some(&world), None, None).unwrap(), (4, 3));
+        assert_eq!(size(Some(&world), None, Some((4, 3))).unwrap(), (4, 3));
+        assert!(size(Some(&world), None, Some((5, 3))).is_err());
+        assert_eq!(size(None, Some(&replay), None).unwrap(), (5, 2));
+        assert!(size(None, Some(&replay), Some((4, 3))).is_err());
+        // The replay brings its own world
+        assert!(size(Some(&world), Some(&replay), None).is_err());
+    }
+
+    #[test]
+    fn snapshot_schedule() {
+        let result = parse();
+        let dir = test_dir("snapshots");
+        let files = |out: &Path| {
+            let mut names: Vec<String> = std::fs::read_dir(out).unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            names.sort();
+            names
+        };
+
+        // Every 10 frames and after the last one
+        let options = HeadlessOptions {
+            frames: 25,
+            size: Some((4, 3)),
+            out: dir.join("every"),
+            ..Default::default()
+        };
+        run_headless(&result, &options).unwrap();
+        assert_eq!(files(&options.out), ["frame_000010.png", "frame_000020.png", "frame_000025.png", "world.png", "world.sandworld"]);
+        let world = WorldSave::load(&options.out.join("world.sandworld")).unwrap();
+        assert_eq!(world.size, (4, 3));
+
+        // Only after the last frame
+        let options = HeadlessOptions { frames: 3, snapshot_every: 0, out: dir.join("last"), ..options };
+        run_headless(&result, &options).unwrap();
+        assert_eq!(files(&options.out), ["frame_000003.png", "world.png", "world.sandworld"]);
+        // The last frame is on the schedule
+        let options = HeadlessOptions { frames: 20, snapshot_every: 10, out: dir.join("multiple"), ..options };
+        run_headless(&result, &options).unwrap();
+        assert_eq!(files(&options.out), ["frame_000010.png", "frame_000020.png", "world.png", "world.sandworld"]);
+    }
+}
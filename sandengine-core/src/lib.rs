@@ -1,11 +1,29 @@
 #[macro_use]
 extern crate glium;
 
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
 
 pub mod simulation;
-use sandengine_lang::parser::materials::SandMaterial;
-use simulation::{Simulation, SimModification};
+pub mod backend;
+pub mod modifications;
+pub mod cpu;
+pub mod world;
+pub mod headless;
+pub mod reload;
+pub mod save;
+pub mod replay;
+pub mod brush;
+use sandengine_lang::GeneratedShaders;
+use sandengine_lang::parser::{ParsingResult, materials::SandMaterial};
+use simulation::Simulation;
+use backend::SimulationBackend;
+use reload::HotReload;
+use replay::{Player, Recorder};
+use headless::Start;
+use brush::{Brush, BrushMode};
 
 pub mod renderer;
 use renderer::{Renderer};
@@ -15,32 +33,96 @@ use winit::event::{WindowEvent, Event, MouseButton, ElementState, MouseScrollDel
 use winit::event_loop::ControlFlow;
 
 
-/// Core function of the engine. Includes the event loop with simulation, rendering and UI
-pub fn run(parsing_result: sandengine_lang::parser::ParsingResult) {
-    // Collects a list of selectable materials, given all the SandMaterial structs from the parser
-    let selectable_materials: Vec<SandMaterial> = parsing_result.materials.iter().filter_map(|m| {
-        if m.selectable {
-            Some(m.clone())
-        } else {
-            None
+/// The world is saved here with F5 and loaded again with F9
+const QUICKSAVE_PATH: &str = "quicksave.sandworld";
+/// F6 starts recording the input and stops it again, which saves the replay here
+const RECORDING_PATH: &str = "recording.sandreplay";
+
+
+/// Options for running the engine with a window
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Size of the simulation. Defaults to the size of the world or replay (or `headless::DEFAULT_SIZE`)
+    pub size: Option<(u32, u32)>,
+    /// The simulation is scaled up to fit into a window of this size (if no scale is given)
+    pub window_size: (u32, u32),
+    /// Size of a cell in pixels, overrides the window size
+    pub scale: Option<f32>,
+    /// Image displayed behind the simulation, defaults to the built-in one
+    pub background: Option<PathBuf>,
+    /// Limits the frame rate (and so the speed of the simulation) to the refresh rate of the monitor
+    pub vsync: bool,
+    /// Seed for the random values of the simulation (see `Params::seed`)
+    pub seed: u32,
+    /// Image the world is loaded from (see `world::load_png`)
+    pub world: Option<PathBuf>,
+    /// Replay to play, it brings its own world and seed (see `replay::Player`)
+    pub replay: Option<PathBuf>,
+}
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            size: None,
+            window_size: (640, 480),
+            scale: None,
+            background: None,
+            vsync: false,
+            seed: 0,
+            world: None,
+            replay: None,
         }
-    }).collect();
+    }
+}
+
+
+/// Core function of the engine. Includes the event loop with simulation, rendering and UI.
+/// Changes to the YAML file at the path, its imports and the shaders are hot-reloaded (see `HotReload`).
+/// The simulation starts with the world of the image (see `world::load_png`), if one is given.
+/// If a replay is given, it is played instead (see `replay::Player`) and the user takes over once it ends.
+///
+/// Only returns if the simulation could not be started, e.g. when the compute shader
+/// does not compile (`simulation::ShaderCompileError`)
+pub fn run(materials_path: PathBuf, parsing_result: ParsingResult, shaders: GeneratedShaders, options: RunOptions) -> anyhow::Result<()> {
+    let mut selectable_materials = selectable(&parsing_result.materials);
     println!("{:#?}", selectable_materials);
 
-    let size = (64, 64);
-    let target_size = (640.max(size.0), 480.max(size.1));
-    let required_scale = target_size.0 as f32 / size.0 as f32;
-    //let size = (1920, 1080);
+    let Start { world, replay, size } = headless::load_start(&parsing_result, options.world.as_deref(), options.replay.as_deref(), options.size)?;
+    let scale = options.scale.unwrap_or_else(|| {
+        let target_size = (options.window_size.0.max(size.0), options.window_size.1.max(size.1));
+        (target_size.0 as f32 / size.0 as f32).min(target_size.1 as f32 / size.1 as f32)
+    });
     let event_loop = winit::event_loop::EventLoopBuilder::new().build();
-    let mut renderer = Renderer::new(size, required_scale, &event_loop);
-    let mut sim = Simulation::new(&renderer.display, size);
+    let mut renderer = Renderer::new(size, scale, &event_loop);
+    let mut sim = Simulation::new(&renderer.display, &parsing_result, &shaders, size)?;
+    if let Some(path) = &options.background {
+        sim.load_background(&renderer.display, path)?;
+    }
+    sim.params.seed = options.seed;
+    if let Some(world) = world {
+        // Replaces the clear of the first frame
+        sim.write_cells(&world.cells);
+    }
+    let mut player = replay.map(|replay| Player::start(replay, &mut sim));
+    let mut recorder: Option<Recorder> = None;
+    let mut brush = Brush::new(selectable_materials.first().cloned().unwrap_or_default());
+    let mut parsing_result = parsing_result;
+    let mut hot_reload = HotReload::new(materials_path, &parsing_result);
+
+    // Time between two steps, if the frame rate is limited
+    let frame_period = options.vsync.then(|| {
+        let millihertz = renderer.window.current_monitor()
+            .and_then(|monitor| monitor.refresh_rate_millihertz())
+            .unwrap_or(60_000);
+        Duration::from_secs_f64(1000.0 / millihertz as f64)
+    });
 
     let mut last_render = Instant::now();
+    let mut last_step = Instant::now();
     event_loop.run(move |event, _, control_flow| {
-        // nanos: 16_666_667
-        let next_frame_time = std::time::Instant::now() + std::time::Duration::from_secs(1);
-        //*control_flow = ControlFlow::WaitUntil(next_frame_time);
-        *control_flow = ControlFlow::Poll;
+        *control_flow = match frame_period {
+            Some(period) => ControlFlow::WaitUntil(last_step + period),
+            None => ControlFlow::Poll,
+        };
         let frame_delta = last_render.elapsed();
         last_render = Instant::now();
         let _fps = 1.0f64 / frame_delta.as_secs_f64();
@@ -55,17 +137,53 @@ pub fn run(parsing_result: sandengine_lang::parser::ParsingResult) {
                 renderer.redraw();
             },
             Event::MainEventsCleared => {
+                if frame_period.is_some_and(|period| last_step.elapsed() < period) {
+                    return;
+                }
+                last_step = Instant::now();
+                // Swaps in the new shader between frames, the previous one keeps running if there are errors
+                if let Some((result, shaders)) = hot_reload.poll() {
+                    match Simulation::new(&renderer.display, &result, &shaders, size) {
+                        Ok(mut new_sim) => {
+                            reload::transfer(&sim, &mut new_sim);
+                            brush.remap(&result.materials, &result.remap_from(&parsing_result));
+                            std::mem::swap(&mut new_sim.background, &mut sim.background);
+                            sim = new_sim;
+                            selectable_materials = selectable(&result.materials);
+                            parsing_result = result;
+                            println!("{}", "[sandengine]: Reloaded.".green().bold());
+                            // The ids and rules changed, so the rest would not match anymore
+                            if let Some(recorder) = recorder.take() {
+                                finish_recording(recorder);
+                            }
+                            if player.take().is_some() {
+                                println!("{}", "[sandengine]: Stopped the replay, the materials changed.".yellow().bold());
+                            }
+                        },
+                        Err(err) => println!("{} {:#}", "[sandengine]:".red().bold(), err),
+                    }
+                }
                 renderer.prepare_frame();
-                if sim.params.mousePressed {
-                    sim.modifications.push(SimModification{
-                        mod_shape: simulation::MODSHAPE_CIRCLE,
-                        mod_size: sim.params.brushSize as i32,
-                        mod_matID: sim.params.brushMaterial.id as i32,
-                        position: [(sim.params.mousePos.0 * size.0 as f32) as i32, (sim.params.mousePos.1 * size.1 as f32) as i32],
-                        ..Default::default()
-                    });
-                };
-                sim.run();
+                match player.as_mut().and_then(|player| player.play_next(&mut sim)) {
+                    Some(state) => {
+                        brush.size = state.size;
+                        if let Some(material) = sim.materials().iter().find(|m| m.id as u16 == state.material) {
+                            brush.material = material.clone();
+                        }
+                    },
+                    None => {
+                        if let Some(player) = player.take() {
+                            println!("{}", format!("[sandengine]: The replay ended after {} frames.", player.played()).green().bold());
+                        }
+                        let cursor = ((sim.params.mousePos.0 * size.0 as f32) as i32, (sim.params.mousePos.1 * size.1 as f32) as i32);
+                        let modifications = brush.stroke(cursor, sim.params.mousePressed);
+                        if let Some(recorder) = &mut recorder {
+                            recorder.record(&sim, &brush, &modifications);
+                        }
+                        sim.apply_modifications(&modifications);
+                    },
+                }
+                sim.step();
             },
             Event::RedrawRequested(_) => {
                 renderer.start_render();
@@ -83,6 +201,38 @@ pub fn run(parsing_result: sandengine_lang::parser::ParsingResult) {
                 if let Event::WindowEvent {event, .. } = event { match event {
                     WindowEvent::KeyboardInput { input, .. } => {
                         if let Some(code) = input.virtual_keycode {
+                            if input.state == ElementState::Pressed && code == VirtualKeyCode::F6 {
+                                match (recorder.take(), &player) {
+                                    (Some(recorder), _) => finish_recording(recorder),
+                                    (None, Some(_)) => println!("{}", "[sandengine]: Can't record while a replay is playing.".yellow().bold()),
+                                    (None, None) => {
+                                        recorder = Some(Recorder::start(&sim));
+                                        println!("{}", "[sandengine]: Recording, press F6 again to stop.".green().bold());
+                                    },
+                                }
+                                return;
+                            }
+                            if input.state == ElementState::Pressed && matches!(code, VirtualKeyCode::F5 | VirtualKeyCode::F9) {
+                                let result = match code {
+                                    VirtualKeyCode::F5 => sim.save(Path::new(QUICKSAVE_PATH)).map(|_| "Saved"),
+                                    _ => {
+                                        // The recording or replay would not match the loaded world
+                                        if let Some(recorder) = recorder.take() {
+                                            finish_recording(recorder);
+                                        }
+                                        player = None;
+                                        sim.load(Path::new(QUICKSAVE_PATH)).map(|_| "Loaded")
+                                    },
+                                };
+                                match result {
+                                    Ok(action) => println!("{}", format!("[sandengine]: {} '{}'", action, QUICKSAVE_PATH).green().bold()),
+                                    Err(err) => println!("{} {:#}", "[sandengine]:".red().bold(), err),
+                                }
+                                return;
+                            }
+                            if input.state == ElementState::Pressed && handle_brush_key(code, &mut brush, &sim) {
+                                return;
+                            }
                             let idx = match code {
                                 VirtualKeyCode::Key0 => 0,
                                 VirtualKeyCode::Key1 => 1,
@@ -94,10 +244,10 @@ pub fn run(parsing_result: sandengine_lang::parser::ParsingResult) {
                                 VirtualKeyCode::Key7 => 7,
                                 VirtualKeyCode::Key8 => 8,
                                 VirtualKeyCode::Key9 => 9,
-                                _ => 0,
+                                _ => return,
                             };
                             if idx < selectable_materials.len() {
-                                sim.params.brushMaterial = selectable_materials[idx].clone();
+                                brush.material = selectable_materials[idx].clone();
                             };
                         }
                     },
@@ -114,9 +264,9 @@ pub fn run(parsing_result: sandengine_lang::parser::ParsingResult) {
                         }
                     },
                     WindowEvent::MouseWheel {delta: MouseScrollDelta::LineDelta(_x, y), .. } => {
-                        let new = std::cmp::max(1, sim.params.brushSize as i32 + y.signum() as i32);
-                        sim.params.brushSize = new as u32;
-                        println!("Brush Size: {}", sim.params.brushSize);
+                        let new = std::cmp::max(1, brush.size as i32 + y.signum() as i32);
+                        brush.size = new as u32;
+                        println!("Brush Size: {}", brush.size);
                     },
                     _ => (),
                     }
@@ -125,4 +275,58 @@ pub fn run(parsing_result: sandengine_lang::parser::ParsingResult) {
         }
         
     });
-}
\ No newline at end of file
+}
+
+
+/// Changes the brush: B cycles through the shapes, E toggles the eraser, R only replaces the material
+/// under the cursor (or paints everything again) and -/+ change the density of the spray.
+/// Returns whether the key was one of them
+fn handle_brush_key(code: VirtualKeyCode, brush: &mut Brush, sim: &Simulation) -> bool {
+    match code {
+        VirtualKeyCode::B => brush.shape = brush.shape.next(),
+        VirtualKeyCode::E => brush.mode = match brush.mode {
+            BrushMode::Erase => BrushMode::Paint,
+            _ => BrushMode::Erase,
+        },
+        VirtualKeyCode::R => brush.mode = match brush.mode {
+            BrushMode::Replace(_) => BrushMode::Paint,
+            _ => {
+                let size = sim.size();
+                let pos = ((sim.params.mousePos.0 * size.0 as f32) as u32, (sim.params.mousePos.1 * size.1 as f32) as u32);
+                if pos.0 >= size.0 || pos.1 >= size.1 {
+                    return true;
+                }
+                BrushMode::Replace(sim.read_cells()[(pos.1 * size.0 + pos.0) as usize])
+            },
+        },
+        VirtualKeyCode::Minus => brush.density = (brush.density - 0.05).max(0.05),
+        VirtualKeyCode::Equals => brush.density = (brush.density + 0.05).min(1.0),
+        _ => return false,
+    }
+    brush.cancel();
+    let mode = match brush.mode {
+        BrushMode::Replace(id) => {
+            let name = sim.materials().iter().find(|m| m.id as u16 == id).map_or("?", |m| m.name.as_str());
+            format!("Replace '{}'", name)
+        },
+        mode => format!("{:?}", mode),
+    };
+    println!("Brush: {:?}, {}, density {:.2}", brush.shape, mode, brush.density);
+    true
+}
+
+
+/// Saves the recorded replay to `RECORDING_PATH`
+fn finish_recording(recorder: Recorder) {
+    let frames = recorder.len();
+    match recorder.finish().save(Path::new(RECORDING_PATH)) {
+        Ok(()) => println!("{}", format!("[sandengine]: Recorded {} frames to '{}'", frames, RECORDING_PATH).green().bold()),
+        Err(err) => println!("{} {:#}", "[sandengine]:".red().bold(), err),
+    }
+}
+
+
+/// Collects a list of selectable materials, given all the SandMaterial structs from the parser
+fn selectable(materials: &[SandMaterial]) -> Vec<SandMaterial> {
+    materials.iter().filter(|m| m.selectable).cloned().collect()
+}
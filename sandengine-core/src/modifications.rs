@@ -0,0 +1,156 @@
+use crate::simulation::SimModification;
+
+
+
+/// The most modifications applied in one frame. The rest stay queued and are applied in the next frames
+pub const MAX_MODIFICATIONS: usize = 4096;
+/// Width and height in cells of the tiles the modifications are sorted into (`MOD_TILE_SIZE` in GLSL)
+pub const TILE_SIZE: u32 = 16;
+
+
+/// Takes the modifications of the next frame from the front of the queue, in the order they were queued
+pub(crate) fn next_batch(queue: &mut Vec<SimModification>) -> Vec<SimModification> {
+    let len = queue.len().min(MAX_MODIFICATIONS);
+    queue.drain(..len).collect()
+}
+
+
+/// Number of tiles in each direction
+pub(crate) fn num_tiles(size: (u32, u32)) -> (u32, u32) {
+    (size.0.div_ceil(TILE_SIZE), size.1.div_ceil(TILE_SIZE))
+}
+
+
+/// The modifications of one frame, sorted into the tiles they touch, so that every cell
+/// only checks the modifications near it.
+///
+/// `data` starts with the offset of every tile (and one more after the last tile), the entries of a
+/// tile are `data[data[tile]..data[tile + 1]]`. They are the indices of the modifications,
+/// in the order they were queued (the last one wins)
+pub(crate) struct ModificationTiles {
+    num_tiles: (u32, u32),
+    pub data: Vec<u32>,
+}
+impl ModificationTiles {
+    pub fn new(modifications: &[SimModification], size: (u32, u32)) -> Self {
+        let num_tiles = num_tiles(size);
+        let tiles: Vec<_> = modifications.iter()
+            .map(|modification| Self::tiles_of(modification, num_tiles))
+            .collect();
+
+        // Counts the entries of every tile first, then turns the counts into offsets
+        let mut data = vec![0; (num_tiles.0 * num_tiles.1) as usize + 1];
+        for (x0, y0, x1, y1) in tiles.iter().flatten() {
+            for y in *y0..=*y1 {
+                for x in *x0..=*x1 {
+                    data[(y * num_tiles.0 + x) as usize + 1] += 1;
+                }
+            }
+        }
+        let mut offset = data.len() as u32;
+        for entry in data.iter_mut() {
+            offset += *entry;
+            *entry = offset;
+        }
+
+        let mut next = data.clone();
+        data.resize(offset as usize, 0);
+        for (idx, tiles) in tiles.iter().enumerate() {
+            if let Some((x0, y0, x1, y1)) = *tiles {
+                for y in y0..=y1 {
+                    for x in x0..=x1 {
+                        let tile = (y * num_tiles.0 + x) as usize;
+                        data[next[tile] as usize] = idx as u32;
+                        next[tile] += 1;
+                    }
+                }
+            }
+        }
+        Self { num_tiles, data }
+    }
+
+    /// First and last tile (x0, y0, x1, y1) the modification can change, None if it is outside
+    /// of the simulation or changes nothing
+    fn tiles_of(modification: &SimModification, num_tiles: (u32, u32)) -> Option<(u32, u32, u32, u32)> {
+        if modification.mod_size <= 0 {
+            return None;
+        }
+        let size = (num_tiles.0 * TILE_SIZE, num_tiles.1 * TILE_SIZE);
+        let [x, y] = modification.position;
+        let (min, max) = ((x - modification.mod_size, y - modification.mod_size), (x + modification.mod_size, y + modification.mod_size));
+        if max.0 < 0 || max.1 < 0 || min.0 >= size.0 as i32 || min.1 >= size.1 as i32 {
+            return None;
+        }
+        let tile = |v: i32, max: u32| (v.max(0) as u32 / TILE_SIZE).min(max - 1);
+        Some((tile(min.0, num_tiles.0), tile(min.1, num_tiles.1), tile(max.0, num_tiles.0), tile(max.1, num_tiles.1)))
+    }
+
+    /// Indices of the modifications, that can change the cell
+    pub fn at(&self, pos: (i32, i32)) -> &[u32] {
+        let tile = (pos.1 as u32 / TILE_SIZE * self.num_tiles.0 + pos.0 as u32 / TILE_SIZE) as usize;
+        &self.data[self.data[tile] as usize..self.data[tile + 1] as usize]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modification(x: i32, y: i32, size: i32) -> SimModification {
+        SimModification {
+            position: [x, y],
+            mod_size: size,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn modification_spanning_tiles() {
+        // 4x3 tiles, the first modification touches the tiles (0, 0) and (1, 0)
+        let tiles = ModificationTiles::new(&[modification(15, 5, 2), modification(3, 3, 1)], (64, 48));
+        assert_eq!(tiles.at((0, 0)), [0, 1]);
+        assert_eq!(tiles.at((17, 2)), [0]);
+        assert!(tiles.at((32, 0)).is_empty());
+        assert!(tiles.at((0, 16)).is_empty());
+        // Cells of the last, partial tile
+        let tiles = ModificationTiles::new(&[modification(60, 40, 4)], (62, 42));
+        assert_eq!(tiles.at((61, 41)), [0]);
+    }
+
+    #[test]
+    fn modification_off_screen() {
+        let tiles = ModificationTiles::new(&[
+            modification(-10, -10, 2),
+            modification(100, 5, 3),
+            modification(5, 50, 1),
+        ], (64, 48));
+        // Only the offsets of the 12 tiles (and the end), no entries
+        assert_eq!(tiles.data.len(), 13);
+        // Partly outside is kept
+        let tiles = ModificationTiles::new(&[modification(-1, 5, 2)], (64, 48));
+        assert_eq!(tiles.at((0, 5)), [0]);
+    }
+
+    #[test]
+    fn modification_without_size() {
+        let tiles = ModificationTiles::new(&[modification(5, 5, 0), modification(5, 5, -3)], (64, 48));
+        assert!(tiles.at((5, 5)).is_empty());
+        assert_eq!(tiles.data.len(), 13);
+    }
+
+    #[test]
+    fn overflow_is_carried() {
+        let mut queue: Vec<_> = (0..5000).map(|i| modification(i, 0, 1)).collect();
+        let batch = next_batch(&mut queue);
+        assert_eq!(batch.len(), MAX_MODIFICATIONS);
+        assert_eq!(batch[0].position, [0, 0]);
+        assert_eq!(queue.len(), 5000 - MAX_MODIFICATIONS);
+        // The rest follows in the next frame, in the same order
+        let batch = next_batch(&mut queue);
+        assert_eq!(batch.len(), 5000 - MAX_MODIFICATIONS);
+        assert_eq!(batch[0].position, [MAX_MODIFICATIONS as i32, 0]);
+        assert!(queue.is_empty());
+        assert!(next_batch(&mut queue).is_empty());
+    }
+}
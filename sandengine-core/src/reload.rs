@@ -0,0 +1,142 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use colored::Colorize;
+use sandengine_lang::{GeneratedShaders, ParsingResult};
+
+use crate::backend::SimulationBackend;
+use crate::save::WorldSave;
+
+
+
+/// How often the modification times of the watched files are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// The handwritten shaders are reloaded from this directory (if it exists)
+const SHADER_DIR: &str = "shaders/compute";
+
+
+/// Watches files for changes by polling their modification times
+pub struct FileWatcher {
+    /// The files and their modification times (None if the file could not be read)
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    last_poll: Instant,
+}
+impl FileWatcher {
+    pub fn new(files: impl IntoIterator<Item = PathBuf>) -> Self {
+        let mut watcher = FileWatcher {
+            files: vec![],
+            last_poll: Instant::now(),
+        };
+        watcher.watch(files);
+        watcher
+    }
+
+    /// Replaces the watched files, their current state counts as unchanged
+    pub fn watch(&mut self, files: impl IntoIterator<Item = PathBuf>) {
+        self.files = files.into_iter()
+            .map(|path| {
+                let modified = modified(&path);
+                (path, modified)
+            })
+            .collect();
+    }
+
+    /// Whether any of the files was changed, created or removed since the last call.
+    /// Only checks the files every `POLL_INTERVAL`, so it can be called every frame
+    pub fn changed(&mut self) -> bool {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        let mut changed = false;
+        for (path, last_modified) in self.files.iter_mut() {
+            let modified = modified(path);
+            if modified != *last_modified {
+                *last_modified = modified;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+
+/// Re-parses the YAML file (and its imports) and regenerates the compute shader whenever one of them
+/// or one of the handwritten shaders in `shaders/compute` changes
+pub struct HotReload {
+    path: PathBuf,
+    watcher: FileWatcher,
+}
+impl HotReload {
+    /// Watches the YAML file at the path and all files the parsing result was read from
+    pub fn new(path: PathBuf, parsing_result: &ParsingResult) -> Self {
+        let files = watched_files(&path, parsing_result.report.files());
+        HotReload {
+            path,
+            watcher: FileWatcher::new(files),
+        }
+    }
+
+    /// Returns the new parsing result and shaders, if any file changed since the last call.
+    /// Errors are printed and return None, so the previous shader can keep running
+    pub fn poll(&mut self) -> Option<(ParsingResult, GeneratedShaders)> {
+        if !self.watcher.changed() {
+            return None;
+        }
+        println!("{}", "[sandengine]: Reloading...".bold());
+        let result = sandengine_lang::parse_path(self.path.clone());
+        // Also watch files that were imported for the first time (even if they contain errors)
+        let report = match &result {
+            Ok(result) => &result.report,
+            Err(report) => report,
+        };
+        self.watcher.watch(watched_files(&self.path, report.files()));
+
+        let result = match result {
+            Ok(result) => result,
+            Err(report) => {
+                println!("{}\n{}", "[sandengine-lang]:".red().bold(), report);
+                return None;
+            },
+        };
+        if !result.report.diagnostics.is_empty() {
+            println!("{}", result.report);
+        }
+        let mut shaders = match sandengine_lang::create_glsl_from_parser(&result) {
+            Ok(shaders) => shaders,
+            Err(err) => {
+                println!("{} {:#}", "[sandengine-lang]:".red().bold(), err);
+                return None;
+            },
+        };
+        if Path::new(SHADER_DIR).is_dir() {
+            if let Err(err) = shaders.load_compute_files(Path::new(SHADER_DIR)) {
+                println!("{} {:#}", "[sandengine]:".red().bold(), err);
+                return None;
+            }
+        }
+        Some((result, shaders))
+    }
+}
+
+
+/// The YAML file, the files it imports and the handwritten shaders
+fn watched_files<'a>(path: &Path, yaml_files: impl Iterator<Item = &'a str>) -> Vec<PathBuf> {
+    let mut files = vec![path.to_path_buf()];
+    files.extend(yaml_files.map(PathBuf::from));
+    files.extend(GeneratedShaders::compute_file_names().map(|name| Path::new(SHADER_DIR).join(name)));
+    files
+}
+
+
+/// Moves the world (cells, fields and light) and the parameters (time, seed, ...) of the old simulation into the
+/// new one, which uses the new materials. Cells and fields are remapped by name (see `WorldSave::restore`)
+pub fn transfer(old: &impl SimulationBackend, new: &mut impl SimulationBackend) {
+    *new.params_mut() = old.params().clone();
+    WorldSave::capture(old, true, true).restore(new);
+}
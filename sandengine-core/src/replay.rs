@@ -0,0 +1,397 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use sandengine_lang::parser::materials;
+
+use crate::backend::SimulationBackend;
+use crate::brush::Brush;
+use crate::save::{self, Reader, WorldSave};
+use crate::simulation::SimModification;
+
+
+
+/// The first bytes of every replay file
+const MAGIC: &[u8; 8] = b"SANDRPLY";
+/// Version of the file format, files with a newer version can't be played
+pub const REPLAY_VERSION: u32 = 1;
+
+
+/// The brush during one frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BrushState {
+    /// Position of the mouse, relative to the size of the window (0-1)
+    pub mouse_pos: (f32, f32),
+    pub mouse_pressed: bool,
+    pub size: u32,
+    /// Id of the material
+    pub material: u16,
+}
+
+
+/// Everything the simulation got from the outside during one frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayFrame {
+    pub time: f32,
+    pub brush: BrushState,
+    /// The modifications applied in this frame
+    pub modifications: Vec<SimModification>,
+}
+
+
+/// A recorded session: the world and the seed at the start and the input of every frame after it.
+/// Playing it on the same materials produces the same frames again.
+///
+/// The file format (version 1, all numbers little endian):
+///
+/// | Content | Type |
+/// |---|---|
+/// | `SANDRPLY` | 8 bytes |
+/// | version | u32 |
+/// | seed | u32 |
+/// | number of frames | u32 |
+/// | length of the world, world file (see `WorldSave`) | u32, bytes |
+/// | zlib compressed: every frame | see below |
+///
+/// Every frame consists of the time (f32), the brush (mouse position: 2 f32, pressed: u8, size: u32,
+/// material id: u16) and the modifications (number: u16, then for each: position: 2 i32, shape: u8,
+/// size: u32, material id: u16, replaced material id: i32, density: f32)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+    pub seed: u32,
+    /// The world when the recording started
+    pub world: WorldSave,
+    pub frames: Vec<ReplayFrame>,
+}
+impl Replay {
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_bytes()?)
+            .with_context(|| format!("Could not save the replay '{}'", path.display()))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Could not load the replay '{}'", path.display()))?;
+        Replay::from_bytes(&bytes)
+            .with_context(|| format!("Invalid replay file '{}'", path.display()))
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let world = self.world.to_bytes()?;
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(REPLAY_VERSION.to_le_bytes());
+        bytes.extend(self.seed.to_le_bytes());
+        bytes.extend((self.frames.len() as u32).to_le_bytes());
+        bytes.extend((world.len() as u32).to_le_bytes());
+        bytes.extend(world);
+
+        let mut data = vec![];
+        for frame in self.frames.iter() {
+            let brush = &frame.brush;
+            data.extend(frame.time.to_le_bytes());
+            data.extend(brush.mouse_pos.0.to_le_bytes());
+            data.extend(brush.mouse_pos.1.to_le_bytes());
+            data.push(brush.mouse_pressed as u8);
+            data.extend(brush.size.to_le_bytes());
+            data.extend(brush.material.to_le_bytes());
+            let num_modifications = u16::try_from(frame.modifications.len())
+                .map_err(|_| anyhow!("Too many modifications in one frame ({})", frame.modifications.len()))?;
+            data.extend(num_modifications.to_le_bytes());
+            for modification in frame.modifications.iter() {
+                data.extend(modification.position[0].to_le_bytes());
+                data.extend(modification.position[1].to_le_bytes());
+                data.push(u8::try_from(modification.mod_shape).map_err(|_| anyhow!("Invalid shape {}", modification.mod_shape))?);
+                data.extend(u32::try_from(modification.mod_size).map_err(|_| anyhow!("Invalid size {}", modification.mod_size))?.to_le_bytes());
+                data.extend(u16::try_from(modification.mod_matID).map_err(|_| anyhow!("Invalid material id {}", modification.mod_matID))?.to_le_bytes());
+                data.extend(modification.mod_replaceID.to_le_bytes());
+                data.extend(modification.mod_density.to_le_bytes());
+            }
+        }
+        let mut encoder = ZlibEncoder::new(bytes, Compression::default());
+        encoder.write_all(&data)?;
+        Ok(encoder.finish()?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            bail!("Not a replay file");
+        }
+        let version = reader.u32()?;
+        if version > REPLAY_VERSION {
+            bail!("The replay was recorded with a newer version of the format ({}, supported up to {})", version, REPLAY_VERSION);
+        }
+        let seed = reader.u32()?;
+        let num_frames = reader.u32()?;
+        let world_len = reader.u32()? as usize;
+        let world = WorldSave::from_bytes(reader.take(world_len)?)
+            .context("The world of the replay is invalid")?;
+
+        let mut data = vec![];
+        ZlibDecoder::new(&bytes[reader.pos..]).read_to_end(&mut data)
+            .context("The frames are corrupted")?;
+        let mut data = Reader { bytes: &data, pos: 0 };
+        let mut frames = vec![];
+        for _ in 0..num_frames {
+            let time = f32::from_bits(data.u32()?);
+            let brush = BrushState {
+                mouse_pos: (f32::from_bits(data.u32()?), f32::from_bits(data.u32()?)),
+                mouse_pressed: data.u8()? != 0,
+                size: data.u32()?,
+                material: data.u16()?,
+            };
+            let num_modifications = data.u16()?;
+            let mut modifications = vec![];
+            for _ in 0..num_modifications {
+                modifications.push(SimModification {
+                    position: [data.u32()? as i32, data.u32()? as i32],
+                    mod_shape: data.u8()? as i32,
+                    mod_size: data.u32()? as i32,
+                    mod_matID: data.u16()? as i32,
+                    mod_replaceID: data.u32()? as i32,
+                    mod_density: f32::from_bits(data.u32()?),
+                    ..Default::default()
+                });
+            }
+            frames.push(ReplayFrame { time, brush, modifications });
+        }
+        if data.pos != data.bytes.len() {
+            bail!("The frames do not match the number of frames ({})", num_frames);
+        }
+
+        Ok(Replay {
+            seed,
+            world,
+            frames,
+        })
+    }
+}
+
+
+/// Records the input of every frame, starting with the current world of the simulation
+pub struct Recorder {
+    replay: Replay,
+    /// Modifications that were still queued when the recording started, they are recorded
+    /// in front of the modifications of the first frame
+    queued: Vec<SimModification>,
+}
+impl Recorder {
+    pub fn start(sim: &(impl SimulationBackend + ?Sized)) -> Self {
+        Self {
+            replay: Replay {
+                seed: sim.params().seed,
+                world: WorldSave::capture(sim, true, true),
+                frames: vec![],
+            },
+            queued: sim.queued_modifications().to_vec(),
+        }
+    }
+
+    /// Records the brush and the modifications of the next frame. Call it before `step`,
+    /// with the same modifications that are applied to the simulation
+    pub fn record(&mut self, sim: &(impl SimulationBackend + ?Sized), brush: &Brush, modifications: &[SimModification]) {
+        let params = sim.params();
+        self.replay.frames.push(ReplayFrame {
+            time: params.time,
+            brush: BrushState {
+                mouse_pos: params.mousePos,
+                mouse_pressed: params.mousePressed,
+                size: brush.size,
+                material: brush.material.id as u16,
+            },
+            modifications: self.queued.drain(..).chain(modifications.iter().copied()).collect(),
+        });
+    }
+
+    /// Number of recorded frames
+    pub fn len(&self) -> usize {
+        self.replay.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replay.frames.is_empty()
+    }
+
+    pub fn finish(self) -> Replay {
+        self.replay
+    }
+}
+
+
+/// Plays a replay frame by frame. Material ids are remapped by name (like when loading a world),
+/// but the frames are only the same if the materials and rules didn't change since recording
+pub struct Player {
+    replay: Replay,
+    /// New id of every id of the replay
+    ids: Vec<u16>,
+    next: usize,
+}
+impl Player {
+    /// Restores the world and the seed of the replay
+    pub fn start(replay: Replay, sim: &mut (impl SimulationBackend + ?Sized)) -> Self {
+        // Also restores the frame (at least 1, the first frame clears the world on the GPU)
+        replay.world.restore(sim);
+        sim.params_mut().seed = replay.seed;
+        let ids = materials::remap_names(sim.materials(), replay.world.materials.iter().map(|(name, id)| (name.as_str(), *id)));
+        Self {
+            replay,
+            ids,
+            next: 0,
+        }
+    }
+
+    /// Queues the modifications of the next frame, call it before `step`. Returns the (remapped) brush
+    /// of the frame, or None (and changes nothing) once all frames were played
+    pub fn play_next(&mut self, sim: &mut (impl SimulationBackend + ?Sized)) -> Option<BrushState> {
+        let frame = self.replay.frames.get(self.next)?;
+        self.next += 1;
+
+        let id = |id: u16| self.ids.get(id as usize).copied().unwrap_or(save::ID_EMPTY);
+        let params = sim.params_mut();
+        params.time = frame.time;
+        params.mousePos = frame.brush.mouse_pos;
+        params.mousePressed = frame.brush.mouse_pressed;
+
+        let modifications: Vec<SimModification> = frame.modifications.iter()
+            .map(|m| SimModification {
+                mod_matID: id(m.mod_matID as u16) as i32,
+                mod_replaceID: match m.mod_replaceID {
+                    replace if replace >= 0 => id(replace as u16) as i32,
+                    replace => replace,
+                },
+                ..*m
+            })
+            .collect();
+        sim.apply_modifications(&modifications);
+        Some(BrushState { material: id(frame.brush.material), ..frame.brush })
+    }
+
+    /// Number of frames that were played so far
+    pub fn played(&self) -> usize {
+        self.next
+    }
+
+    pub fn len(&self) -> usize {
+        self.replay.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replay.frames.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sandengine_lang::{parse_string, ParsingResult};
+
+    use super::*;
+    use crate::brush::{BrushMode, BrushShape};
+    use crate::cpu::CpuSimulation;
+
+    fn parse() -> ParsingResult {
+        parse_string("
+fields:
+    heat: float
+rules:
+    fall:
+        if: SELF.density > DOWN.density
+        do: [SWAP SELF DOWN, INC SELF heat 1.0]
+types:
+    movable_solid:
+        base_rules: [fall]
+    solid:
+materials:
+    sand:
+        type: movable_solid
+        color: [255, 255, 0]
+        density: 1.5
+    rock:
+        type: solid
+        color: [50, 50, 50]
+        density: 4.0
+", None).unwrap()
+    }
+
+    fn material(result: &ParsingResult, name: &str) -> materials::SandMaterial {
+        result.materials.iter().find(|m| m.name == name).unwrap().clone()
+    }
+
+    #[test]
+    fn round_trip() {
+        let result = parse();
+        let mut sim = CpuSimulation::new(&result, (4, 3), 5);
+        sim.write_cells(&[material(&result, "sand").id as u16; 12]);
+        let mut brush = Brush::new(material(&result, "rock"));
+        brush.mode = BrushMode::Replace(material(&result, "sand").id as u16);
+        brush.shape = BrushShape::Spray;
+        let replay = Replay {
+            seed: 5,
+            world: WorldSave::capture(&sim, true, true),
+            frames: vec![
+                ReplayFrame {
+                    time: 0.5,
+                    brush: BrushState { mouse_pos: (0.25, 0.75), mouse_pressed: true, size: 3, material: 4 },
+                    modifications: vec![brush.modification((1, 2)), brush.modification((-3, 7))],
+                },
+                ReplayFrame {
+                    time: 0.75,
+                    brush: BrushState { mouse_pos: (0.5, 0.5), mouse_pressed: false, size: 3, material: 4 },
+                    modifications: vec![],
+                },
+            ],
+        };
+        let bytes = replay.to_bytes().unwrap();
+        assert_eq!(Replay::from_bytes(&bytes).unwrap(), replay);
+
+        // Broken files
+        assert!(Replay::from_bytes(&bytes[..bytes.len() - 4]).is_err());
+        let mut newer = bytes.clone();
+        newer[MAGIC.len()] = REPLAY_VERSION as u8 + 1;
+        assert!(Replay::from_bytes(&newer).is_err());
+    }
+
+    #[test]
+    fn record_and_play() {
+        let result = parse();
+        let (sand, rock) = (material(&result, "sand").id as u16, material(&result, "rock").id as u16);
+        let mut sim = CpuSimulation::new(&result, (16, 12), 7);
+        let mut cells = vec![save::ID_EMPTY; 16 * 12];
+        cells[16 * 11..].fill(rock);
+        sim.write_cells(&cells);
+
+        let mut brush = Brush::new(material(&result, "sand"));
+        brush.size = 2;
+        // Queued before the recording started, so it is recorded in the first frame
+        sim.apply_modifications(&[brush.modification((8, 1))]);
+        let mut recorder = Recorder::start(&sim);
+        for frame in 0..40 {
+            let modifications = match frame {
+                0..=9 => brush.stroke((2 + frame, 3), true),
+                20 => {
+                    brush.mode = BrushMode::Erase;
+                    brush.stroke((4, 9), true)
+                },
+                _ => brush.stroke((0, 0), false),
+            };
+            recorder.record(&sim, &brush, &modifications);
+            sim.apply_modifications(&modifications);
+            sim.step();
+        }
+        assert_eq!(recorder.len(), 40);
+        let replay = Replay::from_bytes(&recorder.finish().to_bytes().unwrap()).unwrap();
+
+        // A different seed and frame, both are restored from the replay
+        let mut played = CpuSimulation::new(&result, (16, 12), 0);
+        played.step();
+        let mut player = Player::start(replay, &mut played);
+        while player.play_next(&mut played).is_some() {
+            played.step();
+        }
+        assert_eq!(player.played(), 40);
+        assert_eq!(played.read_cells(), sim.read_cells());
+        assert_eq!(played.read_fields(), sim.read_fields());
+        assert!(played.read_cells().contains(&sand));
+    }
+}
@@ -0,0 +1,399 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use sandengine_lang::parser::materials;
+
+use crate::backend::SimulationBackend;
+
+
+
+/// The first bytes of every world file
+const MAGIC: &[u8; 8] = b"SANDWRLD";
+/// Version of the file format, files with a newer version can't be loaded
+pub const WORLD_VERSION: u32 = 1;
+
+const FLAG_FIELDS: u32 = 1;
+const FLAG_LIGHT: u32 = 2;
+
+pub(crate) const ID_EMPTY: u16 = 0;
+
+
+/// A saved world: the cells, the custom fields and the light of a simulation.
+/// Ids are only meaningful together with the material table, so worlds can still be loaded after
+/// the materials changed (see `ParsingResult::remap_from`).
+///
+/// The file format (version 1, all numbers little endian):
+///
+/// | Content | Type |
+/// |---|---|
+/// | `SANDWRLD` | 8 bytes |
+/// | version | u32 |
+/// | width, height | u32, u32 |
+/// | frame, time | i32, f32 |
+/// | flags (1: fields, 2: light) | u32 |
+/// | number of materials, then for each: id, name | u32, (u16, string) |
+/// | number of fields, then for each: name | u32, string |
+/// | zlib compressed: ids, fields (if flag 1), light (if flag 2) | u16 per cell, f32 per field and cell, 4 f32 per cell |
+///
+/// Strings are stored as their length (u16) followed by the UTF-8 bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorldSave {
+    pub size: (u32, u32),
+    pub frame: i32,
+    pub time: f32,
+    /// Name and id of every material at the time of saving
+    pub materials: Vec<(String, u16)>,
+    /// Material id of every cell, row by row
+    pub cells: Vec<u16>,
+    /// Names of the custom fields and their values (one value per field and cell), if they were saved
+    pub fields: Option<(Vec<String>, Vec<f32>)>,
+    /// Light of every cell, if it was saved
+    pub light: Option<Vec<[f32; 4]>>,
+}
+impl WorldSave {
+    /// Reads the cells of the simulation back. The fields are only saved if there are any
+    pub fn capture(sim: &(impl SimulationBackend + ?Sized), with_fields: bool, with_light: bool) -> Self {
+        let fields = (with_fields && !sim.fields().is_empty()).then(|| {
+            (sim.fields().iter().map(|f| f.name.clone()).collect(), sim.read_fields())
+        });
+        WorldSave {
+            size: sim.size(),
+            frame: sim.params().frame,
+            time: sim.params().time,
+            materials: sim.materials().iter().map(|m| (m.name.clone(), m.id as u16)).collect(),
+            cells: sim.read_cells(),
+            fields,
+            light: with_light.then(|| sim.read_light()),
+        }
+    }
+
+    /// Writes the world into the simulation. Cells are remapped by the name of their material, materials
+    /// that don't exist anymore become EMPTY. Fields are matched by name, fields that weren't saved (and all
+    /// fields of cells whose material was removed) get the default value of the material.
+    /// If the sizes differ, the world is cropped or filled up with EMPTY cells
+    pub fn restore(&self, sim: &mut (impl SimulationBackend + ?Sized)) {
+        let new_names: Vec<&str> = sim.materials().iter().map(|m| m.name.as_str()).collect();
+        let ids = materials::remap_names(sim.materials(), self.materials.iter().map(|(name, id)| (name.as_str(), *id)));
+        // Whether the material of an (old) id still exists
+        let kept: Vec<bool> = (0..ids.len())
+            .map(|id| self.materials.iter().any(|(name, old)| *old as usize == id && new_names.contains(&name.as_str())))
+            .collect();
+
+        let size = sim.size();
+        let num_cells = (size.0 * size.1) as usize;
+        // Index of every cell of the simulation inside of the saved world
+        let source: Vec<Option<usize>> = (0..num_cells)
+            .map(|idx| (idx as u32 % size.0, idx as u32 / size.0))
+            .map(|(x, y)| (x < self.size.0 && y < self.size.1).then(|| (y * self.size.0 + x) as usize))
+            .collect();
+
+        let cells: Vec<u16> = source.iter()
+            .map(|src| src.map_or(ID_EMPTY, |src| ids.get(self.cells[src] as usize).copied().unwrap_or(ID_EMPTY)))
+            .collect();
+        sim.write_cells(&cells);
+
+        if let Some((names, values)) = &self.fields {
+            let new_fields: Vec<Option<usize>> = sim.fields().iter()
+                .map(|f| names.iter().position(|n| *n == f.name))
+                .collect();
+            let num_fields = new_fields.len();
+            let mut fields = sim.read_fields();
+            for (idx, src) in source.iter().enumerate() {
+                let Some(src) = *src else {
+                    continue;
+                };
+                if !kept.get(self.cells[src] as usize).copied().unwrap_or(false) {
+                    continue;
+                }
+                for (field, old) in new_fields.iter().enumerate() {
+                    if let Some(old) = old {
+                        fields[idx * num_fields + field] = values[src * names.len() + old];
+                    }
+                }
+            }
+            sim.write_fields(&fields);
+        }
+
+        if let Some(light) = &self.light {
+            let light: Vec<[f32; 4]> = source.iter()
+                .map(|src| src.map_or([0.0; 4], |src| light[src]))
+                .collect();
+            sim.write_light(&light);
+        }
+
+        let params = sim.params_mut();
+        // The first frame clears the world on the GPU
+        params.frame = self.frame.max(1);
+        params.time = self.time;
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_bytes()?)
+            .with_context(|| format!("Could not save the world '{}'", path.display()))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Could not load the world '{}'", path.display()))?;
+        WorldSave::from_bytes(&bytes)
+            .with_context(|| format!("Invalid world file '{}'", path.display()))
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut flags = 0;
+        if self.fields.is_some() {
+            flags |= FLAG_FIELDS;
+        }
+        if self.light.is_some() {
+            flags |= FLAG_LIGHT;
+        }
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(WORLD_VERSION.to_le_bytes());
+        bytes.extend(self.size.0.to_le_bytes());
+        bytes.extend(self.size.1.to_le_bytes());
+        bytes.extend(self.frame.to_le_bytes());
+        bytes.extend(self.time.to_le_bytes());
+        bytes.extend(flags.to_le_bytes());
+        bytes.extend((self.materials.len() as u32).to_le_bytes());
+        for (name, id) in self.materials.iter() {
+            bytes.extend(id.to_le_bytes());
+            write_str(&mut bytes, name)?;
+        }
+        let field_names = self.fields.as_ref().map_or(&[][..], |(names, _)| names);
+        bytes.extend((field_names.len() as u32).to_le_bytes());
+        for name in field_names {
+            write_str(&mut bytes, name)?;
+        }
+
+        let mut data = vec![];
+        data.extend(self.cells.iter().flat_map(|id| id.to_le_bytes()));
+        if let Some((_, values)) = &self.fields {
+            data.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+        }
+        if let Some(light) = &self.light {
+            data.extend(light.iter().flatten().flat_map(|v| v.to_le_bytes()));
+        }
+        let mut encoder = ZlibEncoder::new(bytes, Compression::default());
+        encoder.write_all(&data)?;
+        Ok(encoder.finish()?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            bail!("Not a world file");
+        }
+        let version = reader.u32()?;
+        if version > WORLD_VERSION {
+            bail!("The world was saved with a newer version of the format ({}, supported up to {})", version, WORLD_VERSION);
+        }
+        let size = (reader.u32()?, reader.u32()?);
+        let frame = reader.u32()? as i32;
+        let time = f32::from_bits(reader.u32()?);
+        let flags = reader.u32()?;
+        let num_materials = reader.u32()?;
+        let mut materials = vec![];
+        for _ in 0..num_materials {
+            let id = reader.u16()?;
+            materials.push((reader.str()?, id));
+        }
+        let num_fields = reader.u32()?;
+        let field_names = (0..num_fields).map(|_| reader.str()).collect::<anyhow::Result<Vec<String>>>()?;
+
+        let mut data = vec![];
+        ZlibDecoder::new(&bytes[reader.pos..]).read_to_end(&mut data)
+            .context("The cell data is corrupted")?;
+        let mut data = Reader { bytes: &data, pos: 0 };
+        let num_cells = size.0 as usize * size.1 as usize;
+        let cells = (0..num_cells).map(|_| data.u16()).collect::<anyhow::Result<Vec<u16>>>()?;
+        let fields = match flags & FLAG_FIELDS != 0 {
+            true => {
+                let values = (0..num_cells * field_names.len())
+                    .map(|_| data.u32().map(f32::from_bits))
+                    .collect::<anyhow::Result<Vec<f32>>>()?;
+                Some((field_names, values))
+            },
+            false => None,
+        };
+        let light = match flags & FLAG_LIGHT != 0 {
+            true => {
+                let values = (0..num_cells * 4)
+                    .map(|_| data.u32().map(f32::from_bits))
+                    .collect::<anyhow::Result<Vec<f32>>>()?;
+                Some(values.chunks(4).map(|c| [c[0], c[1], c[2], c[3]]).collect())
+            },
+            false => None,
+        };
+        if data.pos != data.bytes.len() {
+            bail!("The cell data does not match the size {}x{}", size.0, size.1);
+        }
+
+        Ok(WorldSave {
+            size,
+            frame,
+            time,
+            materials,
+            cells,
+            fields,
+            light,
+        })
+    }
+}
+
+
+pub(crate) fn write_str(bytes: &mut Vec<u8>, s: &str) -> anyhow::Result<()> {
+    let len = u16::try_from(s.len()).map_err(|_| anyhow!("The name '{}' is too long", s))?;
+    bytes.extend(len.to_le_bytes());
+    bytes.extend(s.as_bytes());
+    Ok(())
+}
+
+
+/// Reads little endian numbers and strings from bytes
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) pos: usize,
+}
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos + len).ok_or(anyhow!("The file is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    pub(crate) fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub(crate) fn str(&mut self) -> anyhow::Result<String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sandengine_lang::{parse_string, ParsingResult};
+
+    use super::*;
+    use crate::cpu::CpuSimulation;
+
+    fn parse(fields: &str, materials: &str) -> ParsingResult {
+        parse_string(&format!("
+fields:
+{}
+rules:
+    fall:
+        if: SELF.density > DOWN.density
+        do: SWAP SELF DOWN
+types:
+    movable_solid:
+        base_rules: [fall]
+materials:
+    sand:
+        type: movable_solid
+        color: [255, 255, 0]
+        density: 1.5
+{}", fields, materials), None).unwrap()
+    }
+
+    fn id(result: &ParsingResult, name: &str) -> u16 {
+        result.materials.iter().find(|m| m.name == name).unwrap().id as u16
+    }
+
+    const WATER: &str = "
+    water:
+        type: movable_solid
+        color: [0, 0, 255]
+        density: 1.0";
+
+    #[test]
+    fn round_trip() {
+        let result = parse("    heat: float", WATER);
+        let mut sim = CpuSimulation::new(&result, (4, 3), 0);
+        let cells: Vec<u16> = (0..12).map(|idx| [ID_EMPTY, id(&result, "sand"), id(&result, "water")][idx % 3]).collect();
+        sim.write_cells(&cells);
+        sim.write_fields(&(0..12).map(|idx| idx as f32 * 0.5).collect::<Vec<_>>());
+        sim.write_light(&(0..12).map(|idx| [idx as f32, 0.0, 1.0, 0.5]).collect::<Vec<_>>());
+        sim.params_mut().frame = 17;
+        sim.params_mut().time = 2.5;
+
+        let world = WorldSave::capture(&sim, true, true);
+        assert_eq!(WorldSave::from_bytes(&world.to_bytes().unwrap()).unwrap(), world);
+        let without = WorldSave::capture(&sim, false, false);
+        assert_eq!(WorldSave::from_bytes(&without.to_bytes().unwrap()).unwrap(), without);
+
+        let mut loaded = CpuSimulation::new(&result, (4, 3), 0);
+        world.restore(&mut loaded);
+        assert_eq!(loaded.read_cells(), cells);
+        assert_eq!(loaded.read_fields(), sim.read_fields());
+        assert_eq!(loaded.read_light(), sim.read_light());
+        assert_eq!((loaded.params().frame, loaded.params().time), (17, 2.5));
+
+        // Broken files
+        let bytes = world.to_bytes().unwrap();
+        assert!(WorldSave::from_bytes(&bytes[..20]).is_err());
+        let mut newer = bytes.clone();
+        newer[MAGIC.len()] = WORLD_VERSION as u8 + 1;
+        assert!(WorldSave::from_bytes(&newer).is_err());
+    }
+
+    #[test]
+    fn size_change() {
+        let result = parse("    heat: float", WATER);
+        let sand = id(&result, "sand");
+        let mut sim = CpuSimulation::new(&result, (4, 3), 0);
+        sim.write_cells(&[sand; 12]);
+        sim.write_light(&[[1.0; 4]; 12]);
+        let world = WorldSave::capture(&sim, true, true);
+
+        // Wider but lower: the bottom row is cropped, the new columns are EMPTY
+        let mut loaded = CpuSimulation::new(&result, (6, 2), 0);
+        world.restore(&mut loaded);
+        let cells = loaded.read_cells();
+        let light = loaded.read_light();
+        for y in 0..2 {
+            for x in 0..6 {
+                let idx = y * 6 + x;
+                match x < 4 {
+                    true => assert_eq!((cells[idx], light[idx]), (sand, [1.0; 4])),
+                    false => assert_eq!((cells[idx], light[idx]), (ID_EMPTY, [0.0; 4])),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fields_by_name() {
+        let old = parse("    age: int\n    heat: float", &format!("{}
+    gone:
+        type: movable_solid
+        color: [1, 2, 3]
+        density: 9.0", WATER));
+        let mut sim = CpuSimulation::new(&old, (2, 1), 0);
+        sim.write_cells(&[id(&old, "water"), id(&old, "gone")]);
+        // (age, heat) of both cells
+        sim.write_fields(&[3.0, 40.0, 5.0, 60.0]);
+        let world = WorldSave::capture(&sim, true, false);
+
+        // The fields were reordered, 'age' was removed and 'wet' added
+        let new = parse("    heat: float\n    wet:\n        type: int\n        default: 1", WATER);
+        let mut loaded = CpuSimulation::new(&new, (2, 1), 0);
+        world.restore(&mut loaded);
+        assert_eq!(loaded.read_cells(), [id(&new, "water"), ID_EMPTY]);
+        // 'heat' is kept, 'wet' gets the default and the cell whose material was removed gets the defaults
+        assert_eq!(loaded.read_fields(), [40.0, 1.0, 0.0, 1.0]);
+    }
+}
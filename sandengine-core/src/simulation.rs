@@ -1,8 +1,14 @@
-use glium::{texture::{self, RawImage2d}, uniforms, Surface};
-use rand::Rng;
-use image::{io::Reader as ImageReader, GenericImageView};
-use sandengine_lang::parser::materials::SandMaterial;
+use std::path::Path;
+use std::rc::Rc;
+
+use anyhow::Context as _;
+use glium::{backend::{Context, Facade}, buffer::{Buffer, BufferMode, BufferType, Content}, texture::{self, RawImage2d, TextureAnyImage}, uniforms, Rect, Surface};
+use sandengine_lang::GeneratedShaders;
+use sandengine_lang::interpreter::hash43;
+use sandengine_lang::parser::{ParsingResult, fields::{self, SandField}, materials::SandMaterial};
 use crate::RendererDisplay;
+use crate::backend::SimulationBackend;
+use crate::modifications::{self, ModificationTiles};
 
 
 #[repr(C)]
@@ -38,12 +44,17 @@ struct SimRBCell {
 implement_uniform_block!(SimRBCell, matID, orig_pos, pos, rb_idx);
 
 
+/// The image, that is displayed behind the simulation
+const BACKGROUND_IMAGE: &[u8] = include_bytes!("../../data/ice_pepe.png");
+
+
 pub const MODSHAPE_CIRCLE: i32 = 0;
 pub const MODSHAPE_SQUARE: i32 = 1;
-const MAX_MODIFICATIONS: usize = 256;
+/// A circle, in which only some of the cells are modified (see `mod_density`)
+pub const MODSHAPE_SPRAY: i32 = 2;
 
 #[repr(C)]
-#[derive(Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct SimModification {
     pub position: [i32; 2],
     //pub _pad: [i32; 1],
@@ -52,16 +63,26 @@ pub struct SimModification {
     pub mod_size: i32,
     //pub _pad3: [i32; 1],
     pub mod_matID: i32,
-    pub _pad4: [i32; 3],
+    /// Only cells of this material are modified (-1: all cells)
+    pub mod_replaceID: i32,
+    /// Fraction of the cells a spray modifies (0-1)
+    pub mod_density: f32,
+    pub _pad4: [i32; 1],
 }
-implement_uniform_block!(SimModification, position, mod_shape, mod_size, mod_matID);
-
-#[repr(C)]
-#[derive(Clone, Copy)]
-struct SimModifications {
-    sim_modifications: [SimModification; MAX_MODIFICATIONS]
+impl Default for SimModification {
+    fn default() -> Self {
+        Self {
+            position: [0, 0],
+            mod_shape: MODSHAPE_CIRCLE,
+            mod_size: 0,
+            mod_matID: 0,
+            mod_replaceID: -1,
+            mod_density: 1.0,
+            _pad4: [0],
+        }
+    }
 }
-implement_uniform_block!(SimModifications, sim_modifications);
+implement_uniform_block!(SimModification, position, mod_shape, mod_size, mod_matID, mod_replaceID, mod_density);
 
 
 #[repr(C)]
@@ -71,19 +92,17 @@ pub struct Params {
     pub moveRight: bool,
     pub mousePos: (f32, f32),
     pub mousePressed: bool,
-    // TODO: Move brush properties outside of the Simulation struct
-    pub brushSize: u32,
-    pub brushMaterial: SandMaterial,
     pub time: f32,
     pub frame: i32,
+    /// Seed of every random value of the simulation. Together with the same world and the same
+    /// modifications, the same seed always produces the same frames (see `replay`)
+    pub seed: u32,
 }
 impl Params {
     pub fn new() -> Self {
         Self {
             moveRight: true,
             mousePos: (0.0, 0.0),
-            brushSize: 5,
-            brushMaterial: SandMaterial::default(),
             mousePressed: false,
             frame: 0,
             ..Default::default()
@@ -92,20 +111,42 @@ impl Params {
 }
 
 
+/// Whether the cells move to the right in this frame. Derived from the seed instead of a random
+/// number generator, so that replays produce the same frames
+pub fn move_right(seed: u32, frame: i32) -> bool {
+    hash43([0, 0, frame as u32], seed)[3] < 0.5
+}
+
+
+
+/// The compute shader could not be compiled. Holds the diagnostics, which point at the YAML rules
+#[derive(Debug)]
+pub struct ShaderCompileError(pub String);
+impl std::fmt::Display for ShaderCompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The compute shader could not be compiled:\n\n{}", self.0)
+    }
+}
+impl std::error::Error for ShaderCompileError {}
+
+
 
 /// Holds all neccessary data to run the falling sand simulation (compute shader)
 pub struct Simulation {
     /// The compute shader
     compute_shader: glium::program::ComputeShader,
+    /// Needed to recreate the cell data texture when cells are written
+    context: Rc<Context>,
     /// The size of the simulation
     size: (u32, u32),
     /// The number of work groups for the compute shader
     workgroups: (u32, u32, u32),
 
-    /// Input Texture, that stores the cell information (material id etc.)
-    input_data: texture::Texture2d,
-    /// Output Texture, that stores the cell information (material id etc.)
-    output_data: texture::Texture2d,
+    /// Input Texture, that stores the cell information (material id and custom fields).
+    /// Each layer holds 4 values
+    input_data: texture::Texture2dArray,
+    /// Output Texture, that stores the cell information (material id and custom fields)
+    output_data: texture::Texture2dArray,
     /// The final color returned from the compute shader
     pub output_color: texture::Texture2d,
     /// Input Texture, that stores the illumination for each cell
@@ -121,21 +162,38 @@ pub struct Simulation {
     /// The parameters for the simulation (uniforms)
     pub params: Params,
 
-    modifications_buffer: glium::uniforms::UniformBuffer<SimModifications>,
-    pub modifications: Vec<SimModification>
+    /// The modifications of the current frame and the tiles they are sorted into (see `ModificationTiles`).
+    /// Both buffers grow when they are too small
+    modifications_buffer: Buffer<[SimModification]>,
+    modification_tiles: Buffer<[u32]>,
+    /// Whether the tiles still contain the modifications of the last frame
+    tiles_in_use: bool,
+    /// The queued modifications. At most `MAX_MODIFICATIONS` of them are sent to the shader per frame,
+    /// the rest stay queued for the next frames
+    pub modifications: Vec<SimModification>,
+
+    materials: Vec<SandMaterial>,
+    fields: Vec<SandField>,
+    /// The cell data (all layers) of a new cell of each material, indexed by material id
+    default_cells: Vec<Vec<[f32; fields::CHANNELS_PER_LAYER]>>,
 }
 impl Simulation {
-    pub fn new(display: &RendererDisplay, size: (u32, u32)) -> Self {
-        let current_dir = std::env::current_dir().unwrap();
-        let compute_shader_src = std::fs::read_to_string(current_dir.join("shaders/compute/gen/falling_sand.glsl")).unwrap();
+    /// Creates the simulation for the materials and fields of the parsing result,
+    /// using the compute shader generated from it. Fails if the shader does not compile
+    pub fn new(display: &RendererDisplay, parsing_result: &ParsingResult, shaders: &GeneratedShaders, size: (u32, u32)) -> anyhow::Result<Self> {
+        let data_layers = parsing_result.num_data_layers();
 
         // Creates the shader program
-        let program = glium::program::ComputeShader::from_source(display, &compute_shader_src);
-        if let Err(err) = program {
-            println!("{}", err);
-            panic!();
+        let program = match glium::program::ComputeShader::from_source(display, &shaders.compute) {
+            Ok(program) => program,
+            Err(err) => {
+                // Points the errors at the YAML rules (or the original files) instead of the assembled shader
+                let diagnostics: Vec<String> = shaders.diagnose(&err.to_string(), &parsing_result.report).iter()
+                    .map(|d| parsing_result.report.render(d))
+                    .collect();
+                return Err(ShaderCompileError(diagnostics.join("\n\n")).into());
+            },
         };
-        let program = program.unwrap();
         
         // Set up all the required textures with their format and mipmaps
 
@@ -146,36 +204,26 @@ impl Simulation {
         let collision_tex_scale = 8;
         let colsize = (size.0 / collision_tex_scale, size.1 / collision_tex_scale);
         let coldata : Vec<f32> = vec![0.0; (colsize.0 * colsize.1 * 4) as usize];
+        let data_array = || (0..data_layers).map(|_| RawImage2d::from_raw_rgba(data.clone(), size)).collect::<Vec<_>>();
 
         let output_color = texture::Texture2d::with_format(display, RawImage2d::from_raw_rgba(data.clone(), size), format, auto_mip).unwrap();
 
-        let path = current_dir.join("data").join("ice_pepe.png");
-        let bg_img = ImageReader::open(path).unwrap().decode().unwrap();
-        let background = texture::Texture2d::with_format(
-            display,
-            RawImage2d::from_raw_rgba_reversed(bg_img.as_bytes(), bg_img.dimensions()),
-            texture::UncompressedFloatFormat::F32F32F32F32,
-            texture::MipmapsOption::AutoGeneratedMipmaps).unwrap();
+        let background = background_texture(display, &image::load_from_memory(BACKGROUND_IMAGE).unwrap());
         
 
-        let mods = [
-            SimModification {
-                mod_shape: MODSHAPE_CIRCLE,
-                mod_size: 0,
-                mod_matID: 0,
-                ..Default::default()
-            }; MAX_MODIFICATIONS];
-        let modifications_buffer = glium::uniforms::UniformBuffer::new(
-            display,
-            SimModifications {sim_modifications: mods}).unwrap();
-        
-        Self {
+        let storage = BufferType::ShaderStorageBuffer;
+        let modifications_buffer = Buffer::empty_array(display, storage, 1, BufferMode::Dynamic).unwrap();
+        // Every tile starts out without modifications
+        let modification_tiles = Buffer::new(display, &ModificationTiles::new(&[], size).data[..], storage, BufferMode::Dynamic).unwrap();
+
+        Ok(Self {
             compute_shader: program,
+            context: display.get_context().clone(),
             size,
             workgroups: (((size.0 + 7) as f32 / 8.0) as u32, ((size.1 + 7) as f32 / 8.0) as u32, 1),
 
-            input_data: texture::Texture2d::with_format(display, RawImage2d::from_raw_rgba(data.clone(), size), format, no_mip).unwrap(),
-            output_data: texture::Texture2d::with_format(display, RawImage2d::from_raw_rgba(data.clone(), size), format, no_mip).unwrap(),
+            input_data: texture::Texture2dArray::with_format(display, data_array(), format, no_mip).unwrap(),
+            output_data: texture::Texture2dArray::with_format(display, data_array(), format, no_mip).unwrap(),
             output_color,
             input_light: texture::Texture2d::with_format(display, RawImage2d::from_raw_rgba(data.clone(), size), format, no_mip).unwrap(),
             output_light: texture::Texture2d::with_format(display, RawImage2d::from_raw_rgba(data.clone(), size), format, no_mip).unwrap(),
@@ -187,8 +235,29 @@ impl Simulation {
             params: Params::new(),
 
             modifications_buffer,
-            modifications: vec![]
-        }
+            modification_tiles,
+            tiles_in_use: false,
+            modifications: vec![],
+
+            materials: parsing_result.materials.clone(),
+            fields: parsing_result.fields.clone(),
+            // Ids without a material get the cell of NULL
+            default_cells: parsing_result.material_indices().iter()
+                .map(|idx| &parsing_result.materials[*idx])
+                .map(|m| {
+                    let values = parsing_result.fields.iter().map(|f| m.field_value(f).as_f32()).collect::<Vec<f32>>();
+                    fields::pack_cell(m.id as u16, &values, &parsing_result.fields)
+                })
+                .collect(),
+        })
+    }
+
+    /// Replaces the image, that is displayed behind the simulation
+    pub fn load_background(&mut self, display: &RendererDisplay, path: &Path) -> anyhow::Result<()> {
+        let image = image::open(path)
+            .with_context(|| format!("Could not load the background '{}'", path.display()))?;
+        self.background = background_texture(display, &image);
+        Ok(())
     }
 
     /// Runs the simulation for one step
@@ -196,17 +265,18 @@ impl Simulation {
         self.collision_data.as_surface().clear_color(0.0, 0.0, 0.0, 1.0);
 
         // Updates simulation parameters
-        let mut rng = rand::thread_rng();
-        self.params.moveRight = rng.gen_bool(0.5);
         self.params.frame += 1;
-
-        {
-            let mut buf = self.modifications_buffer.map();
-            for i in 0..(self.modifications.len()).min(MAX_MODIFICATIONS) {
-                buf.sim_modifications[i] = self.modifications[i];
-            }
+        self.params.moveRight = move_right(self.params.seed, self.params.frame);
+
+        // Modifications, that don't fit into this frame, stay queued
+        let batch = modifications::next_batch(&mut self.modifications);
+        if !batch.is_empty() || self.tiles_in_use {
+            let tiles = ModificationTiles::new(&batch, self.size);
+            write_buffer(&self.context, &mut self.modifications_buffer, &batch);
+            write_buffer(&self.context, &mut self.modification_tiles, &tiles.data);
+            self.tiles_in_use = !batch.is_empty();
         }
-        
+
         // Prepares the textures as images in order for them to be writable by the compute shader
         let img_unit_format = glium::uniforms::ImageUnitFormat::RGBA32F;
         let write = glium::uniforms::ImageUnitAccess::Write;
@@ -230,7 +300,10 @@ impl Simulation {
                 time: self.params.time,
                 simSize: (self.size.0 as i32, self.size.1 as i32),
                 frame: self.params.frame,
+                seed: self.params.seed,
                 SimModifications: &self.modifications_buffer,
+                ModificationTiles: &self.modification_tiles,
+                modTilesX: modifications::num_tiles(self.size).0 as i32,
             }, self.workgroups.0, self.workgroups.1, self.workgroups.2);
 
         // Swaps the input and output textures so that the output of the current frame
@@ -242,13 +315,149 @@ impl Simulation {
             self.output_color.generate_mipmaps();
             //self.output_light.generate_mipmaps();
         };
+    }
+
+
+    fn rect(&self) -> Rect {
+        Rect { left: 0, bottom: 0, width: self.size.0, height: self.size.1 }
+    }
+
+    /// Reads a texture with one cell per pixel
+    fn read_texture(&self, texture: &texture::Texture2d) -> Vec<[f32; 4]> {
+        let image: TextureAnyImage = texture.main_level().into();
+        let rows: Vec<Vec<(f32, f32, f32, f32)>> = image.raw_read(&self.rect());
+        rows.into_iter().flatten().map(|(r, g, b, a)| [r, g, b, a]).collect()
+    }
+
+    /// Reads all layers of the latest cell data (after `run`, the input holds the latest cells)
+    fn read_data(&self) -> Vec<Vec<[f32; 4]>> {
+        (0..self.input_data.array_size())
+            .map(|layer| {
+                let image: TextureAnyImage = self.input_data.main_level().layer(layer).unwrap().into();
+                let rows: Vec<Vec<(f32, f32, f32, f32)>> = image.raw_read(&self.rect());
+                rows.into_iter().flatten().map(|(r, g, b, a)| [r, g, b, a]).collect()
+            })
+            .collect()
+    }
+
+    /// Replaces the cell data. Layers of a texture array can't be written one by one, so the texture is recreated
+    fn write_data(&mut self, layers: Vec<Vec<[f32; 4]>>) {
+        let images = layers.into_iter()
+            .map(|layer| RawImage2d::from_raw_rgba(layer.into_iter().flatten().collect::<Vec<f32>>(), self.size))
+            .collect();
+        let format = texture::UncompressedFloatFormat::F32F32F32F32;
+        self.input_data = texture::Texture2dArray::with_format(&self.context, images, format, texture::MipmapsOption::NoMipmap).unwrap();
+    }
+}
 
-        {
-            let mut buf = self.modifications_buffer.map();
-            for i in 0..(self.modifications.len()).min(MAX_MODIFICATIONS) {
-                buf.sim_modifications[i].mod_size = 0;
+
+/// Writes the data to the start of the buffer, which is replaced by a larger one if it is too small
+fn write_buffer<T>(context: &Rc<Context>, buffer: &mut Buffer<[T]>, data: &[T]) where [T]: Content, T: Copy {
+    if data.is_empty() {
+        return;
+    }
+    if buffer.len() < data.len() {
+        *buffer = Buffer::empty_array(context, BufferType::ShaderStorageBuffer, data.len().next_power_of_two(), BufferMode::Dynamic).unwrap();
+    }
+    buffer.slice(..data.len()).unwrap().write(data);
+}
+
+
+/// Creates the texture of a background image
+fn background_texture(display: &RendererDisplay, image: &image::DynamicImage) -> texture::Texture2d {
+    let image = image.to_rgba8();
+    let dimensions = image.dimensions();
+    texture::Texture2d::with_format(
+        display,
+        RawImage2d::from_raw_rgba_reversed(&image.into_raw(), dimensions),
+        texture::UncompressedFloatFormat::F32F32F32F32,
+        texture::MipmapsOption::AutoGeneratedMipmaps).unwrap()
+}
+
+
+impl SimulationBackend for Simulation {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn materials(&self) -> &[SandMaterial] {
+        &self.materials
+    }
+
+    fn fields(&self) -> &[SandField] {
+        &self.fields
+    }
+
+    fn params(&self) -> &Params {
+        &self.params
+    }
+
+    fn params_mut(&mut self) -> &mut Params {
+        &mut self.params
+    }
+
+    fn apply_modifications(&mut self, modifications: &[SimModification]) {
+        self.modifications.extend_from_slice(modifications);
+    }
+
+    fn queued_modifications(&self) -> &[SimModification] {
+        &self.modifications
+    }
+
+    fn step(&mut self) {
+        self.run();
+    }
+
+    fn read_cells(&self) -> Vec<u16> {
+        self.read_data()[0].iter().map(|cell| cell[0] as u16).collect()
+    }
+
+    fn write_cells(&mut self, cells: &[u16]) {
+        let num_cells = (self.size.0 * self.size.1) as usize;
+        let layers = (0..self.input_data.array_size() as usize)
+            .map(|layer| (0..num_cells)
+                .map(|idx| {
+                    // Unknown ids become NULL, just like in `getMaterialFromID`
+                    let id = cells.get(idx).copied().unwrap_or(0) as usize;
+                    self.default_cells.get(id).unwrap_or(&self.default_cells[1])[layer]
+                })
+                .collect())
+            .collect();
+        self.write_data(layers);
+        // The first frame clears the world, which would remove the written cells
+        self.params.frame = self.params.frame.max(1);
+    }
+
+    fn read_fields(&self) -> Vec<f32> {
+        let data = self.read_data();
+        let num_cells = (self.size.0 * self.size.1) as usize;
+        (0..num_cells)
+            .flat_map(|idx| self.fields.iter().map(|f| data[f.layer()][idx][f.slot % fields::CHANNELS_PER_LAYER]).collect::<Vec<f32>>())
+            .collect()
+    }
+
+    fn write_fields(&mut self, values: &[f32]) {
+        let mut data = self.read_data();
+        for (idx, cell) in values.chunks(self.fields.len().max(1)).enumerate().take(data[0].len()) {
+            for (field, value) in self.fields.iter().zip(cell) {
+                data[field.layer()][idx][field.slot % fields::CHANNELS_PER_LAYER] = *value;
             }
         }
-        self.modifications.clear();
+        self.write_data(data);
     }
-}
\ No newline at end of file
+
+    fn read_color(&self) -> Vec<[f32; 4]> {
+        self.read_texture(&self.output_color)
+    }
+
+    fn read_light(&self) -> Vec<[f32; 4]> {
+        // The light textures are swapped after each step
+        self.read_texture(&self.input_light)
+    }
+
+    fn write_light(&mut self, light: &[[f32; 4]]) {
+        let num_cells = (self.size.0 * self.size.1) as usize;
+        let data: Vec<f32> = light.iter().copied().chain(std::iter::repeat([0.0; 4])).take(num_cells).flatten().collect();
+        self.input_light.write(self.rect(), RawImage2d::from_raw_rgba(data, self.size));
+    }
+}
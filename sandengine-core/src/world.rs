@@ -0,0 +1,138 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use colored::Colorize;
+use sandengine_lang::ParsingResult;
+use sandengine_lang::parser::materials::SandMaterial;
+
+
+const ID_EMPTY: u16 = 0;
+
+
+/// Converts a color (0.0 to 1.0) to 8 bit RGBA
+pub fn to_rgba8(color: [f32; 4]) -> [u8; 4] {
+    color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+
+/// A color of an imported image that has no material of its own, its pixels became the material
+/// with the closest color
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnmatchedColor {
+    pub color: [u8; 3],
+    /// Number of pixels with the color
+    pub pixels: usize,
+    /// Name of the material the pixels became
+    pub material: String,
+}
+impl std::fmt::Display for UnmatchedColor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [r, g, b] = self.color;
+        write!(f, "#{:02x}{:02x}{:02x} ({} pixels) became '{}'", r, g, b, self.pixels, self.material)
+    }
+}
+
+
+/// A world loaded from an image
+#[derive(Debug, Clone)]
+pub struct ImportedWorld {
+    pub size: (u32, u32),
+    /// Material id of every cell, row by row
+    pub cells: Vec<u16>,
+    /// Colors that were not in the palette (or not the color of any material), by number of pixels
+    pub unmatched: Vec<UnmatchedColor>,
+}
+impl ImportedWorld {
+    /// Prints a warning listing the unmatched colors, if there are any
+    pub fn print_unmatched(&self, path: &Path) {
+        if self.unmatched.is_empty() {
+            return;
+        }
+        println!("{} {} color(s) of '{}' have no material, they became the closest one:", "[sandengine]:".yellow().bold(), self.unmatched.len(), path.display());
+        for color in self.unmatched.iter() {
+            println!("    {}", color);
+        }
+    }
+}
+
+
+/// Converts an image into a world, every pixel is a cell. Transparent pixels are EMPTY.
+/// With a palette (`ParsingResult::palette`), the colors are matched against it, otherwise
+/// against the colors of the materials. Colors that don't match exactly become the closest
+/// color and are reported in `unmatched`
+pub fn import_image(img: &image::RgbaImage, parsing_result: &ParsingResult) -> ImportedWorld {
+    let id_of = |name: &str| parsing_result.materials.iter().find(|m| m.name == name).map_or(ID_EMPTY, |m| m.id as u16);
+    // The colors pixels are matched against and the materials they become
+    let table: Vec<([u8; 3], u16)> = match parsing_result.palette.is_empty() {
+        true => parsing_result.materials.iter()
+            .filter(|m| m.name != "NULL")
+            .map(|m| {
+                let [r, g, b, _] = to_rgba8(m.color);
+                ([r, g, b], m.id as u16)
+            })
+            .collect(),
+        false => parsing_result.palette.iter().map(|p| (p.color, id_of(&p.material))).collect(),
+    };
+    let distance = |a: [u8; 3], b: [u8; 3]| (0..3).map(|c| (a[c] as i32 - b[c] as i32).pow(2)).sum::<i32>();
+
+    let mut unmatched: Vec<UnmatchedColor> = vec![];
+    let mut cells = Vec::with_capacity(img.len() / 4);
+    for pixel in img.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a == 0 {
+            cells.push(ID_EMPTY);
+            continue;
+        }
+        let color = [r, g, b];
+        let Some((closest, id)) = table.iter().min_by_key(|(c, _)| distance(*c, color)).copied() else {
+            cells.push(ID_EMPTY);
+            continue;
+        };
+        if closest != color {
+            match unmatched.iter_mut().find(|u| u.color == color) {
+                Some(entry) => entry.pixels += 1,
+                None => unmatched.push(UnmatchedColor {
+                    color,
+                    pixels: 1,
+                    material: parsing_result.material(id).map_or(String::new(), |m| m.name.clone()),
+                }),
+            }
+        }
+        cells.push(id);
+    }
+    unmatched.sort_by_key(|u| std::cmp::Reverse(u.pixels));
+
+    ImportedWorld {
+        size: img.dimensions(),
+        cells,
+        unmatched,
+    }
+}
+
+
+/// Loads a world from an image (see `import_image`)
+pub fn load_png(path: &Path, parsing_result: &ParsingResult) -> anyhow::Result<ImportedWorld> {
+    let img = image::open(path)
+        .with_context(|| format!("Could not load the world '{}'", path.display()))?
+        .into_rgba8();
+    Ok(import_image(&img, parsing_result))
+}
+
+
+/// Saves a world as an image, where every cell is a pixel with the color of its material.
+/// Can be loaded again with `load_png` (without a palette), as long as no two materials share a color
+pub fn save_png(path: &Path, size: (u32, u32), cells: &[u16], materials: &[SandMaterial]) -> anyhow::Result<()> {
+    let pixels = cells.iter()
+        .flat_map(|id| to_rgba8(materials.iter().find(|m| m.id == *id as usize).map_or([0.0; 4], |mat| mat.color)))
+        .collect();
+    save_rgba(path, size, pixels)
+}
+
+
+/// Saves 8 bit RGBA pixel data (row by row) as an image
+pub fn save_rgba(path: &Path, size: (u32, u32), pixels: Vec<u8>) -> anyhow::Result<()> {
+    let img = image::RgbaImage::from_raw(size.0, size.1, pixels)
+        .ok_or(anyhow!("The image data does not match the size {}x{}", size.0, size.1))?;
+    img.save(path)
+        .with_context(|| format!("Could not save '{}'", path.display()))
+}
@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+
+use crate::ParsingResult;
+use crate::parser::expression::{BinaryOp, Expr, ExprKind, Property};
+use crate::parser::materials::SandMaterial;
+use crate::parser::rules::{Action, SandRule, SandRuleType, CHOOSE_SEED_OFFSET};
+use crate::parser::thermal::{self, Transition};
+use crate::parser::DEFAULT_VAL_PROBABILITY;
+
+
+
+const ID_EMPTY: u16 = 0;
+const ID_NULL: u16 = 1;
+const ID_WALL: u16 = 2;
+
+
+/// A single cell: the id of its material and the values of all custom fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub id: u16,
+    /// Values of the fields, in the order of `ParsingResult::fields`. Int fields are stored as floats,
+    /// just like in the cell data texture
+    pub fields: Vec<f32>,
+}
+
+
+/// The world the interpreter steps. Cells are stored row by row, starting at the top left
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grid {
+    pub width: usize,
+    pub height: usize,
+    /// Material id of every cell
+    pub cells: Vec<u16>,
+    /// Field values of every cell, `num_fields` values per cell
+    pub fields: Vec<f32>,
+    pub num_fields: usize,
+    /// Number of the last simulated frame. Frame 1 is the one that clears the world on the GPU,
+    /// so the first step simulates frame 2
+    pub frame: i32,
+}
+impl Grid {
+    pub fn in_bounds(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height
+    }
+
+    fn index(&self, x: i32, y: i32) -> usize {
+        y as usize * self.width + x as usize
+    }
+
+    /// Material id of the cell, out of bounds is WALL (the screen is the border)
+    pub fn get(&self, x: i32, y: i32) -> u16 {
+        match self.in_bounds(x, y) {
+            true => self.cells[self.index(x, y)],
+            false => ID_WALL,
+        }
+    }
+
+    /// Reads a whole cell, including its fields. None if it is out of bounds
+    pub fn cell(&self, x: i32, y: i32) -> Option<Cell> {
+        if !self.in_bounds(x, y) {
+            return None;
+        }
+        let idx = self.index(x, y);
+        Some(Cell {
+            id: self.cells[idx],
+            fields: self.fields[idx * self.num_fields..(idx + 1) * self.num_fields].to_vec(),
+        })
+    }
+
+    /// Overwrites a cell, cells out of bounds are ignored
+    pub fn set_cell(&mut self, x: i32, y: i32, cell: Cell) {
+        if !self.in_bounds(x, y) {
+            return;
+        }
+        let idx = self.index(x, y);
+        self.cells[idx] = cell.id;
+        self.fields[idx * self.num_fields..(idx + 1) * self.num_fields].copy_from_slice(&cell.fields);
+    }
+}
+
+
+/// A value an expression evaluates to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Val<'a> {
+    Bool(bool),
+    Number(f32),
+    /// Index of the cell inside of the block
+    Cell(usize),
+    Material(u16),
+    Type(&'a str),
+}
+impl<'a> Val<'a> {
+    fn as_bool(&self) -> bool {
+        matches!(self, Val::Bool(true))
+    }
+}
+
+
+/// Runs the rules of a ParsingResult on the CPU, the same way the generated compute shader does
+/// (`simulate()` in falling_sand.glsl): the same Margolus offsets, random values, mirroring and
+/// order of the rules. Given the same seed, stepping the same grid always produces the same result
+pub struct Interpreter {
+    materials: Vec<SandMaterial>,
+    /// Index inside of `materials` of every id (ids don't need to be contiguous)
+    indices: Vec<usize>,
+    material_ids: HashMap<String, u16>,
+    /// Names of the types each material is of (its own type and all types it inherits from)
+    material_types: Vec<Vec<String>>,
+    field_names: Vec<String>,
+    /// Default field values of each material
+    default_fields: Vec<Vec<f32>>,
+    mirrored_rules: Vec<SandRule>,
+    left_rules: Vec<SandRule>,
+    right_rules: Vec<SandRule>,
+    /// Which rules pass their precondition for each material, by rule name
+    applicable: Vec<Vec<String>>,
+    transitions: Vec<Transition>,
+    /// Index of the temperature field, if the temperature is simulated
+    temperature: Option<usize>,
+    seed: u32,
+    /// Whether blocks are mirrored, instead of deciding it randomly
+    mirror: Option<bool>,
+}
+impl Interpreter {
+    /// Seed 0 produces the same random values as the compute shader
+    pub fn new(result: &ParsingResult, seed: u32) -> Self {
+        let materials = result.materials.clone();
+        let material_ids = materials.iter().map(|m| (m.name.clone(), m.id as u16)).collect();
+
+        let parent_of = |name: &str| result.types.iter()
+            .find(|t| t.name == name)
+            .map(|t| t.inherits.clone())
+            .filter(|p| !p.is_empty());
+        let mut material_types = vec![];
+        let mut applicable = vec![];
+        for mat in materials.iter() {
+            let mut types = vec![mat.mattype.clone()];
+            while let Some(parent) = parent_of(types.last().unwrap()) {
+                types.push(parent);
+            }
+            // Rules of all types the material is of, like the precondition of the generated rules
+            let mut rules: Vec<String> = result.types.iter()
+                .filter(|t| types.contains(&t.name))
+                .flat_map(|t| t.base_rules.iter().cloned())
+                .collect();
+            rules.extend(mat.extra_rules.iter().cloned());
+            material_types.push(types);
+            applicable.push(rules);
+        }
+
+        let used_rules = |ruletype: SandRuleType| result.rules.iter()
+            .filter(|r| r.used && r.ruletype == ruletype)
+            .cloned()
+            .collect::<Vec<SandRule>>();
+
+        Interpreter {
+            default_fields: materials.iter()
+                .map(|m| result.fields.iter().map(|f| m.field_value(f).as_f32()).collect())
+                .collect(),
+            materials,
+            indices: result.material_indices(),
+            material_ids,
+            material_types,
+            field_names: result.fields.iter().map(|f| f.name.clone()).collect(),
+            mirrored_rules: used_rules(SandRuleType::Mirrored),
+            left_rules: used_rules(SandRuleType::Left),
+            right_rules: used_rules(SandRuleType::Right),
+            applicable,
+            transitions: thermal::transitions(&result.materials),
+            temperature: result.fields.iter().position(|f| f.name == thermal::TEMPERATURE_FIELD),
+            seed,
+            mirror: None,
+        }
+    }
+
+    /// Changes the seed of the random values, which takes effect in the next step
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+    }
+
+    /// Mirrors all blocks (`Some(true)`), none of them (`Some(false)`) or decides it randomly
+    /// for every block like the compute shader (`None`)
+    pub fn force_mirror(&mut self, mirror: Option<bool>) {
+        self.mirror = mirror;
+    }
+
+    /// Creates a grid of the given size, filled with EMPTY
+    pub fn new_grid(&self, width: usize, height: usize) -> Grid {
+        let empty = self.new_cell(ID_EMPTY);
+        Grid {
+            width,
+            height,
+            cells: vec![ID_EMPTY; width * height],
+            fields: empty.fields.repeat(width * height),
+            num_fields: self.field_names.len(),
+            frame: 1,
+        }
+    }
+
+    /// Id of the material with the name
+    pub fn material_id(&self, name: &str) -> Option<u16> {
+        self.material_ids.get(name).copied()
+    }
+
+    /// Id of a material named by a rule. The parser rejects unknown names, but if one slips
+    /// through it becomes NULL (like unknown ids) instead of panicking mid-frame
+    fn id_of(&self, name: &str) -> u16 {
+        self.material_id(name).unwrap_or(ID_NULL)
+    }
+
+    /// Index of the field inside of `Cell::fields`
+    pub fn field_index(&self, name: &str) -> Option<usize> {
+        self.field_names.iter().position(|f| f == name)
+    }
+
+    /// A new cell of the material, with the field values of the material (`newCell` in GLSL)
+    pub fn new_cell(&self, id: u16) -> Cell {
+        let idx = self.material_index(id);
+        Cell {
+            id: self.materials[idx].id as u16,
+            fields: self.default_fields[idx].clone(),
+        }
+    }
+
+    /// Places a new cell of the material
+    pub fn set(&self, grid: &mut Grid, x: i32, y: i32, id: u16) {
+        grid.set_cell(x, y, self.new_cell(id));
+    }
+
+    /// Index inside of `materials`, unknown ids are NULL, just like `getMaterialFromID`
+    fn material_index(&self, id: u16) -> usize {
+        self.indices.get(id as usize).copied().unwrap_or(self.indices[ID_NULL as usize])
+    }
+
+    fn material(&self, id: u16) -> &SandMaterial {
+        &self.materials[self.material_index(id)]
+    }
+
+    fn is_boundary(&self, cell: &Cell) -> bool {
+        let mattype = &self.material(cell.id).mattype;
+        mattype == "WALL" || mattype == "NULL"
+    }
+
+    /// Simulates the next frame
+    pub fn step(&self, grid: &mut Grid) {
+        grid.frame += 1;
+        for origin in self.block_origins(grid) {
+            let block = self.simulate_block(grid, origin);
+            write_block(grid, origin, block);
+        }
+    }
+
+    /// Top left corners of all 2x2 blocks of the frame `grid.frame`. The blocks never overlap
+    pub fn block_origins(&self, grid: &Grid) -> Vec<(i32, i32)> {
+        let (off_x, off_y) = margolus_offset(grid.frame);
+        let mut origins = vec![];
+        for y in (-off_y..grid.height as i32).step_by(2) {
+            for x in (-off_x..grid.width as i32).step_by(2) {
+                origins.push((x, y));
+            }
+        }
+        origins
+    }
+
+    /// Runs the rules on the block at the origin in the frame `grid.frame` and returns the
+    /// new cells (self, right, down, downright). Only reads the cells of the block
+    pub fn simulate_block(&self, grid: &Grid, (x, y): (i32, i32)) -> [Cell; 4] {
+        let read = |x: i32, y: i32| match grid.cell(x, y) {
+            // Unknown ids are read as NULL
+            Some(cell) => Cell { id: self.material(cell.id).id as u16, ..cell },
+            None => self.new_cell(ID_WALL),
+        };
+        let mut block = [read(x, y), read(x + 1, y), read(x, y + 1), read(x + 1, y + 1)];
+        if block.iter().all(|c| c.id == ID_EMPTY) {
+            return [0; 4].map(|_| self.new_cell(ID_EMPTY));
+        }
+
+        let key = [x as u32, y as u32, grid.frame as u32];
+        let rand = hash43(key, self.seed);
+        self.apply_temperature(&mut block);
+
+        let should_mirror = self.mirror.unwrap_or(rand[0] < 0.5);
+        if should_mirror {
+            self.swap(&mut block, 0, 1);
+            self.swap(&mut block, 2, 3);
+        }
+        self.apply_rules(&self.mirrored_rules, &mut block, &rand, key);
+        if should_mirror {
+            self.swap(&mut block, 0, 1);
+            self.swap(&mut block, 2, 3);
+        }
+
+        if !should_mirror {
+            self.apply_rules(&self.right_rules, &mut block, &rand, key);
+        } else {
+            self.apply_rules(&self.left_rules, &mut block, &rand, key);
+        }
+        block
+    }
+
+    /// `swap()` in GLSL, boundary cells never move
+    fn swap(&self, block: &mut [Cell; 4], a: usize, b: usize) {
+        if self.is_boundary(&block[a]) || self.is_boundary(&block[b]) {
+            return;
+        }
+        block.swap(a, b);
+    }
+
+    fn apply_temperature(&self, block: &mut [Cell; 4]) {
+        let Some(field) = self.temperature else {
+            return;
+        };
+        for (a, b) in [(0, 1), (2, 3), (0, 2), (1, 3)] {
+            if self.is_boundary(&block[a]) || self.is_boundary(&block[b]) {
+                continue;
+            }
+            let conductivity = self.material(block[a].id).thermal.conductivity.min(self.material(block[b].id).thermal.conductivity);
+            let flow = (block[b].fields[field] - block[a].fields[field]) * conductivity * 0.5;
+            block[a].fields[field] += flow;
+            block[b].fields[field] -= flow;
+        }
+        for cell in block.iter_mut() {
+            let name = &self.material(cell.id).name;
+            let temperature = cell.fields[field];
+            let transition = self.transitions.iter()
+                .filter(|t| &t.from == name)
+                .find(|t| match t.rising {
+                    true => temperature >= t.point,
+                    false => temperature < t.point,
+                });
+            if let Some(transition) = transition {
+                // The cell keeps its fields
+                cell.id = self.id_of(&transition.to);
+            }
+        }
+    }
+
+    fn apply_rules(&self, rules: &[SandRule], block: &mut [Cell; 4], rand: &[f32; 4], key: [u32; 3]) {
+        for rule in rules {
+            let applicable = &self.applicable[self.material_index(block[0].id)];
+            if rule.precondition.is_some() && !applicable.contains(&rule.name) {
+                continue;
+            }
+            // Only the first level of the if-else chain whose condition is true runs
+            for ((cond, actions), probability) in rule.if_conds.iter().zip(rule.do_actions.iter()).zip(rule.probabilities.iter()) {
+                let chance = *probability == DEFAULT_VAL_PROBABILITY || rand[1] <= *probability;
+                if chance && self.eval(cond, block, rand).as_bool() {
+                    self.run_actions(actions, block, key);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// `key` is the position and frame the block hashes its random values with, the CHOOSEs use their own seeds
+    fn run_actions(&self, actions: &[Action], block: &mut [Cell; 4], key: [u32; 3]) {
+        for action in actions {
+            match action {
+                Action::Swap(a, b) => self.swap(block, cell_index(a), cell_index(b)),
+                Action::Set(cell, mat) => block[cell_index(cell)] = self.new_cell(self.id_of(mat)),
+                Action::SetCell(dst, src) => {
+                    let (dst, src) = (cell_index(dst), cell_index(src));
+                    if !self.is_boundary(&block[dst]) && !self.is_boundary(&block[src]) {
+                        block[dst] = self.new_cell(block[src].id);
+                    }
+                },
+                Action::Copy(dst, src) => {
+                    let (dst, src) = (cell_index(dst), cell_index(src));
+                    if !self.is_boundary(&block[dst]) && !self.is_boundary(&block[src]) {
+                        block[dst] = block[src].clone();
+                    }
+                },
+                Action::Convert(a, b) => {
+                    let (a, b) = (cell_index(a), cell_index(b));
+                    if !self.is_boundary(&block[a]) && !self.is_boundary(&block[b]) {
+                        let id = block[a].id;
+                        block[a].id = block[b].id;
+                        block[b].id = id;
+                    }
+                },
+                Action::Spawn(mat, cell, condition) => {
+                    let cell = cell_index(cell);
+                    if condition.as_ref().is_none_or(|c| block[cell].id == self.id_of(c)) {
+                        block[cell] = self.new_cell(self.id_of(mat));
+                    }
+                },
+                Action::SetField(cell, field, value) => {
+                    let field = self.field_index(field).unwrap();
+                    block[cell_index(cell)].fields[field] = value.as_f32();
+                },
+                Action::Inc(cell, field, amount) => {
+                    let field = self.field_index(field).unwrap();
+                    block[cell_index(cell)].fields[field] += amount.as_f32();
+                },
+                Action::Move(src, dst) => {
+                    let (src, dst) = (cell_index(src), cell_index(dst));
+                    if !self.is_boundary(&block[src]) && block[dst].id == ID_EMPTY {
+                        block[dst] = block[src].clone();
+                        block[src] = self.new_cell(ID_EMPTY);
+                    }
+                },
+                Action::Choose(index, choices) => {
+                    let choice = hash43(key, self.seed.wrapping_add(CHOOSE_SEED_OFFSET + index))[0];
+                    let total: f32 = choices.iter().map(|(weight, _)| weight).sum();
+                    let mut threshold = 0.0;
+                    for (idx, (weight, actions)) in choices.iter().enumerate() {
+                        threshold += weight / total;
+                        if idx == choices.len() - 1 || choice < threshold {
+                            self.run_actions(actions, block, key);
+                            break;
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    fn eval<'a>(&'a self, expr: &'a Expr, block: &[Cell; 4], rand: &[f32; 4]) -> Val<'a> {
+        match &expr.kind {
+            ExprKind::Bool(b) => Val::Bool(*b),
+            ExprKind::Number(n) => Val::Number(*n),
+            ExprKind::Rand => Val::Number(rand[2]),
+            ExprKind::Cell(cell) => Val::Cell(cell_index(cell)),
+            ExprKind::Material(mat) => Val::Material(self.id_of(mat)),
+            ExprKind::Type(t) => Val::Type(t),
+            ExprKind::Name(_) => unreachable!("names are resolved by the parser"),
+            ExprKind::Property(base, prop) => {
+                let (mat, cell) = match self.eval(base, block, rand) {
+                    Val::Cell(idx) => (block[idx].id, Some(&block[idx])),
+                    Val::Material(id) => (id, None),
+                    _ => unreachable!("the type checker only allows properties of cells and materials"),
+                };
+                let material = self.material(mat);
+                match prop {
+                    Property::Material => Val::Material(material.id as u16),
+                    Property::Density => Val::Number(material.density),
+                    Property::Type => Val::Type(&material.mattype),
+                    Property::Id => Val::Number(material.id as f32),
+                    Property::Field(field) => Val::Number(cell.unwrap().fields[self.field_index(field).unwrap()]),
+                }
+            },
+            ExprKind::IsType(t, cell) => {
+                let Val::Cell(idx) = self.eval(cell, block, rand) else {
+                    unreachable!("the type checker only allows cells");
+                };
+                Val::Bool(self.material_types[self.material_index(block[idx].id)].contains(t))
+            },
+            ExprKind::Not(inner) => Val::Bool(!self.eval(inner, block, rand).as_bool()),
+            ExprKind::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs, block, rand);
+                match op {
+                    BinaryOp::And => return Val::Bool(lhs.as_bool() && self.eval(rhs, block, rand).as_bool()),
+                    BinaryOp::Or => return Val::Bool(lhs.as_bool() || self.eval(rhs, block, rand).as_bool()),
+                    _ => (),
+                }
+                let rhs = self.eval(rhs, block, rand);
+                let numbers = match (lhs, rhs) {
+                    (Val::Number(l), Val::Number(r)) => Some((l, r)),
+                    _ => None,
+                };
+                Val::Bool(match (op, numbers) {
+                    (BinaryOp::Eq, _) => lhs == rhs,
+                    (BinaryOp::Ne, _) => lhs != rhs,
+                    (BinaryOp::Lt, Some((l, r))) => l < r,
+                    (BinaryOp::Le, Some((l, r))) => l <= r,
+                    (BinaryOp::Gt, Some((l, r))) => l > r,
+                    (BinaryOp::Ge, Some((l, r))) => l >= r,
+                    _ => unreachable!("the type checker only allows ordering numbers"),
+                })
+            },
+        }
+    }
+}
+
+
+/// Writes the cells returned by `simulate_block` back into the grid
+pub fn write_block(grid: &mut Grid, (x, y): (i32, i32), block: [Cell; 4]) {
+    let [c0, c1, c2, c3] = block;
+    grid.set_cell(x, y, c0);
+    grid.set_cell(x + 1, y, c1);
+    grid.set_cell(x, y + 1, c2);
+    grid.set_cell(x + 1, y + 1, c3);
+}
+
+
+/// Index of a cell (as named in rules) inside of the block. Like `applyLeftRules` in GLSL, left
+/// rules get the same cells as the right ones, so LEFT is at the same index as RIGHT
+fn cell_index(name: &str) -> usize {
+    match name {
+        "SELF" => 0,
+        "RIGHT" | "LEFT" => 1,
+        "DOWN" => 2,
+        "DOWNRIGHT" | "DOWNLEFT" => 3,
+        _ => unreachable!("the parser only allows the global cell names"),
+    }
+}
+
+
+/// `getMargolusOffset` in GLSL
+pub fn margolus_offset(frame: i32) -> (i32, i32) {
+    match frame % 4 {
+        1 => (1, 1),
+        2 => (0, 1),
+        3 => (1, 0),
+        _ => (0, 0),
+    }
+}
+
+
+/// `hash43` in GLSL, 4 random values from 0-1. The seed is added to the hashed value
+pub fn hash43(p: [u32; 3], seed: u32) -> [f32; 4] {
+    let x = p[0].wrapping_mul(461)
+        .wrapping_add(p[1].wrapping_mul(2131))
+        .wrapping_add(p[2].wrapping_mul(2131 * 2131))
+        .wrapping_add(seed.wrapping_mul(0x9e3779b9));
+    [213u32, 2131, 21313, 213132].map(|m| {
+        let mut x = x.wrapping_mul(m);
+        x ^= x >> 16;
+        x = x.wrapping_mul(0x7feb352d);
+        x ^= x >> 15;
+        x = x.wrapping_mul(0x846ca68b);
+        x ^= x >> 16;
+        x as f32 / u32::MAX as f32
+    })
+}
@@ -1,23 +1,61 @@
 pub mod parser;
-use std::path::PathBuf;
+pub mod validator;
+pub mod interpreter;
+pub mod testing;
+pub mod shaders;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+pub use parser::{parse_string, GLSLConvertible, ParsingResult, report::ParseReport};
+use parser::lock::MaterialLock;
+pub use shaders::GeneratedShaders;
+
+
+/// Reads a file to a string and parses that string using the parser.
+/// The ids of the materials are pinned by the lockfile next to it (`materials.yaml` -> `materials.lock`),
+/// which is only read here, see `write_lock`
+pub fn parse_path(filepath: PathBuf) -> Result<ParsingResult, ParseReport> {
+    let error = |err: anyhow::Error| {
+        let mut report = ParseReport::default();
+        report.error(err);
+        report
+    };
+    let f = std::fs::read_to_string(&filepath)
+        .map_err(|err| error(anyhow!("Could not read '{}': {}", filepath.display(), err)))?;
+    let lock = MaterialLock::load(&lock_path(&filepath)).map_err(|err| error(anyhow!("{:#}", err)))?;
+
+    parser::parse_string_with_lock(&f, Some(&filepath.display().to_string()), &lock)
+}
 
-use colored::Colorize;
-pub use parser::{parse_string, GLSLConvertible, ParsingResult};
 
-// TODO: Create a validator function (extra file) that checks every if/ do condition??
+/// Pins the ids of the materials in the lockfile next to the file (see `parse_path`).
+/// The file is created or updated when materials were added, returns whether it changed
+pub fn write_lock(filepath: &Path, result: &ParsingResult) -> anyhow::Result<bool> {
+    let lock_path = lock_path(filepath);
+    let mut lock = MaterialLock::load(&lock_path)?;
+    let changed = lock.update(&result.materials);
+    if changed {
+        lock.save(&lock_path)?;
+    }
+    Ok(changed)
+}
+
 
-/// Reads a file to a string and parses that string using the parser
-pub fn parse_path(filepath: PathBuf) -> anyhow::Result<ParsingResult> {
-    let f = std::fs::read_to_string(filepath).unwrap();
-    parse_string(&f)
+fn lock_path(filepath: &Path) -> PathBuf {
+    filepath.with_extension("lock")
 }
 
 
-/// Creates the procedually generated GLSL files from the ParsingResult
-pub fn create_glsl_from_parser(result: &ParsingResult) {
-    let cwd = std::env::current_dir().unwrap();
+/// Creates the procedually generated GLSL code from the ParsingResult and assembles the compute shader.
+/// Nothing is written to disk, use `GeneratedShaders::write_to` for that
+pub fn create_glsl_from_parser(result: &ParsingResult) -> anyhow::Result<GeneratedShaders> {
+    // ========== fields.glsl contains the custom fields every cell carries ==========
+    let fields = parser::fields::fields_glsl(&result.fields);
+
+    // ========== thermal.glsl contains the heat exchange and transitions ==========
+    let thermal = parser::thermal::thermal_glsl(&result.materials, &result.fields);
 
-    // ========== Create materials.glsl which contains materials and types ==========
+    // ========== materials.glsl contains materials and types ==========
     let mut materials_types = String::from("");
 
     for t in result.types.iter() {
@@ -64,18 +102,22 @@ Material getMaterialFromID(int id) {{
 
     materials_types.push_str(helpers_functions.as_str());
 
-    let path = cwd
-        .join("shaders")
-        .join("compute")
-        .join("gen")
-        .join("materials.glsl");
-    let res = std::fs::write(path.clone(), materials_types);
-    if let Err(err) = res {
-        println!("{} Err creating file '{}': '{}'", "[sandengine-lang]:".red().bold(), path.display(), err);
-    };
-
-    // ========== Create rules.glsl which contains all rules and rule callers ==========
+    // New cells get the field values of their material
+    let mut material_fields = String::new();
+    let field_defaults = parser::fields::constructor_args(result.fields.iter().map(|f| f.default));
+    for m in result.materials.iter().filter(|m| !m.fields.is_empty()) {
+        let values = parser::fields::constructor_args(result.fields.iter().map(|f| m.field_value(f)));
+        material_fields.push_str(format!("    if (mat == MAT_{}) {{\n        return Fields({});\n    }}\n", m.name, values).as_str());
+    }
+    materials_types.push_str(format!("
+Fields defaultFields(Material mat) {{
+{}    return Fields({});
+}}\n", material_fields, field_defaults).as_str());
+
+    // ========== rules.glsl contains all rules and rule callers ==========
+    let rules_header = "\n// =============== RULES ===============\n";
     let mut rule_functions = String::new();
+    let mut rule_spans = vec![];
     let mut mirrored_rules_call = String::new();
     let mut left_rules_call = String::new();
     let mut right_rules_call = String::new();
@@ -83,14 +125,18 @@ Material getMaterialFromID(int id) {{
     result.rules.iter().for_each(|r| {
         // only generate code for rules that have actually been used by types or materials
         if r.used {
-            rule_functions.push_str(format!("{}\n\n", r.get_glsl_code()).as_str());
+            // Remembers which lines belong to which rule, to point errors of the GLSL compiler at the YAML
+            let first_line = rules_header.lines().count() + rule_functions.lines().count() + 1;
+            let (code, mut spans) = r.get_glsl_code_with_spans(first_line);
+            rule_functions.push_str(format!("{}\n\n", code).as_str());
+            rule_spans.append(&mut spans);
             // Depending on the mirrored type of the rule, we call them with different cells
             match r.ruletype {
                 parser::rules::SandRuleType::Mirrored => {
                     mirrored_rules_call.push_str(format!("rule_{}(self, right, down, downright, rand, pos);\n", r.name).as_str());
                 },
                 parser::rules::SandRuleType::Left => {
-                    left_rules_call.push_str(format!("rule_{}(self, left, down, downright, rand, pos);\n", r.name).as_str());
+                    left_rules_call.push_str(format!("rule_{}(self, left, down, downleft, rand, pos);\n", r.name).as_str());
                 },
                 parser::rules::SandRuleType::Right => {
                     right_rules_call.push_str(format!("rule_{}(self, right, down, downright, rand, pos);\n", r.name).as_str());
@@ -98,15 +144,8 @@ Material getMaterialFromID(int id) {{
             };
         }
     });
-    let path = cwd
-        .join("shaders")
-        .join("compute")
-        .join("gen")
-        .join("rules.glsl");
     let rulefile_content = format!(
-"
-// =============== RULES ===============
-{}
+"{}{}
 
 
 // =============== CALLERS ===============
@@ -123,9 +162,9 @@ void applyMirroredRules(
 
 void applyLeftRules(
     inout Cell self,
-    inout Cell right,
+    inout Cell left,
     inout Cell down,
-    inout Cell downright,
+    inout Cell downleft,
     vec4 rand,
     ivec2 pos) {{
     {}
@@ -139,10 +178,7 @@ void applyRightRules(
     vec4 rand,
     ivec2 pos) {{
     {}
-}}", rule_functions, mirrored_rules_call.trim_end(), left_rules_call.trim_end(), right_rules_call.trim_end());
+}}", rules_header, rule_functions, mirrored_rules_call.trim_end(), left_rules_call.trim_end(), right_rules_call.trim_end());
 
-    let res = std::fs::write(path.clone(), rulefile_content);
-    if let Err(err) = res {
-        println!("{} Err creating file '{}': '{}'", "[sandengine-lang]:".red().bold(), path.display(), err);
-    };
+    GeneratedShaders::new(fields, thermal, materials_types, rulefile_content, rule_spans)
 }
\ No newline at end of file
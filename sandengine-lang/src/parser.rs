@@ -1,4 +1,4 @@
-use std::fmt::Debug;
+use std::{collections::HashMap, ops::Range};
 
 use anyhow::{anyhow, bail};
 use thiserror::Error;
@@ -9,19 +9,40 @@ use colored::Colorize;
 pub mod rules;
 pub mod types;
 pub mod materials;
+pub mod expression;
+pub mod source;
+pub mod report;
+pub mod packs;
+pub mod fields;
+pub mod reactions;
+pub mod thermal;
+pub mod rule_tests;
+pub mod lock;
+pub mod palette;
 
 use rules::SandRule;
 use types::SandType;
 use materials::SandMaterial;
+use fields::SandField;
+use reactions::SandReaction;
+use rule_tests::SandTest;
+use source::SourceFile;
+use report::ParseReport;
+use packs::PackFile;
+use lock::MaterialLock;
+use palette::PaletteColor;
+
+use crate::validator;
 
 
 // ========== Hints that will be displayed on an error message ==========
-const TYPE_HINT_STRING: &'static str = "string";
-const TYPE_HINT_BOOL: &'static str = "bool (true/false)";
-const TYPE_HINT_FLOAT: &'static str = "float (0.0 to 1.0)";
-const TYPE_HINT_SEQUENCE: &'static str = "sequence (array, '[...]')";
-const TYPE_HINT_COLOR: &'static str = "sequence (array, '[...]') of 3-4 floats (range 0.0-1.0) OR integers (range 0-255). (With 3 elements, the alpha channel defaults to 1.0)";
-const TYPE_HINT_MAPPING: &'static str = "mapping (dictionary-like)";
+pub(crate) const TYPE_HINT_STRING: &'static str = "string";
+pub(crate) const TYPE_HINT_BOOL: &'static str = "bool (true/false)";
+pub(crate) const TYPE_HINT_FLOAT: &'static str = "float (0.0 to 1.0)";
+pub(crate) const TYPE_HINT_SEQUENCE: &'static str = "sequence (array, '[...]')";
+pub(crate) const TYPE_HINT_COLOR: &'static str = "sequence (array, '[...]') of 3-4 floats (range 0.0-1.0) OR integers (range 0-255). (With 3 elements, the alpha channel defaults to 1.0)";
+pub(crate) const TYPE_HINT_MAPPING: &'static str = "mapping (dictionary-like)";
+pub(crate) const TYPE_HINT_WEIGHT: &str = "positive number";
 
 // ========== List of valid global scope Cell names ==========
 const GLOBAL_CELLNAMES: [&'static str; 6] = [
@@ -33,15 +54,23 @@ const GLOBAL_CELLNAMES: [&'static str; 6] = [
     "DOWNLEFT"
 ];
 
+// ========== Materials and types which are always defined ==========
+pub(crate) const BUILTIN_NAMES: [&str; 3] = [
+    "EMPTY",
+    "NULL",
+    "WALL"
+];
+
 // ========== Default values for properties ==========
 const DEFAULT_VAL_MIRRORED: bool = true;
 const DEFAULT_VAL_PRECONDITION: bool = true;
-const DEFAULT_VAL_PROBABILITY: f32 = 1.0;
+pub(crate) const DEFAULT_VAL_PROBABILITY: f32 = 1.0;
+const DEFAULT_VAL_WEIGHT: f32 = 1.0;
 
 
 #[derive(Debug, Error)]
 /// Custom Error type using the thiserror crate. Will be displayed in the console
-enum ParsingErr<T: Debug> {
+pub enum ParsingErr {
     /// Emitted, when a mandatory property in the YAML file was not found
     #[error("{} Mandatory field '{}' is {} '{}'", "(MissingField)".red(), .field_name.bold(), "missing in".bold(), .missing_in.bold())]
     MissingField {
@@ -50,9 +79,9 @@ enum ParsingErr<T: Debug> {
     },
 
     /// Emitted, when a property in the YAML has an invalid data type
-    #[error("{} The type of the field '{:?}' inside of '{}' {}. Expected: '{}'", "(InvalidType)".red(),.wrong_type, .missing_in.bold(), "is invalid".bold(), .expected.bold())]
+    #[error("{} The type of the field '{}' inside of '{}' {}. Expected: '{}'", "(InvalidType)".red(),.wrong_type, .missing_in.bold(), "is invalid".bold(), .expected.bold())]
     InvalidType {
-        wrong_type: T,
+        wrong_type: String,
         missing_in: String,
         expected: &'static str,
     },
@@ -69,10 +98,87 @@ enum ParsingErr<T: Debug> {
     NotRecognized {
         unrecog: String,
         missing_in: String
+    },
+
+    /// Emitted, when an expression combines values that have incompatible types
+    #[error("{} The expression '{}' (in '{}') {}. Expected: '{}', found: '{}'", "(TypeMismatch)".red(), .expr.bold(), .missing_in.bold(), "has the wrong type".bold(), .expected.bold(), .found.bold())]
+    TypeMismatch {
+        expr: String,
+        missing_in: String,
+        expected: String,
+        found: String,
+    },
+
+    /// Emitted, when types inherit from each other in a circle
+    #[error("{} The types '{}' (in '{}') {}. A type can't (indirectly) inherit from itself", "(InheritanceCycle)".red(), .cycle.bold(), .missing_in.bold(), "form an inheritance cycle".bold())]
+    InheritanceCycle {
+        cycle: String,
+        missing_in: String,
+    },
+
+    /// Emitted, when a name is defined in more than one file
+    #[error("{} The name '{}' (in '{}') {} '{}'", "(AlreadyDefined)".red(), .name.bold(), .missing_in.bold(), "is already defined in".bold(), .other_file.bold())]
+    AlreadyDefined {
+        name: String,
+        other_file: String,
+        missing_in: String,
+    },
+
+    /// Emitted, when two materials use the same id or symbol (or the lockfile pins the id to another material)
+    #[error("{} The id '{}' (in '{}') {} '{}'", "(IdCollision)".red(), .id.bold(), .second.bold(), "is already used by".bold(), .first.bold())]
+    IdCollision {
+        id: String,
+        /// Where the id was used first
+        first: String,
+        second: String,
+    },
+
+    /// Emitted, when an imported file can't be read
+    #[error("{} The file '{}' (in '{}') {}: {}", "(ImportFailed)".red(), .import.bold(), .missing_in.bold(), "could not be imported".bold(), .reason)]
+    ImportFailed {
+        import: String,
+        reason: String,
+        missing_in: String,
+    },
+
+    /// Emitted, when the GLSL compiler reports a problem in the code generated from a rule
+    #[error("{} The GLSL compiler {} the code generated from '{}': {}", "(InvalidGlsl)".red(), "reported a problem in".bold(), .missing_in.bold(), .message)]
+    InvalidGlsl {
+        message: String,
+        missing_in: String,
+    }
+}
+
+
+impl ParsingErr {
+    /// Path of the YAML node the error occured in (like `rules/grow/if`)
+    pub fn path(&self) -> &str {
+        match self {
+            ParsingErr::MissingField { missing_in, .. }
+            | ParsingErr::InvalidType { missing_in, .. }
+            | ParsingErr::NotFound { missing_in, .. }
+            | ParsingErr::NotRecognized { missing_in, .. }
+            | ParsingErr::TypeMismatch { missing_in, .. }
+            | ParsingErr::InheritanceCycle { missing_in, .. }
+            | ParsingErr::AlreadyDefined { missing_in, .. }
+            | ParsingErr::ImportFailed { missing_in, .. }
+            | ParsingErr::InvalidGlsl { missing_in, .. } => missing_in,
+            ParsingErr::IdCollision { second, .. } => second,
+        }
     }
 }
 
 
+#[derive(Debug, Error)]
+#[error("{err}")]
+/// ParsingErr, which only refers to a part of a string value (e.g. a name inside of a condition)
+pub struct SpannedErr {
+    pub err: ParsingErr,
+    /// Byte range inside of the string value
+    pub span: Range<usize>,
+}
+
+
 /// Each struct returned from the parser implements this to simplify the conversion to GLSL
 pub trait GLSLConvertible {
     fn get_glsl_code(&self) -> String;
@@ -85,18 +191,93 @@ pub struct ParsingResult {
     pub rules: Vec<SandRule>,
     pub types: Vec<SandType>,
     pub materials: Vec<SandMaterial>,
+    /// Custom per-cell fields, in the order they are stored in the cell data
+    pub fields: Vec<SandField>,
+    /// Reactions between two materials, each of them also generated a rule (see `SandReaction::rule_name`)
+    pub reactions: Vec<SandReaction>,
+    /// Expected behaviour of the rules, checked by `sandengine test`
+    pub tests: Vec<SandTest>,
+    /// Colors of world images and the materials they stand for
+    pub palette: Vec<PaletteColor>,
     pub data_serialized: Vec<Box<dyn GLSLConvertible>>,
+    /// Warnings that were found while parsing
+    pub report: ParseReport,
 }
 
 
-/// Parses a string (YAML syntax) and converts it into Rust structs holding the data
-pub fn parse_string(f: &str) -> anyhow::Result<ParsingResult> {
-    // Convert the string into a serde_yaml object
-    let data: Result<_, serde_yaml::Error> = serde_yaml::from_str(f);
-    if let Err(err) = data {
-        bail!(err);
+impl ParsingResult {
+    /// Number of layers the cell data texture (array) needs to store all fields
+    pub fn num_data_layers(&self) -> usize {
+        fields::num_data_layers(&self.fields)
+    }
+
+    /// The material with the id. The ids don't need to be continuous (see `MaterialLock`)
+    pub fn material(&self, id: u16) -> Option<&SandMaterial> {
+        self.materials.iter().find(|m| m.id == id as usize)
     }
-    let data: serde_yaml::Value = data.unwrap();
+
+    /// Index inside of `materials` of every id, up to the largest one. Ids without a material
+    /// are NULL, just like in `getMaterialFromID`
+    pub fn material_indices(&self) -> Vec<usize> {
+        let max_id = self.materials.iter().map(|m| m.id).max().unwrap_or_default();
+        let null = self.materials.iter().position(|m| m.name == "NULL").unwrap_or_default();
+        let mut indices = vec![null; max_id + 1];
+        for (idx, mat) in self.materials.iter().enumerate() {
+            indices[mat.id] = idx;
+        }
+        indices
+    }
+
+    /// Table to convert the ids of an older version of the materials into the current ones
+    /// (e.g. for saved worlds): `table[old_id]` is the new id. Materials are matched by name,
+    /// materials that were removed become EMPTY and unknown ids stay NULL
+    pub fn remap_from(&self, old: &ParsingResult) -> Vec<u16> {
+        materials::remap_names(&self.materials, old.materials.iter().map(|m| (m.name.as_str(), m.id as u16)))
+    }
+}
+
+
+/// Parses a string (YAML syntax) and converts it into Rust structs holding the data.
+/// The name is displayed in error messages, use the file name if the string comes from a file.
+/// Files listed in `imports` are resolved relative to the directory of that name.
+/// 
+/// Parsing continues after errors, so that all of them can be reported at once.
+/// If there was any error, no ParsingResult is produced
+pub fn parse_string(f: &str, name: Option<&str>) -> Result<ParsingResult, ParseReport> {
+    parse_string_with_lock(f, name, &MaterialLock::default())
+}
+
+
+/// Like `parse_string`, but the materials keep the ids they are pinned to in the lock
+pub fn parse_string_with_lock(f: &str, name: Option<&str>, lock: &MaterialLock) -> Result<ParsingResult, ParseReport> {
+    let mut report = ParseReport::default();
+    let mut files = packs::load_files(SourceFile::new(name.unwrap_or("<string>"), f), &mut report);
+    // Without all files, the remaining errors would mostly be about missing names
+    let result = match report.has_errors() {
+        true => None,
+        false => {
+            packs::resolve_namespaces(&mut files, &mut report);
+            parse_files(&files, lock, &mut report)
+        },
+    };
+    for file in files {
+        report.add_source(file.source);
+    }
+
+    match result {
+        Some(mut result) if !report.has_errors() => {
+            result.report = report;
+            Ok(result)
+        },
+        _ => Err(report),
+    }
+}
+
+
+/// Parses the (already loaded) files and records all errors inside of the report.
+/// The last file is the one that imported all the others
+fn parse_files(files: &[PackFile], lock: &MaterialLock, report: &mut ParseReport) -> Option<ParsingResult> {
+    let root = files.last()?;
 
     // Create the required lists of structs
     let mut data_serialized: Vec<Box<dyn GLSLConvertible>> = vec![];
@@ -104,90 +285,111 @@ pub fn parse_string(f: &str) -> anyhow::Result<ParsingResult> {
     let mut types: Vec<SandType> = vec![];
     let mut materials: Vec<SandMaterial> = vec![];
 
-    // Checks the input for 'rules', 'types' and 'materials', throws error if not
-    // Afterwards, tries to convert them into dictionaries (mappings), errors if not possible
-    let raw_rules = check_and_convert_key_to_mapping(&data, "rules")?;
-    let raw_types = check_and_convert_key_to_mapping(&data, "types")?;
-    let raw_materials = check_and_convert_key_to_mapping(&data, "materials")?;
+    // Combines 'rules', 'types' and 'materials' of all files, errors if none of them contain it
+    // or if they are not dictionaries (mappings).
+    // Missing sections are treated as empty to find the errors in the other sections
+    let mut origins = HashMap::new();
+    let sections = ["rules", "types", "materials"].map(|section| {
+        let merged = packs::merge_section(files, section, &mut origins, report);
+        report.set_current_file(Some(root.source.name.clone()));
+        merged.unwrap_or_else(|| {
+            report.error(anyhow!(ParsingErr::MissingField {
+                field_name: section.to_string(),
+                missing_in: "Root/ Base level of YAML file".to_string()
+            }));
+            Mapping::new()
+        })
+    });
+
+    // Reads which validator checks are allowed or denied
+    let check_levels = validator::parse_check_levels(&root.data, report);
+
+    // Custom fields, reactions, tests and the palette are optional, so there is no error if no file contains them
+    let raw_fields = packs::merge_section(files, "fields", &mut origins, report).unwrap_or_default();
+    let raw_reactions = packs::merge_section(files, "reactions", &mut origins, report).unwrap_or_default();
+    let raw_tests = packs::merge_section(files, "tests", &mut origins, report).unwrap_or_default();
+    let raw_palette = packs::merge_section(files, "palette", &mut origins, report).unwrap_or_default();
+
+    // From here on, the file of each error is found through the item it belongs to
+    report.set_current_file(None);
+
+    let [raw_rules, raw_types, raw_materials] = &sections;
     
     // Pre-parse the rule-/ material-/ type names in order for them to be referenced earlier than defined
-    let rule_names = preparse_keys(&raw_rules,"rules")?;
-    let mut type_names = preparse_keys(&raw_types,"types")?;
-    type_names.push(String::from("EMPTY"));
-    let mut material_names = preparse_keys(&raw_materials,"materials")?;
-    material_names.push(String::from("EMPTY"));
+    let rule_names = preparse_keys(raw_rules);
+    let mut type_names = preparse_keys(raw_types);
+    type_names.extend(BUILTIN_NAMES.map(String::from));
+    let mut material_names = preparse_keys(raw_materials);
+    material_names.extend(BUILTIN_NAMES.map(String::from));
+
+    // Try to parse the fields, which are referenced by the rules and materials
+    let mut fields = fields::parse_fields(&raw_fields, report);
+    if thermal::uses_temperature(raw_materials) {
+        thermal::add_temperature_field(&mut fields, report);
+    }
 
     // Try to parse the rules
-    let res = rules::parse_rules(&raw_rules, &type_names, &material_names);
-    if let Ok(mut result) = res {
-        rules.append(&mut result.0);
-        data_serialized.append(&mut result.1);
-    } else {
-        bail!("Error while parsing rules: '{}'", res.err().unwrap());
-    }
+    let mut result = rules::parse_rules(raw_rules, &type_names, &material_names, &fields, report);
+    rules.append(&mut result.0);
+    data_serialized.append(&mut result.1);
+
+    // Try to parse the reactions, which are run after the rules
+    let (reactions, mut reaction_rules, mut result) = reactions::parse_reactions(&raw_reactions, &rule_names, &type_names, &material_names, report);
+    rules.append(&mut reaction_rules);
+    data_serialized.append(&mut result);
 
     // Try to parse the types
-    let res = types::parse_types(&raw_types, &mut rules, &rule_names, &type_names);
-    if let Ok(mut result) = res {
-        types.append(&mut result.0);
-        data_serialized.append(&mut result.1);
-    } else {
-        bail!("Error while parsing types: '{}'", res.err().unwrap());
-    }
+    let mut result = types::parse_types(raw_types, &mut rules, &rule_names, &type_names, report);
+    types.append(&mut result.0);
+    data_serialized.append(&mut result.1);
 
     // Try to parse the materials
-    let res = materials::parse_materials(&raw_materials, &mut rules, &type_names);
-    if let Ok(mut result) = res {
-        materials.append(&mut result.0);
-        data_serialized.append(&mut result.1);
-    } else {
-        bail!("Error while parsing materials: '{}'", res.err().unwrap());
-    }
+    let mut result = materials::parse_materials(raw_materials, &mut rules, &type_names, &material_names, &fields, lock, report);
+    materials.append(&mut result.0);
+    data_serialized.append(&mut result.1);
+
+    // Try to parse the tests, whose grids reference the symbols of the materials
+    let tests = rule_tests::parse_tests(&raw_tests, &materials, report);
+    let palette = palette::parse_palette(&raw_palette, &materials, report);
 
-    Ok(ParsingResult {
+    let result = ParsingResult {
         rules,
         types, materials,
-        data_serialized
-    })
+        fields,
+        reactions,
+        tests,
+        palette,
+        data_serialized,
+        report: ParseReport::default(),
+    };
+
+    // Only run the validator on a complete result, otherwise it would just repeat the errors
+    if !report.has_errors() {
+        report.diagnostics.extend(validator::validate(&result, &check_levels));
+    }
+    report.assign_files(&origins, &root.source.name);
+    Some(result)
 }
 
 
-/// Looks at all the keys in some section of the YAML and collects them
-fn preparse_keys(map: &Mapping, err_name: &str) -> anyhow::Result<Vec<String>> {
-    let mut keynames = vec![];
-    for key in map {
-        let name = key.0.as_str()
-            .ok_or(anyhow!(ParsingErr::InvalidType {
-                wrong_type: key.0.clone(),
-                missing_in: err_name.to_string(),
-                expected: TYPE_HINT_STRING
-            }))?
-            .to_string();
-        keynames.push(name);
-    };
-    Ok(keynames)
+/// Looks at all the keys in some section of the YAML and collects them.
+/// Invalid keys are skipped, they are reported when the section itself is parsed
+fn preparse_keys(map: &Mapping) -> Vec<String> {
+    map.keys()
+        .filter_map(|key| key.as_str())
+        .map(String::from)
+        .collect()
 }
 
 
-/// Checks if the keyname exists in the dictionary and tries to convert it to a Mapping
-/// Throws ParsingErr::MissingField if nonexistent or ParsingErr::InvalidType if not a Mapping
-fn check_and_convert_key_to_mapping<'a>(dict: &'a Value, keyname: &str) -> anyhow::Result<&'a Mapping> {
-    let key = dict.get(keyname)
-        .ok_or(anyhow!(ParsingErr::MissingField::<bool> {
-            field_name: keyname.to_string(),
-            missing_in: "Root/ Base level of YAML file".to_string()
-        }))?;
-    let map = key.as_mapping()
-        .ok_or(anyhow!(ParsingErr::InvalidType {
-            wrong_type: key.clone(),
-            missing_in: "Root/ Base level of YAML file".to_string(),
-            expected: TYPE_HINT_MAPPING
-        }))?;
-    Ok(map)
+/// Formats a YAML value (e.g. an invalid key) for error messages
+fn yaml_repr(value: &Value) -> String {
+    serde_yaml::to_string(value)
+        .map(|s| s.trim_end().to_string())
+        .unwrap_or_else(|_| format!("{:?}", value))
 }
 
 
-
 /// Helper function to convert a mapping into a [f32; 4] (the rust type representing a color)
 fn extract_vec4(yaml_data: &Value, parent_name: String, field_name: &'static str, default: [f32; 4], mandatory: bool) -> anyhow::Result<[f32; 4]> {
     let missing_in = format!("materials/{}/{}", parent_name, field_name);
@@ -195,7 +397,7 @@ fn extract_vec4(yaml_data: &Value, parent_name: String, field_name: &'static str
 
     if vec4_data.is_none() {
         if mandatory {
-            bail!(ParsingErr::<bool>::MissingField {
+            bail!(ParsingErr::MissingField {
                 field_name: field_name.to_string(),
                 missing_in,
             });
@@ -211,7 +413,7 @@ fn extract_vec4(yaml_data: &Value, parent_name: String, field_name: &'static str
     if let Some(comps) = vec4_val.as_sequence() {
         if comps.is_empty() || comps.len() > 4 || comps.len() < 3 {
             bail!(ParsingErr::InvalidType {
-                wrong_type: field_name,
+                wrong_type: field_name.to_string(),
                 missing_in,
                 expected: TYPE_HINT_COLOR,
             });
@@ -235,14 +437,14 @@ fn extract_vec4(yaml_data: &Value, parent_name: String, field_name: &'static str
             }
             
             bail!(ParsingErr::InvalidType {
-                wrong_type: field_name,
+                wrong_type: field_name.to_string(),
                 missing_in,
                 expected: TYPE_HINT_COLOR
             });
         };
     } else {
         bail!(ParsingErr::InvalidType {
-            wrong_type: field_name,
+            wrong_type: field_name.to_string(),
             missing_in,
             expected: TYPE_HINT_COLOR
         });
@@ -0,0 +1,693 @@
+use std::ops::Range;
+
+use anyhow::bail;
+
+use crate::{GLSLConvertible, parser::{ParsingErr, SpannedErr}};
+
+use super::GLOBAL_CELLNAMES;
+
+
+
+/// Single token of a condition, produced by the tokenizer
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f32),
+    Dot,
+    Comma,
+    LParen,
+    RParen,
+    Minus,
+    Not,
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+
+/// Operators that combine two expressions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    And,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+impl BinaryOp {
+    /// Binding strength of the operator, used to place parentheses in the generated GLSL
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 0,
+            BinaryOp::And => 1,
+            _ => 2,
+        }
+    }
+
+    fn is_comparison(&self) -> bool {
+        !matches!(self, BinaryOp::And | BinaryOp::Or)
+    }
+
+    fn glsl(&self) -> &'static str {
+        match self {
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+        }
+    }
+}
+
+
+/// Properties that can be accessed on a cell or material using the '.' syntax
+#[derive(Debug, Clone, PartialEq)]
+pub enum Property {
+    /// `<Cell>.mat` - the material of a cell
+    Material,
+    /// `<Cell/ Material>.density`
+    Density,
+    /// `<Cell/ Material>.type`
+    Type,
+    /// `<Cell/ Material>.id`
+    Id,
+    /// `<Cell>.<field>` - one of the custom fields defined in the 'fields' section
+    Field(String),
+}
+impl Property {
+    /// Any name that is not a builtin property is treated as a field, the type checker
+    /// makes sure the field exists
+    fn from_name(name: &str) -> Property {
+        match name {
+            "mat" => Property::Material,
+            "density" => Property::Density,
+            "type" => Property::Type,
+            "id" => Property::Id,
+            _ => Property::Field(name.to_string()),
+        }
+    }
+}
+
+
+/// The type an expression evaluates to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+    Bool,
+    Number,
+    Cell,
+    Material,
+    Type,
+}
+impl std::fmt::Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ValueType::Bool => "bool",
+            ValueType::Number => "number",
+            ValueType::Cell => "cell",
+            ValueType::Material => "material",
+            ValueType::Type => "type",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+
+/// Node of the syntax tree of a condition
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Bool(bool),
+    Number(f32),
+    /// `RAND`, a random value from 0-1 which changes every frame
+    Rand,
+    /// One of the GLOBAL_CELLNAMES
+    Cell(String),
+    Material(String),
+    Type(String),
+    /// Identifier which has not been resolved to a material or type yet.
+    /// Only exists during parsing, `parse_condition` never returns it
+    Name(String),
+    Property(Box<Expr>, Property),
+    /// `isType_<type>(<Cell>)`
+    IsType(String, Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+
+/// Expression of an 'if' condition together with its position inside the condition string
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    /// Byte range of the expression inside of the source string
+    pub span: Range<usize>,
+}
+impl Expr {
+    fn new(kind: ExprKind, span: Range<usize>) -> Self {
+        Self { kind, span }
+    }
+
+    /// Creates a condition that is always true (used for an 'else' without 'if')
+    pub fn always_true() -> Self {
+        Expr::new(ExprKind::Bool(true), 0..0)
+    }
+
+    /// Whether the expression is the literal `true`
+    pub fn is_always_true(&self) -> bool {
+        self.kind == ExprKind::Bool(true)
+    }
+
+    /// Collects the names of all cells that are referenced in this expression
+    pub fn cells(&self) -> Vec<String> {
+        let mut cells = vec![];
+        self.visit(&mut |e| {
+            if let ExprKind::Cell(c) = &e.kind {
+                if !cells.contains(c) {
+                    cells.push(c.clone());
+                }
+            }
+        });
+        cells
+    }
+
+    /// Calls the function for this node and all of its children
+    pub fn visit<F: FnMut(&Expr)>(&self, func: &mut F) {
+        func(self);
+        match &self.kind {
+            ExprKind::Property(e, _) | ExprKind::IsType(_, e) | ExprKind::Not(e) => e.visit(func),
+            ExprKind::Binary(_, l, r) => {
+                l.visit(func);
+                r.visit(func);
+            },
+            _ => (),
+        }
+    }
+
+    /// Generates GLSL for the expression as the operand of `parent`,
+    /// wrapping it in parentheses if it binds weaker than the operator
+    pub fn glsl_as_operand(&self, parent: BinaryOp, is_rhs: bool) -> String {
+        let parent_prec = parent.precedence() + is_rhs as u8;
+        if let ExprKind::Binary(op, _, _) = &self.kind {
+            if op.precedence() < parent_prec {
+                return format!("({})", self.get_glsl_code());
+            }
+        }
+        self.get_glsl_code()
+    }
+}
+impl GLSLConvertible for Expr {
+    fn get_glsl_code(&self) -> String {
+        match &self.kind {
+            ExprKind::Bool(b) => b.to_string(),
+            ExprKind::Number(n) => format!("{:?}", n),
+            ExprKind::Rand => String::from("rand.z"),
+            ExprKind::Cell(c) => glsl_cellname(c),
+            ExprKind::Material(m) => format!("MAT_{}", m),
+            ExprKind::Type(t) => format!("TYPE_{}", t),
+            // Never reaches the code generation, since it is resolved during parsing
+            ExprKind::Name(n) => n.clone(),
+            ExprKind::Property(base, prop) => {
+                let base_is_cell = matches!(base.kind, ExprKind::Cell(_));
+                let base = base.get_glsl_code();
+                if let Property::Field(field) = prop {
+                    return format!("{}.fields.{}", base, field);
+                }
+                let mat = if base_is_cell {format!("{}.mat", base)} else {base};
+                match prop {
+                    Property::Material => mat,
+                    Property::Density => format!("{}.density", mat),
+                    Property::Type => format!("{}.type", mat),
+                    Property::Id => format!("{}.id", mat),
+                    Property::Field(_) => unreachable!(),
+                }
+            },
+            ExprKind::IsType(t, cell) => format!("isType_{}({})", t, cell.get_glsl_code()),
+            ExprKind::Not(e) => {
+                match e.kind {
+                    ExprKind::Binary(..) => format!("!({})", e.get_glsl_code()),
+                    _ => format!("!{}", e.get_glsl_code()),
+                }
+            },
+            ExprKind::Binary(op, l, r) => {
+                // Comparisons of comparisons are not possible, so only the logical operators need care
+                format!("{} {} {}", l.glsl_as_operand(*op, false), op.glsl(), r.glsl_as_operand(*op, true))
+            },
+        }
+    }
+}
+
+
+/// Converts the name of a global scope cell (e.g. `DOWNRIGHT`) into the name of the GLSL variable
+pub fn glsl_cellname(cell: &str) -> String {
+    cell.to_lowercase()
+}
+
+
+
+
+/// Parses an 'if' condition into an expression tree and checks that all names and types are valid
+pub fn parse_condition(src: &str, parent_path: &str, type_names: &Vec<String>, material_names: &Vec<String>, field_names: &Vec<String>) -> anyhow::Result<Expr> {
+    let tokens = tokenize(src, parent_path)?;
+    let mut parser = ExprParser {
+        src,
+        tokens,
+        pos: 0,
+        parent_path,
+    };
+    let mut expr = parser.parse_or()?;
+    if parser.pos < parser.tokens.len() {
+        let span = parser.tokens[parser.pos].1.start..src.trim_end().len();
+        bail!(SpannedErr {
+            err: ParsingErr::NotRecognized {
+                unrecog: src[span.clone()].to_string(),
+                missing_in: parent_path.to_string()
+            },
+            span
+        });
+    }
+
+    let checker = TypeChecker {
+        src,
+        parent_path,
+        type_names,
+        material_names,
+        field_names,
+    };
+    let value_type = checker.check(&mut expr)?;
+    if value_type != ValueType::Bool {
+        bail!(checker.mismatch(&expr, ValueType::Bool, value_type));
+    }
+    Ok(expr)
+}
+
+
+/// Splits the condition into tokens, keeping track of where in the string they are
+fn tokenize(src: &str, parent_path: &str) -> anyhow::Result<Vec<(Token, Range<usize>)>> {
+    let mut tokens = vec![];
+    let chars: Vec<(usize, char)> = src.char_indices().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        let next = chars.get(i + 1).map(|(_, c)| *c);
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        // Identifiers and keywords
+        if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = i;
+            while end < chars.len() && (chars[end].1.is_ascii_alphanumeric() || chars[end].1 == '_') {
+                end += 1;
+            }
+            let end_byte = chars.get(end).map_or(src.len(), |(b, _)| *b);
+            let word = &src[start..end_byte];
+            let token = match word {
+                "and" => Token::And,
+                "or" => Token::Or,
+                "not" => Token::Not,
+                _ => Token::Ident(word.to_string()),
+            };
+            tokens.push((token, start..end_byte));
+            i = end;
+            continue;
+        }
+
+        // Number literals
+        if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) {
+            let mut end = i;
+            while end < chars.len() && (chars[end].1.is_ascii_digit() || chars[end].1 == '.') {
+                end += 1;
+            }
+            let end_byte = chars.get(end).map_or(src.len(), |(b, _)| *b);
+            let number = &src[start..end_byte];
+            let value = number.parse::<f32>().ok();
+            if let Some(value) = value {
+                tokens.push((Token::Number(value), start..end_byte));
+                i = end;
+                continue;
+            }
+            bail!(SpannedErr {
+                err: ParsingErr::NotRecognized {
+                    unrecog: number.to_string(),
+                    missing_in: parent_path.to_string()
+                },
+                span: start..end_byte
+            });
+        }
+
+        // Operators made out of two characters
+        let double = match (c, next) {
+            ('=', Some('=')) => Some(Token::Eq),
+            ('!', Some('=')) => Some(Token::Ne),
+            ('<', Some('=')) => Some(Token::Le),
+            ('>', Some('=')) => Some(Token::Ge),
+            ('&', Some('&')) => Some(Token::And),
+            ('|', Some('|')) => Some(Token::Or),
+            _ => None,
+        };
+        if let Some(token) = double {
+            tokens.push((token, start..start + 2));
+            i += 2;
+            continue;
+        }
+
+        let single = match c {
+            '.' => Token::Dot,
+            ',' => Token::Comma,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '-' => Token::Minus,
+            '!' => Token::Not,
+            '<' => Token::Lt,
+            '>' => Token::Gt,
+            _ => {
+                bail!(SpannedErr {
+                    err: ParsingErr::NotRecognized {
+                        unrecog: c.to_string(),
+                        missing_in: parent_path.to_string()
+                    },
+                    span: start..start + c.len_utf8()
+                });
+            }
+        };
+        tokens.push((single, start..start + c.len_utf8()));
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+
+/// Recursive descent parser turning the tokens into an expression tree
+struct ExprParser<'a> {
+    src: &'a str,
+    tokens: Vec<(Token, Range<usize>)>,
+    pos: usize,
+    parent_path: &'a str,
+}
+impl<'a> ExprParser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(t, _)| t)
+    }
+
+    fn next(&mut self) -> Option<(Token, Range<usize>)> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    /// Error for a token that is not valid at this point (or for a missing token at the end)
+    fn unexpected(&self, span: Option<Range<usize>>) -> anyhow::Error {
+        let (unrecog, span) = match span {
+            Some(span) => (self.src[span.clone()].to_string(), span),
+            None => {
+                let end = self.src.trim_end().len();
+                (format!("{} <- (unexpected end)", self.src.trim_end()), end..end)
+            },
+        };
+        SpannedErr {
+            err: ParsingErr::NotRecognized {
+                unrecog,
+                missing_in: self.parent_path.to_string()
+            },
+            span
+        }.into()
+    }
+
+    fn expect(&mut self, token: Token) -> anyhow::Result<Range<usize>> {
+        match self.next() {
+            Some((t, span)) if t == token => Ok(span),
+            Some((_, span)) => Err(self.unexpected(Some(span))),
+            None => Err(self.unexpected(None)),
+        }
+    }
+
+    fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+        let span = lhs.span.start..rhs.span.end;
+        Expr::new(ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)), span)
+    }
+
+    // or_expr := and_expr (('or' | '||') and_expr)*
+    fn parse_or(&mut self) -> anyhow::Result<Expr> {
+        let mut lhs = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            let rhs = self.parse_and()?;
+            lhs = ExprParser::binary(BinaryOp::Or, lhs, rhs);
+        }
+        Ok(lhs)
+    }
+
+    // and_expr := not_expr (('and' | '&&') not_expr)*
+    fn parse_and(&mut self) -> anyhow::Result<Expr> {
+        let mut lhs = self.parse_not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            let rhs = self.parse_not()?;
+            lhs = ExprParser::binary(BinaryOp::And, lhs, rhs);
+        }
+        Ok(lhs)
+    }
+
+    // not_expr := ('not' | '!') not_expr | comparison
+    fn parse_not(&mut self) -> anyhow::Result<Expr> {
+        if self.peek() == Some(&Token::Not) {
+            let (_, span) = self.next().unwrap();
+            let inner = self.parse_not()?;
+            let span = span.start..inner.span.end;
+            return Ok(Expr::new(ExprKind::Not(Box::new(inner)), span));
+        }
+        self.parse_comparison()
+    }
+
+    // comparison := primary (cmp_op primary)?
+    fn parse_comparison(&mut self) -> anyhow::Result<Expr> {
+        let lhs = self.parse_primary()?;
+        let op = match self.peek() {
+            Some(Token::Eq) => BinaryOp::Eq,
+            Some(Token::Ne) => BinaryOp::Ne,
+            Some(Token::Lt) => BinaryOp::Lt,
+            Some(Token::Le) => BinaryOp::Le,
+            Some(Token::Gt) => BinaryOp::Gt,
+            Some(Token::Ge) => BinaryOp::Ge,
+            _ => return Ok(lhs),
+        };
+        self.next();
+        let rhs = self.parse_primary()?;
+        Ok(ExprParser::binary(op, lhs, rhs))
+    }
+
+    // primary := '(' or_expr ')' | ['-'] number | ident ('.' ident)* | ident '(' or_expr ')'
+    fn parse_primary(&mut self) -> anyhow::Result<Expr> {
+        let (token, span) = match self.next() {
+            Some(t) => t,
+            None => return Err(self.unexpected(None)),
+        };
+        match token {
+            Token::LParen => {
+                let inner = self.parse_or()?;
+                let end = self.expect(Token::RParen)?;
+                Ok(Expr::new(inner.kind, span.start..end.end))
+            },
+            Token::Number(n) => Ok(Expr::new(ExprKind::Number(n), span)),
+            Token::Minus => {
+                match self.next() {
+                    Some((Token::Number(n), num_span)) => Ok(Expr::new(ExprKind::Number(-n), span.start..num_span.end)),
+                    Some((_, span)) => Err(self.unexpected(Some(span))),
+                    None => Err(self.unexpected(None)),
+                }
+            },
+            Token::Ident(name) => {
+                // Function call
+                if self.peek() == Some(&Token::LParen) {
+                    self.next();
+                    let arg = self.parse_or()?;
+                    let end = self.expect(Token::RParen)?;
+                    let typename = match name.strip_prefix("isType_") {
+                        Some(t) => t.to_string(),
+                        None => return Err(self.unexpected(Some(span.start..end.end))),
+                    };
+                    return Ok(Expr::new(ExprKind::IsType(typename, Box::new(arg)), span.start..end.end));
+                }
+
+                let kind = match name.as_str() {
+                    "true" => ExprKind::Bool(true),
+                    "false" => ExprKind::Bool(false),
+                    "RAND" => ExprKind::Rand,
+                    n if GLOBAL_CELLNAMES.contains(&n) => ExprKind::Cell(name),
+                    _ => ExprKind::Name(name),
+                };
+                let mut expr = Expr::new(kind, span);
+
+                // Property access
+                while self.peek() == Some(&Token::Dot) {
+                    self.next();
+                    let (prop, prop_span) = match self.next() {
+                        Some((Token::Ident(prop), prop_span)) => (prop, prop_span),
+                        Some((_, span)) => return Err(self.unexpected(Some(span))),
+                        None => return Err(self.unexpected(None)),
+                    };
+                    let full_span = expr.span.start..prop_span.end;
+                    let prop = Property::from_name(&prop);
+                    expr = Expr::new(ExprKind::Property(Box::new(expr), prop), full_span);
+                }
+                Ok(expr)
+            },
+            _ => Err(self.unexpected(Some(span))),
+        }
+    }
+}
+
+
+/// Resolves the names inside of an expression and makes sure all operands have fitting types
+struct TypeChecker<'a> {
+    src: &'a str,
+    parent_path: &'a str,
+    type_names: &'a Vec<String>,
+    material_names: &'a Vec<String>,
+    field_names: &'a Vec<String>,
+}
+impl<'a> TypeChecker<'a> {
+    fn mismatch(&self, expr: &Expr, expected: ValueType, found: ValueType) -> anyhow::Error {
+        SpannedErr {
+            err: ParsingErr::TypeMismatch {
+                expr: self.src[expr.span.clone()].to_string(),
+                missing_in: self.parent_path.to_string(),
+                expected: expected.to_string(),
+                found: found.to_string(),
+            },
+            span: expr.span.clone()
+        }.into()
+    }
+
+    fn not_found(&self, name: &str, span: &Range<usize>) -> anyhow::Error {
+        SpannedErr {
+            err: ParsingErr::NotFound {
+                missing: name.to_string(),
+                missing_in: self.parent_path.to_string()
+            },
+            span: span.clone()
+        }.into()
+    }
+
+    /// Resolves a bare name to a material or type. If the name exists as both,
+    /// the type the surrounding expression expects is preferred
+    fn resolve_name(&self, expr: &mut Expr, expected: ValueType) -> anyhow::Result<ValueType> {
+        if let ExprKind::Name(name) = &expr.kind {
+            let is_material = self.material_names.contains(name);
+            let is_type = self.type_names.contains(name);
+            let (kind, resolved) = match expected {
+                ValueType::Type if is_type => (ExprKind::Type(name.clone()), ValueType::Type),
+                _ if is_material => (ExprKind::Material(name.clone()), ValueType::Material),
+                _ if is_type => (ExprKind::Type(name.clone()), ValueType::Type),
+                _ => return Err(self.not_found(name, &expr.span)),
+            };
+            expr.kind = kind;
+            return Ok(resolved);
+        }
+        self.check(expr)
+    }
+
+    fn expect(&self, expr: &mut Expr, expected: ValueType) -> anyhow::Result<()> {
+        let found = self.resolve_name(expr, expected)?;
+        if found != expected {
+            return Err(self.mismatch(expr, expected, found));
+        }
+        Ok(())
+    }
+
+    fn check(&self, expr: &mut Expr) -> anyhow::Result<ValueType> {
+        if let ExprKind::Name(_) = expr.kind {
+            return self.resolve_name(expr, ValueType::Bool);
+        }
+        let span = expr.span.clone();
+        match &mut expr.kind {
+            ExprKind::Bool(_) => Ok(ValueType::Bool),
+            ExprKind::Number(_) | ExprKind::Rand => Ok(ValueType::Number),
+            ExprKind::Cell(_) => Ok(ValueType::Cell),
+            ExprKind::Material(_) => Ok(ValueType::Material),
+            ExprKind::Type(_) => Ok(ValueType::Type),
+            ExprKind::Name(_) => unreachable!(),
+            ExprKind::Property(base, prop) => {
+                // Properties of materials can be accessed from the cell directly (SELF.density)
+                let base_type = self.resolve_name(base, ValueType::Material)?;
+                match (base_type, &*prop) {
+                    (ValueType::Cell, Property::Material) => Ok(ValueType::Material),
+                    // Fields are stored per cell, materials only define their default values
+                    (ValueType::Cell, Property::Field(field)) if self.field_names.contains(field) => Ok(ValueType::Number),
+                    (ValueType::Cell, Property::Field(_)) => {
+                        let text = &self.src[span.clone()];
+                        let prop_start = span.start + text.rfind('.').map_or(0, |dot| dot + 1);
+                        Err(SpannedErr {
+                            err: ParsingErr::NotFound {
+                                missing: text.to_string(),
+                                missing_in: self.parent_path.to_string()
+                            },
+                            span: prop_start..span.end
+                        }.into())
+                    },
+                    (ValueType::Cell | ValueType::Material, Property::Density | Property::Id) => Ok(ValueType::Number),
+                    (ValueType::Cell | ValueType::Material, Property::Type) => Ok(ValueType::Type),
+                    _ => Err(self.not_found(&self.src[span.clone()], &span)),
+                }
+            },
+            ExprKind::IsType(typename, cell) => {
+                if !self.type_names.contains(typename) {
+                    let name = format!("isType_{}", typename);
+                    return Err(self.not_found(&name, &(span.start..span.start + name.len())));
+                }
+                self.expect(cell, ValueType::Cell)?;
+                Ok(ValueType::Bool)
+            },
+            ExprKind::Not(inner) => {
+                self.expect(inner, ValueType::Bool)?;
+                Ok(ValueType::Bool)
+            },
+            ExprKind::Binary(op, lhs, rhs) => {
+                if !op.is_comparison() {
+                    self.expect(lhs, ValueType::Bool)?;
+                    self.expect(rhs, ValueType::Bool)?;
+                    return Ok(ValueType::Bool);
+                }
+
+                // Names are resolved based on the type of the other side of the comparison
+                let (lhs_type, rhs_type) = match (&lhs.kind, &rhs.kind) {
+                    (ExprKind::Name(_), ExprKind::Name(_)) => {
+                        return Err(self.not_found(&self.src[lhs.span.clone()], &lhs.span));
+                    },
+                    (ExprKind::Name(_), _) => {
+                        let rhs_type = self.check(rhs)?;
+                        (self.resolve_name(lhs, rhs_type)?, rhs_type)
+                    },
+                    _ => {
+                        let lhs_type = self.check(lhs)?;
+                        (lhs_type, self.resolve_name(rhs, lhs_type)?)
+                    },
+                };
+                let comparable = match op {
+                    BinaryOp::Eq | BinaryOp::Ne => lhs_type != ValueType::Cell,
+                    _ => lhs_type == ValueType::Number,
+                };
+                if !comparable {
+                    let expected = if matches!(op, BinaryOp::Eq | BinaryOp::Ne) {ValueType::Material} else {ValueType::Number};
+                    return Err(self.mismatch(lhs, expected, lhs_type));
+                }
+                if lhs_type != rhs_type {
+                    return Err(self.mismatch(rhs, lhs_type, rhs_type));
+                }
+                Ok(ValueType::Bool)
+            },
+        }
+    }
+}
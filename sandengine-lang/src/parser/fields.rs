@@ -0,0 +1,355 @@
+use std::fmt;
+
+use anyhow::anyhow;
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+
+use crate::{GLSLConvertible, parser::{ParsingErr, TYPE_HINT_MAPPING, TYPE_HINT_STRING, yaml_repr}};
+
+use super::report::ParseReport;
+
+
+
+pub(crate) const TYPE_HINT_FIELD_TYPE: &str = "'int' or 'float'";
+
+/// Names that can't be used for fields, because they are already properties of a cell
+const RESERVED_FIELD_NAMES: [&str; 5] = [
+    "mat",
+    "density",
+    "type",
+    "id",
+    "pos",
+];
+
+/// Keywords, built-in types and reserved words of GLSL, which can't be the names of struct members.
+/// Names starting with `gl_` or containing `__` are reserved as well
+const GLSL_KEYWORDS: [&str; 132] = [
+    "attribute", "const", "uniform", "varying", "buffer", "shared", "coherent", "volatile", "restrict",
+    "readonly", "writeonly", "layout", "centroid", "flat", "smooth", "noperspective", "patch", "sample",
+    "subroutine", "in", "out", "inout", "invariant", "precise", "precision", "lowp", "mediump", "highp",
+    "break", "continue", "do", "for", "while", "switch", "case", "default", "if", "else", "discard", "return",
+    "struct", "true", "false", "void", "bool", "int", "uint", "float", "double",
+    "vec2", "vec3", "vec4", "bvec2", "bvec3", "bvec4", "ivec2", "ivec3", "ivec4", "uvec2", "uvec3", "uvec4",
+    "dvec2", "dvec3", "dvec4", "mat2", "mat3", "mat4", "mat2x2", "mat2x3", "mat2x4", "mat3x2", "mat3x3",
+    "mat3x4", "mat4x2", "mat4x3", "mat4x4", "dmat2", "dmat3", "dmat4", "atomic_uint",
+    "sampler1D", "sampler2D", "sampler3D", "samplerCube", "sampler2DArray", "sampler2DShadow", "isampler2D",
+    "usampler2D", "image1D", "image2D", "image3D", "image2DArray", "iimage2D", "uimage2D",
+    // Reserved for future use
+    "common", "partition", "active", "asm", "class", "union", "enum", "typedef", "template", "this",
+    "resource", "goto", "inline", "noinline", "public", "static", "extern", "external", "interface",
+    "long", "short", "half", "fixed", "unsigned", "superp", "input", "output", "hvec2", "hvec3", "hvec4",
+    "fvec2", "fvec3", "fvec4", "filter", "sizeof", "cast", "namespace", "using",
+];
+
+/// Number of values that fit into a single layer (RGBA) of the cell data texture
+pub const CHANNELS_PER_LAYER: usize = 4;
+const CHANNEL_NAMES: [&str; CHANNELS_PER_LAYER] = ["r", "g", "b", "a"];
+
+
+/// Data type of a custom field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    Int,
+    Float,
+}
+impl FieldType {
+    fn from_name(name: &str) -> Option<FieldType> {
+        match name {
+            "int" => Some(FieldType::Int),
+            "float" => Some(FieldType::Float),
+            _ => None,
+        }
+    }
+
+    /// Converts a YAML number into a value of this type. Floats can't be used for int fields
+    pub fn value_from_yaml(&self, value: &Value) -> Option<FieldValue> {
+        match self {
+            FieldType::Int => value.as_i64().map(|v| FieldValue::Int(v as i32)),
+            FieldType::Float => value.as_f64().map(|v| FieldValue::Float(v as f32)),
+        }
+    }
+
+    /// Same as `value_from_yaml`, but for numbers written inside of a 'do' action
+    pub fn value_from_str(&self, value: &str) -> Option<FieldValue> {
+        match self {
+            FieldType::Int => value.parse::<i32>().ok().map(FieldValue::Int),
+            FieldType::Float => value.parse::<f32>().ok().map(FieldValue::Float),
+        }
+    }
+
+    pub fn hint(&self) -> &'static str {
+        match self {
+            FieldType::Int => "int (whole number)",
+            FieldType::Float => "float",
+        }
+    }
+
+    fn default_value(&self) -> FieldValue {
+        match self {
+            FieldType::Int => FieldValue::Int(0),
+            FieldType::Float => FieldValue::Float(0.0),
+        }
+    }
+}
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldType::Int => write!(f, "int"),
+            FieldType::Float => write!(f, "float"),
+        }
+    }
+}
+
+
+/// Value of a custom field. Displays as a GLSL literal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValue {
+    Int(i32),
+    Float(f32),
+}
+impl FieldValue {
+    pub fn as_f32(&self) -> f32 {
+        match self {
+            FieldValue::Int(v) => *v as f32,
+            FieldValue::Float(v) => *v,
+        }
+    }
+}
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Int(v) => write!(f, "{}", v),
+            FieldValue::Float(v) => write!(f, "{:?}", v),
+        }
+    }
+}
+
+
+/// Holds information about a custom per-cell field defined in the YAML file
+#[derive(Debug, Clone)]
+pub struct SandField {
+    /// Name of the field (Mapping key)
+    pub name: String,
+    /// Data type of the field
+    pub fieldtype: FieldType,
+    /// Value of the field for materials which don't define their own
+    pub default: FieldValue,
+    /// Index of the value inside of the cell data. Slot 0 is the material id
+    pub slot: usize,
+}
+impl SandField {
+    /// Layer of the cell data texture (array) the field is stored in
+    pub fn layer(&self) -> usize {
+        self.slot / CHANNELS_PER_LAYER
+    }
+
+    /// Channel (r, g, b or a) of the layer the field is stored in
+    pub fn channel(&self) -> &'static str {
+        CHANNEL_NAMES[self.slot % CHANNELS_PER_LAYER]
+    }
+}
+impl GLSLConvertible for SandField {
+    fn get_glsl_code(&self) -> String {
+        format!("    {} {};\n", self.fieldtype, self.name)
+    }
+}
+
+
+/// Number of layers the cell data texture needs to store the material id and all fields
+pub fn num_data_layers(fields: &[SandField]) -> usize {
+    fields.len() / CHANNELS_PER_LAYER + 1
+}
+
+
+/// Creates the GLSL code for the `Fields` struct (which every Cell carries) and the
+/// functions to read it from/ write it to the layers of the cell data texture
+pub fn fields_glsl(fields: &[SandField]) -> String {
+    let mut members = String::new();
+    let mut unpack = String::new();
+    let mut pack = String::new();
+    for field in fields {
+        members.push_str(&field.get_glsl_code());
+        let data = format!("data[{}].{}", field.layer(), field.channel());
+        unpack.push_str(&format!("    fields.{} = {}({});\n", field.name, field.fieldtype, data));
+        pack.push_str(&format!("    {} = float(fields.{});\n", data, field.name));
+    }
+    // GLSL does not allow empty structs
+    if fields.is_empty() {
+        members.push_str("    int _unused;\n");
+    }
+
+    // Starts with a newline, since the include replaces the line break before it
+    format!(
+"
+#define NUM_DATA_LAYERS {layers}
+
+struct Fields {{
+{members}}};
+
+// Defined in materials.glsl, as it depends on the materials
+Fields defaultFields(Material mat);
+
+Fields unpackFields(vec4 data[NUM_DATA_LAYERS]) {{
+    Fields fields = Fields({zero});
+{unpack}    return fields;
+}}
+
+vec4[NUM_DATA_LAYERS] packFields(int id, Fields fields) {{
+    vec4 data[NUM_DATA_LAYERS];
+    for (int i = 0; i < NUM_DATA_LAYERS; i++) {{
+        data[i] = vec4(0.0);
+    }}
+    data[0].r = float(id);
+{pack}    return data;
+}}
+", layers = num_data_layers(fields),
+    members = members,
+    zero = constructor_args(fields.iter().map(|f| f.fieldtype.default_value())),
+    unpack = unpack,
+    pack = pack)
+}
+
+
+/// Packs the material id and field values of a cell into the layers of the cell data texture,
+/// like `packFields` in GLSL. `values` are in the order of `fields`
+pub fn pack_cell(id: u16, values: &[f32], fields: &[SandField]) -> Vec<[f32; CHANNELS_PER_LAYER]> {
+    let mut data = vec![[0.0; CHANNELS_PER_LAYER]; num_data_layers(fields)];
+    data[0][0] = id as f32;
+    for (field, value) in fields.iter().zip(values) {
+        data[field.layer()][field.slot % CHANNELS_PER_LAYER] = *value;
+    }
+    data
+}
+
+
+/// Arguments for the `Fields(...)` constructor
+pub(crate) fn constructor_args(values: impl Iterator<Item = FieldValue>) -> String {
+    let args = values.map(|v| v.to_string()).collect::<Vec<String>>();
+    match args.is_empty() {
+        true => "0".to_string(),
+        false => args.join(", "),
+    }
+}
+
+
+/// Parses the optional 'fields' section. A field is either just its type or a mapping with
+/// 'type' and 'default':
+///
+/// ```yaml
+/// fields:
+///     lifetime: int
+///     heat:
+///         type: float
+///         default: 20.0
+/// ```
+pub fn parse_fields(fields: &Mapping, report: &mut ParseReport) -> Vec<SandField> {
+    let mut field_structs: Vec<SandField> = vec![];
+    let valid_name = Regex::new(r"^[A-Za-z_]\w*$").unwrap();
+
+    for (key, field) in fields {
+        let name = key.as_str()
+            .ok_or(anyhow!(ParsingErr::InvalidType {
+                wrong_type: yaml_repr(key),
+                missing_in: "fields".to_string(),
+                expected: TYPE_HINT_STRING
+            }));
+        let Some(name) = report.check(name) else {
+            continue;
+        };
+        let path = format!("fields/{}", name);
+        // Fields end up as GLSL struct members
+        let glsl_reserved = GLSL_KEYWORDS.contains(&name) || name.starts_with("gl_") || name.contains("__");
+        if !valid_name.is_match(name) || RESERVED_FIELD_NAMES.contains(&name) || glsl_reserved {
+            report.error(anyhow!(ParsingErr::NotRecognized {
+                unrecog: name.to_string(),
+                missing_in: path
+            }));
+            continue;
+        }
+
+        let (fieldtype, default) = match field {
+            Value::Mapping(_) => (field.get("type"), field.get("default")),
+            _ => (Some(field), None),
+        };
+        let type_path = match field.is_mapping() {
+            true => format!("{}/type", path),
+            false => path.clone(),
+        };
+        let Some(fieldtype) = fieldtype else {
+            report.error(anyhow!(ParsingErr::MissingField {
+                field_name: "type".to_string(),
+                missing_in: path
+            }));
+            continue;
+        };
+        let Some(fieldtype) = fieldtype.as_str().and_then(FieldType::from_name) else {
+            report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: "type".to_string(),
+                missing_in: type_path,
+                expected: TYPE_HINT_FIELD_TYPE
+            }));
+            continue;
+        };
+        let default = match default {
+            Some(default) => match fieldtype.value_from_yaml(default) {
+                Some(default) => default,
+                None => {
+                    report.error(anyhow!(ParsingErr::InvalidType {
+                        wrong_type: "default".to_string(),
+                        missing_in: format!("{}/default", path),
+                        expected: fieldtype.hint()
+                    }));
+                    continue;
+                }
+            },
+            None => fieldtype.default_value(),
+        };
+
+        field_structs.push(SandField {
+            name: name.to_string(),
+            fieldtype,
+            default,
+            // Slot 0 holds the material id
+            slot: field_structs.len() + 1,
+        });
+    }
+    field_structs
+}
+
+
+/// Parses the per-material field values (`fields: {lifetime: 100}` inside of a material)
+pub fn parse_material_fields(mat: &Value, name: &str, fields: &[SandField], report: &mut ParseReport) -> Vec<(String, FieldValue)> {
+    let mut values = vec![];
+    let Some(mat_fields) = mat.get("fields") else {
+        return values;
+    };
+    let Some(mat_fields) = mat_fields.as_mapping() else {
+        report.error(anyhow!(ParsingErr::InvalidType {
+            wrong_type: "fields".to_string(),
+            missing_in: format!("materials/{}/fields", name),
+            expected: TYPE_HINT_MAPPING
+        }));
+        return values;
+    };
+
+    for (key, value) in mat_fields {
+        let field_name = key.as_str().map(String::from).unwrap_or_else(|| yaml_repr(key));
+        let path = format!("materials/{}/fields/{}", name, field_name);
+        let Some(field) = fields.iter().find(|f| f.name == field_name) else {
+            report.error(anyhow!(ParsingErr::NotFound {
+                missing: field_name,
+                missing_in: path
+            }));
+            continue;
+        };
+        match field.fieldtype.value_from_yaml(value) {
+            Some(value) => values.push((field_name, value)),
+            None => report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: field_name,
+                missing_in: path,
+                expected: field.fieldtype.hint()
+            })),
+        }
+    }
+    values
+}
@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use serde_yaml::Mapping;
+
+use super::materials::SandMaterial;
+
+
+
+const LOCKFILE_HEADER: &str = "# Generated by sandengine, do not edit.
+# Pins the id of every material (by name), so that saved worlds keep their materials
+# when materials are added, removed or reordered. Materials that were removed stay listed,
+# so that their ids are not reused.
+";
+
+
+/// The ids that were assigned to the materials (`<name>.lock` next to the YAML file, see `parse_path`).
+/// Materials that are pinned keep their id, new materials get a free one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaterialLock {
+    /// Name and id of every material that was ever assigned an id, sorted by id
+    pub ids: Vec<(String, u16)>,
+}
+impl MaterialLock {
+    /// Parses the contents of a lockfile, a mapping of material names to ids
+    pub fn parse(src: &str) -> anyhow::Result<Self> {
+        let map: Mapping = match serde_yaml::from_str::<Option<Mapping>>(src)? {
+            Some(map) => map,
+            None => Mapping::new(),
+        };
+        let mut lock = MaterialLock::default();
+        for (name, id) in map.iter() {
+            let name = name.as_str().ok_or(anyhow!("Invalid material name '{:?}'", name))?;
+            let id = id.as_u64()
+                .and_then(|id| u16::try_from(id).ok())
+                .ok_or(anyhow!("Invalid id of the material '{}'", name))?;
+            if let Some((other, _)) = lock.ids.iter().find(|(_, other_id)| *other_id == id) {
+                return Err(anyhow!("The materials '{}' and '{}' have the same id ({})", other, name, id));
+            }
+            lock.ids.push((name.to_string(), id));
+        }
+        lock.ids.sort_by_key(|(_, id)| *id);
+        Ok(lock)
+    }
+
+    /// Loads the lockfile at the path, a missing file is an empty lock
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(MaterialLock::default());
+        }
+        let src = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read '{}'", path.display()))?;
+        MaterialLock::parse(&src)
+            .with_context(|| format!("Invalid lockfile '{}'", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, self.to_string())
+            .with_context(|| format!("Could not write '{}'", path.display()))
+    }
+
+    /// Id the material with the name is pinned to
+    pub fn id(&self, name: &str) -> Option<u16> {
+        self.ids.iter().find(|(n, _)| n == name).map(|(_, id)| *id)
+    }
+
+    /// Name of the material the id is pinned to
+    pub fn name(&self, id: u16) -> Option<&str> {
+        self.ids.iter().find(|(_, i)| *i == id).map(|(n, _)| n.as_str())
+    }
+
+    /// Pins the ids of the materials (except the builtin ones, which never change).
+    /// Materials that were removed stay pinned. Returns whether anything changed
+    pub fn update(&mut self, materials: &[SandMaterial]) -> bool {
+        let before = self.clone();
+        for mat in materials.iter().filter(|m| m.id >= super::BUILTIN_NAMES.len()) {
+            // An explicit 'id' moves the material, its old id is free again
+            self.ids.retain(|(name, id)| name != &mat.name && *id as usize != mat.id);
+            self.ids.push((mat.name.clone(), mat.id as u16));
+        }
+        self.ids.sort_by_key(|(_, id)| *id);
+        *self != before
+    }
+}
+impl std::fmt::Display for MaterialLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", LOCKFILE_HEADER)?;
+        for (name, id) in self.ids.iter() {
+            writeln!(f, "{}: {}", name, id)?;
+        }
+        Ok(())
+    }
+}
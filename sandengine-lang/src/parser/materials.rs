@@ -1,12 +1,19 @@
 use anyhow::{anyhow, bail};
-use serde_yaml::Mapping;
+use serde_yaml::{Mapping, Value};
 
-use crate::{GLSLConvertible, parser::{TYPE_HINT_STRING, ParsingErr, TYPE_HINT_SEQUENCE}};
+use crate::{GLSLConvertible, parser::{TYPE_HINT_STRING, ParsingErr, TYPE_HINT_SEQUENCE, yaml_repr}};
 
-use super::{rules::SandRule, types::SandType, extract_vec4, TYPE_HINT_FLOAT};
+use super::{rules::SandRule, report::ParseReport, extract_vec4, BUILTIN_NAMES, TYPE_HINT_FLOAT};
+use super::fields::{self, FieldValue, SandField};
+use super::thermal::{self, ThermalProps};
+use super::lock::MaterialLock;
 
 
 
+const TYPE_HINT_SYMBOL: &str = "a single character (e.g. 's')";
+const TYPE_HINT_ID: &str = "int (whole number) from 3 to 65535, 0 to 2 are the ids of EMPTY, NULL and WALL";
+
+
 #[derive(Debug, Clone, Default)]
 pub struct SandMaterial {
     /// Index/ ID of the material
@@ -25,11 +32,25 @@ pub struct SandMaterial {
     pub density: f32,
     /// Extra rules of the material, which are unique to this
     /// material and cannot be defined in the base_rules of the type
-    pub extra_rules: Vec<String>
+    pub extra_rules: Vec<String>,
+    /// Values of custom fields which differ from the field's default, used for new cells of this material
+    pub fields: Vec<(String, FieldValue)>,
+    /// Conductivity and the transitions at certain temperatures
+    pub thermal: ThermalProps,
+    /// Character that stands for the material in the grids of the 'tests' section
+    pub symbol: Option<char>,
+}
+impl SandMaterial {
+    /// Value a field has in a new cell of this material
+    pub fn field_value(&self, field: &SandField) -> FieldValue {
+        self.fields.iter()
+            .find(|(name, _)| name == &field.name)
+            .map_or(field.default, |(_, value)| *value)
+    }
 }
 impl GLSLConvertible for SandMaterial {
     fn get_glsl_code(&self) -> String {
-        format!("#define MAT_{} Material({}, vec4({}, {}, {}, {}), {}, vec4({}, {}, {}, {}), TYPE_{})\n",
+        format!("#define MAT_{} Material({}, vec4({}, {}, {}, {}), {}, vec4({}, {}, {}, {}), TYPE_{}, {})\n",
             self.name,
             self.id,
             self.color[0],
@@ -42,13 +63,18 @@ impl GLSLConvertible for SandMaterial {
             self.emission[2],
             self.emission[3],
             self.mattype,
+            self.thermal.conductivity,
         )
     }
 }
 
 
-/// Parses a serde_yaml Mapping (dict) and converts it into SandMaterial's
-pub fn parse_materials(materials: &Mapping, rules: &mut Vec<SandRule>, type_names: &Vec<String>) -> anyhow::Result<(Vec<SandMaterial>, Vec<Box<dyn GLSLConvertible>>)> {
+/// Parses a serde_yaml Mapping (dict) and converts it into SandMaterial's.
+/// Materials containing errors are skipped, the errors are recorded in the report.
+///
+/// The id of a material is its 'id' key, otherwise the id it is pinned to in the lock,
+/// otherwise the lowest id that is neither used nor pinned
+pub fn parse_materials(materials: &Mapping, rules: &mut [SandRule], type_names: &[String], material_names: &[String], fields: &[SandField], lock: &MaterialLock, report: &mut ParseReport) -> (Vec<SandMaterial>, Vec<Box<dyn GLSLConvertible>>) {
     let mut material_structs: Vec<SandMaterial> = vec![
         SandMaterial {
             id: 0,
@@ -58,6 +84,7 @@ pub fn parse_materials(materials: &Mapping, rules: &mut Vec<SandRule>, type_name
             emission: [0.0, 0.0, 0.0, 0.0],
             selectable: true,
             density: 1.0,
+            symbol: Some('.'),
             ..Default::default()
         },
         SandMaterial {
@@ -78,6 +105,7 @@ pub fn parse_materials(materials: &Mapping, rules: &mut Vec<SandRule>, type_name
             emission: [0.0, 0.0, 0.0, 0.0],
             selectable: false,
             density: 9999.0,
+            symbol: Some('#'),
             ..Default::default()
         },
     ];
@@ -87,38 +115,28 @@ pub fn parse_materials(materials: &Mapping, rules: &mut Vec<SandRule>, type_name
         Box::new(material_structs[2].clone()),
     ];
 
-    // Index is 3 because of the 3 default materials
-    let mut idx = material_structs.len();
+    let explicit_ids = parse_ids(materials, lock, report);
+    let mut used_ids: Vec<usize> = material_structs.iter().map(|m| m.id).collect();
     for mat in materials {
         let name = mat.0.as_str()
             .ok_or(anyhow!(ParsingErr::InvalidType {
-                wrong_type: mat.0.clone(),
+                wrong_type: yaml_repr(mat.0),
                 missing_in: "materials".to_string(),
                 expected: TYPE_HINT_STRING
-            }))?
-            .to_string();
+            }));
+        let Some(name) = report.check(name) else {
+            continue;
+        };
+        let name = name.to_string();
+        let errors_before = report.error_count();
 
-        let mattype = mat.1.get("type")
-            .ok_or(anyhow!(ParsingErr::<bool>::MissingField {
-                field_name: "type".to_string(),
-                missing_in: format!("materials/{}", name)
-            }))?;
-        let mattype = mattype.as_str()
-            .ok_or(anyhow!(ParsingErr::InvalidType {
-                wrong_type: "type".to_string(),
-                missing_in: format!("materials/{}", name),
-                expected: TYPE_HINT_STRING
-            }))?
-            .to_string();
-        if !type_names.contains(&mattype) {
-            bail!(ParsingErr::<bool>::NotFound {
-                missing: mattype,
-                missing_in: format!("materials/{}/type", name)
-            });
-        }
+        let mattype = parse_mattype(mat.1, &name, type_names);
+        let mattype = report.check(mattype).unwrap_or_default();
 
-        let color = extract_vec4(mat.1, name.clone(), "color", [1.0, 0.0, 1.0, 1.0], true)?;
-        let emission = extract_vec4(mat.1, name.clone(), "emission", [0.0, 0.0, 0.0, 0.0], false)?;
+        let color = extract_vec4(mat.1, name.clone(), "color", [1.0, 0.0, 1.0, 1.0], true);
+        let color = report.check(color).unwrap_or_default();
+        let emission = extract_vec4(mat.1, name.clone(), "emission", [0.0, 0.0, 0.0, 0.0], false);
+        let emission = report.check(emission).unwrap_or_default();
 
         let selectable = match mat.1.get("selectable") {
             Some(selectable) => {
@@ -132,23 +150,34 @@ pub fn parse_materials(materials: &Mapping, rules: &mut Vec<SandRule>, type_name
         };
 
         let density = mat.1.get("density")
-            .ok_or(anyhow!(ParsingErr::<bool>::MissingField {
+            .ok_or(anyhow!(ParsingErr::MissingField {
                 field_name: "density".to_string(),
                 missing_in: format!("materials/{}", name)
-            }))?;
-        let density = density.as_f64()
-            .ok_or(ParsingErr::InvalidType {
-                wrong_type: "density".to_string(),
-                missing_in: format!("materials/{}/density", name),
-                expected: TYPE_HINT_FLOAT
-            })?
-            as f32;
+            }))
+            .and_then(|density| density.as_f64()
+                .ok_or(anyhow!(ParsingErr::InvalidType {
+                    wrong_type: "density".to_string(),
+                    missing_in: format!("materials/{}/density", name),
+                    expected: TYPE_HINT_FLOAT
+                })));
+        let density = report.check(density).unwrap_or_default() as f32;
+
+        let symbol = parse_symbol(mat.1, &name, &material_structs, report);
+
+        let mut field_values = fields::parse_material_fields(mat.1, &name, fields, report);
+
+        // The 'temperature' key is the same as setting the temperature field
+        let (temperature, thermal) = thermal::parse_thermal(mat.1, &name, material_names, report);
+        if let Some(temperature) = temperature {
+            field_values.retain(|(field, _)| field != thermal::TEMPERATURE_FIELD);
+            field_values.push((thermal::TEMPERATURE_FIELD.to_string(), FieldValue::Float(temperature)));
+        }
         
         let mut extra_rules = Vec::<String>::new();
         let extra_rules_data = mat.1.get("extra_rules");
         if let Some(extra) = extra_rules_data {
             if let Some(extra) = extra.as_sequence() {
-                for extra_rule in extra {
+                for (rule_idx, extra_rule) in extra.iter().enumerate() {
                     if let Some(extra_rule) = extra_rule.as_str() {
                         for r in rules.iter_mut() {
                             if r.name == extra_rule {
@@ -160,42 +189,171 @@ pub fn parse_materials(materials: &Mapping, rules: &mut Vec<SandRule>, type_name
                                         r.precondition = Some(format!("{} || self.mat == MAT_{}", precondition, name));
                                     }
                                 }
-                                extra_rules.push(extra_rule.to_string());
                             }
                         }
+                        // Rules that don't exist are reported by the validator
+                        extra_rules.push(extra_rule.to_string());
                     } else {
-                        bail!(ParsingErr::InvalidType {
-                            wrong_type: "extra_rules",
-                            missing_in: format!("materials/{}", name),
+                        report.error(anyhow!(ParsingErr::InvalidType {
+                            wrong_type: "extra_rules".to_string(),
+                            missing_in: format!("materials/{}/extra_rules/{}", name, rule_idx),
                             expected: TYPE_HINT_STRING
-                        });
+                        }));
                     }
                 }
             } else {
-                bail!(ParsingErr::InvalidType {
-                    wrong_type: "extra_rules",
-                    missing_in: format!("materials/{}", name),
+                report.error(anyhow!(ParsingErr::InvalidType {
+                    wrong_type: "extra_rules".to_string(),
+                    missing_in: format!("materials/{}/extra_rules", name),
                     expected: TYPE_HINT_SEQUENCE,
-                });
+                }));
             }
         }
+
+        // The material is incomplete, continue with the next one to find the remaining errors
+        if report.error_count() > errors_before {
+            continue;
+        }
         
+        let id = match explicit_ids.iter().find(|(n, _)| *n == name) {
+            Some((_, id)) => *id,
+            None => {
+                let is_taken = |id: &usize| used_ids.contains(id) || explicit_ids.iter().any(|(_, other)| other == id);
+                match lock.id(&name).map(usize::from).filter(|id| !is_taken(id)) {
+                    Some(id) => id,
+                    // Ids pinned to materials that were removed are not reused, saved worlds might still contain them
+                    None => (BUILTIN_NAMES.len()..)
+                        .find(|id| !is_taken(id) && lock.name(*id as u16).is_none())
+                        .unwrap_or_default(),
+                }
+            },
+        };
+        used_ids.push(id);
+
         let mat = SandMaterial {
-            id: idx,
+            id,
             name,
             mattype,
             color,
             emission,
             selectable,
             density,
-            extra_rules
+            extra_rules,
+            fields: field_values,
+            thermal,
+            symbol,
         };
         material_structs.push(mat.clone());
         glsl_structs.push(Box::new(mat));
-
-        idx += 1;
     }
 
     
-    Ok((material_structs, glsl_structs))
-}
\ No newline at end of file
+    (material_structs, glsl_structs)
+}
+
+
+/// Table to convert old material ids (given by the name and id of the old materials) into the ids of
+/// the materials: `table[old_id]` is the new id. Materials that were removed become EMPTY, unknown ids NULL
+pub fn remap_names<'a>(materials: &[SandMaterial], old: impl IntoIterator<Item = (&'a str, u16)>) -> Vec<u16> {
+    let id_of = |name: &str| materials.iter().find(|m| m.name == name).map(|m| m.id as u16);
+    let empty = id_of("EMPTY").unwrap_or_default();
+    let null = id_of("NULL").unwrap_or_default();
+    let mut table = vec![];
+    for (name, id) in old {
+        if table.len() <= id as usize {
+            table.resize(id as usize + 1, null);
+        }
+        table[id as usize] = match name {
+            "NULL" => null,
+            name => id_of(name).unwrap_or(empty),
+        };
+    }
+    table
+}
+
+
+/// Extracts the explicit 'id' keys of all materials (name, id). An id can only be used by one material
+/// and can't be pinned to another material by the lock (that would change the material of saved cells)
+fn parse_ids(materials: &Mapping, lock: &MaterialLock, report: &mut ParseReport) -> Vec<(String, usize)> {
+    let mut ids: Vec<(String, usize)> = vec![];
+    for (name, mat) in materials {
+        let (Some(name), Some(id)) = (name.as_str(), mat.get("id")) else {
+            continue;
+        };
+        let path = format!("materials/{}/id", name);
+        let Some(id) = id.as_u64().filter(|id| *id >= BUILTIN_NAMES.len() as u64 && *id <= u16::MAX as u64) else {
+            report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: "id".to_string(),
+                missing_in: path,
+                expected: TYPE_HINT_ID
+            }));
+            continue;
+        };
+        let other = ids.iter()
+            .find(|(_, other)| *other as u64 == id)
+            .map(|(other, _)| format!("materials/{}/id", other))
+            .or(lock.name(id as u16).filter(|other| *other != name).map(|other| format!("materials.lock (pinned to '{}')", other)));
+        if let Some(other) = other {
+            report.error(anyhow!(ParsingErr::IdCollision {
+                id: id.to_string(),
+                first: other,
+                second: path
+            }));
+            continue;
+        }
+        ids.push((name.to_string(), id as usize));
+    }
+    ids
+}
+
+
+/// Extracts the symbol of a material, a single character which no other material uses
+fn parse_symbol(mat: &Value, name: &str, materials: &[SandMaterial], report: &mut ParseReport) -> Option<char> {
+    let path = format!("materials/{}/symbol", name);
+    let symbol = mat.get("symbol")?;
+    let mut chars = symbol.as_str().unwrap_or_default().chars();
+    let symbol = match (chars.next(), chars.next()) {
+        (Some(symbol), None) if !symbol.is_whitespace() => symbol,
+        _ => {
+            report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: "symbol".to_string(),
+                missing_in: path,
+                expected: TYPE_HINT_SYMBOL
+            }));
+            return None;
+        },
+    };
+    if let Some(other) = materials.iter().find(|m| m.symbol == Some(symbol)) {
+        report.error(anyhow!(ParsingErr::IdCollision {
+            id: symbol.to_string(),
+            first: format!("materials/{}/symbol", other.name),
+            second: path
+        }));
+        return None;
+    }
+    Some(symbol)
+}
+
+
+/// Extracts the type of a material and checks that it exists
+fn parse_mattype(mat: &Value, name: &str, type_names: &[String]) -> anyhow::Result<String> {
+    let mattype = mat.get("type")
+        .ok_or(anyhow!(ParsingErr::MissingField {
+            field_name: "type".to_string(),
+            missing_in: format!("materials/{}", name)
+        }))?;
+    let mattype = mattype.as_str()
+        .ok_or(anyhow!(ParsingErr::InvalidType {
+            wrong_type: "type".to_string(),
+            missing_in: format!("materials/{}/type", name),
+            expected: TYPE_HINT_STRING
+        }))?
+        .to_string();
+    if !type_names.contains(&mattype) {
+        bail!(ParsingErr::NotFound {
+            missing: mattype,
+            missing_in: format!("materials/{}/type", name)
+        });
+    }
+    Ok(mattype)
+}
@@ -0,0 +1,272 @@
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}};
+
+use anyhow::anyhow;
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+
+use super::{ParsingErr, SpannedErr, TYPE_HINT_MAPPING, TYPE_HINT_SEQUENCE, TYPE_HINT_STRING};
+use super::report::{Diagnostic, ParseReport, Related};
+use super::source::SourceFile;
+
+
+
+/// A single YAML file of a (possibly multi-file) set of rules, types and materials
+pub struct PackFile {
+    pub source: SourceFile,
+    pub data: Value,
+    /// Namespace of the file, used for `pack::name` (see `Namespaces`). Either the 'pack' key or the file name
+    pub pack: String,
+}
+
+
+/// Parses the root file and (recursively) all files it imports via `imports: [...]`.
+/// Imports are resolved relative to the importing file and are placed before it,
+/// every file is only loaded once
+pub fn load_files(root: SourceFile, report: &mut ParseReport) -> Vec<PackFile> {
+    let mut files = vec![];
+    let mut loaded = vec![];
+    load_file(root, report, &mut files, &mut loaded);
+    files
+}
+
+
+fn load_file(source: SourceFile, report: &mut ParseReport, files: &mut Vec<PackFile>, loaded: &mut Vec<PathBuf>) {
+    loaded.push(canonical_path(&source.name));
+    report.set_current_file(Some(source.name.clone()));
+
+    // Convert the string into a serde_yaml object
+    let data: Result<Value, serde_yaml::Error> = serde_yaml::from_str(&source.src);
+    let Some(data) = report.check(data.map_err(anyhow::Error::from)) else {
+        report.add_source(source);
+        return;
+    };
+
+    let pack = match data.get("pack") {
+        Some(pack) => {
+            let pack = pack.as_str().ok_or(anyhow!(ParsingErr::InvalidType {
+                wrong_type: "pack".to_string(),
+                missing_in: "pack".to_string(),
+                expected: TYPE_HINT_STRING
+            }));
+            report.check(pack).unwrap_or_default().to_string()
+        },
+        None => Path::new(&source.name).file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    };
+
+    // Load the imported files first, so their definitions come before ours
+    let mut imported = vec![];
+    if let Some(imports) = data.get("imports") {
+        match imports.as_sequence() {
+            Some(imports) => {
+                let dir = Path::new(&source.name).parent().unwrap_or(Path::new(""));
+                for (idx, import) in imports.iter().enumerate() {
+                    let path = format!("imports/{}", idx);
+                    let Some(import) = import.as_str() else {
+                        report.error(anyhow!(ParsingErr::InvalidType {
+                            wrong_type: "imports".to_string(),
+                            missing_in: path,
+                            expected: TYPE_HINT_STRING
+                        }));
+                        continue;
+                    };
+                    let import_path = dir.join(import);
+                    if loaded.contains(&canonical_path(&import_path.display().to_string())) {
+                        continue;
+                    }
+                    match std::fs::read_to_string(&import_path) {
+                        Ok(src) => imported.push(SourceFile::new(&import_path.display().to_string(), &src)),
+                        Err(err) => report.error(anyhow!(ParsingErr::ImportFailed {
+                            import: import.to_string(),
+                            reason: err.to_string(),
+                            missing_in: path
+                        })),
+                    }
+                }
+            },
+            None => report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: "imports".to_string(),
+                missing_in: "imports".to_string(),
+                expected: TYPE_HINT_SEQUENCE
+            })),
+        }
+    }
+
+    for import in imported {
+        // The file might have been loaded by one of the other imports in the meantime
+        if loaded.contains(&canonical_path(&import.name)) {
+            continue;
+        }
+        load_file(import, report, files, loaded);
+    }
+    report.set_current_file(Some(source.name.clone()));
+
+    files.push(PackFile {
+        source,
+        data,
+        pack,
+    });
+}
+
+
+fn canonical_path(name: &str) -> PathBuf {
+    std::fs::canonicalize(name).unwrap_or_else(|_| PathBuf::from(name))
+}
+
+
+/// Combines a section (like 'materials') of all files into a single Mapping.
+/// Records in `origins` which file each item (like `materials/sand`) comes from.
+/// Returns None, if none of the files contain the section
+pub fn merge_section(files: &[PackFile], section: &str, origins: &mut HashMap<String, String>, report: &mut ParseReport) -> Option<Mapping> {
+    let mut merged: Option<Mapping> = None;
+    for file in files {
+        let Some(value) = file.data.get(section) else {
+            continue;
+        };
+        report.set_current_file(Some(file.source.name.clone()));
+        // The section exists, even if it is empty or invalid
+        let merged = merged.get_or_insert_with(Mapping::new);
+        if value.is_null() {
+            continue;
+        }
+        let Some(map) = value.as_mapping() else {
+            report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: section.to_string(),
+                missing_in: "Root/ Base level of YAML file".to_string(),
+                expected: TYPE_HINT_MAPPING
+            }));
+            continue;
+        };
+
+        for (key, item) in map {
+            if let Some(name) = key.as_str() {
+                let item_path = format!("{}/{}", section, name);
+                if let Some(other_file) = origins.get(&item_path) {
+                    let mut diagnostic: Diagnostic = anyhow!(ParsingErr::AlreadyDefined {
+                        name: name.to_string(),
+                        other_file: other_file.clone(),
+                        missing_in: item_path.clone()
+                    }).into();
+                    diagnostic.related.push(Related {
+                        message: format!("'{}' is first defined here", name),
+                        file: other_file.clone(),
+                        path: item_path,
+                    });
+                    report.push(diagnostic);
+                    continue;
+                }
+                origins.insert(item_path, file.source.name.clone());
+            }
+            merged.insert(key.clone(), item.clone());
+        }
+    }
+    merged
+}
+
+
+/// Sections whose items can be referenced by name (and namespaced)
+const NAMED_SECTIONS: [&str; 3] = ["rules", "types", "materials"];
+/// Sections whose strings can reference the named items
+const REFERENCING_SECTIONS: [&str; 5] = ["rules", "types", "materials", "reactions", "palette"];
+
+
+/// Resolves `pack::name` to the plain name, after checking that the pack defines it.
+/// Every name can only be defined once across all files (see `merge_section`), so the
+/// namespace only documents where a name comes from
+pub struct Namespaces {
+    /// The names each pack defines (in any of the named sections)
+    packs: HashMap<String, HashSet<String>>,
+    /// Matches namespaced names (`wet::water`)
+    name_regex: Regex,
+}
+impl Namespaces {
+    pub fn new(files: &[PackFile]) -> Self {
+        let mut packs: HashMap<String, HashSet<String>> = HashMap::new();
+        for file in files {
+            let names = packs.entry(file.pack.clone()).or_default();
+            for section in NAMED_SECTIONS {
+                if let Some(map) = file.data.get(section).and_then(|s| s.as_mapping()) {
+                    names.extend(map.keys().filter_map(|k| k.as_str()).map(String::from));
+                }
+            }
+        }
+        Self {
+            packs,
+            name_regex: Regex::new(r"([A-Za-z_]\w*)::([A-Za-z_]\w*)").unwrap(),
+        }
+    }
+
+    fn defines(&self, pack: &str, name: &str) -> bool {
+        // Checker functions can be namespaced as well: 'pack::isType_liquid'
+        let type_name = name.strip_prefix("isType_");
+        self.packs.get(pack).is_some_and(|names| names.contains(name) || type_name.is_some_and(|t| names.contains(t)))
+    }
+
+    /// Replaces the namespaced names inside of all strings of the file with the plain names
+    pub fn resolve_file(&self, file: &mut PackFile, report: &mut ParseReport) {
+        report.set_current_file(Some(file.source.name.clone()));
+        for section in REFERENCING_SECTIONS {
+            let Some(map) = file.data.get_mut(section).and_then(|s| s.as_mapping_mut()) else {
+                continue;
+            };
+            for (key, item) in map.iter_mut() {
+                let path = format!("{}/{}", section, key.as_str().unwrap_or_default());
+                self.resolve_value(item, &path, report);
+            }
+        }
+    }
+
+    fn resolve_value(&self, value: &mut Value, path: &str, report: &mut ParseReport) {
+        match value {
+            Value::String(text) => {
+                let mut resolved = String::new();
+                let mut rewrites = vec![];
+                let mut last = 0;
+                for captures in self.name_regex.captures_iter(text) {
+                    let whole = captures.get(0).unwrap();
+                    let name = &captures[2];
+                    if !self.defines(&captures[1], name) {
+                        report.error(anyhow!(SpannedErr {
+                            err: ParsingErr::NotFound {
+                                missing: whole.as_str().to_string(),
+                                missing_in: path.to_string()
+                            },
+                            span: whole.range()
+                        }));
+                    }
+                    resolved.push_str(&text[last..whole.start()]);
+                    rewrites.push((resolved.len()..resolved.len() + name.len(), whole.len()));
+                    resolved.push_str(name);
+                    last = whole.end();
+                }
+                if !rewrites.is_empty() {
+                    resolved.push_str(&text[last..]);
+                    *text = resolved;
+                    report.add_rewrites(path, rewrites);
+                }
+            },
+            Value::Sequence(seq) => {
+                for (idx, item) in seq.iter_mut().enumerate() {
+                    self.resolve_value(item, &format!("{}/{}", path, idx), report);
+                }
+            },
+            Value::Mapping(map) => {
+                for (key, item) in map.iter_mut() {
+                    let key = key.as_str().map(String::from).unwrap_or_default();
+                    self.resolve_value(item, &format!("{}/{}", path, key), report);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+
+/// Resolves the namespaces of all files (see `Namespaces`), before their sections are merged
+pub fn resolve_namespaces(files: &mut [PackFile], report: &mut ParseReport) {
+    let namespaces = Namespaces::new(files);
+    for file in files.iter_mut() {
+        namespaces.resolve_file(file, report);
+    }
+}
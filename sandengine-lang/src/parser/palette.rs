@@ -0,0 +1,88 @@
+use anyhow::anyhow;
+use serde_yaml::Mapping;
+
+use crate::parser::{ParsingErr, TYPE_HINT_STRING, yaml_repr};
+
+use super::materials::SandMaterial;
+use super::report::ParseReport;
+
+
+
+const TYPE_HINT_HEX_COLOR: &str = "hex color in quotes (e.g. \"#ff8800\")";
+
+
+/// A color of the images that worlds are imported from, and the material it stands for
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaletteColor {
+    /// RGB, the alpha of the pixels is ignored
+    pub color: [u8; 3],
+    /// Name of the material
+    pub material: String,
+}
+
+
+/// Parses the optional 'palette' section, which maps the colors of world images to materials.
+/// Without a palette (or for colors that aren't in it), pixels become the material with the closest color:
+///
+/// ```yaml
+/// palette:
+///     "#ffff00": sand
+///     "#0000ff": water
+///     "#000000": WALL
+/// ```
+pub fn parse_palette(palette: &Mapping, materials: &[SandMaterial], report: &mut ParseReport) -> Vec<PaletteColor> {
+    let mut colors: Vec<PaletteColor> = vec![];
+
+    for (key, material) in palette {
+        let Some((hex, color)) = key.as_str().and_then(|hex| Some((hex, parse_hex_color(hex)?))) else {
+            report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: yaml_repr(key),
+                missing_in: "palette".to_string(),
+                expected: TYPE_HINT_HEX_COLOR
+            }));
+            continue;
+        };
+        let path = format!("palette/{}", hex);
+        let Some(material) = material.as_str() else {
+            report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: hex.to_string(),
+                missing_in: path,
+                expected: TYPE_HINT_STRING
+            }));
+            continue;
+        };
+        if !materials.iter().any(|m| m.name == material) {
+            report.error(anyhow!(ParsingErr::NotFound {
+                missing: material.to_string(),
+                missing_in: path
+            }));
+            continue;
+        }
+        // The same color can be written in different ways ('#FF0000' and '#ff0000')
+        if let Some(other) = colors.iter().find(|c| c.color == color) {
+            report.error(anyhow!(ParsingErr::AlreadyDefined {
+                name: hex.to_string(),
+                other_file: format!("palette (color of '{}')", other.material),
+                missing_in: path
+            }));
+            continue;
+        }
+        colors.push(PaletteColor {
+            color,
+            material: material.to_string(),
+        });
+    }
+
+    colors
+}
+
+
+/// Parses a color like '#ff8800' (the '#' is optional)
+fn parse_hex_color(hex: &str) -> Option<[u8; 3]> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |idx: usize| u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).ok();
+    Some([channel(0)?, channel(1)?, channel(2)?])
+}
@@ -0,0 +1,288 @@
+use anyhow::anyhow;
+use serde_yaml::{Mapping, Value};
+
+use crate::{GLSLConvertible, parser::{ParsingErr, DEFAULT_VAL_PROBABILITY, TYPE_HINT_FLOAT, TYPE_HINT_STRING, yaml_repr}};
+
+use super::expression;
+use super::report::ParseReport;
+use super::rules::{Action, SandRule, SandRuleType};
+
+
+
+const TYPE_HINT_PAIR: &str = "sequence (array, '[...]') of 2 material names";
+const TYPE_HINT_ADJACENCY: &str = "'any', 'horizontal', 'vertical', 'diagonal', 'above', 'below' or a sequence of them";
+
+
+/// How the two reactants need to be positioned relative to each other
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Adjacency {
+    /// Next to each other, in any order
+    Horizontal,
+    /// Above each other, in any order
+    Vertical,
+    /// Diagonally next to each other, in any order
+    Diagonal,
+    /// The first reactant is above the second one
+    Above,
+    /// The first reactant is below the second one
+    Below,
+}
+impl Adjacency {
+    const ANY: [Adjacency; 3] = [Adjacency::Horizontal, Adjacency::Vertical, Adjacency::Diagonal];
+
+    fn from_name(name: &str) -> Option<Vec<Adjacency>> {
+        match name {
+            "any" => Some(Adjacency::ANY.to_vec()),
+            "horizontal" => Some(vec![Adjacency::Horizontal]),
+            "vertical" => Some(vec![Adjacency::Vertical]),
+            "diagonal" => Some(vec![Adjacency::Diagonal]),
+            "above" => Some(vec![Adjacency::Above]),
+            "below" => Some(vec![Adjacency::Below]),
+            _ => None,
+        }
+    }
+
+    /// Cells of the (mirrored) 2x2 block, at which the first and second reactant can be
+    fn pairings(&self) -> Vec<(&'static str, &'static str)> {
+        let (pairs, both_orders) = match self {
+            Adjacency::Horizontal => (vec![("SELF", "RIGHT"), ("DOWN", "DOWNRIGHT")], true),
+            Adjacency::Vertical => (vec![("SELF", "DOWN"), ("RIGHT", "DOWNRIGHT")], true),
+            Adjacency::Diagonal => (vec![("SELF", "DOWNRIGHT"), ("RIGHT", "DOWN")], true),
+            Adjacency::Above => (vec![("SELF", "DOWN"), ("RIGHT", "DOWNRIGHT")], false),
+            Adjacency::Below => (vec![("DOWN", "SELF"), ("DOWNRIGHT", "RIGHT")], false),
+        };
+        match both_orders {
+            true => pairs.iter().copied().chain(pairs.iter().map(|(a, b)| (*b, *a))).collect(),
+            false => pairs,
+        }
+    }
+}
+
+
+/// Holds information about a reaction defined in the YAML file.
+/// Each reaction is turned into a rule, which checks all pairings of cells of the block
+#[derive(Debug, Clone)]
+pub struct SandReaction {
+    /// Name of the reaction (Mapping key)
+    pub name: String,
+    /// The two materials that react with each other
+    pub reactants: [String; 2],
+    /// The materials the reactants are turned into (in the same order)
+    pub products: [String; 2],
+    /// The probability that two adjacent reactants react in a frame
+    pub probability: f32,
+    /// How the reactants need to be positioned
+    pub adjacency: Vec<Adjacency>,
+}
+impl SandReaction {
+    /// Name of the rule generated for the reaction
+    pub fn rule_name(&self) -> String {
+        format!("reaction_{}", self.name)
+    }
+
+    /// Creates the rule that checks each pairing of cells (as one if-else level each)
+    fn to_rule(&self, type_names: &Vec<String>, material_names: &Vec<String>) -> anyhow::Result<SandRule> {
+        let mut pairings: Vec<(&str, &str)> = vec![];
+        for pairing in self.adjacency.iter().flat_map(|a| a.pairings()) {
+            // Identical reactants produce the same pairing for both orders
+            let duplicate = pairings.contains(&pairing)
+                || (self.reactants[0] == self.reactants[1] && self.products[0] == self.products[1] && pairings.contains(&(pairing.1, pairing.0)));
+            if !duplicate {
+                pairings.push(pairing);
+            }
+        }
+
+        let path = format!("reactions/{}", self.name);
+        let mut if_conds = vec![];
+        let mut do_actions = vec![];
+        for (first, second) in pairings.iter() {
+            let cond = format!("{}.mat == {} and {}.mat == {}", first, self.reactants[0], second, self.reactants[1]);
+            if_conds.push(expression::parse_condition(&cond, &path, type_names, material_names, &vec![])?);
+            // Reactants that stay the same keep their fields
+            let actions = [(first, 0), (second, 1)].into_iter()
+                .filter(|(_, idx)| self.products[*idx] != self.reactants[*idx])
+                .map(|(cell, idx)| Action::Set(cell.to_string(), self.products[idx].clone()))
+                .collect();
+            do_actions.push(actions);
+        }
+
+        Ok(SandRule {
+            name: self.rule_name(),
+            path,
+            ruletype: SandRuleType::Mirrored,
+            probabilities: vec![self.probability; if_conds.len()],
+            if_conds,
+            do_actions,
+            mirror: true,
+            // Reactions don't depend on the type of SELF
+            precondition: None,
+            used: true,
+        })
+    }
+}
+
+
+/// Parses the optional 'reactions' section and creates a rule for every reaction:
+///
+/// ```yaml
+/// reactions:
+///     extinguish:
+///         reactants: [water, lava]
+///         products: [steam, rock]
+///         probability: 0.2
+///         adjacency: any
+/// ```
+pub fn parse_reactions(
+    reactions: &Mapping,
+    rule_names: &[String],
+    type_names: &Vec<String>,
+    material_names: &Vec<String>,
+    report: &mut ParseReport
+) -> (Vec<SandReaction>, Vec<SandRule>, Vec<Box<dyn GLSLConvertible>>) {
+    let mut reaction_structs: Vec<SandReaction> = vec![];
+    let mut rule_structs: Vec<SandRule> = vec![];
+    let mut glsl_structs: Vec<Box<dyn GLSLConvertible>> = vec![];
+
+    for (key, reaction) in reactions {
+        let name = key.as_str()
+            .ok_or(anyhow!(ParsingErr::InvalidType {
+                wrong_type: yaml_repr(key),
+                missing_in: "reactions".to_string(),
+                expected: TYPE_HINT_STRING
+            }));
+        let Some(name) = report.check(name) else {
+            continue;
+        };
+        let path = format!("reactions/{}", name);
+        let errors_before = report.error_count();
+
+        let reactants = parse_pair(reaction, "reactants", &path, material_names, report);
+        let products = parse_pair(reaction, "products", &path, material_names, report);
+
+        let probability = match reaction.get("probability") {
+            Some(prob) => match prob.as_f64() {
+                Some(prob) => prob as f32,
+                None => {
+                    report.error(anyhow!(ParsingErr::InvalidType {
+                        wrong_type: "probability".to_string(),
+                        missing_in: format!("{}/probability", path),
+                        expected: TYPE_HINT_FLOAT
+                    }));
+                    DEFAULT_VAL_PROBABILITY
+                },
+            },
+            None => DEFAULT_VAL_PROBABILITY,
+        };
+
+        let adjacency = parse_adjacency(reaction.get("adjacency"), &path, report);
+
+        let rule_name = format!("reaction_{}", name);
+        if rule_names.contains(&rule_name) {
+            report.error(anyhow!(ParsingErr::AlreadyDefined {
+                name: rule_name,
+                other_file: "rules".to_string(),
+                missing_in: path.clone()
+            }));
+        }
+
+        // The reaction is incomplete, continue with the next one to find the remaining errors
+        if report.error_count() > errors_before {
+            continue;
+        }
+        let (Some(reactants), Some(products)) = (reactants, products) else {
+            continue;
+        };
+
+        let reaction = SandReaction {
+            name: name.to_string(),
+            reactants,
+            products,
+            probability,
+            adjacency,
+        };
+        let Some(rule) = report.check(reaction.to_rule(type_names, material_names)) else {
+            continue;
+        };
+        rule_structs.push(rule.clone());
+        glsl_structs.push(Box::new(rule));
+        reaction_structs.push(reaction);
+    }
+
+    (reaction_structs, rule_structs, glsl_structs)
+}
+
+
+/// Parses the 'reactants' or 'products', which need to be exactly two materials
+fn parse_pair(reaction: &Value, key: &str, path: &str, material_names: &[String], report: &mut ParseReport) -> Option<[String; 2]> {
+    let Some(pair) = reaction.get(key) else {
+        report.error(anyhow!(ParsingErr::MissingField {
+            field_name: key.to_string(),
+            missing_in: path.to_string()
+        }));
+        return None;
+    };
+    let names = pair.as_sequence()
+        .filter(|seq| seq.len() == 2)
+        .map(|seq| seq.iter().map(|n| match n {
+            // YAML reads an unquoted NULL as null
+            Value::Null => Some("NULL".to_string()),
+            _ => n.as_str().map(String::from),
+        }).collect::<Option<Vec<String>>>());
+    let Some(Some(names)) = names else {
+        report.error(anyhow!(ParsingErr::InvalidType {
+            wrong_type: key.to_string(),
+            missing_in: format!("{}/{}", path, key),
+            expected: TYPE_HINT_PAIR
+        }));
+        return None;
+    };
+
+    let mut valid = true;
+    for (idx, name) in names.iter().enumerate() {
+        if !material_names.contains(name) {
+            report.error(anyhow!(ParsingErr::NotFound {
+                missing: name.clone(),
+                missing_in: format!("{}/{}/{}", path, key, idx)
+            }));
+            valid = false;
+        }
+    }
+    match valid {
+        true => Some([names[0].clone(), names[1].clone()]),
+        false => None,
+    }
+}
+
+
+/// Parses the 'adjacency', which is either a single name or a list of names. Defaults to 'any'
+fn parse_adjacency(adjacency: Option<&Value>, path: &str, report: &mut ParseReport) -> Vec<Adjacency> {
+    let path = format!("{}/adjacency", path);
+    let names = match adjacency {
+        None => return Adjacency::ANY.to_vec(),
+        Some(Value::String(name)) => vec![(name.as_str(), path.clone())],
+        Some(Value::Sequence(seq)) => seq.iter()
+            .enumerate()
+            .map(|(idx, name)| (name.as_str().unwrap_or_default(), format!("{}/{}", path, idx)))
+            .collect(),
+        Some(_) => vec![("", path.clone())],
+    };
+
+    let mut adjacency = vec![];
+    for (name, path) in names {
+        match Adjacency::from_name(name) {
+            Some(adj) => {
+                for a in adj {
+                    if !adjacency.contains(&a) {
+                        adjacency.push(a);
+                    }
+                }
+            },
+            None => report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: "adjacency".to_string(),
+                missing_in: path,
+                expected: TYPE_HINT_ADJACENCY
+            })),
+        }
+    }
+    adjacency
+}
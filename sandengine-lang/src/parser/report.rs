@@ -0,0 +1,259 @@
+use std::{collections::HashMap, fmt, ops::Range};
+
+use colored::Colorize;
+
+use super::{ParsingErr, SpannedErr, source::{Location, SourceFile}};
+
+
+
+/// How severe a diagnostic is. Any error prevents the ParsingResult from being produced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+
+/// A single error or warning found while parsing
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Stable code of the validator check that produced the diagnostic (like `V001`)
+    pub code: Option<&'static str>,
+    /// The (colored) message that is displayed to the user
+    pub message: String,
+    /// Name of the file the diagnostic belongs to
+    pub file: Option<String>,
+    /// Path of the YAML node (like `rules/grow/if`)
+    pub path: Option<String>,
+    /// Byte range inside of the string value of the node
+    pub span: Option<Range<usize>>,
+    /// Exact location in the file, used when there is no path (e.g. YAML syntax errors)
+    pub location: Option<Location>,
+    /// Other locations that are part of the problem (e.g. the first definition of a duplicate name)
+    pub related: Vec<Related>,
+}
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+impl From<anyhow::Error> for Diagnostic {
+    fn from(err: anyhow::Error) -> Self {
+        let mut diagnostic = Diagnostic {
+            severity: Severity::Error,
+            code: None,
+            message: err.to_string(),
+            file: None,
+            path: None,
+            span: None,
+            location: None,
+            related: vec![],
+        };
+        if let Some(e) = err.downcast_ref::<SpannedErr>() {
+            diagnostic.path = Some(e.err.path().to_string());
+            diagnostic.span = Some(e.span.clone());
+        } else if let Some(e) = err.downcast_ref::<ParsingErr>() {
+            diagnostic.path = Some(e.path().to_string());
+        } else if let Some(e) = err.downcast_ref::<serde_yaml::Error>() {
+            diagnostic.location = e.location().map(|l| Location { line: l.line(), col: l.column() });
+        }
+        diagnostic
+    }
+}
+
+
+/// Additional location of a diagnostic, displayed as a note
+#[derive(Debug, Clone)]
+pub struct Related {
+    pub message: String,
+    pub file: String,
+    /// Path of the YAML node (like `materials/sand`)
+    pub path: String,
+}
+
+
+/// Where the items and strings of the merged files come from, so that diagnostics point at the source
+#[derive(Default)]
+struct SourceMap {
+    /// The file each item (like `rules/grow`) was defined in, see `assign_files`.
+    /// Everything else belongs to the root file, stored as the empty item
+    origins: HashMap<String, String>,
+    /// Strings whose names were resolved, by their path: the ranges of the resolved names and
+    /// the length of the text they replace. Spans inside of these strings are moved back onto the source
+    rewrites: HashMap<String, Vec<(Range<usize>, usize)>>,
+}
+
+
+/// All errors and warnings that were found while parsing, together with
+/// the sources they refer to (needed to display them)
+#[derive(Default)]
+pub struct ParseReport {
+    pub diagnostics: Vec<Diagnostic>,
+    sources: Vec<SourceFile>,
+    /// File that new diagnostics are assigned to
+    current_file: Option<String>,
+    /// Boxed, so that the report stays small enough to be returned as an error
+    source_map: Box<SourceMap>,
+}
+impl ParseReport {
+    pub fn errors(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics.iter().filter(|d| d.is_error())
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics.iter().filter(|d| !d.is_error())
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.errors().next().is_some()
+    }
+
+    /// Records an error and continues parsing
+    pub(crate) fn error(&mut self, err: anyhow::Error) {
+        self.push(err.into());
+    }
+
+    /// Records a diagnostic, assigning it to the current file
+    pub(crate) fn push(&mut self, mut diagnostic: Diagnostic) {
+        if diagnostic.file.is_none() {
+            diagnostic.file = self.current_file.clone();
+        }
+        if let (Some(path), Some(span)) = (&diagnostic.path, &mut diagnostic.span) {
+            if let Some(rewrites) = self.source_map.rewrites.get(path) {
+                *span = original_pos(span.start, rewrites, false)..original_pos(span.end, rewrites, true);
+            }
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    /// Records that names inside of the string at the path were replaced, see `rewrites`
+    pub(crate) fn add_rewrites(&mut self, path: &str, rewrites: Vec<(Range<usize>, usize)>) {
+        self.source_map.rewrites.insert(path.to_string(), rewrites);
+    }
+
+    /// Sets the file that the following diagnostics belong to. With None, the file
+    /// is assigned later on, using the path of the diagnostic (see `assign_files`)
+    pub(crate) fn set_current_file(&mut self, file: Option<String>) {
+        self.current_file = file;
+    }
+
+    /// Returns the value of a parsing step, or records its error and returns None
+    pub(crate) fn check<T>(&mut self, res: anyhow::Result<T>) -> Option<T> {
+        match res {
+            Ok(val) => Some(val),
+            Err(err) => {
+                self.error(err);
+                None
+            }
+        }
+    }
+
+    /// Number of errors recorded so far, used to check whether a single item failed to parse
+    pub(crate) fn error_count(&self) -> usize {
+        self.errors().count()
+    }
+
+    /// Names of all files that were read (the root file and its imports), as their paths
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.sources.iter().map(|s| s.name.as_str())
+    }
+
+    /// Adds a source file, which is needed to display the diagnostics inside of it
+    pub(crate) fn add_source(&mut self, source: SourceFile) {
+        self.sources.push(source);
+    }
+
+    /// Assigns all diagnostics without a file to the file that defined the item their
+    /// path starts with. `origins` maps a section and name (like `rules/grow`) to a file
+    pub(crate) fn assign_files(&mut self, origins: &HashMap<String, String>, default_file: &str) {
+        self.source_map.origins = origins.clone();
+        self.source_map.origins.insert(String::new(), default_file.to_string());
+        for idx in 0..self.diagnostics.len() {
+            if self.diagnostics[idx].file.is_none() {
+                let file = self.file_of(self.diagnostics[idx].path.as_deref().unwrap_or_default());
+                self.diagnostics[idx].file = file.map(String::from);
+            }
+        }
+    }
+
+    /// Name of the file that defined the item the path (like `rules/grow/if`) starts with.
+    /// Only known after parsing, used to point diagnostics found later on at the right file
+    pub fn file_of(&self, path: &str) -> Option<&str> {
+        let item = path.splitn(3, '/').take(2).collect::<Vec<&str>>().join("/");
+        self.source_map.origins.get(&item).or(self.source_map.origins.get("")).map(|f| f.as_str())
+    }
+
+    /// Formats a diagnostic together with the snippet of the source it points at
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let label = match diagnostic.code {
+            Some(code) => format!("{}[{}]:", diagnostic.severity, code),
+            None => format!("{}:", diagnostic.severity),
+        };
+        let label = match diagnostic.severity {
+            Severity::Error => label.red().bold(),
+            Severity::Warning => label.yellow().bold(),
+        };
+        let message = format!("{} {}", label, diagnostic.message);
+        let mut rendered = match self.source(diagnostic.file.as_deref()) {
+            Some(source) => source.render(&message, diagnostic.path.as_deref(), diagnostic.span.as_ref(), diagnostic.location),
+            None => message,
+        };
+        for related in diagnostic.related.iter() {
+            let message = format!("{} {}", "note:".blue().bold(), related.message);
+            let note = match self.source(Some(&related.file)) {
+                Some(source) => source.render(&message, Some(&related.path), None, None),
+                None => message,
+            };
+            rendered.push('\n');
+            rendered.push_str(&note);
+        }
+        rendered
+    }
+
+    fn source(&self, name: Option<&str>) -> Option<&SourceFile> {
+        self.sources.iter().find(|s| Some(s.name.as_str()) == name)
+    }
+}
+impl fmt::Display for ParseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for diagnostic in self.diagnostics.iter() {
+            writeln!(f, "{}\n", self.render(diagnostic))?;
+        }
+        let num_errors = self.error_count();
+        let num_warnings = self.diagnostics.len() - num_errors;
+        write!(f, "{} error(s), {} warning(s)", num_errors, num_warnings)
+    }
+}
+impl fmt::Debug for ParseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParseReport")
+            .field("diagnostics", &self.diagnostics)
+            .finish()
+    }
+}
+impl std::error::Error for ParseReport {}
+
+
+/// Moves a position inside of a resolved string back onto the source. Positions inside of
+/// a replaced name move to its start (or end)
+fn original_pos(pos: usize, rewrites: &[(Range<usize>, usize)], end: bool) -> usize {
+    let mut offset = 0isize;
+    for (range, len) in rewrites {
+        if pos >= range.end {
+            offset += *len as isize - range.len() as isize;
+        } else if pos > range.start {
+            let start = (range.start as isize + offset) as usize;
+            return if end { start + len } else { start };
+        }
+    }
+    (pos as isize + offset) as usize
+}
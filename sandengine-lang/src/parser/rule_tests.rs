@@ -0,0 +1,197 @@
+use anyhow::anyhow;
+use serde_yaml::{Mapping, Value};
+
+use crate::parser::{ParsingErr, TYPE_HINT_BOOL, TYPE_HINT_STRING, yaml_repr};
+
+use super::materials::SandMaterial;
+use super::report::ParseReport;
+
+
+
+const TYPE_HINT_GRID: &str = "string or sequence of strings, with one material symbol per cell and the same number of cells in every row";
+const TYPE_HINT_SAME_SIZE: &str = "grid of the same size as 'before'";
+const TYPE_HINT_COUNT: &str = "positive int (whole number)";
+const TYPE_HINT_SEED: &str = "int (whole number, 0 or larger)";
+
+const DEFAULT_VAL_STEPS: u32 = 1;
+const DEFAULT_VAL_SEED: u32 = 0;
+
+
+/// Expected behaviour of the rules: after simulating `steps` frames on the `before` grid,
+/// the result needs to match the `after` grid
+#[derive(Debug, Clone)]
+pub struct SandTest {
+    /// Name of the test (Mapping key)
+    pub name: String,
+    /// Material ids of the cells, row by row
+    pub before: Vec<Vec<u16>>,
+    /// Expected material ids of the cells, row by row
+    pub after: Vec<Vec<u16>>,
+    pub steps: u32,
+    /// Seed for the random values of the rules (see `Interpreter::new`)
+    pub seed: u32,
+    /// Forces all blocks to be mirrored (or not), instead of choosing randomly
+    pub mirror: Option<bool>,
+}
+
+
+/// Parses the optional 'tests' section. Grids use the `symbol` of the materials,
+/// EMPTY is '.' and WALL is '#':
+///
+/// ```yaml
+/// tests:
+///     sand_falls_through_water:
+///         before: |
+///             s
+///             w
+///         after: |
+///             w
+///             s
+///         steps: 4
+///         mirror: false
+/// ```
+pub fn parse_tests(tests: &Mapping, materials: &[SandMaterial], report: &mut ParseReport) -> Vec<SandTest> {
+    let mut test_structs = vec![];
+
+    for (key, test) in tests {
+        let name = key.as_str()
+            .ok_or(anyhow!(ParsingErr::InvalidType {
+                wrong_type: yaml_repr(key),
+                missing_in: "tests".to_string(),
+                expected: TYPE_HINT_STRING
+            }));
+        let Some(name) = report.check(name) else {
+            continue;
+        };
+        let path = format!("tests/{}", name);
+        let errors_before = report.error_count();
+
+        let before = parse_grid(test, "before", &path, materials, report);
+        let after = parse_grid(test, "after", &path, materials, report);
+        if let (Some(before), Some(after)) = (&before, &after) {
+            if before.len() != after.len() || before[0].len() != after[0].len() {
+                report.error(anyhow!(ParsingErr::InvalidType {
+                    wrong_type: "after".to_string(),
+                    missing_in: format!("{}/after", path),
+                    expected: TYPE_HINT_SAME_SIZE
+                }));
+            }
+        }
+
+        let steps = match test.get("steps") {
+            Some(steps) => match steps.as_u64().filter(|s| *s > 0) {
+                Some(steps) => steps as u32,
+                None => {
+                    report.error(anyhow!(ParsingErr::InvalidType {
+                        wrong_type: "steps".to_string(),
+                        missing_in: format!("{}/steps", path),
+                        expected: TYPE_HINT_COUNT
+                    }));
+                    DEFAULT_VAL_STEPS
+                },
+            },
+            None => DEFAULT_VAL_STEPS,
+        };
+
+        let seed = match test.get("seed") {
+            Some(seed) => match seed.as_u64() {
+                Some(seed) => seed as u32,
+                None => {
+                    report.error(anyhow!(ParsingErr::InvalidType {
+                        wrong_type: "seed".to_string(),
+                        missing_in: format!("{}/seed", path),
+                        expected: TYPE_HINT_SEED
+                    }));
+                    DEFAULT_VAL_SEED
+                },
+            },
+            None => DEFAULT_VAL_SEED,
+        };
+
+        let mirror = match test.get("mirror") {
+            Some(mirror) => {
+                if mirror.as_bool().is_none() {
+                    report.error(anyhow!(ParsingErr::InvalidType {
+                        wrong_type: "mirror".to_string(),
+                        missing_in: format!("{}/mirror", path),
+                        expected: TYPE_HINT_BOOL
+                    }));
+                }
+                mirror.as_bool()
+            },
+            None => None,
+        };
+
+        // The test is incomplete, continue with the next one to find the remaining errors
+        if report.error_count() > errors_before {
+            continue;
+        }
+        let (Some(before), Some(after)) = (before, after) else {
+            continue;
+        };
+        test_structs.push(SandTest {
+            name: name.to_string(),
+            before,
+            after,
+            steps,
+            seed,
+            mirror,
+        });
+    }
+
+    test_structs
+}
+
+
+/// Parses a grid of material symbols, either a (multi-line) string or a sequence of rows.
+/// Whitespace around the rows and empty rows are ignored
+fn parse_grid(test: &Value, key: &str, path: &str, materials: &[SandMaterial], report: &mut ParseReport) -> Option<Vec<Vec<u16>>> {
+    let Some(grid) = test.get(key) else {
+        report.error(anyhow!(ParsingErr::MissingField {
+            field_name: key.to_string(),
+            missing_in: path.to_string()
+        }));
+        return None;
+    };
+    let path = format!("{}/{}", path, key);
+    let rows: Option<Vec<&str>> = match grid {
+        Value::String(grid) => Some(grid.lines().collect()),
+        Value::Sequence(rows) => rows.iter().map(|r| r.as_str()).collect(),
+        _ => None,
+    };
+    let rows: Vec<&str> = rows.unwrap_or_default()
+        .into_iter()
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .collect();
+    let width = rows.first().map_or(0, |r| r.chars().count());
+    if width == 0 || rows.iter().any(|r| r.chars().count() != width) {
+        report.error(anyhow!(ParsingErr::InvalidType {
+            wrong_type: key.to_string(),
+            missing_in: path,
+            expected: TYPE_HINT_GRID
+        }));
+        return None;
+    }
+
+    let mut unknown: Vec<char> = vec![];
+    let mut cells = vec![];
+    for row in rows {
+        let mut ids = vec![];
+        for symbol in row.chars() {
+            match materials.iter().find(|m| m.symbol == Some(symbol)) {
+                Some(mat) => ids.push(mat.id as u16),
+                None if !unknown.contains(&symbol) => {
+                    report.error(anyhow!(ParsingErr::NotFound {
+                        missing: symbol.to_string(),
+                        missing_in: path.clone()
+                    }));
+                    unknown.push(symbol);
+                },
+                None => (),
+            }
+        }
+        cells.push(ids);
+    }
+    unknown.is_empty().then_some(cells)
+}
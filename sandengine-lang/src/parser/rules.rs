@@ -2,9 +2,12 @@ use anyhow::{anyhow, bail};
 use regex::Regex;
 use serde_yaml::{Mapping, Value};
 
-use crate::{GLSLConvertible, parser::{DEFAULT_VAL_PROBABILITY, TYPE_HINT_STRING, ParsingErr, TYPE_HINT_BOOL, TYPE_HINT_FLOAT}};
+use crate::{GLSLConvertible, shaders::GlslSpan, parser::{DEFAULT_VAL_PROBABILITY, TYPE_HINT_STRING, ParsingErr, TYPE_HINT_BOOL, TYPE_HINT_FLOAT, yaml_repr}};
 
-use super::{DEFAULT_VAL_MIRRORED, DEFAULT_VAL_PRECONDITION, GLOBAL_CELLNAMES};
+use super::{DEFAULT_VAL_MIRRORED, DEFAULT_VAL_PRECONDITION, DEFAULT_VAL_WEIGHT, GLOBAL_CELLNAMES, TYPE_HINT_SEQUENCE, TYPE_HINT_WEIGHT};
+use super::expression::{self, BinaryOp, Expr, glsl_cellname};
+use super::report::ParseReport;
+use super::fields::{FieldValue, SandField};
 
 
 
@@ -20,18 +23,110 @@ pub enum SandRuleType {
 
 
 
+/// Added to the seed for the random value of the first CHOOSE, the next ones count up from there.
+/// 0 is used by the rand of the rules and 1 by the modifications
+pub const CHOOSE_SEED_OFFSET: u32 = 2;
+
+/// Single action of a 'do'
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// `SWAP <Cell> <Cell>`
+    Swap(String, String),
+    /// `SET <Cell> <Material>`
+    Set(String, String),
+    /// `SET <Cell> <Cell>` - a new cell with the material of the second cell
+    SetCell(String, String),
+    /// `COPY <Cell> <Cell>` - the first cell becomes a duplicate of the second one (including its fields)
+    Copy(String, String),
+    /// `CONVERT <Cell> <Cell>` - swaps the materials of both cells, but keeps their fields
+    Convert(String, String),
+    /// `MOVE <Cell> <Cell>` - moves the first cell into the second one if that is empty, leaving EMPTY behind
+    Move(String, String),
+    /// `SPAWN <Material> IN <Cell> (IF <Material>)` - only replaces the cell if it has the
+    /// material of the condition (if there is one)
+    Spawn(String, String, Option<String>),
+    /// `SET_FIELD <Cell> <field> <value>`
+    SetField(String, String, FieldValue),
+    /// `INC <Cell> <field> <amount>`, the amount can be negative
+    Inc(String, String, FieldValue),
+    /// `CHOOSE: [{weight: <w>, do: <actions>}, ...]` - runs one of the lists of actions, randomly picked by weight.
+    /// The first value is the index of the CHOOSE in the file, which gives each one its own random value
+    Choose(u32, Vec<(f32, Vec<Action>)>),
+}
+impl Action {
+    /// Names of the cells this action reads or writes
+    pub fn cells(&self) -> Vec<String> {
+        match self {
+            Action::Swap(a, b) | Action::SetCell(a, b) | Action::Copy(a, b) | Action::Convert(a, b) | Action::Move(a, b) => vec![a.clone(), b.clone()],
+            Action::Set(cell, _) | Action::SetField(cell, _, _) | Action::Inc(cell, _, _) | Action::Spawn(_, cell, _) => vec![cell.clone()],
+            Action::Choose(_, choices) => choices.iter()
+                .flat_map(|(_, actions)| actions.iter().flat_map(|a| a.cells()))
+                .collect(),
+        }
+    }
+}
+impl GLSLConvertible for Action {
+    fn get_glsl_code(&self) -> String {
+        match self {
+            Action::Swap(a, b) => format!("swap({}, {});", glsl_cellname(a), glsl_cellname(b)),
+            Action::Set(cell, mat) => format!("{} = newCell(MAT_{}, pos);", glsl_cellname(cell), mat),
+            Action::SetCell(a, b) => format!("setMaterial({}, {});", glsl_cellname(a), glsl_cellname(b)),
+            Action::Copy(a, b) => format!("copyCell({}, {});", glsl_cellname(a), glsl_cellname(b)),
+            Action::Convert(a, b) => format!("convert({}, {});", glsl_cellname(a), glsl_cellname(b)),
+            Action::Move(a, b) => format!("moveCell({}, {});", glsl_cellname(a), glsl_cellname(b)),
+            Action::Spawn(mat, cell, condition) => {
+                let spawn = format!("{} = newCell(MAT_{}, pos);", glsl_cellname(cell), mat);
+                match condition {
+                    Some(cond) => format!("if ({}.mat == MAT_{}) {{\n    {}\n}}", glsl_cellname(cell), cond, spawn),
+                    None => spawn,
+                }
+            },
+            Action::SetField(cell, field, value) => format!("{}.fields.{} = {};", glsl_cellname(cell), field, value),
+            Action::Inc(cell, field, amount) => format!("{}.fields.{} += {};", glsl_cellname(cell), field, amount),
+            Action::Choose(index, choices) => {
+                // Every CHOOSE hashes with its own seed, so it is independent of RAND, the probability and the other CHOOSEs
+                let total: f32 = choices.iter().map(|(weight, _)| weight).sum();
+                let mut threshold = 0.0;
+                let choice = format!("choice{}", index);
+                let mut code = format!("float {} = hash43(uvec3(pos, frame), seed + {}u).x;\n", choice, CHOOSE_SEED_OFFSET + index);
+                for (idx, (weight, actions)) in choices.iter().enumerate() {
+                    let body = actions.iter()
+                        .map(|a| a.get_glsl_code().replace('\n', "\n    "))
+                        .collect::<Vec<String>>()
+                        .join("\n    ");
+                    threshold += weight / total;
+                    // The last choice takes whatever is left, so rounding errors can't skip all choices
+                    let condition = match idx == choices.len() - 1 {
+                        true => String::new(),
+                        false => format!("if ({} < {:?}) ", choice, threshold),
+                    };
+                    if idx > 0 {
+                        code.push_str(" else ");
+                    }
+                    code.push_str(&format!("{}{{\n    {}\n}}", condition, body));
+                }
+                code
+            },
+        }
+    }
+}
+
+
+
 /// Holds information about a rule defined in the YAML file
 #[derive(Debug, Clone)]
 pub struct SandRule {
     /// Name of the rule (Mapping key)
     pub name: String,
+    /// Path of the YAML node the rule was defined in (`rules/<name>` or `reactions/<name>`)
+    pub path: String,
     /// Type of the rule to help sort the rules
     pub ruletype: SandRuleType,
     /// Expression that will need to be true in order
-    /// for the 'do' action(s) to run
-    pub if_conds: Vec<String>,
+    /// for the 'do' action(s) to run. An 'else' without an 'if' is always true
+    pub if_conds: Vec<Expr>,
     /// Action(s) that will be run when the if-condition evals to true
-    pub do_actions: Vec<String>,
+    pub do_actions: Vec<Vec<Action>>,
     /// The probabilities that each if-else level will be run
     pub probabilities: Vec<f32>,
     /// Whether the rule is mirrored horizontally
@@ -43,41 +138,86 @@ pub struct SandRule {
     pub used: bool,
 }
 impl SandRule {
-    /// Helpers function to handle nested conditionals and actions
-    fn get_func_logic(mut if_conds: Vec<String>, mut do_actions: Vec<String>, mut probabilities: Vec<f32>, indent_lvl: usize) -> String {
-        if if_conds.is_empty() && do_actions.is_empty() {
+    /// Names of all cells referenced inside of the conditions and actions of the rule
+    pub fn cells(&self) -> Vec<String> {
+        let mut cells: Vec<String> = vec![];
+        let referenced = self.if_conds.iter()
+            .flat_map(|c| c.cells())
+            .chain(self.do_actions.iter().flatten().flat_map(|a| a.cells()));
+        for cell in referenced {
+            if !cells.contains(&cell) {
+                cells.push(cell);
+            }
+        }
+        cells
+    }
+
+    /// Names of the cells the rule gets passed, depending on its type
+    pub fn received_cells(&self) -> [&'static str; 4] {
+        match self.ruletype {
+            SandRuleType::Mirrored | SandRuleType::Right => ["SELF", "RIGHT", "DOWN", "DOWNRIGHT"],
+            SandRuleType::Left => ["SELF", "LEFT", "DOWN", "DOWNLEFT"],
+        }
+    }
+
+    /// Paths of the YAML nodes the condition and the actions of an if-else level (0 is the outermost 'if')
+    /// were parsed from
+    fn level_paths(&self, level: usize) -> (String, String) {
+        // The levels of reactions are generated from its reactants and products (one per pairing of cells)
+        if self.path.starts_with("reactions/") {
+            return (format!("{}/reactants", self.path), format!("{}/products", self.path));
+        }
+        let level_path = format!("{}{}", self.path, "/else".repeat(level));
+        (format!("{}/if", level_path), format!("{}/do", level_path))
+    }
+
+    /// Helpers function to handle nested conditionals and actions, starting at the if-else level.
+    /// Records the lines of the conditions and actions, the first line of the code has the number `first_line`
+    fn get_func_logic(&self, level: usize, first_line: usize, spans: &mut Vec<GlslSpan>) -> String {
+        if level >= self.if_conds.len() || level >= self.do_actions.len() {
             return String::new();
-        } else if if_conds.is_empty() && !do_actions.is_empty() {
-            return do_actions.remove(0);
-        } else if !if_conds.is_empty() && !do_actions.is_empty() {
-            let ind1 = " ".repeat(indent_lvl * 4);
-            let ind2 = " ".repeat((indent_lvl + 1) * 4);;
-            let p = probabilities.remove(0);
-            let prob = {
-                if p == DEFAULT_VAL_PROBABILITY {
-                    String::new()
-                } else {
-                    format!("rand.y <= {} && ", p)
-                }
-            };
-            return format!(
-"{ind1}if ({prob}{}) {{
+        }
+        let ind1 = " ".repeat((level + 1) * 4);
+        let ind2 = " ".repeat((level + 2) * 4);
+        let actions = self.do_actions[level].iter()
+            .map(|a| a.get_glsl_code().replace('\n', &format!("\n{}", ind2)))
+            .collect::<Vec<String>>()
+            .join(&format!("\n{}", ind2));
+        let num_action_lines = actions.lines().count().max(1);
+        let (cond_path, actions_path) = self.level_paths(level);
+
+        let p = self.probabilities[level];
+        let if_cond = &self.if_conds[level];
+        let cond = {
+            if p == DEFAULT_VAL_PROBABILITY {
+                if_cond.get_glsl_code()
+            } else if if_cond.is_always_true() {
+                format!("rand.y <= {:?}", p)
+            } else {
+                format!("rand.y <= {:?} && {}", p, if_cond.glsl_as_operand(BinaryOp::And, false))
+            }
+        };
+        // An 'else' without 'if' and probability does not need to be wrapped in an if
+        if cond == "true" {
+            spans.push(GlslSpan { path: actions_path, lines: first_line..first_line + num_action_lines });
+            return format!("{ind1}{}", actions);
+        }
+        spans.push(GlslSpan { path: cond_path, lines: first_line..first_line + 1 });
+        spans.push(GlslSpan { path: actions_path, lines: first_line + 1..first_line + 1 + num_action_lines });
+        format!(
+"{ind1}if ({}) {{
 {ind2}{}
 {ind1}}} else {{
 {}
-{ind1}}}", if_conds.remove(0),
-            do_actions.remove(0),
-            SandRule::get_func_logic(if_conds, do_actions, probabilities, indent_lvl + 1));
-        };
-        String::new()
+{ind1}}}", cond,
+        actions,
+        self.get_func_logic(level + 1, first_line + num_action_lines + 2, spans))
     }
-}
-impl GLSLConvertible for SandRule {
-    fn get_glsl_code(&self) -> String {
-        let directional_cell = match self.ruletype {
-            SandRuleType::Mirrored | SandRuleType::Right => "right",
-            SandRuleType::Left => "left"
-        };
+
+    /// The GLSL code of the rule, together with the lines that were generated from the conditions and
+    /// actions (see `GeneratedShaders::rule_spans`). The first line of the code has the number `first_line`
+    pub fn get_glsl_code_with_spans(&self, first_line: usize) -> (String, Vec<GlslSpan>) {
+        let [_, directional_cell, _, diagonal_cell] = self.received_cells().map(glsl_cellname);
 
         let precond = match &self.precondition {
             Some(cond) => format!(
@@ -86,14 +226,24 @@ impl GLSLConvertible for SandRule {
     }}\n", cond),
             None => String::new(),
         };
-        format!(
-"void rule_{rulename} (inout Cell self, inout Cell {celldir}, inout Cell down, inout Cell downright, vec4 rand, ivec2 pos) {{
+        let mut spans = vec![];
+        let ruletext = self.get_func_logic(0, first_line + 1 + precond.lines().count(), &mut spans);
+        let code = format!(
+"void rule_{rulename} (inout Cell self, inout Cell {celldir}, inout Cell down, inout Cell {celldiag}, vec4 rand, ivec2 pos) {{
 {precondition}{ruletext}
 }}", rulename = self.name,
     celldir = directional_cell,
+    celldiag = diagonal_cell,
     precondition = precond,
-    ruletext = SandRule::get_func_logic(self.if_conds.clone(), self.do_actions.clone(), self.probabilities.clone(), 1))
-    
+    ruletext = ruletext);
+        // The whole function belongs to the rule (e.g. the precondition)
+        spans.insert(0, GlslSpan { path: self.path.clone(), lines: first_line..first_line + code.lines().count() });
+        (code, spans)
+    }
+}
+impl GLSLConvertible for SandRule {
+    fn get_glsl_code(&self) -> String {
+        self.get_glsl_code_with_spans(1).0
     }
 }
 
@@ -101,35 +251,40 @@ impl GLSLConvertible for SandRule {
 
 
 
-/// Parses a serde_yaml Mapping (dict) and converts it into SandRule's
-pub fn parse_rules(rules: &Mapping, type_names: &Vec<String>, material_names: &Vec<String>) -> anyhow::Result<(Vec<SandRule>, Vec<Box<dyn GLSLConvertible>>)> {
+/// Parses a serde_yaml Mapping (dict) and converts it into SandRule's.
+/// Rules containing errors are skipped, the errors are recorded in the report
+pub fn parse_rules(rules: &Mapping, type_names: &Vec<String>, material_names: &Vec<String>, fields: &[SandField], report: &mut ParseReport) -> (Vec<SandRule>, Vec<Box<dyn GLSLConvertible>>) {
     let mut rule_structs: Vec<SandRule> = vec![];
     let mut glsl_structs: Vec<Box<dyn GLSLConvertible>> = vec![];
+    let mut chooses = 0;
 
     for key in rules {
         // Extract the name of the rule
         let name = key.0.as_str()
             .ok_or(anyhow!(ParsingErr::InvalidType{
-                wrong_type: key.0.clone(),
+                wrong_type: yaml_repr(key.0),
                 missing_in: "rules".to_string(),
                 expected: TYPE_HINT_STRING
-            }))?
-            .to_string();
+            }));
+        let Some(name) = report.check(name) else {
+            continue;
+        };
+        let name = name.to_string();
+        let errors_before = report.error_count();
         
-        let mut if_conds = vec![];
-        let mut do_actions = vec![];
-        let mut probabilities = vec![];
         // Parse (possibly nested) if's and do's
-        parse_conditionals(
-            key.1,
-            false,
-            format!("rules/{}", name),
-            &mut if_conds,
-            &mut do_actions,
-            &mut probabilities,
-            &type_names,
-            &material_names
-        )?;
+        let mut conditionals = Conditionals {
+            type_names,
+            material_names,
+            fields,
+            if_conds: vec![],
+            do_actions: vec![],
+            probabilities: vec![],
+            chooses,
+        };
+        parse_conditionals(key.1, false, format!("rules/{}", name), &mut conditionals, report);
+        let Conditionals { if_conds, do_actions, probabilities, chooses: parsed_chooses, .. } = conditionals;
+        chooses = parsed_chooses;
         
 
         // Checks the input for the 'mirrored' keyword, uses default value if not found
@@ -139,29 +294,17 @@ pub fn parse_rules(rules: &Mapping, type_names: &Vec<String>, material_names: &V
                 if let Some(mirror) = mirror.as_bool() {
                     mirror
                 } else {
-                    bail!(ParsingErr::InvalidType {
-                        wrong_type: "mirrored",
-                        missing_in: format!("rules/{}", name),
-                        expected: TYPE_HINT_BOOL })
+                    report.error(anyhow!(ParsingErr::InvalidType {
+                        wrong_type: "mirrored".to_string(),
+                        missing_in: format!("rules/{}/mirrored", name),
+                        expected: TYPE_HINT_BOOL }));
+                    DEFAULT_VAL_MIRRORED
                 }
             } else {
                 DEFAULT_VAL_MIRRORED
             }
         };
 
-        // Generates the type of the rule based on whether the if's and do's contain certain keywords
-        // TODO: Rule cant contain both LEFT and RIGHT, throw error if so
-        let ruletype = match is_mirrored {
-            true => SandRuleType::Mirrored,
-            false => {
-                if do_actions[0].contains("LEFT") {
-                    SandRuleType::Left
-                } else {
-                    SandRuleType::Right
-                }
-            }
-        };
-
         // Checks for the 'precondition' key, if not found use default value
         let do_precondition = {
             let pre = key.1.get("precondition");
@@ -170,10 +313,11 @@ pub fn parse_rules(rules: &Mapping, type_names: &Vec<String>, material_names: &V
                 if let Some(pre) = pre.as_bool() {
                     pre
                 } else {
-                    bail!(ParsingErr::InvalidType {
-                        wrong_type: "precondition",
-                        missing_in: format!("rules/{}", name),
-                        expected: TYPE_HINT_BOOL })
+                    report.error(anyhow!(ParsingErr::InvalidType {
+                        wrong_type: "precondition".to_string(),
+                        missing_in: format!("rules/{}/precondition", name),
+                        expected: TYPE_HINT_BOOL }));
+                    DEFAULT_VAL_PRECONDITION
                 }
             } else {
                 DEFAULT_VAL_PRECONDITION
@@ -184,9 +328,15 @@ pub fn parse_rules(rules: &Mapping, type_names: &Vec<String>, material_names: &V
             false => None
         };
 
-        let rule = SandRule {
+        // The rule is incomplete, continue with the next one to find the remaining errors
+        if report.error_count() > errors_before {
+            continue;
+        }
+
+        let mut rule = SandRule {
+            path: format!("rules/{}", name),
             name,
-            ruletype,
+            ruletype: SandRuleType::Mirrored,
             if_conds,
             do_actions,
             probabilities,
@@ -194,116 +344,95 @@ pub fn parse_rules(rules: &Mapping, type_names: &Vec<String>, material_names: &V
             precondition,
             used: false,
         };
+        // Generates the type of the rule based on whether the if's and do's contain certain keywords.
+        // Rules which contain both LEFT and RIGHT are reported by the validator
+        if !is_mirrored {
+            let cells = rule.cells();
+            rule.ruletype = if cells.iter().any(|c| c == "LEFT" || c == "DOWNLEFT") {
+                SandRuleType::Left
+            } else {
+                SandRuleType::Right
+            };
+        }
         //println!("{:#?}", rule);
         rule_structs.push(rule.clone());
         glsl_structs.push(Box::new(rule));
     }
 
-    Ok((rule_structs, glsl_structs))
+    (rule_structs, glsl_structs)
+}
+
+
+/// The names the if-do-else's of a rule can refer to and the parsed if-do-else's (see `parse_conditionals`)
+struct Conditionals<'a> {
+    type_names: &'a Vec<String>,
+    material_names: &'a Vec<String>,
+    fields: &'a [SandField],
+    if_conds: Vec<Expr>,
+    do_actions: Vec<Vec<Action>>,
+    probabilities: Vec<f32>,
+    /// Number of CHOOSEs parsed so far in all rules, used as index of the next one
+    chooses: u32,
 }
 
 
-/// Function to recursively parse the if-do-else's
+/// Function to recursively parse the if-do-else's.
+/// Continues with the 'do' and 'else' if the 'if' has errors, so all of them get recorded
 fn parse_conditionals(
     parent: &Value,
     parent_is_else: bool,
     parent_path: String,
-    if_conds: &mut Vec<String>,
-    do_actions: &mut Vec<String>,
-    probabilities: &mut Vec<f32>,
-    type_names: &Vec<String>,
-    material_names: &Vec<String>
-) -> anyhow::Result<()> {
+    conditionals: &mut Conditionals,
+    report: &mut ParseReport
+) {
     let if_cond = parent.get("if");
 
     // We are on the top level of all conditions, so an 'if' is mandatory
     if if_cond.is_none() && !parent_is_else {
-        bail!(anyhow!(ParsingErr::<bool>::MissingField {
+        report.error(anyhow!(ParsingErr::MissingField {
             field_name: "if".to_string(),
-            missing_in: format!("{}", parent_path)
+            missing_in: parent_path.to_string()
         }));
     } else if if_cond.is_some() {
         let if_cond = if_cond.unwrap();
         let parent_path = format!("{}/if", parent_path);
 
         // Check that the if condition has the right type
-        let mut if_cond = if_cond.as_str()
+        let if_cond = if_cond.as_str()
             .ok_or(anyhow!(ParsingErr::InvalidType {
                 wrong_type: "if".to_string(),
-                missing_in: format!("{}", parent_path),
+                missing_in: parent_path.to_string(),
                 expected: TYPE_HINT_STRING
-            }))?
-            .to_string();
-
-        // Passes the if condition through the global scope parser
-        parse_global_scope(&mut if_cond);
-        
-        // Use regex to find when a material is being accessed
-        //         Would trigger here
-        //               VVV
-        // "SELF.mat == vine"
-        
-        let material_pattern = r"\w*.mat\s*(==|!=)\s*(\w*)";
-        let re = Regex::new(material_pattern).unwrap();
-        'outer: for capture in re.captures_iter(if_cond.clone().as_str()) {
-            let capture = capture.get(2).unwrap().as_str();
-            for m in material_names {
-                if m == capture {
-                    if_cond = if_cond.replace(m, format!("MAT_{}", m).as_str());
-                    continue 'outer;
-                };
-            };
-            bail!(anyhow!(ParsingErr::NotFound::<bool> {
-                missing: capture.to_string(),
-                missing_in: format!("{}", parent_path)
-            }));
-        };
-
-        let type_pattern = r"isType_(\w*)\(\w*\)";
-        let re = Regex::new(type_pattern).unwrap();
-        for capture in re.captures_iter(if_cond.clone().as_str()) {
-            let capture = capture.get(1).unwrap().as_str();
-            if !type_names.contains(&capture.to_string()) {
-                bail!(anyhow!(ParsingErr::NotFound::<bool> {
-                    missing: capture.to_string(),
-                    missing_in: format!("{} -> isType_", parent_path)
-                }));
-            }
-        };
-
-        if_conds.push(if_cond);
+            }))
+            // Turns the condition into an expression tree, resolving all cells, materials and types
+            .and_then(|cond| {
+                let field_names = conditionals.fields.iter().map(|f| f.name.clone()).collect();
+                expression::parse_condition(cond, &parent_path, conditionals.type_names, conditionals.material_names, &field_names)
+            });
+        if let Some(if_cond) = report.check(if_cond) {
+            conditionals.if_conds.push(if_cond);
+        }
+    } else {
+        // An 'else' without an 'if' always runs
+        conditionals.if_conds.push(Expr::always_true());
     }
     
-    // A 'do' is always mandatory, so bail if non existent
-    let do_action = parent
-        .get("do")
-        .ok_or(anyhow!(ParsingErr::<bool>::MissingField {
-            field_name: "do".to_string(),
-            missing_in: format!("{}", parent_path)
-        }))?;
-    
+    // A 'do' is always mandatory
+    let do_action = parent.get("do");
     let do_parent_path = format!("{}/do", parent_path);
 
-    // Do processing on the 'do' to convert it into something more GLSL-like
-    // The final string that is the do action
-    let mut do_string = String::new();
-    if let Some(do_action) = do_action.as_str() {
-        do_string = parse_do(&do_parent_path, &do_action)?;
-        parse_global_scope(&mut do_string);
-    };
-
-    // Also process do's which are written as list of actions
-    if let Some(do_list) = do_action.as_sequence() {
-        for do_action in do_list {
-            if let Some(do_action) = do_action.as_str() {
-                let mut do_str = parse_do(&do_parent_path, do_action)?;
-                parse_global_scope(&mut do_str);
-                do_string.push_str(&do_str);
-            }
-        }
+    // Converts the 'do' into a list of actions
+    let actions = match do_action {
+        Some(do_action) => parse_actions(do_action, &do_parent_path, conditionals, report),
+        None => {
+            report.error(anyhow!(ParsingErr::MissingField {
+                field_name: "do".to_string(),
+                missing_in: parent_path.to_string()
+            }));
+            vec![]
+        },
     };
-    do_string = do_string.trim_end().to_string();
-    do_actions.push(do_string);
+    conditionals.do_actions.push(actions);
 
 
     // Check if the "probability" key exists, use the default value if not
@@ -313,108 +442,313 @@ fn parse_conditionals(
             if let Some(prob) = prob.as_f64() {
                 prob as f32
             } else {
-                bail!(ParsingErr::InvalidType {
-                    wrong_type: "probability",
-                    missing_in: format!("{}", parent_path),
-                    expected: TYPE_HINT_FLOAT })
+                report.error(anyhow!(ParsingErr::InvalidType {
+                    wrong_type: "probability".to_string(),
+                    missing_in: format!("{}/probability", parent_path),
+                    expected: TYPE_HINT_FLOAT }));
+                DEFAULT_VAL_PROBABILITY
             }
         } else {
             DEFAULT_VAL_PROBABILITY
         }
     };
-    probabilities.push(probability);
+    conditionals.probabilities.push(probability);
 
 
     let else_: Option<&Value> = parent.get("else");
     if let Some(e) = else_ {
-        parse_conditionals(e, true, format!("{}/else", parent_path), if_conds, do_actions, probabilities, type_names, material_names)
-    } else {
-        Ok(())
+        parse_conditionals(e, true, format!("{}/else", parent_path), conditionals, report);
     }
 }
 
 
-/// Replaces all global scope variables with GLSL-friendly ones
-fn parse_global_scope(parse_str: &mut String) {
-    *parse_str = parse_str.replace(" or ", " || ");
-    *parse_str = parse_str.replace(" and ", " && ");
-    *parse_str = parse_str.replace("not ", " !");
-    
-    *parse_str = parse_str.replace("empty", "MAT_EMPTY");
-
-    *parse_str = parse_str.replace("SELF", "self");
-    *parse_str = parse_str.replace("RIGHT", "right");
-    *parse_str = parse_str.replace("LEFT", "left");
-    *parse_str = parse_str.replace("DOWN", "down");
-    *parse_str = parse_str.replace("DOWNRIGHT", "downright");
-    *parse_str = parse_str.replace("DOWNLEFT", "downleft");
+/// Converts the value of a 'do' into a list of actions. It can be a single action,
+/// a list of actions or a CHOOSE (which can also be part of a list)
+fn parse_actions(do_action: &Value, do_path: &str, conditionals: &mut Conditionals, report: &mut ParseReport) -> Vec<Action> {
+    let mut actions = vec![];
+    match do_action {
+        Value::String(do_action) => {
+            if let Some(action) = parse_action(do_action, do_path, conditionals, report) {
+                actions.push(action);
+            }
+        },
+        Value::Mapping(_) => {
+            if let Some(action) = parse_choose(do_action, do_path, conditionals, report) {
+                actions.push(action);
+            }
+        },
+        // Also process do's which are written as list of actions
+        Value::Sequence(do_list) => {
+            for (idx, do_action) in do_list.iter().enumerate() {
+                let path = format!("{}/{}", do_path, idx);
+                let action = match do_action {
+                    Value::String(do_action) => parse_action(do_action, &path, conditionals, report),
+                    Value::Mapping(_) => parse_choose(do_action, &path, conditionals, report),
+                    _ => {
+                        report.error(anyhow!(ParsingErr::InvalidType {
+                            wrong_type: "do".to_string(),
+                            missing_in: path,
+                            expected: TYPE_HINT_STRING
+                        }));
+                        None
+                    },
+                };
+                actions.extend(action);
+            }
+        },
+        _ => report.error(anyhow!(ParsingErr::InvalidType {
+            wrong_type: "do".to_string(),
+            missing_in: do_path.to_string(),
+            expected: TYPE_HINT_STRING
+        })),
+    };
+    actions
 }
 
 
-/// Converts a string with YAML 'do-syntax' into valid GLSL code which can be run
-fn parse_do(parent: &str, do_str: &str) -> anyhow::Result<String> {
-    let mut do_string = String::new();
+/// Converts a single action written as string, which is either a CHOOSE (`CHOOSE [...]`) or
+/// any other action (see `parse_do`)
+fn parse_action(do_action: &str, do_path: &str, conditionals: &mut Conditionals, report: &mut ParseReport) -> Option<Action> {
+    let choose_pattern = r"(?s)^\s*CHOOSE\s*(\[.*)$";
+    let re = Regex::new(choose_pattern).unwrap();
+    let Some(captures) = re.captures(do_action) else {
+        return report.check(parse_do(do_path, do_action, conditionals.material_names, conditionals.fields));
+    };
+    let choose_path = format!("{}/CHOOSE", do_path);
+    let options = serde_yaml::from_str::<Value>(captures.get(1).unwrap().as_str())
+        .map_err(|_| anyhow!(ParsingErr::InvalidType {
+            wrong_type: "CHOOSE".to_string(),
+            missing_in: choose_path.clone(),
+            expected: TYPE_HINT_SEQUENCE
+        }));
+    let options = report.check(options)?;
+    parse_choices(&options, &choose_path, conditionals, report)
+}
+
+
+/// Converts a weighted random choice between lists of actions:
+///
+/// ```yaml
+/// do:
+///     CHOOSE:
+///         - weight: 2
+///           do: SET SELF sand
+///         - weight: 1
+///           do: [SWAP SELF DOWN]
+/// ```
+///
+/// or in one line: `do: "CHOOSE [{weight: 2, do: SET SELF sand}, {weight: 1, do: [SWAP SELF DOWN]}]"`
+fn parse_choose(choose: &Value, do_path: &str, conditionals: &mut Conditionals, report: &mut ParseReport) -> Option<Action> {
+    let Some(options) = choose.get("CHOOSE") else {
+        report.error(anyhow!(ParsingErr::NotRecognized {
+            unrecog: yaml_repr(choose),
+            missing_in: do_path.to_string()
+        }));
+        return None;
+    };
+    parse_choices(options, &format!("{}/CHOOSE", do_path), conditionals, report)
+}
 
-    let mut found_match = false;
 
+/// Converts the list of `{weight, do}` options of a CHOOSE
+fn parse_choices(options: &Value, choose_path: &str, conditionals: &mut Conditionals, report: &mut ParseReport) -> Option<Action> {
+    let Some(options) = options.as_sequence() else {
+        report.error(anyhow!(ParsingErr::InvalidType {
+            wrong_type: "CHOOSE".to_string(),
+            missing_in: choose_path.to_string(),
+            expected: TYPE_HINT_SEQUENCE
+        }));
+        return None;
+    };
+
+    // Nested CHOOSEs get the following indices
+    let index = conditionals.chooses;
+    conditionals.chooses += 1;
+    let errors_before = report.error_count();
+    let mut choices = vec![];
+    for (idx, option) in options.iter().enumerate() {
+        let option_path = format!("{}/{}", choose_path, idx);
+        let weight = match option.get("weight") {
+            Some(weight) => match weight.as_f64() {
+                Some(weight) if weight > 0.0 => weight as f32,
+                _ => {
+                    report.error(anyhow!(ParsingErr::InvalidType {
+                        wrong_type: "weight".to_string(),
+                        missing_in: format!("{}/weight", option_path),
+                        expected: TYPE_HINT_WEIGHT
+                    }));
+                    continue;
+                },
+            },
+            None => DEFAULT_VAL_WEIGHT,
+        };
+        let Some(do_action) = option.get("do") else {
+            report.error(anyhow!(ParsingErr::MissingField {
+                field_name: "do".to_string(),
+                missing_in: option_path
+            }));
+            continue;
+        };
+        choices.push((weight, parse_actions(do_action, &format!("{}/do", option_path), conditionals, report)));
+    }
+
+    if report.error_count() > errors_before {
+        return None;
+    }
+    Some(Action::Choose(index, choices))
+}
+
+
+/// Converts a string with YAML 'do-syntax' into an action
+fn parse_do(parent: &str, do_str: &str, material_names: &[String], fields: &[SandField]) -> anyhow::Result<Action> {
     // Use regex to find when a swap operation is requested as action
     // Would trigger here
     //       VVV
     // "SWAP SELF DOWN"
-    let swap_pattern = r"SWAP (\w+) (\w+)";
+    let swap_pattern = r"^\s*SWAP\s+(\w+)\s+(\w+)\s*$";
     let re = Regex::new(swap_pattern).unwrap();
     
     // Check that the arguments to this function are correct
     if let Some(captures) = re.captures(do_str) {
-        found_match = true;
-
         let first_cell = captures.get(1).unwrap().as_str();
         if !GLOBAL_CELLNAMES.contains(&first_cell) {
-            bail!(ParsingErr::<bool>::NotFound {
+            bail!(ParsingErr::NotFound {
                 missing: first_cell.to_string(),
-                missing_in: format!("{}", parent)
+                missing_in: parent.to_string()
             });
         };
         let second_cell = captures.get(2).unwrap().as_str();
         if !GLOBAL_CELLNAMES.contains(&second_cell) {
-            bail!(ParsingErr::<bool>::NotFound {
+            bail!(ParsingErr::NotFound {
                 missing: second_cell.to_string(),
-                missing_in: format!("{}", parent)
+                missing_in: parent.to_string()
             });
         }
 
-        do_string.push_str(format!("swap({}, {});\n", first_cell, second_cell).as_str());
+        return Ok(Action::Swap(first_cell.to_string(), second_cell.to_string()));
     }
 
     // Use regex to find when a set (newCell) operation is requested as action
     // Would trigger here
     //           VVV
     // "SET SELF vine"
-    let set_pattern = r"SET (\w+) (\w+)";
+    let set_pattern = r"^\s*SET\s+(\w+)\s+(\w+)\s*$";
     let re = Regex::new(set_pattern).unwrap();
     if let Some(captures) = re.captures(do_str) {
-        found_match = true;
-        
         // Needs to be a cell
         let first_arg = captures.get(1).unwrap().as_str();
         if !GLOBAL_CELLNAMES.contains(&first_arg) {
-            bail!(ParsingErr::<bool>::NotFound {
+            bail!(ParsingErr::NotFound {
                 missing: first_arg.to_string(),
-                missing_in: format!("{}", parent)
+                missing_in: parent.to_string()
             });
         }
-        // TODO: Check if it is either a GLOBAL_CELL or material
-        // Right now, it just assumes its a material
+        // Either a cell or a material
         let second_arg = captures.get(2).unwrap().as_str();
-        do_string.push_str(format!("{} = newCell(MAT_{}, pos);\n", first_arg, second_arg).as_str());
+        if GLOBAL_CELLNAMES.contains(&second_arg) {
+            return Ok(Action::SetCell(first_arg.to_string(), second_arg.to_string()));
+        }
+        if !material_names.iter().any(|m| m == second_arg) {
+            bail!(ParsingErr::NotFound {
+                missing: second_arg.to_string(),
+                missing_in: parent.to_string()
+            });
+        }
+        return Ok(Action::Set(first_arg.to_string(), second_arg.to_string()));
+    }
+
+    // Use regex to find when cells should be copied, converted or moved
+    // Would trigger here
+    //              VVV
+    // "COPY SELF RIGHT", "CONVERT SELF DOWN" or "MOVE SELF DOWN"
+    let copy_pattern = r"^\s*(COPY|CONVERT|MOVE)\s+(\w+)\s+(\w+)\s*$";
+    let re = Regex::new(copy_pattern).unwrap();
+    if let Some(captures) = re.captures(do_str) {
+        let first_cell = captures.get(2).unwrap().as_str();
+        let second_cell = captures.get(3).unwrap().as_str();
+        for cell in [first_cell, second_cell] {
+            if !GLOBAL_CELLNAMES.contains(&cell) {
+                bail!(ParsingErr::NotFound {
+                    missing: cell.to_string(),
+                    missing_in: parent.to_string()
+                });
+            }
+        }
+        let (first_cell, second_cell) = (first_cell.to_string(), second_cell.to_string());
+        return Ok(match captures.get(1).unwrap().as_str() {
+            "COPY" => Action::Copy(first_cell, second_cell),
+            "CONVERT" => Action::Convert(first_cell, second_cell),
+            _ => Action::Move(first_cell, second_cell),
+        });
+    }
+
+    // Use regex to find when a new cell should be spawned (optionally only into a specific material)
+    // Would trigger here
+    //                            VVV
+    // "SPAWN sand IN DOWN IF EMPTY"
+    let spawn_pattern = r"^\s*SPAWN\s+(\w+)\s+IN\s+(\w+)(?:\s+IF\s+(\w+))?\s*$";
+    let re = Regex::new(spawn_pattern).unwrap();
+    if let Some(captures) = re.captures(do_str) {
+        let mat = captures.get(1).unwrap().as_str();
+        let cell = captures.get(2).unwrap().as_str();
+        if !GLOBAL_CELLNAMES.contains(&cell) {
+            bail!(ParsingErr::NotFound {
+                missing: cell.to_string(),
+                missing_in: parent.to_string()
+            });
+        }
+        let condition = captures.get(3).map(|c| c.as_str());
+        for mat in std::iter::once(mat).chain(condition) {
+            if !material_names.iter().any(|m| m == mat) {
+                bail!(ParsingErr::NotFound {
+                    missing: mat.to_string(),
+                    missing_in: parent.to_string()
+                });
+            }
+        }
+        let condition = condition.map(|c| c.to_string());
+        return Ok(Action::Spawn(mat.to_string(), cell.to_string(), condition));
     }
 
-    if !found_match {
-        bail!(ParsingErr::<bool>::NotRecognized {
-            unrecog: do_str.to_string(),
-            missing_in: format!("{}", parent)
+    // Use regex to find when a custom field of a cell should be set or incremented
+    // Would trigger here
+    //                          VVV
+    // "SET_FIELD SELF lifetime 10" or "INC SELF lifetime -1"
+    let field_pattern = r"^\s*(SET_FIELD|INC)\s+(\w+)\s+(\w+)\s+(\S+)\s*$";
+    let re = Regex::new(field_pattern).unwrap();
+    if let Some(captures) = re.captures(do_str) {
+        let cell = captures.get(2).unwrap().as_str();
+        if !GLOBAL_CELLNAMES.contains(&cell) {
+            bail!(ParsingErr::NotFound {
+                missing: cell.to_string(),
+                missing_in: parent.to_string()
+            });
+        }
+        let field_name = captures.get(3).unwrap().as_str();
+        let Some(field) = fields.iter().find(|f| f.name == field_name) else {
+            bail!(ParsingErr::NotFound {
+                missing: field_name.to_string(),
+                missing_in: parent.to_string()
+            });
+        };
+        let value = captures.get(4).unwrap().as_str();
+        let Some(value) = field.fieldtype.value_from_str(value) else {
+            bail!(ParsingErr::InvalidType {
+                wrong_type: value.to_string(),
+                missing_in: parent.to_string(),
+                expected: field.fieldtype.hint()
+            });
+        };
+
+        let (cell, field_name) = (cell.to_string(), field_name.to_string());
+        return Ok(match captures.get(1).unwrap().as_str() {
+            "SET_FIELD" => Action::SetField(cell, field_name, value),
+            _ => Action::Inc(cell, field_name, value),
         });
     }
 
-    Ok(do_string)
-}
\ No newline at end of file
+    bail!(ParsingErr::NotRecognized {
+        unrecog: do_str.to_string(),
+        missing_in: parent.to_string()
+    });
+}
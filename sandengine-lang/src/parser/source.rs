@@ -0,0 +1,236 @@
+use std::{collections::HashMap, ops::Range};
+
+use colored::Colorize;
+use yaml_rust::{parser::{Event, MarkedEventReceiver, Parser}, scanner::{Marker, TScalarStyle}};
+
+
+
+/// Position of a YAML node inside of the source file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    /// Line number, starting at 1
+    pub line: usize,
+    /// Column (in characters), starting at 1
+    pub col: usize,
+}
+impl From<Marker> for Location {
+    fn from(marker: Marker) -> Self {
+        Location {
+            line: marker.line(),
+            col: marker.col() + 1,
+        }
+    }
+}
+
+
+/// Where a single value (and the key it belongs to) was found in the source
+#[derive(Debug, Clone)]
+struct NodeLocation {
+    /// Location of the mapping key, if the node is the value of a mapping
+    key: Option<Location>,
+    /// Location of the start of the value
+    value: Location,
+    /// For scalars: the length of the text and whether it is wrapped in quotes
+    scalar: Option<(usize, bool)>,
+}
+
+
+/// A YAML source text, together with the location of every node inside of it.
+/// Nodes are identified by their path, like `rules/grow/if` or `types/liquid/base_rules/0`
+pub struct SourceFile {
+    /// Name of the file that will be displayed in error messages
+    pub name: String,
+    /// The contents of the file
+    pub src: String,
+    locations: HashMap<String, NodeLocation>,
+}
+impl SourceFile {
+    pub fn new(name: &str, src: &str) -> Self {
+        let mut collector = LocationCollector::default();
+        let mut parser = Parser::new(src.chars());
+        // Syntax errors are reported by serde_yaml, without the locations we just
+        // fall back to displaying the path of the node
+        if parser.load(&mut collector, false).is_err() {
+            collector.locations.clear();
+        }
+        SourceFile {
+            name: name.to_string(),
+            src: src.to_string(),
+            locations: collector.locations,
+        }
+    }
+
+    /// Finds the node of the path or its closest parent that exists in the source
+    fn find_node(&self, path: &str) -> Option<&NodeLocation> {
+        let mut path = path;
+        loop {
+            if let Some(node) = self.locations.get(path) {
+                return Some(node);
+            }
+            path = &path[..path.rfind('/')?];
+        }
+    }
+
+    /// Returns the location of the node at the path (or the closest parent)
+    pub fn locate(&self, path: &str) -> Option<Location> {
+        self.find_node(path).map(|n| n.key.unwrap_or(n.value))
+    }
+
+    /// Calculates the location and length of the text an error should point at.
+    /// `span` is a byte range inside of the string value of the node
+    fn highlight(&self, path: &str, span: Option<&Range<usize>>) -> Option<(Location, usize)> {
+        let exact = self.locations.get(path);
+        if let Some(node @ NodeLocation { scalar: Some((len, quoted)), .. }) = exact {
+            // Point at a part of the string, e.g. a single name inside of a condition
+            let part = span.and_then(|span| {
+                let value = self.scalar_text(node)?;
+                let start = value.get(..span.start)?.chars().count() + *quoted as usize;
+                let len = value.get(span.clone())?.chars().count().max(1);
+                Some((Location { line: node.value.line, col: node.value.col + start }, len))
+            });
+            // Otherwise point at the whole value
+            return part.or(Some((node.value, (len + 2 * *quoted as usize).max(1))));
+        }
+
+        // Point at the key of the node (or the closest parent)
+        let node = self.find_node(path)?;
+        let loc = node.key.unwrap_or(node.value);
+        let len = self.line(loc.line)
+            .map(|l| l.chars().skip(loc.col - 1).take_while(|c| *c != ':').count())
+            .unwrap_or(1);
+        Some((loc, len.max(1)))
+    }
+
+    /// The unquoted text of the scalar as it is written in the source (only the first line)
+    fn scalar_text(&self, node: &NodeLocation) -> Option<String> {
+        let (_, quoted) = node.scalar?;
+        let line = self.line(node.value.line)?;
+        Some(line.chars().skip(node.value.col - 1 + quoted as usize).collect())
+    }
+
+    fn line(&self, line: usize) -> Option<&str> {
+        self.src.lines().nth(line.checked_sub(1)?)
+    }
+
+    /// Formats an error message, adding the file name, line, column
+    /// and an underlined snippet of the source
+    pub fn render(&self, message: &str, path: Option<&str>, span: Option<&Range<usize>>, location: Option<Location>) -> String {
+        let highlight = match (location, path) {
+            (Some(loc), _) => Some((loc, 1)),
+            (None, Some(path)) => self.highlight(path, span),
+            (None, None) => None,
+        };
+        let (loc, len) = match highlight {
+            Some(h) => h,
+            None => return format!("{}\n  {} {}", message, "-->".blue().bold(), self.name),
+        };
+
+        let line_nr = loc.line.to_string();
+        let gutter = " ".repeat(line_nr.len());
+        let line = self.line(loc.line).unwrap_or_default();
+        // Never underline past the end of the line
+        let len = len.min(line.chars().count().saturating_sub(loc.col - 1)).max(1);
+        format!(
+"{message}
+{gutter}{} {}:{}:{}
+{gutter} {}
+{} {} {}
+{gutter} {} {}{}",
+            "-->".blue().bold(), self.name, loc.line, loc.col,
+            "|".blue().bold(),
+            line_nr.blue().bold(), "|".blue().bold(), line,
+            "|".blue().bold(), " ".repeat(loc.col - 1), "^".repeat(len).red().bold())
+    }
+}
+
+
+/// Container that is currently being walked through by the LocationCollector
+enum Frame {
+    Mapping {
+        path: String,
+        /// The key and its location, if the next event is a value
+        key: Option<(String, Location)>,
+    },
+    Sequence {
+        path: String,
+        idx: usize,
+    },
+}
+
+
+/// Receives the events of the yaml-rust parser and records the location of every node
+#[derive(Default)]
+struct LocationCollector {
+    stack: Vec<Frame>,
+    locations: HashMap<String, NodeLocation>,
+}
+impl LocationCollector {
+    /// Registers a new node (scalar, mapping or sequence) and returns its path.
+    /// Returns None, if the node was a mapping key
+    fn add_node(&mut self, mark: Marker, scalar: Option<(&str, TScalarStyle)>) -> Option<String> {
+        let (path, key) = match self.stack.last_mut() {
+            None => (String::new(), None),
+            Some(Frame::Mapping { path, key }) => {
+                match key.take() {
+                    None => {
+                        // This node is a key. Complex keys (mappings as keys) can't be referenced by a path
+                        let name = scalar.map(|(s, _)| s.to_string()).unwrap_or_default();
+                        *key = Some((name, mark.into()));
+                        return None;
+                    },
+                    Some((name, key_loc)) => (join_path(path, &name), Some(key_loc)),
+                }
+            },
+            Some(Frame::Sequence { path, idx }) => {
+                let p = join_path(path, &idx.to_string());
+                *idx += 1;
+                (p, None)
+            },
+        };
+
+        let scalar = scalar.map(|(s, style)| {
+            let quoted = matches!(style, TScalarStyle::SingleQuoted | TScalarStyle::DoubleQuoted);
+            (s.chars().count(), quoted)
+        });
+        self.locations.insert(path.clone(), NodeLocation {
+            key,
+            value: mark.into(),
+            scalar,
+        });
+        Some(path)
+    }
+}
+impl MarkedEventReceiver for LocationCollector {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        match ev {
+            Event::Scalar(value, style, _, _) => {
+                self.add_node(mark, Some((&value, style)));
+            },
+            Event::Alias(_) => {
+                self.add_node(mark, None);
+            },
+            Event::MappingStart(_) => {
+                // Mappings used as keys are skipped together with their content
+                let path = self.add_node(mark, None).unwrap_or_else(|| String::from("?"));
+                self.stack.push(Frame::Mapping { path, key: None });
+            },
+            Event::SequenceStart(_) => {
+                let path = self.add_node(mark, None).unwrap_or_else(|| String::from("?"));
+                self.stack.push(Frame::Sequence { path, idx: 0 });
+            },
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.stack.pop();
+            },
+            _ => (),
+        }
+    }
+}
+
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
@@ -0,0 +1,266 @@
+use anyhow::anyhow;
+use serde_yaml::{Mapping, Value};
+
+use crate::parser::{ParsingErr, TYPE_HINT_FLOAT, TYPE_HINT_STRING};
+
+use super::fields::{FieldType, FieldValue, SandField};
+use super::materials::SandMaterial;
+use super::report::ParseReport;
+
+
+
+const TYPE_HINT_NUMBER: &str = "number";
+
+/// Name of the float field every cell stores its temperature in
+pub const TEMPERATURE_FIELD: &str = "temperature";
+/// Temperature of cells whose material doesn't define one (and of EMPTY)
+pub const AMBIENT_TEMPERATURE: f32 = 20.0;
+/// Conductivity of materials that don't define one, which means they don't exchange heat
+pub const DEFAULT_VAL_CONDUCTIVITY: f32 = 0.0;
+
+/// Keys of a material that enable the temperature simulation
+const THERMAL_KEYS: [&str; 8] = [
+    "temperature",
+    "conductivity",
+    "melting_point",
+    "melts_into",
+    "boiling_point",
+    "boils_into",
+    "ignition_point",
+    "ignites_into",
+];
+
+
+/// How a material reacts to heat. Transitions are (temperature, material it turns into)
+#[derive(Debug, Clone, Default)]
+pub struct ThermalProps {
+    /// How much of the temperature difference to a neighbour is exchanged per step (0.0 to 1.0)
+    pub conductivity: f32,
+    pub melting: Option<(f32, String)>,
+    pub boiling: Option<(f32, String)>,
+    pub ignition: Option<(f32, String)>,
+}
+
+
+/// A material turning into another one, once its temperature passes a point
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub from: String,
+    pub to: String,
+    pub point: f32,
+    /// Whether the transition happens at or above the point (melting) or below it (freezing)
+    pub rising: bool,
+}
+impl Transition {
+    fn glsl_condition(&self) -> String {
+        match self.rising {
+            true => format!("{} >= {:?}", TEMPERATURE_FIELD, self.point),
+            false => format!("{} < {:?}", TEMPERATURE_FIELD, self.point),
+        }
+    }
+}
+
+
+/// Whether any material uses one of the temperature keys
+pub fn uses_temperature(materials: &Mapping) -> bool {
+    materials.values().any(|mat| THERMAL_KEYS.iter().any(|key| mat.get(key).is_some()))
+}
+
+
+/// Adds the float field that stores the temperature of every cell. A field with the same
+/// name from the 'fields' section is used instead, if it is a float
+pub fn add_temperature_field(fields: &mut Vec<SandField>, report: &mut ParseReport) {
+    match fields.iter().find(|f| f.name == TEMPERATURE_FIELD) {
+        Some(field) if field.fieldtype != FieldType::Float => {
+            report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: TEMPERATURE_FIELD.to_string(),
+                missing_in: format!("fields/{}", TEMPERATURE_FIELD),
+                expected: FieldType::Float.hint()
+            }));
+        },
+        Some(_) => (),
+        None => fields.push(SandField {
+            name: TEMPERATURE_FIELD.to_string(),
+            fieldtype: FieldType::Float,
+            default: FieldValue::Float(AMBIENT_TEMPERATURE),
+            slot: fields.len() + 1,
+        }),
+    }
+}
+
+
+/// Parses the temperature keys of a material. Returns the temperature of new cells of the
+/// material (if it has one) and the remaining properties:
+///
+/// ```yaml
+/// ice:
+///     temperature: -10
+///     conductivity: 0.5
+///     melting_point: 0
+///     melts_into: water
+/// ```
+pub fn parse_thermal(mat: &Value, name: &str, material_names: &[String], report: &mut ParseReport) -> (Option<f32>, ThermalProps) {
+    let path = format!("materials/{}", name);
+    let temperature = parse_number(mat, "temperature", &path, report);
+    let conductivity = match parse_number(mat, "conductivity", &path, report) {
+        Some(c) if !(0.0..=1.0).contains(&c) => {
+            report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: "conductivity".to_string(),
+                missing_in: format!("{}/conductivity", path),
+                expected: TYPE_HINT_FLOAT
+            }));
+            DEFAULT_VAL_CONDUCTIVITY
+        },
+        c => c.unwrap_or(DEFAULT_VAL_CONDUCTIVITY),
+    };
+
+    let props = ThermalProps {
+        conductivity,
+        melting: parse_transition(mat, ("melting_point", "melts_into"), &path, material_names, report),
+        boiling: parse_transition(mat, ("boiling_point", "boils_into"), &path, material_names, report),
+        ignition: parse_transition(mat, ("ignition_point", "ignites_into"), &path, material_names, report),
+    };
+    (temperature, props)
+}
+
+
+fn parse_number(mat: &Value, key: &str, path: &str, report: &mut ParseReport) -> Option<f32> {
+    let value = mat.get(key)?;
+    match value.as_f64() {
+        Some(value) => Some(value as f32),
+        None => {
+            report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: key.to_string(),
+                missing_in: format!("{}/{}", path, key),
+                expected: TYPE_HINT_NUMBER
+            }));
+            None
+        },
+    }
+}
+
+
+/// Parses a point and the material the transition results in, both need to be given
+fn parse_transition(mat: &Value, (point_key, into_key): (&str, &str), path: &str, material_names: &[String], report: &mut ParseReport) -> Option<(f32, String)> {
+    let point = parse_number(mat, point_key, path, report);
+    let into = match mat.get(into_key) {
+        Some(into) => match into.as_str() {
+            Some(into) if material_names.iter().any(|n| n == into) => Some(into.to_string()),
+            Some(into) => {
+                report.error(anyhow!(ParsingErr::NotFound {
+                    missing: into.to_string(),
+                    missing_in: format!("{}/{}", path, into_key)
+                }));
+                None
+            },
+            None => {
+                report.error(anyhow!(ParsingErr::InvalidType {
+                    wrong_type: into_key.to_string(),
+                    missing_in: format!("{}/{}", path, into_key),
+                    expected: TYPE_HINT_STRING
+                }));
+                None
+            },
+        },
+        None => None,
+    };
+
+    // Report the key that is missing, if only one of them was given
+    let missing = match (mat.get(point_key), mat.get(into_key)) {
+        (Some(_), None) => Some(into_key),
+        (None, Some(_)) => Some(point_key),
+        _ => None,
+    };
+    if let Some(missing) = missing {
+        report.error(anyhow!(ParsingErr::MissingField {
+            field_name: missing.to_string(),
+            missing_in: path.to_string()
+        }));
+    }
+    point.zip(into)
+}
+
+
+/// Collects all transitions between the materials. Every melting or boiling also creates the
+/// reverse transition (freezing or condensing). If multiple materials melt/ boil into the same
+/// one, it turns back into the first of them. Ignition can't be reversed
+pub fn transitions(materials: &[SandMaterial]) -> Vec<Transition> {
+    let mut transitions = vec![];
+    for mat in materials {
+        let props = &mat.thermal;
+        for (point, into) in [&props.ignition, &props.boiling, &props.melting].into_iter().flatten() {
+            transitions.push(Transition { from: mat.name.clone(), to: into.clone(), point: *point, rising: true });
+        }
+    }
+
+    let mut reverse: Vec<Transition> = vec![];
+    for mat in materials {
+        for (point, into) in [&mat.thermal.melting, &mat.thermal.boiling].into_iter().flatten() {
+            if reverse.iter().any(|t| t.from == *into) {
+                continue;
+            }
+            reverse.push(Transition { from: into.clone(), to: mat.name.clone(), point: *point, rising: false });
+        }
+    }
+    transitions.append(&mut reverse);
+    transitions
+}
+
+
+/// Creates the GLSL code that exchanges heat between the cells of a block and applies the
+/// transitions. Without the temperature field, `applyTemperature` does nothing
+pub fn thermal_glsl(materials: &[SandMaterial], fields: &[SandField]) -> String {
+    if !fields.iter().any(|f| f.name == TEMPERATURE_FIELD) {
+        // Starts with a newline, since the include replaces the line break before it
+        return String::from("
+void applyTemperature(inout Cell self, inout Cell right, inout Cell down, inout Cell downright) {
+}
+");
+    }
+
+    let transitions = transitions(materials);
+    let mut transition_checks = String::new();
+    for mat in materials {
+        let checks = transitions.iter()
+            .filter(|t| t.from == mat.name)
+            .map(|t| format!("if (cell.fields.{}) {{\n            cell.mat = MAT_{};\n        }}", t.glsl_condition(), t.to))
+            .collect::<Vec<String>>();
+        if checks.is_empty() {
+            continue;
+        }
+        transition_checks.push_str(&format!("    if (cell.mat == MAT_{}) {{\n        {}\n        return;\n    }}\n",
+            mat.name,
+            checks.join(" else ")));
+    }
+
+    format!(
+"
+// Moves heat from the warmer to the colder cell, the less conductive material limits the flow
+void exchangeHeat(inout Cell a, inout Cell b) {{
+    if (isBoundary(a) || isBoundary(b)) {{
+        return;
+    }}
+    float flow = (b.fields.{field} - a.fields.{field}) * min(a.mat.conductivity, b.mat.conductivity) * 0.5;
+    a.fields.{field} += flow;
+    b.fields.{field} -= flow;
+}}
+
+// Changes the material of the cell if its temperature passed a transition point.
+// The cell keeps its fields (and temperature)
+void applyTransitions(inout Cell cell) {{
+{transition_checks}}}
+
+void applyTemperature(inout Cell self, inout Cell right, inout Cell down, inout Cell downright) {{
+    exchangeHeat(self, right);
+    exchangeHeat(down, downright);
+    exchangeHeat(self, down);
+    exchangeHeat(right, downright);
+
+    applyTransitions(self);
+    applyTransitions(right);
+    applyTransitions(down);
+    applyTransitions(downright);
+}}
+", field = TEMPERATURE_FIELD,
+    transition_checks = transition_checks)
+}
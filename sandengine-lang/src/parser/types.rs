@@ -1,9 +1,9 @@
 use anyhow::{anyhow, bail};
 use serde_yaml::Mapping;
 
-use crate::{GLSLConvertible, parser::{TYPE_HINT_STRING, ParsingErr, TYPE_HINT_SEQUENCE}};
+use crate::{GLSLConvertible, parser::{TYPE_HINT_STRING, ParsingErr, TYPE_HINT_SEQUENCE, yaml_repr}};
 
-use super::rules::SandRule;
+use super::{rules::SandRule, report::ParseReport};
 
 
 
@@ -47,8 +47,9 @@ impl GLSLConvertible for SandType {
 
 
 
-/// Parses a serde_yaml Mapping (dict) and converts it into SandType's
-pub fn parse_types(types: &Mapping, rules: &mut Vec<SandRule>, rule_names: &Vec<String>, type_names: &Vec<String>) -> anyhow::Result<(Vec<SandType>, Vec<Box<dyn GLSLConvertible>>)> {
+/// Parses a serde_yaml Mapping (dict) and converts it into SandType's.
+/// Errors are recorded in the report
+pub fn parse_types(types: &Mapping, rules: &mut [SandRule], rule_names: &[String], type_names: &[String], report: &mut ParseReport) -> (Vec<SandType>, Vec<Box<dyn GLSLConvertible>>) {
     // Define the default types
     let mut type_structs: Vec<SandType> = vec![
         SandType {
@@ -67,55 +68,44 @@ pub fn parse_types(types: &Mapping, rules: &mut Vec<SandRule>, rule_names: &Vec<
             ..Default::default()
         },
     ];
-    let mut glsl_structs: Vec<Box<dyn GLSLConvertible>> = vec![
-        Box::new(type_structs[0].clone()),
-        Box::new(type_structs[1].clone()),
-        Box::new(type_structs[2].clone())
-    ];
-    
     
-    // This will be used in both loops
-    let update_rule_precondition = |rule: &mut SandRule, typename: &str| {
-        // Update the rules precondition to let this type use the rule
-        // = pass the precondition check
-        if let Some(precondition) = &mut rule.precondition {
-            if precondition.is_empty() {
-                rule.precondition = Some(format!("isType_{}(self)", typename));
-            } else {
-                rule.precondition = Some(format!("{} || isType_{}(self)", precondition, typename));
-            }
-        };
-    };
-    
-    // Iterate over all types, check if the inherited class and the base_rules have been defined somewhere
+    // Iterate over all types, check if the base_rules have been defined somewhere.
+    // Inheritance is resolved afterwards, so types can inherit from types that are declared later
     let mut idx = type_structs.len();
     for sandtype in types {
         // Extract the name of the type
         let name = sandtype.0.as_str()
             .ok_or(anyhow!(ParsingErr::InvalidType {
-                wrong_type: sandtype.0.clone(),
+                wrong_type: yaml_repr(sandtype.0),
                 missing_in: "types".to_string(),
                 expected: TYPE_HINT_STRING
-            }))?
-            .to_string();
+            }));
+        let Some(name) = report.check(name) else {
+            continue;
+        };
+        let name = name.to_string();
 
         // Processes the inheritance of types (and therefore inheritance of base_rules)
         let inherits = sandtype.1.get("inherits");
         let mut parent = String::new();
         if let Some(p) = inherits {
             // If the 'inherits' keyword is present but has a wrong type, error
-            parent = p.as_str().ok_or(anyhow!(ParsingErr::InvalidType {
-                wrong_type: "inherits",
-                missing_in: format!("types/{}", name),
-                expected: TYPE_HINT_STRING
-            }))?.to_string();
-            for typename in type_names {
-                if typename == &parent {
-                    // Modifies the parent SandType to add ourselves to its children
-                    // the parent needs information of its children for its checker function
-                    add_child_to_type(&parent, &name, &mut type_structs);
-                }
-            }
+            let p = p.as_str()
+                .ok_or(anyhow!(ParsingErr::InvalidType {
+                    wrong_type: "inherits".to_string(),
+                    missing_in: format!("types/{}/inherits", name),
+                    expected: TYPE_HINT_STRING
+                }))
+                .and_then(|p| {
+                    if !type_names.contains(&p.to_string()) {
+                        bail!(ParsingErr::NotFound {
+                            missing: p.to_string(),
+                            missing_in: format!("types/{}/inherits", name)
+                        });
+                    }
+                    Ok(p)
+                });
+            parent = report.check(p).unwrap_or_default().to_string();
         };
 
         // Check if all rules defined in base_rules exist
@@ -123,88 +113,183 @@ pub fn parse_types(types: &Mapping, rules: &mut Vec<SandRule>, rule_names: &Vec<
         let b_rules = sandtype.1.get("base_rules");
         if let Some(b_rules) = b_rules {
             if let Some(b_rules) = b_rules.as_sequence() {
-                for baserule in b_rules {
+                for (rule_idx, baserule) in b_rules.iter().enumerate() {
                     // If the thing defined as rulename isnt even a string, error
                     let rulename = baserule.as_str().ok_or(
                         anyhow!(ParsingErr::InvalidType {
-                            wrong_type: "base_rules",
-                            missing_in: format!("types/{}", name),
+                            wrong_type: "base_rules".to_string(),
+                            missing_in: format!("types/{}/base_rules/{}", name, rule_idx),
                             expected: TYPE_HINT_STRING
-                        }))?;
+                        }));
+                    let Some(rulename) = report.check(rulename) else {
+                        continue;
+                    };
                     
                     // Checks all defined rules for the name of the base_rule
                     if !rule_names.contains(&rulename.to_string()) {
-                        bail!(anyhow!(ParsingErr::NotFound::<bool> {
+                        report.error(anyhow!(ParsingErr::NotFound {
                             missing: rulename.to_string(),
-                            missing_in: format!("types/{}/base_rules", name)
+                            missing_in: format!("types/{}/base_rules/{}", name, rule_idx)
                         }));
+                        continue;
                     };
 
-                    let rule = rules.iter_mut().find(|r| {&r.name == rulename}).unwrap();
-                    rule.used = true;
-                    update_rule_precondition(rule, &name);
+                    // The rule might have been skipped because of its own errors
+                    if let Some(rule) = rules.iter_mut().find(|r| {r.name == rulename}) {
+                        rule.used = true;
+                    }
                     base_rules.push(rulename.to_string());
                 }
             } else {
                 // base_rules is not a sequence, error
-                bail!(ParsingErr::InvalidType {
-                    wrong_type: "base_rules",
-                    missing_in: format!("types/{}", name),
+                report.error(anyhow!(ParsingErr::InvalidType {
+                    wrong_type: "base_rules".to_string(),
+                    missing_in: format!("types/{}/base_rules", name),
                     expected: TYPE_HINT_SEQUENCE,
-                });
+                }));
             }
         };
 
-        let s_type = SandType {
+        type_structs.push(SandType {
             id: idx,
             name,
             inherits: parent,
             children: vec![],
             base_rules
-        };
-        type_structs.push(s_type.clone());
-        glsl_structs.push(Box::new(s_type));
+        });
 
         idx += 1;
     };
 
-    for sandtype in type_structs.clone() {
-        if sandtype.inherits.is_empty() {
-            continue;
-        };
-        for rule in get_parents_rules(&type_structs, &sandtype) {
-            let rule = rules.iter_mut().find(|r| {r.name == rule}).unwrap();
-            update_rule_precondition(rule, &sandtype.name);
+    // Types that are part of a cycle lose their parent, so the rest of the graph can still be resolved
+    for cycle in find_inheritance_cycles(&type_structs) {
+        let first = &type_structs[cycle[0]];
+        let names = cycle.iter()
+            .chain(std::iter::once(&cycle[0]))
+            .map(|&t| type_structs[t].name.as_str())
+            .collect::<Vec<&str>>()
+            .join(" -> ");
+        report.error(anyhow!(ParsingErr::InheritanceCycle {
+            cycle: names,
+            missing_in: format!("types/{}/inherits", first.name)
+        }));
+        for t in cycle {
+            type_structs[t].inherits.clear();
+        }
+    }
+
+    // Parents come before their children, so every type can build upon its (already resolved) parent
+    let order = topological_order(&type_structs);
+    for &t in order.iter() {
+        let ancestors = get_ancestors(&type_structs, t);
+
+        // Add ourselves to the children of all ancestors, which need this for their checker function
+        let name = type_structs[t].name.clone();
+        for &a in ancestors.iter() {
+            type_structs[a].children.push(name.clone());
+        }
+
+        // Update the rules precondition to let this type use the rule (own and inherited rules)
+        // = pass the precondition check
+        let mut type_rules: Vec<String> = vec![];
+        for &owner in std::iter::once(&t).chain(ancestors.iter()) {
+            for rule in type_structs[owner].base_rules.iter() {
+                if !type_rules.contains(rule) {
+                    type_rules.push(rule.clone());
+                }
+            }
+        }
+        for rule in type_rules {
+            if let Some(rule) = rules.iter_mut().find(|r| {r.name == rule}) {
+                if let Some(precondition) = &mut rule.precondition {
+                    if precondition.is_empty() {
+                        rule.precondition = Some(format!("isType_{}(self)", name));
+                    } else {
+                        rule.precondition = Some(format!("{} || isType_{}(self)", precondition, name));
+                    }
+                };
+            }
         }
     }
 
-    Ok((type_structs, glsl_structs))
+    let type_structs: Vec<SandType> = order.into_iter()
+        .map(|t| type_structs[t].clone())
+        .collect();
+    let glsl_structs: Vec<Box<dyn GLSLConvertible>> = type_structs.iter()
+        .map(|t| Box::new(t.clone()) as Box<dyn GLSLConvertible>)
+        .collect();
+
+    (type_structs, glsl_structs)
 }
 
 
-/// Recursively adds a type (child) to its parents and parents parents
-fn add_child_to_type(parent_name: &str, childname: &str, types: &mut Vec<SandType>) {
-    let mut parents_parent_name = String::new();
-    for t in types.iter_mut() {
-        if t.name == parent_name {
-            // We found our parent, updates our parents children
-            t.children.push(childname.to_string());
-            parents_parent_name = t.inherits.clone();
-            break;
+/// Finds all cycles in the inheritance graph. Each cycle is a list of type indices,
+/// starting with the type that was declared first
+fn find_inheritance_cycles(types: &[SandType]) -> Vec<Vec<usize>> {
+    let mut cycles: Vec<Vec<usize>> = vec![];
+    // Every type has at most one parent, so following the parents either
+    // ends at a root type or runs into a cycle
+    let mut visited = vec![false; types.len()];
+    for start in 0..types.len() {
+        let mut path: Vec<usize> = vec![];
+        let mut current = Some(start);
+        while let Some(t) = current {
+            if let Some(pos) = path.iter().position(|&p| p == t) {
+                cycles.push(path[pos..].to_vec());
+                break;
+            }
+            if visited[t] {
+                break;
+            }
+            visited[t] = true;
+            path.push(t);
+            current = get_parent(types, t);
         }
-    };
-    // This childs parent also has a parent, add this type to its grandparents children
-    if !parents_parent_name.is_empty() {
-        add_child_to_type(&parents_parent_name, childname, types);
     }
+    for cycle in cycles.iter_mut() {
+        let first = cycle.iter().enumerate().min_by_key(|(_, &t)| t).map(|(i, _)| i).unwrap_or(0);
+        cycle.rotate_left(first);
+    }
+    cycles
 }
 
-fn get_parents_rules(all_types: &Vec<SandType>, current_type: &SandType) -> Vec<String> {
-    if current_type.inherits.is_empty() {
-        return vec![];
-    };
-    let parent = all_types.iter().find(|parent| {parent.name == current_type.inherits}).unwrap();
-    let mut rules = parent.base_rules.clone();
-    rules.append(&mut get_parents_rules(all_types, parent));
-    rules
-}
\ No newline at end of file
+
+/// Orders the types, so that every parent comes before its children.
+/// Apart from that, the order of declaration is kept
+fn topological_order(types: &Vec<SandType>) -> Vec<usize> {
+    let mut order: Vec<usize> = vec![];
+    fn visit(types: &Vec<SandType>, t: usize, order: &mut Vec<usize>) {
+        if order.contains(&t) {
+            return;
+        }
+        if let Some(parent) = get_parent(types, t) {
+            visit(types, parent, order);
+        }
+        order.push(t);
+    }
+    for t in 0..types.len() {
+        visit(types, t, &mut order);
+    }
+    order
+}
+
+
+/// Index of the parent type, if the type inherits from another type
+fn get_parent(types: &[SandType], t: usize) -> Option<usize> {
+    if types[t].inherits.is_empty() {
+        return None;
+    }
+    types.iter().position(|p| p.name == types[t].inherits)
+}
+
+
+/// Indices of the parent, the parents parent and so on. Expects the graph to be free of cycles
+fn get_ancestors(types: &[SandType], t: usize) -> Vec<usize> {
+    let mut ancestors = vec![];
+    let mut current = get_parent(types, t);
+    while let Some(parent) = current {
+        ancestors.push(parent);
+        current = get_parent(types, parent);
+    }
+    ancestors
+}
@@ -0,0 +1,187 @@
+use std::{ops::Range, path::Path};
+
+use anyhow::{anyhow, Context};
+use colored::Colorize;
+
+use crate::parser::ParsingErr;
+use crate::parser::report::{Diagnostic, ParseReport, Severity};
+
+pub mod preprocessor;
+use preprocessor::{PreprocessedShader, Preprocessor, ShaderErr, SourceMap};
+
+
+/// The handwritten compute shader files (relative to `shaders/compute`), embedded into the
+/// binary so that the engine does not depend on the working directory. They are read from the
+/// root of the workspace, outside of this crate
+const COMPUTE_SHADER_FILES: [(&str, &str); 6] = [
+    ("falling_sand.glsl", include_str!("../../shaders/compute/falling_sand.glsl")),
+    ("cell.glsl", include_str!("../../shaders/compute/cell.glsl")),
+    ("directions.glsl", include_str!("../../shaders/compute/directions.glsl")),
+    ("material_helpers.glsl", include_str!("../../shaders/compute/material_helpers.glsl")),
+    ("math.glsl", include_str!("../../shaders/compute/math.glsl")),
+    ("operations.glsl", include_str!("../../shaders/compute/operations.glsl")),
+];
+/// The file that contains `main()` of the compute shader
+const COMPUTE_SHADER_MAIN: &str = "falling_sand.glsl";
+/// The generated file that contains the rules
+const RULES_FILE: &str = "gen/rules.glsl";
+
+
+/// Lines of a generated file that were generated from a node of the YAML file
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlslSpan {
+    /// Path of the YAML node (like `rules/fall/if`)
+    pub path: String,
+    /// Lines of the generated file, starting at 1
+    pub lines: Range<usize>,
+}
+
+
+/// The GLSL code generated from a ParsingResult. The generated files are included by the
+/// handwritten compute shader (`gen/<name>.glsl`).
+///
+/// The handwritten files are embedded from `shaders/compute` of the workspace, so this crate only
+/// builds as part of the workspace (not from a packaged `.crate` or as a git dependency on its own)
+#[derive(Debug, Clone)]
+pub struct GeneratedShaders {
+    /// gen/fields.glsl: the custom fields every cell carries
+    pub fields: String,
+    /// gen/thermal.glsl: the heat exchange and transitions
+    pub thermal: String,
+    /// gen/materials.glsl: materials and types
+    pub materials: String,
+    /// gen/rules.glsl: all rules and rule callers
+    pub rules: String,
+    /// The complete source of the compute shader, with all includes resolved
+    pub compute: String,
+    /// The files and lines the lines of `compute` come from
+    pub source_map: SourceMap,
+    /// The lines of gen/rules.glsl that were generated from each rule and each of its
+    /// conditions and actions. The spans of the conditions and actions lie inside of the span of their rule
+    pub rule_spans: Vec<GlslSpan>,
+    /// The handwritten files (name, contents), embedded unless they were loaded with `load_compute_files`
+    handwritten: Vec<(&'static str, String)>,
+}
+impl GeneratedShaders {
+    /// Assembles the compute shader from the handwritten files and the generated ones
+    pub fn new(fields: String, thermal: String, materials: String, rules: String, rule_spans: Vec<GlslSpan>) -> anyhow::Result<Self> {
+        let mut shaders = GeneratedShaders {
+            fields,
+            thermal,
+            materials,
+            rules,
+            compute: String::new(),
+            source_map: SourceMap::default(),
+            rule_spans,
+            handwritten: COMPUTE_SHADER_FILES.iter().map(|(name, src)| (*name, src.to_string())).collect(),
+        };
+        let compute = shaders.assemble(&[])?;
+        shaders.compute = compute.source;
+        shaders.source_map = compute.source_map;
+        Ok(shaders)
+    }
+
+    /// Names of the handwritten files (relative to `shaders/compute`)
+    pub fn compute_file_names() -> impl Iterator<Item = &'static str> {
+        COMPUTE_SHADER_FILES.iter().map(|(name, _)| *name)
+    }
+
+    /// Replaces the embedded handwritten files with the ones inside of the directory (usually `shaders/compute`)
+    /// and assembles the compute shader again. Files that don't exist in the directory stay embedded.
+    /// Used to edit the shaders while the engine is running
+    pub fn load_compute_files(&mut self, dir: &Path) -> anyhow::Result<()> {
+        for (name, src) in self.handwritten.iter_mut() {
+            let path = dir.join(name);
+            if path.is_file() {
+                *src = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Could not read '{}'", path.display()))?;
+            }
+        }
+        let compute = self.assemble(&[])?;
+        self.compute = compute.source;
+        self.source_map = compute.source_map;
+        Ok(())
+    }
+
+    /// Assembles the compute shader, with additional `#define`s (name, value) at the top
+    pub fn assemble(&self, defines: &[(&str, &str)]) -> Result<PreprocessedShader, ShaderErr> {
+        let mut preprocessor = Preprocessor::new(|path| self.source(path).map(String::from));
+        for (name, value) in defines {
+            preprocessor.define(name, value);
+        }
+        preprocessor.process(COMPUTE_SHADER_MAIN)
+    }
+
+    /// The generated files, as (file name, contents)
+    pub fn files(&self) -> [(&'static str, &str); 5] {
+        [
+            ("fields.glsl", &self.fields),
+            ("thermal.glsl", &self.thermal),
+            ("materials.glsl", &self.materials),
+            ("rules.glsl", &self.rules),
+            (COMPUTE_SHADER_MAIN, &self.compute),
+        ]
+    }
+
+    /// Writes the generated files and the assembled compute shader into the directory
+    /// (usually `shaders/compute/gen`), which is created if needed
+    pub fn write_to(&self, dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create the directory '{}'", dir.display()))?;
+        for (name, contents) in self.files() {
+            let path = dir.join(name);
+            std::fs::write(&path, contents)
+                .with_context(|| format!("Could not write '{}'", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Path of the YAML node a line of gen/rules.glsl was generated from (the innermost span)
+    pub fn rule_path(&self, line: usize) -> Option<&str> {
+        self.rule_spans.iter()
+            .filter(|s| s.lines.contains(&line))
+            .min_by_key(|s| s.lines.len())
+            .map(|s| s.path.as_str())
+    }
+
+    /// Turns the log of the GLSL compiler into diagnostics, which can be displayed using the report
+    /// of the ParsingResult. Messages about the code of a rule point at the YAML node it was generated
+    /// from (like `rules/fall/if`), all others at the original GLSL file and line
+    pub fn diagnose(&self, log: &str, report: &ParseReport) -> Vec<Diagnostic> {
+        let mut diagnostics = vec![];
+        for log_line in log.lines() {
+            let Some((file, line, message)) = self.source_map.locate(log_line) else {
+                continue;
+            };
+            let path = (file == RULES_FILE).then(|| self.rule_path(line)).flatten();
+            let mut diagnostic = match path {
+                Some(path) => {
+                    let mut diagnostic = Diagnostic::from(anyhow!(ParsingErr::InvalidGlsl {
+                        message: message.to_string(),
+                        missing_in: path.to_string()
+                    }));
+                    diagnostic.file = report.file_of(path).map(String::from);
+                    diagnostic
+                },
+                None => Diagnostic::from(anyhow!("{} {}", "(InvalidGlsl)".red(), self.source_map.translate(log_line.trim()))),
+            };
+            if log_line.to_lowercase().contains("warning") {
+                diagnostic.severity = Severity::Warning;
+            }
+            diagnostics.push(diagnostic);
+        }
+        // The format of the log is unknown, so at least show it
+        if diagnostics.is_empty() {
+            diagnostics.push(Diagnostic::from(anyhow!("{} {}", "(InvalidGlsl)".red(), self.source_map.translate(log.trim()))));
+        }
+        diagnostics
+    }
+
+    /// Source of a file the compute shader can include (relative to `shaders/compute`)
+    fn source(&self, path: &str) -> Option<&str> {
+        match path.strip_prefix("gen/") {
+            Some(name) => self.files()[..4].iter().find(|(n, _)| *n == name).map(|(_, src)| *src),
+            None => self.handwritten.iter().find(|(n, _)| *n == path).map(|(_, src)| src.as_str()),
+        }
+    }
+}
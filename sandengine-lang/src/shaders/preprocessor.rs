@@ -0,0 +1,308 @@
+use std::collections::HashSet;
+
+use colored::Colorize;
+use regex::{Captures, Regex};
+use thiserror::Error;
+
+
+
+const DIRECTIVE_INCLUDE: &str = "#include";
+const DIRECTIVE_PRAGMA_ONCE: &str = "#pragma once";
+const DIRECTIVE_VERSION: &str = "#version";
+
+
+#[derive(Debug, Error)]
+/// Errors while preprocessing a shader. Will be displayed in the console
+pub enum ShaderErr {
+    /// Emitted, when an included file does not exist
+    #[error("{} The file '{}' (included in '{}:{}') {}", "(IncludeNotFound)".red(), .include.bold(), .file.bold(), .line, "does not exist".bold())]
+    IncludeNotFound {
+        include: String,
+        file: String,
+        line: usize,
+    },
+
+    /// Emitted, when an include directive is not followed by a path in quotes
+    #[error("{} The directive '{}' (in '{}:{}') {}. Expected: '#include \"<path>\"'", "(InvalidInclude)".red(), .directive.bold(), .file.bold(), .line, "is invalid".bold())]
+    InvalidInclude {
+        directive: String,
+        file: String,
+        line: usize,
+    },
+
+    /// Emitted, when files include each other in a circle
+    #[error("{} The files '{}' {}. Use '#pragma once' or an include guard, if the file should only be included once", "(IncludeCycle)".red(), .cycle.bold(), "include each other".bold())]
+    IncludeCycle {
+        cycle: String,
+    },
+}
+
+
+/// Where the lines of a preprocessed shader come from. The index of a file is the
+/// source string number of the `#line` directives, so compiler messages already refer to the
+/// original line, only the file needs to be looked up
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    /// Paths of all included files, the main file is the first one
+    pub files: Vec<String>,
+    /// (file index, line) of every line of the output, starting at 1.
+    /// None for lines added by the preprocessor (`#line`, injected `#define`s)
+    pub lines: Vec<Option<(usize, usize)>>,
+}
+impl SourceMap {
+    /// File and line, a line of the output (starting at 1) comes from
+    pub fn original(&self, output_line: usize) -> Option<(&str, usize)> {
+        let (file, line) = (*self.lines.get(output_line.checked_sub(1)?)?)?;
+        Some((&self.files[file], line))
+    }
+
+    /// Replaces the locations in the log of the GLSL compiler with the file name and line,
+    /// e.g. `0(42) : error` (NVIDIA) or `ERROR: 3:42: ...` (AMD, Intel, Mesa) become `operations.glsl:42`
+    pub fn translate(&self, log: &str) -> String {
+        log_location_regex().replace_all(log, |caps: &Captures| {
+            let source: usize = caps[2].parse().unwrap_or(usize::MAX);
+            let line = caps.get(3).or(caps.get(4)).map_or("", |l| l.as_str());
+            match self.files.get(source) {
+                Some(file) => format!("{}{}:{}", &caps[1], file, line),
+                None => caps[0].to_string(),
+            }
+        }).to_string()
+    }
+
+    /// File and line a single line of the compiler log refers to, together with the rest of
+    /// the message (after the location)
+    pub fn locate<'l>(&self, log_line: &'l str) -> Option<(&str, usize, &'l str)> {
+        let caps = log_location_regex().captures(log_line)?;
+        let file = self.files.get(caps[2].parse::<usize>().ok()?)?;
+        let line = caps.get(3).or(caps.get(4))?.as_str().parse().ok()?;
+        let message = log_line[caps.get(0)?.end()..].trim_start_matches([' ', ':']);
+        Some((file, line, message))
+    }
+}
+
+
+/// Matches the location at the start of a line of the compiler log: the prefix, the source string number
+/// and the line (either as `3:42` or as `3(42)`)
+fn log_location_regex() -> Regex {
+    Regex::new(r"(?m)^(\s*(?:ERROR: |WARNING: )?)(\d+)(?::(\d+)|\((\d+)\))").unwrap()
+}
+
+
+/// The result of preprocessing a shader
+#[derive(Debug, Clone)]
+pub struct PreprocessedShader {
+    pub source: String,
+    pub source_map: SourceMap,
+}
+
+
+/// Returns the source of a file, given its path
+type Resolver<'a> = Box<dyn Fn(&str) -> Option<String> + 'a>;
+
+
+/// Resolves `#include "<path>"` directives (recursively, relative to the including file),
+/// skips files marked with `#pragma once` or an include guard after the first time and
+/// injects `#define`s after the `#version`. `#line` directives keep the line numbers of the
+/// compiler messages pointing at the original files
+pub struct Preprocessor<'a> {
+    resolve: Resolver<'a>,
+    defines: Vec<(String, String)>,
+}
+impl<'a> Preprocessor<'a> {
+    pub fn new(resolve: impl Fn(&str) -> Option<String> + 'a) -> Self {
+        Preprocessor {
+            resolve: Box::new(resolve),
+            defines: vec![],
+        }
+    }
+
+    /// Adds `#define <name> <value>` to the top of the shader (after the `#version`)
+    pub fn define(&mut self, name: &str, value: &str) -> &mut Self {
+        self.defines.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Preprocesses the file with the path (and all files it includes)
+    pub fn process(&self, path: &str) -> Result<PreprocessedShader, ShaderErr> {
+        let source = (self.resolve)(path).ok_or(ShaderErr::IncludeNotFound {
+            include: path.to_string(),
+            file: "<root>".to_string(),
+            line: 0,
+        })?;
+
+        let mut state = State::default();
+        state.files.push(path.to_string());
+        let mut lines = source.lines().peekable();
+        let mut first_line = 1;
+        // The version needs to be the first line of a shader
+        if let Some(version) = lines.next_if(|l| l.trim_start().starts_with(DIRECTIVE_VERSION)) {
+            state.push(version, Some((0, 1)));
+            first_line = 2;
+        }
+        for (name, value) in self.defines.iter() {
+            state.push(&format!("#define {} {}", name, value), None);
+        }
+        if !self.defines.is_empty() {
+            state.push(&format!("#line {} 0", first_line), None);
+        }
+
+        let lines: Vec<&str> = lines.collect();
+        self.process_lines(path, 0, &lines, first_line, &mut vec![path.to_string()], &mut state)?;
+        Ok(PreprocessedShader {
+            source: state.output,
+            source_map: SourceMap {
+                files: state.files,
+                lines: state.lines,
+            },
+        })
+    }
+
+    /// Processes the lines of a file, the first one has the number `first_line`.
+    /// `stack` holds the paths of the files that are currently being included
+    fn process_lines(&self, path: &str, file_idx: usize, lines: &[&str], first_line: usize, stack: &mut Vec<String>, state: &mut State) -> Result<(), ShaderErr> {
+        let mut in_comment = false;
+        for (idx, line) in lines.iter().enumerate() {
+            let line_nr = first_line + idx;
+            let was_in_comment = in_comment;
+            in_comment = ends_in_block_comment(line, in_comment);
+            let directive = line.trim_start();
+            if was_in_comment {
+                state.push(line, Some((file_idx, line_nr)));
+            } else if directive.starts_with(DIRECTIVE_PRAGMA_ONCE) {
+                state.once.insert(path.to_string());
+                // Keeps the line numbers the same
+                state.push("", Some((file_idx, line_nr)));
+            } else if let Some(include) = directive.strip_prefix(DIRECTIVE_INCLUDE) {
+                let include = include.trim().strip_prefix('"')
+                    .and_then(|i| i.split_once('"'))
+                    .map(|(i, _)| i)
+                    .ok_or(ShaderErr::InvalidInclude {
+                        directive: directive.to_string(),
+                        file: path.to_string(),
+                        line: line_nr,
+                    })?;
+                let include = join_path(path, include);
+                self.include(&include, (path, line_nr), stack, state)?;
+                state.push(&format!("#line {} {}", line_nr + 1, file_idx), None);
+            } else {
+                state.push(line, Some((file_idx, line_nr)));
+            }
+        }
+        Ok(())
+    }
+
+    fn include(&self, path: &str, (from, line): (&str, usize), stack: &mut Vec<String>, state: &mut State) -> Result<(), ShaderErr> {
+        if state.once.contains(path) {
+            return Ok(());
+        }
+        if let Some(start) = stack.iter().position(|p| p == path) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(path.to_string());
+            return Err(ShaderErr::IncludeCycle { cycle: cycle.join(" -> ") });
+        }
+        let source = (self.resolve)(path).ok_or(ShaderErr::IncludeNotFound {
+            include: path.to_string(),
+            file: from.to_string(),
+            line,
+        })?;
+
+        let lines: Vec<&str> = source.lines().collect();
+        let guard = include_guard(&lines);
+        if guard.as_ref().is_some_and(|g| state.guards.contains(g) || self.defines.iter().any(|(name, _)| name == g)) {
+            return Ok(());
+        }
+
+        let file_idx = match state.files.iter().position(|f| f == path) {
+            Some(idx) => idx,
+            None => {
+                state.files.push(path.to_string());
+                state.files.len() - 1
+            },
+        };
+        state.push(&format!("#line 1 {}", file_idx), None);
+        stack.push(path.to_string());
+        self.process_lines(path, file_idx, &lines, 1, stack, state)?;
+        stack.pop();
+        if let Some(guard) = guard {
+            state.guards.insert(guard);
+        }
+        Ok(())
+    }
+}
+
+
+#[derive(Default)]
+struct State {
+    output: String,
+    files: Vec<String>,
+    lines: Vec<Option<(usize, usize)>>,
+    /// Files that contain `#pragma once`
+    once: HashSet<String>,
+    /// Macros of the include guards of all included files
+    guards: HashSet<String>,
+}
+impl State {
+    fn push(&mut self, line: &str, origin: Option<(usize, usize)>) {
+        self.output.push_str(line);
+        self.output.push('\n');
+        self.lines.push(origin);
+    }
+}
+
+
+/// The macro of an include guard (`#ifndef X`, `#define X` as the first directives,
+/// `#endif` as the last one), if the file has one
+fn include_guard(lines: &[&str]) -> Option<String> {
+    let mut code = lines.iter()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with("//"));
+    let name = code.next()?.strip_prefix("#ifndef")?.trim();
+    let define = code.next()?.strip_prefix("#define")?.trim();
+    let ends_with_endif = code.next_back().is_some_and(|l| l.starts_with("#endif"));
+    (name == define && ends_with_endif).then(|| name.to_string())
+}
+
+
+/// Whether a block comment (`/* */`) is still open at the end of the line
+fn ends_in_block_comment(line: &str, mut in_comment: bool) -> bool {
+    let mut rest = line;
+    loop {
+        if in_comment {
+            match rest.find("*/") {
+                Some(end) => {
+                    rest = &rest[end + 2..];
+                    in_comment = false;
+                },
+                None => return true,
+            }
+        } else {
+            let start = rest.find("/*");
+            let line_comment = rest.find("//");
+            match (start, line_comment) {
+                (Some(start), Some(lc)) if lc < start => return false,
+                (Some(start), _) => {
+                    rest = &rest[start + 2..];
+                    in_comment = true;
+                },
+                (None, _) => return false,
+            }
+        }
+    }
+}
+
+
+/// Path of an included file, relative to the directory of the including file
+fn join_path(from: &str, include: &str) -> String {
+    let mut parts: Vec<&str> = from.split('/').collect();
+    parts.pop();
+    for part in include.split('/') {
+        match part {
+            "." => (),
+            ".." => {
+                parts.pop();
+            },
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
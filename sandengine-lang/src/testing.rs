@@ -0,0 +1,87 @@
+use std::fmt;
+
+use colored::Colorize;
+
+use crate::ParsingResult;
+use crate::interpreter::Interpreter;
+use crate::parser::rule_tests::SandTest;
+
+
+
+/// Symbol of materials that don't have one
+const UNKNOWN_SYMBOL: char = '?';
+
+
+/// Result of running a test from the 'tests' section
+#[derive(Debug, Clone)]
+pub struct TestOutcome {
+    pub name: String,
+    pub steps: u32,
+    /// Expected rows of the grid, as material symbols
+    pub expected: Vec<String>,
+    /// Rows of the grid after the steps, as material symbols
+    pub actual: Vec<String>,
+}
+impl TestOutcome {
+    pub fn passed(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+impl fmt::Display for TestOutcome {
+    /// The name of the test and, if it failed, both grids next to each other.
+    /// Rows that differ are marked with '<'
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.passed() {
+            return write!(f, "test {} ... {}", self.name, "ok".green());
+        }
+        writeln!(f, "test {} ... {} (after {} steps)", self.name, "FAILED".red().bold(), self.steps)?;
+        let width = self.expected[0].chars().count().max("expected".len());
+        write!(f, "    {}   {}", format!("{:width$}", "expected", width = width).bold(), "actual".bold())?;
+        for (expected, actual) in self.expected.iter().zip(self.actual.iter()) {
+            let marker = match expected == actual {
+                true => "",
+                false => "  <",
+            };
+            write!(f, "\n    {:width$} | {}{}", expected, actual, marker.red().bold(), width = width)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Runs a test on the CPU (see `Interpreter`): fills a grid with the cells of `before`, simulates
+/// the steps and compares the result with `after`. Outside of the grid is WALL
+pub fn run_test(result: &ParsingResult, test: &SandTest) -> TestOutcome {
+    let mut interpreter = Interpreter::new(result, test.seed);
+    interpreter.force_mirror(test.mirror);
+
+    let mut grid = interpreter.new_grid(test.before[0].len(), test.before.len());
+    for (y, row) in test.before.iter().enumerate() {
+        for (x, id) in row.iter().enumerate() {
+            interpreter.set(&mut grid, x as i32, y as i32, *id);
+        }
+    }
+    for _ in 0..test.steps {
+        interpreter.step(&mut grid);
+    }
+
+    let symbol = |id: u16| result.material(id)
+        .and_then(|m| m.symbol)
+        .unwrap_or(UNKNOWN_SYMBOL);
+    TestOutcome {
+        name: test.name.clone(),
+        steps: test.steps,
+        expected: test.after.iter()
+            .map(|row| row.iter().map(|id| symbol(*id)).collect())
+            .collect(),
+        actual: grid.cells.chunks(grid.width)
+            .map(|row| row.iter().map(|id| symbol(*id)).collect())
+            .collect(),
+    }
+}
+
+
+/// Runs all tests of the 'tests' section, in the order they are defined
+pub fn run_tests(result: &ParsingResult) -> Vec<TestOutcome> {
+    result.tests.iter().map(|test| run_test(result, test)).collect()
+}
@@ -1 +1,308 @@
-// TODO
\ No newline at end of file
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use colored::Colorize;
+use serde_yaml::Value;
+
+use crate::{ParsingResult, parser::{ParsingErr, BUILTIN_NAMES, TYPE_HINT_MAPPING, TYPE_HINT_SEQUENCE, report::{Diagnostic, ParseReport, Severity}, materials::SandMaterial, rules::{Action, SandRule, SandRuleType}, thermal}};
+
+
+
+/// A single semantic check that is run over a ParsingResult.
+/// Each check has a stable code (like `V001`) and a name (like `unused_rule`),
+/// both can be used to allow or deny the check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Check {
+    /// A rule is neither used as a base_rule of a type nor as an extra_rule of a material
+    UnusedRule,
+    /// An entry of `extra_rules` names a rule which doesn't exist
+    UnknownExtraRule,
+    /// A non-mirrored rule references cells on both sides (LEFT and RIGHT)
+    MixedDirections,
+    /// A rule references a cell which it doesn't get passed (e.g. LEFT in a mirrored rule)
+    UnreceivedCell,
+    /// A `SET` or `SPAWN` places NULL, which only marks cells outside of the world and unknown ids
+    InvalidSetTarget,
+    /// Two materials have the same density, so swap rules comparing densities can't decide between them
+    DuplicateDensity,
+    /// A reactant of a reaction never ends up in a block with the other one (e.g. it is never placed)
+    UnreachableReaction,
+}
+impl Check {
+    pub const ALL: [Check; 7] = [
+        Check::UnusedRule,
+        Check::UnknownExtraRule,
+        Check::MixedDirections,
+        Check::UnreceivedCell,
+        Check::InvalidSetTarget,
+        Check::DuplicateDensity,
+        Check::UnreachableReaction,
+    ];
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Check::UnusedRule => "V001",
+            Check::UnknownExtraRule => "V002",
+            Check::MixedDirections => "V003",
+            Check::UnreceivedCell => "V004",
+            Check::InvalidSetTarget => "V005",
+            Check::DuplicateDensity => "V006",
+            Check::UnreachableReaction => "V007",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Check::UnusedRule => "unused_rule",
+            Check::UnknownExtraRule => "unknown_extra_rule",
+            Check::MixedDirections => "mixed_directions",
+            Check::UnreceivedCell => "unreceived_cell",
+            Check::InvalidSetTarget => "invalid_set_target",
+            Check::DuplicateDensity => "duplicate_density",
+            Check::UnreachableReaction => "unreachable_reaction",
+        }
+    }
+
+    /// Level of the check, if it was neither allowed nor denied.
+    /// Everything that would produce broken GLSL code is denied by default
+    pub fn default_level(&self) -> Level {
+        match self {
+            Check::UnusedRule | Check::DuplicateDensity | Check::UnreachableReaction => Level::Warn,
+            _ => Level::Deny,
+        }
+    }
+
+    /// Finds the check by its code or name
+    pub fn from_name(name: &str) -> Option<Check> {
+        Check::ALL.into_iter().find(|c| c.code() == name || c.name() == name)
+    }
+}
+
+
+/// What happens when a check finds something
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// The finding is ignored
+    Allow,
+    /// The finding is reported as a warning
+    Warn,
+    /// The finding is reported as an error
+    Deny,
+}
+
+
+/// Levels of all checks that differ from their default level
+#[derive(Debug, Clone, Default)]
+pub struct CheckLevels {
+    levels: HashMap<Check, Level>,
+}
+impl CheckLevels {
+    pub fn set(&mut self, check: Check, level: Level) {
+        self.levels.insert(check, level);
+    }
+
+    pub fn get(&self, check: Check) -> Level {
+        self.levels.get(&check).copied().unwrap_or(check.default_level())
+    }
+}
+
+
+/// Reads the optional 'checks' section of the YAML, which allows or denies checks:
+///
+/// ```yaml
+/// checks:
+///     allow: [unused_rule]
+///     deny: [V006]
+/// ```
+pub fn parse_check_levels(data: &Value, report: &mut ParseReport) -> CheckLevels {
+    let mut levels = CheckLevels::default();
+    let Some(checks) = data.get("checks") else {
+        return levels;
+    };
+    if !checks.is_mapping() {
+        report.error(anyhow!(ParsingErr::InvalidType {
+            wrong_type: "checks".to_string(),
+            missing_in: "checks".to_string(),
+            expected: TYPE_HINT_MAPPING,
+        }));
+        return levels;
+    }
+
+    for (key, level) in [("allow", Level::Allow), ("warn", Level::Warn), ("deny", Level::Deny)] {
+        let Some(names) = checks.get(key) else {
+            continue;
+        };
+        let Some(names) = names.as_sequence() else {
+            report.error(anyhow!(ParsingErr::InvalidType {
+                wrong_type: key.to_string(),
+                missing_in: format!("checks/{}", key),
+                expected: TYPE_HINT_SEQUENCE,
+            }));
+            continue;
+        };
+        for (idx, name) in names.iter().enumerate() {
+            match name.as_str().and_then(Check::from_name) {
+                Some(check) => levels.set(check, level),
+                None => report.error(anyhow!(ParsingErr::NotFound {
+                    missing: name.as_str().map(String::from).unwrap_or_else(|| format!("{:?}", name)),
+                    missing_in: format!("checks/{}/{}", key, idx),
+                })),
+            }
+        }
+    }
+    levels
+}
+
+
+/// Runs all checks over the ParsingResult and returns the findings,
+/// which are not allowed, as diagnostics
+pub fn validate(result: &ParsingResult, levels: &CheckLevels) -> Vec<Diagnostic> {
+    let mut findings: Vec<(Check, String, String)> = vec![];
+
+    for rule in result.rules.iter() {
+        check_rule(rule, &mut findings);
+    }
+
+    for mat in result.materials.iter() {
+        // Unknown extra rules
+        for (idx, extra_rule) in mat.extra_rules.iter().enumerate() {
+            if !result.rules.iter().any(|r| &r.name == extra_rule) {
+                findings.push((Check::UnknownExtraRule,
+                    format!("The rule '{}' (in extra_rules of '{}') {}", extra_rule.bold(), mat.name.bold(), "does not exist".bold()),
+                    format!("materials/{}/extra_rules/{}", mat.name, idx)));
+            }
+        }
+
+        // Duplicate densities, only reported at the later material.
+        // EMPTY, NULL and WALL are not moved by swap rules, so they are left out
+        let is_builtin = |m: &SandMaterial| BUILTIN_NAMES.contains(&m.name.as_str());
+        let same_density = result.materials.iter()
+            .take_while(|m| m.id != mat.id)
+            .filter(|m| !is_builtin(m))
+            .find(|m| m.density == mat.density && !is_builtin(mat));
+        if let Some(other) = same_density {
+            findings.push((Check::DuplicateDensity,
+                format!("The materials '{}' and '{}' {} ({}), swap rules comparing densities can't decide between them",
+                    other.name.bold(), mat.name.bold(), "have the same density".bold(), mat.density),
+                format!("materials/{}/density", mat.name)));
+        }
+    }
+
+    // Reactions can only happen if both reactants are part of a block at some point
+    let placed = placed_materials(result);
+    for reaction in result.reactions.iter() {
+        for (idx, reactant) in reaction.reactants.iter().enumerate() {
+            let reason = match reactant.as_str() {
+                // Cells outside of the screen are WALL, so NULL is never part of a block
+                "NULL" => "is never part of a block",
+                _ if !placed.contains(reactant) => "is never placed (it is neither selectable nor created by a rule or reaction)",
+                _ => continue,
+            };
+            findings.push((Check::UnreachableReaction,
+                format!("The reaction '{}' {}, since its reactant '{}' {}", reaction.name.bold(), "can never happen".bold(), reactant.bold(), reason),
+                format!("reactions/{}/reactants/{}", reaction.name, idx)));
+        }
+    }
+
+    findings.into_iter()
+        .filter_map(|(check, message, path)| {
+            let severity = match levels.get(check) {
+                Level::Allow => return None,
+                Level::Warn => Severity::Warning,
+                Level::Deny => Severity::Error,
+            };
+            Some(Diagnostic {
+                severity,
+                code: Some(check.code()),
+                message: format!("{} ({})", message, check.name()),
+                file: None,
+                path: Some(path),
+                span: None,
+                location: None,
+                related: vec![],
+            })
+        })
+        .collect()
+}
+
+
+/// Runs the checks that concern a single rule
+fn check_rule(rule: &SandRule, findings: &mut Vec<(Check, String, String)>) {
+    let rule_path = &rule.path;
+    if !rule.used {
+        findings.push((Check::UnusedRule,
+            format!("The rule '{}' {}. Add it to the base_rules of a type or the extra_rules of a material", rule.name.bold(), "is never used".bold()),
+            rule_path.clone()));
+    }
+
+    let cells = rule.cells();
+    let uses_left = cells.iter().any(|c| c == "LEFT" || c == "DOWNLEFT");
+    let uses_right = cells.iter().any(|c| c == "RIGHT" || c == "DOWNRIGHT");
+    if rule.ruletype != SandRuleType::Mirrored && uses_left && uses_right {
+        findings.push((Check::MixedDirections,
+            format!("The non-mirrored rule '{}' {}. Split it into two rules or make it mirrored", rule.name.bold(), "uses both LEFT and RIGHT cells".bold()),
+            rule_path.clone()));
+    } else {
+        let received = rule.received_cells();
+        for cell in cells.iter().filter(|c| !received.contains(&c.as_str())) {
+            findings.push((Check::UnreceivedCell,
+                format!("The rule '{}' {} '{}'. It only gets passed {}", rule.name.bold(), "references the cell".bold(), cell.bold(), received.join(", ")),
+                rule_path.clone()));
+        }
+    }
+
+    // Each if-else level is nested one 'else' deeper
+    for (level, actions) in rule.do_actions.iter().enumerate() {
+        let do_path = format!("{}{}/do", rule_path, "/else".repeat(level));
+        check_actions(actions, &do_path, findings);
+    }
+}
+
+
+/// Materials that can exist in the world: the selectable ones, the ones that actions
+/// or reactions create, the results of temperature transitions and EMPTY and WALL
+fn placed_materials(result: &ParsingResult) -> Vec<String> {
+    fn created_by(actions: &[Action], placed: &mut Vec<String>) {
+        for action in actions {
+            match action {
+                Action::Set(_, mat) | Action::Spawn(mat, _, _) => placed.push(mat.clone()),
+                Action::Choose(_, choices) => choices.iter().for_each(|(_, actions)| created_by(actions, placed)),
+                _ => (),
+            }
+        }
+    }
+
+    let mut placed = vec!["EMPTY".to_string(), "WALL".to_string()];
+    placed.extend(result.materials.iter().filter(|m| m.selectable).map(|m| m.name.clone()));
+    for rule in result.rules.iter().filter(|r| r.used) {
+        rule.do_actions.iter().for_each(|actions| created_by(actions, &mut placed));
+    }
+    placed.extend(result.reactions.iter().flat_map(|r| r.products.iter().cloned()));
+    placed.extend(thermal::transitions(&result.materials).into_iter().map(|t| t.to));
+    placed
+}
+
+
+/// Checks that the actions only place materials which can exist in the world, including the actions inside of a CHOOSE
+fn check_actions(actions: &[Action], do_path: &str, findings: &mut Vec<(Check, String, String)>) {
+    for (idx, action) in actions.iter().enumerate() {
+        let path = format!("{}/{}", do_path, idx);
+        let (keyword, target) = match action {
+            Action::Set(_, target) => ("SET", target),
+            Action::Spawn(target, _, _) => ("SPAWN", target),
+            Action::Choose(_, choices) => {
+                for (choice_idx, (_, actions)) in choices.iter().enumerate() {
+                    check_actions(actions, &format!("{}/CHOOSE/{}/do", path, choice_idx), findings);
+                }
+                continue;
+            },
+            _ => continue,
+        };
+        // Unknown materials are already rejected by the parser
+        if target == "NULL" {
+            findings.push((Check::InvalidSetTarget,
+                format!("The target '{}' of '{}' {}", target.bold(), keyword.bold(), "can't be placed".bold()),
+                path));
+        }
+    }
+}
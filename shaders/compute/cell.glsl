@@ -1,11 +1,14 @@
+#pragma once
 
 
 
 struct Cell {
     Material mat;
     ivec2 pos;
+    // Custom fields (see gen/fields.glsl), they move together with the cell
+    Fields fields;
 };
 
 Cell newCell(Material mat, ivec2 pos) {
-    return Cell(mat, pos);
+    return Cell(mat, pos, defaultFields(mat));
 }
\ No newline at end of file
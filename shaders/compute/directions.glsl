@@ -1,3 +1,4 @@
+#pragma once
 #define UP ivec2(0, -1)
 #define DOWN ivec2(0, 1)
 #define LEFT ivec2(-1, 0)
@@ -16,15 +16,19 @@ struct Material {
     vec4 emission;
 
     int type;
+    // How well the material exchanges heat with its neighbours
+    float conductivity;
 };
+#include "gen/fields.glsl"
 #include "cell.glsl"
 
 #include "gen/materials.glsl"
 
 //#include "material_helpers.glsl"
 
-uniform sampler2D input_data;
-layout(rgba32f) uniform writeonly image2D output_data;
+// One layer per 4 values of the cell data (material id and custom fields)
+uniform sampler2DArray input_data;
+layout(rgba32f) uniform writeonly image2DArray output_data;
 layout(rgba32f) uniform writeonly image2D output_color;
 // uniform Params {
 // } params 
@@ -32,6 +36,8 @@ uniform bool moveRight;
 uniform float time;
 uniform ivec2 simSize;
 uniform int frame;
+// Seed of all random values, see Params::seed
+uniform uint seed;
 
 layout(rgba32f) uniform image2D collision_data;
 
@@ -42,20 +48,38 @@ layout(r32ui, binding = 7) uniform volatile coherent uimage2D image_lock;
 
 #define MODSHAPE_CIRCLE 0
 #define MODSHAPE_SQUARE 1
+// A circle, in which only some of the cells are modified (see mod_density)
+#define MODSHAPE_SPRAY 2
 
 struct SimModification {
     ivec2 position;
     int mod_shape;
     int mod_size;
     int mod_matID;
+    // Only cells of this material are modified (-1: all cells)
+    int mod_replaceID;
+    // Fraction of the cells a spray modifies
+    float mod_density;
 };
 
-uniform SimModifications {
-    SimModification sim_modifications[256];
+// The modifications of this frame, their number changes from frame to frame
+layout(std430) buffer SimModifications {
+    SimModification sim_modifications[];
 };
 
+// Width and height of the tiles the modifications are sorted into, see modifications::TILE_SIZE
+#define MOD_TILE_SIZE 16
+// The first entries are the offset of every tile (and one after the last tile), the entries of a tile
+// are mod_tiles[mod_tiles[tile]] to mod_tiles[mod_tiles[tile + 1] - 1]. They are indices into sim_modifications
+layout(std430) buffer ModificationTiles {
+    uint mod_tiles[];
+};
+// Number of tiles in a row
+uniform int modTilesX;
+
 #include "operations.glsl"
 #include "gen/rules.glsl"
+#include "gen/thermal.glsl"
 
 
 Cell simulate() {
@@ -80,8 +104,10 @@ Cell simulate() {
 
     Cell up = getCell(pos_rounded + UP);
     Cell upright = getCell(pos_rounded + UPRIGHT);
-    vec4 rand = hash43(uvec3(pos_rounded, frame));
-    vec4 rand2 = hash43(uvec3(pos_rounded, frame/8));
+    vec4 rand = hash43(uvec3(pos_rounded, frame), seed);
+    vec4 rand2 = hash43(uvec3(pos_rounded, frame/8), seed);
+
+    applyTemperature(self, right, down, downright);
 
     bool shouldMirror = rand.x < 0.5;
     if (shouldMirror) {
@@ -134,12 +160,14 @@ void main() {
     bool got_modified = false;
     Material final_modification_mat = MAT_NULL;
     int count = 0;
-    for (int i = 0; i < sim_modifications.length(); i++) {
-        SimModification mod = sim_modifications[i];
-        if (mod.mod_size == 0) {
-            break;
+    ivec2 mod_tile = pos / MOD_TILE_SIZE;
+    int tile_idx = mod_tile.x + mod_tile.y * modTilesX;
+    for (uint i = mod_tiles[tile_idx]; i < mod_tiles[tile_idx + 1]; i++) {
+        SimModification mod = sim_modifications[mod_tiles[i]];
+
+        if (mod.mod_replaceID >= 0 && getCell(pos).mat.id != mod.mod_replaceID) {
+            continue;
         }
-        
         ivec2 diff_pos = abs(mod.position - pos);
         Material mat = getMaterialFromID(mod.mod_matID);
         switch (mod.mod_shape) {
@@ -158,6 +186,15 @@ void main() {
                     count++;
                 };
                 break;
+            case MODSHAPE_SPRAY:
+                float spray_dist = sqrt(pow(diff_pos.x, 2) + pow(diff_pos.y, 2));
+                // A different seed, so that the spray does not depend on RAND
+                if (spray_dist <= mod.mod_size && hash43(uvec3(pos, frame), seed + 1u).x < mod.mod_density) {
+                    got_modified = true;
+                    final_modification_mat = mat;
+                    count++;
+                };
+                break;
         }
     };
 
@@ -6,6 +6,9 @@ layout(local_size_x = 16, local_size_y = 16, local_size_z = 1) in;
 //#define DEBUG_SHOW_UPDATERECT
 //#define DEBUG_SHOW_ORIG_POS
 //#define DEBUG_SHOW_MOVERIGHT
+
+#line 1 1
+
 #define UP ivec2(0, -1)
 #define DOWN ivec2(0, 1)
 #define LEFT ivec2(-1, 0)
@@ -37,7 +40,8 @@ ivec2[2] getMoveDirs(ivec2 pos, bool moveRight) {
     arr[1] += pos;
     return arr;
 }
-
+#line 11 0
+#line 1 2
 
 // Gold Noise ©2015 dcerisano@standard3d.com
 // - based on the Golden Ratio
@@ -119,6 +123,13 @@ vec4 hash43(uvec3 p)
     //return vec4(hash(x), hash(x), hash(x), hash(x));
 }
 
+// Same as hash43, but every seed produces different values (seed 0 gives the same as hash43)
+vec4 hash43(uvec3 p, uint seed)
+{
+    uint x = p.x*461u + p.y*2131u + p.z*2131u*2131u + seed*0x9e3779b9u;
+    return vec4( hash4i(x) ) / float( 0xffffffffU );
+}
+
 
 float _noise( in vec2 p )
 {
@@ -205,9 +216,7 @@ ivec2[8] getDiagonalNeighbours(ivec2 pos) {
     };
     return neighs;
 }
-
-
-
+#line 12 0
 struct Material {
     int id;
     vec4 color;
@@ -215,20 +224,52 @@ struct Material {
     vec4 emission;
 
     int type;
+    // How well the material exchanges heat with its neighbours
+    float conductivity;
+};
+#line 1 3
+
+#define NUM_DATA_LAYERS 1
+
+struct Fields {
+    int _unused;
 };
 
+// Defined in materials.glsl, as it depends on the materials
+Fields defaultFields(Material mat);
+
+Fields unpackFields(vec4 data[NUM_DATA_LAYERS]) {
+    Fields fields = Fields(0);
+    return fields;
+}
+
+vec4[NUM_DATA_LAYERS] packFields(int id, Fields fields) {
+    vec4 data[NUM_DATA_LAYERS];
+    for (int i = 0; i < NUM_DATA_LAYERS; i++) {
+        data[i] = vec4(0.0);
+    }
+    data[0].r = float(id);
+    return data;
+}
+#line 23 0
+#line 1 4
+
+
+
 
 struct Cell {
     Material mat;
     ivec2 pos;
+    // Custom fields (see gen/fields.glsl), they move together with the cell
+    Fields fields;
 };
 
 Cell newCell(Material mat, ivec2 pos) {
-    return Cell(mat, pos);
+    return Cell(mat, pos, defaultFields(mat));
 }
+#line 24 0
 
-
-
+#line 1 5
 #define TYPE_EMPTY 0
 
 #define TYPE_NULL 1
@@ -278,17 +319,17 @@ bool isType_plant(Cell cell) {
 }
 
 
-#define MAT_EMPTY Material(0, vec4(0, 0, 0, 0), 1, vec4(0, 0, 0, 0), TYPE_EMPTY)
-#define MAT_NULL Material(1, vec4(1, 0, 1, 1), 0, vec4(0, 0, 0, 0), TYPE_NULL)
-#define MAT_WALL Material(2, vec4(0.1, 0.2, 0.3, 1), 9999, vec4(0, 0, 0, 0), TYPE_WALL)
-#define MAT_sand Material(3, vec4(1, 1, 0, 1), 1.5, vec4(0, 0, 0, 0), TYPE_movable_solid)
-#define MAT_rock Material(4, vec4(0.2, 0.2, 0.2, 1), 4, vec4(0, 0, 0, 0), TYPE_solid)
-#define MAT_water Material(5, vec4(0, 0, 1, 0.5), 1.3, vec4(0, 0, 0, 0), TYPE_liquid)
-#define MAT_radioactive Material(6, vec4(0.196, 0.55, 0.184, 1), 5, vec4(0.05, 0.7, 0.05, 0.9), TYPE_solid)
-#define MAT_smoke Material(7, vec4(0.3, 0.3, 0.3, 0.3), 0.1, vec4(0, 0, 0, 0), TYPE_gas)
-#define MAT_toxic_sludge Material(8, vec4(0, 0.7, 0, 0.5), 1.49, vec4(0.7, 0, 0, 0.99999), TYPE_liquid)
-#define MAT_vine Material(9, vec4(0.34117648, 0.49803922, 0.24313726, 1), 2.5, vec4(0, 0, 0, 0), TYPE_plant)
-#define MAT_dirt Material(10, vec4(0.43137255, 0.2784314, 0.14509805, 1), 1.5, vec4(0, 0, 0, 0), TYPE_movable_solid)
+#define MAT_EMPTY Material(0, vec4(0, 0, 0, 0), 1, vec4(0, 0, 0, 0), TYPE_EMPTY, 0)
+#define MAT_NULL Material(1, vec4(1, 0, 1, 1), 0, vec4(0, 0, 0, 0), TYPE_NULL, 0)
+#define MAT_WALL Material(2, vec4(0.1, 0.2, 0.3, 1), 9999, vec4(0, 0, 0, 0), TYPE_WALL, 0)
+#define MAT_sand Material(3, vec4(1, 1, 0, 1), 1.5, vec4(0, 0, 0, 0), TYPE_movable_solid, 0)
+#define MAT_rock Material(4, vec4(0.2, 0.2, 0.2, 1), 4, vec4(0, 0, 0, 0), TYPE_solid, 0)
+#define MAT_water Material(5, vec4(0, 0, 1, 0.5), 1.3, vec4(0, 0, 0, 0), TYPE_liquid, 0)
+#define MAT_radioactive Material(6, vec4(0.196, 0.55, 0.184, 1), 5, vec4(0.05, 0.7, 0.05, 0.9), TYPE_solid, 0)
+#define MAT_smoke Material(7, vec4(0.3, 0.3, 0.3, 0.3), 0.1, vec4(0, 0, 0, 0), TYPE_gas, 0)
+#define MAT_toxic_sludge Material(8, vec4(0, 0.7, 0, 0.5), 1.49, vec4(0.7, 0, 0, 0.99999), TYPE_liquid, 0)
+#define MAT_vine Material(9, vec4(0.34117648, 0.49803922, 0.24313726, 1), 2.5, vec4(0, 0, 0, 0), TYPE_plant, 0)
+#define MAT_dirt Material(10, vec4(0.43137255, 0.2784314, 0.14509805, 1), 1.5, vec4(0, 0, 0, 0), TYPE_movable_solid, 0)
 
 Material[11] materials() {
     Material allMaterials[11] = {
@@ -317,14 +358,16 @@ Material getMaterialFromID(int id) {
 }
 
 
-
-
-
+Fields defaultFields(Material mat) {
+    return Fields(0);
+}
+#line 26 0
 
 //#include "material_helpers.glsl"
 
-uniform sampler2D input_data;
-layout(rgba32f) uniform writeonly image2D output_data;
+// One layer per 4 values of the cell data (material id and custom fields)
+uniform sampler2DArray input_data;
+layout(rgba32f) uniform writeonly image2DArray output_data;
 layout(rgba32f) uniform writeonly image2D output_color;
 // uniform Params {
 // } params 
@@ -332,6 +375,8 @@ uniform bool moveRight;
 uniform float time;
 uniform ivec2 simSize;
 uniform int frame;
+// Seed of all random values, see Params::seed
+uniform uint seed;
 
 layout(rgba32f) uniform image2D collision_data;
 
@@ -342,17 +387,37 @@ layout(r32ui, binding = 7) uniform volatile coherent uimage2D image_lock;
 
 #define MODSHAPE_CIRCLE 0
 #define MODSHAPE_SQUARE 1
+// A circle, in which only some of the cells are modified (see mod_density)
+#define MODSHAPE_SPRAY 2
 
 struct SimModification {
     ivec2 position;
     int mod_shape;
     int mod_size;
     int mod_matID;
+    // Only cells of this material are modified (-1: all cells)
+    int mod_replaceID;
+    // Fraction of the cells a spray modifies
+    float mod_density;
+};
+
+// The modifications of this frame, their number changes from frame to frame
+layout(std430) buffer SimModifications {
+    SimModification sim_modifications[];
 };
 
-uniform SimModifications {
-    SimModification sim_modifications[256];
+// Width and height of the tiles the modifications are sorted into, see modifications::TILE_SIZE
+#define MOD_TILE_SIZE 16
+// The first entries are the offset of every tile (and one after the last tile), the entries of a tile
+// are mod_tiles[mod_tiles[tile]] to mod_tiles[mod_tiles[tile + 1] - 1]. They are indices into sim_modifications
+layout(std430) buffer ModificationTiles {
+    uint mod_tiles[];
 };
+// Number of tiles in a row
+uniform int modTilesX;
+
+#line 1 6
+
 
 
 
@@ -377,6 +442,47 @@ void swap(inout Cell a, inout Cell b) {
     b = tmp;
 }
 
+bool isBoundary(Cell cell) {
+    return cell.mat.type == TYPE_WALL || cell.mat.type == TYPE_NULL;
+}
+
+// Swaps the materials of both cells, the fields stay where they are
+void convert(inout Cell a, inout Cell b) {
+    if (isBoundary(a) || isBoundary(b)) {
+        return;
+    }
+    Material tmp = a.mat;
+    a.mat = b.mat;
+    b.mat = tmp;
+}
+
+// Turns 'dst' into a duplicate of 'src' (material and fields), only the position stays
+void copyCell(inout Cell dst, Cell src) {
+    if (isBoundary(dst) || isBoundary(src)) {
+        return;
+    }
+    dst.mat = src.mat;
+    dst.fields = src.fields;
+}
+
+// Moves 'src' into 'dst' if that is empty (material and fields) and leaves an empty cell behind
+void moveCell(inout Cell src, inout Cell dst) {
+    if (isBoundary(src) || dst.mat != MAT_EMPTY) {
+        return;
+    }
+    dst.mat = src.mat;
+    dst.fields = src.fields;
+    src = newCell(MAT_EMPTY, src.pos);
+}
+
+// Replaces 'dst' with a new cell of the material of 'src'
+void setMaterial(inout Cell dst, Cell src) {
+    if (isBoundary(dst) || isBoundary(src)) {
+        return;
+    }
+    dst = newCell(src.mat, dst.pos);
+}
+
 ivec2 getMargolusOffset(int frame) {
     frame = frame % 4;
     if (frame == 1)
@@ -404,11 +510,14 @@ Cell getCell(ivec2 pos) {
         #endif // SCREEN_IS_BORDER
         return newCell(MAT_NULL, pos);
     };
-    ivec4 data = ivec4(texelFetch(input_data, pos, 0));
-    // data: ___id___  00000000  00000000  00000000
-    int matID = int(data.r);
+    // data: ___id___  field 1  field 2  field 3  (| field 4 ...)
+    vec4 data[NUM_DATA_LAYERS];
+    for (int layer = 0; layer < NUM_DATA_LAYERS; layer++) {
+        data[layer] = texelFetch(input_data, ivec3(pos, layer), 0);
+    }
+    int matID = int(data[0].r);
 
-    return Cell(getMaterialFromID(matID), pos);
+    return Cell(getMaterialFromID(matID), pos, unpackFields(data));
 }
 
 Cell getCell(ivec2 pos, ivec2 offset) {
@@ -463,8 +572,10 @@ void setCell(ivec2 pos, Cell cell) {
     };
     
     //imageStore(output_effects, pos, vec4(cell.mat.emission, 1.0));
-    ivec4 data = ivec4(cell.mat.id, 0, 0, 0);
-    imageStore(output_data, pos, data);
+    vec4 data[NUM_DATA_LAYERS] = packFields(cell.mat.id, cell.fields);
+    for (int layer = 0; layer < NUM_DATA_LAYERS; layer++) {
+        imageStore(output_data, ivec3(pos, layer), data[layer]);
+    }
 
     ivec2[8] neighs = getDiagonalNeighbours(pos);
     Cell[8] neighCells;
@@ -527,9 +638,8 @@ void setCell(ivec2 pos, Cell cell) {
 void setCell(ivec2 pos, Material mat) {
     setCell(pos, newCell(mat, pos));
 }
-
-
-
+#line 81 0
+#line 1 7
 
 // =============== RULES ===============
 void rule_fall_slide (inout Cell self, inout Cell right, inout Cell down, inout Cell downright, vec4 rand, ivec2 pos) {
@@ -578,10 +688,10 @@ void rule_horizontal_slide (inout Cell self, inout Cell right, inout Cell down,
 }
 
 void rule_rise_up (inout Cell self, inout Cell right, inout Cell down, inout Cell downright, vec4 rand, ivec2 pos) {
-    if (isType_gas(down) &&  !isType_solid(self) && down.mat.density < self.mat.density) {
+    if (isType_gas(down) && !isType_solid(self) && down.mat.density < self.mat.density) {
         swap(down, self);
     } else {
-        if (isType_gas(down) &&  !isType_solid(right) && down.mat.density < right.mat.density) {
+        if (isType_gas(down) && !isType_solid(right) && down.mat.density < right.mat.density) {
             swap(down, right);
         } else {
 
@@ -651,9 +761,9 @@ rule_die_off(self, right, down, downright, rand, pos);
 
 void applyLeftRules(
     inout Cell self,
-    inout Cell right,
+    inout Cell left,
     inout Cell down,
-    inout Cell downright,
+    inout Cell downleft,
     vec4 rand,
     ivec2 pos) {
     
@@ -668,9 +778,12 @@ void applyRightRules(
     ivec2 pos) {
     
 }
+#line 82 0
+#line 1 8
 
-
-
+void applyTemperature(inout Cell self, inout Cell right, inout Cell down, inout Cell downright) {
+}
+#line 83 0
 
 
 Cell simulate() {
@@ -695,8 +808,10 @@ Cell simulate() {
 
     Cell up = getCell(pos_rounded + UP);
     Cell upright = getCell(pos_rounded + UPRIGHT);
-    vec4 rand = hash43(uvec3(pos_rounded, frame));
-    vec4 rand2 = hash43(uvec3(pos_rounded, frame/8));
+    vec4 rand = hash43(uvec3(pos_rounded, frame), seed);
+    vec4 rand2 = hash43(uvec3(pos_rounded, frame/8), seed);
+
+    applyTemperature(self, right, down, downright);
 
     bool shouldMirror = rand.x < 0.5;
     if (shouldMirror) {
@@ -749,12 +864,14 @@ void main() {
     bool got_modified = false;
     Material final_modification_mat = MAT_NULL;
     int count = 0;
-    for (int i = 0; i < sim_modifications.length(); i++) {
-        SimModification mod = sim_modifications[i];
-        if (mod.mod_size == 0) {
-            break;
+    ivec2 mod_tile = pos / MOD_TILE_SIZE;
+    int tile_idx = mod_tile.x + mod_tile.y * modTilesX;
+    for (uint i = mod_tiles[tile_idx]; i < mod_tiles[tile_idx + 1]; i++) {
+        SimModification mod = sim_modifications[mod_tiles[i]];
+
+        if (mod.mod_replaceID >= 0 && getCell(pos).mat.id != mod.mod_replaceID) {
+            continue;
         }
-        
         ivec2 diff_pos = abs(mod.position - pos);
         Material mat = getMaterialFromID(mod.mod_matID);
         switch (mod.mod_shape) {
@@ -773,6 +890,15 @@ void main() {
                     count++;
                 };
                 break;
+            case MODSHAPE_SPRAY:
+                float spray_dist = sqrt(pow(diff_pos.x, 2) + pow(diff_pos.y, 2));
+                // A different seed, so that the spray does not depend on RAND
+                if (spray_dist <= mod.mod_size && hash43(uvec3(pos, frame), seed + 1u).x < mod.mod_density) {
+                    got_modified = true;
+                    final_modification_mat = mat;
+                    count++;
+                };
+                break;
         }
     };
 
@@ -0,0 +1,23 @@
+
+#define NUM_DATA_LAYERS 1
+
+struct Fields {
+    int _unused;
+};
+
+// Defined in materials.glsl, as it depends on the materials
+Fields defaultFields(Material mat);
+
+Fields unpackFields(vec4 data[NUM_DATA_LAYERS]) {
+    Fields fields = Fields(0);
+    return fields;
+}
+
+vec4[NUM_DATA_LAYERS] packFields(int id, Fields fields) {
+    vec4 data[NUM_DATA_LAYERS];
+    for (int i = 0; i < NUM_DATA_LAYERS; i++) {
+        data[i] = vec4(0.0);
+    }
+    data[0].r = float(id);
+    return data;
+}
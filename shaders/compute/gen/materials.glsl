@@ -47,17 +47,17 @@ bool isType_plant(Cell cell) {
 }
 
 
-#define MAT_EMPTY Material(0, vec4(0, 0, 0, 0), 1, vec4(0, 0, 0, 0), TYPE_EMPTY)
-#define MAT_NULL Material(1, vec4(1, 0, 1, 1), 0, vec4(0, 0, 0, 0), TYPE_NULL)
-#define MAT_WALL Material(2, vec4(0.1, 0.2, 0.3, 1), 9999, vec4(0, 0, 0, 0), TYPE_WALL)
-#define MAT_sand Material(3, vec4(1, 1, 0, 1), 1.5, vec4(0, 0, 0, 0), TYPE_movable_solid)
-#define MAT_rock Material(4, vec4(0.2, 0.2, 0.2, 1), 4, vec4(0, 0, 0, 0), TYPE_solid)
-#define MAT_water Material(5, vec4(0, 0, 1, 0.5), 1.3, vec4(0, 0, 0, 0), TYPE_liquid)
-#define MAT_radioactive Material(6, vec4(0.196, 0.55, 0.184, 1), 5, vec4(0.05, 0.7, 0.05, 0.9), TYPE_solid)
-#define MAT_smoke Material(7, vec4(0.3, 0.3, 0.3, 0.3), 0.1, vec4(0, 0, 0, 0), TYPE_gas)
-#define MAT_toxic_sludge Material(8, vec4(0, 0.7, 0, 0.5), 1.49, vec4(0.7, 0, 0, 0.99999), TYPE_liquid)
-#define MAT_vine Material(9, vec4(0.34117648, 0.49803922, 0.24313726, 1), 2.5, vec4(0, 0, 0, 0), TYPE_plant)
-#define MAT_dirt Material(10, vec4(0.43137255, 0.2784314, 0.14509805, 1), 1.5, vec4(0, 0, 0, 0), TYPE_movable_solid)
+#define MAT_EMPTY Material(0, vec4(0, 0, 0, 0), 1, vec4(0, 0, 0, 0), TYPE_EMPTY, 0)
+#define MAT_NULL Material(1, vec4(1, 0, 1, 1), 0, vec4(0, 0, 0, 0), TYPE_NULL, 0)
+#define MAT_WALL Material(2, vec4(0.1, 0.2, 0.3, 1), 9999, vec4(0, 0, 0, 0), TYPE_WALL, 0)
+#define MAT_sand Material(3, vec4(1, 1, 0, 1), 1.5, vec4(0, 0, 0, 0), TYPE_movable_solid, 0)
+#define MAT_rock Material(4, vec4(0.2, 0.2, 0.2, 1), 4, vec4(0, 0, 0, 0), TYPE_solid, 0)
+#define MAT_water Material(5, vec4(0, 0, 1, 0.5), 1.3, vec4(0, 0, 0, 0), TYPE_liquid, 0)
+#define MAT_radioactive Material(6, vec4(0.196, 0.55, 0.184, 1), 5, vec4(0.05, 0.7, 0.05, 0.9), TYPE_solid, 0)
+#define MAT_smoke Material(7, vec4(0.3, 0.3, 0.3, 0.3), 0.1, vec4(0, 0, 0, 0), TYPE_gas, 0)
+#define MAT_toxic_sludge Material(8, vec4(0, 0.7, 0, 0.5), 1.49, vec4(0.7, 0, 0, 0.99999), TYPE_liquid, 0)
+#define MAT_vine Material(9, vec4(0.34117648, 0.49803922, 0.24313726, 1), 2.5, vec4(0, 0, 0, 0), TYPE_plant, 0)
+#define MAT_dirt Material(10, vec4(0.43137255, 0.2784314, 0.14509805, 1), 1.5, vec4(0, 0, 0, 0), TYPE_movable_solid, 0)
 
 Material[11] materials() {
     Material allMaterials[11] = {
@@ -85,3 +85,7 @@ Material getMaterialFromID(int id) {
     return MAT_NULL;
 }
 
+
+Fields defaultFields(Material mat) {
+    return Fields(0);
+}
@@ -46,10 +46,10 @@ void rule_horizontal_slide (inout Cell self, inout Cell right, inout Cell down,
 }
 
 void rule_rise_up (inout Cell self, inout Cell right, inout Cell down, inout Cell downright, vec4 rand, ivec2 pos) {
-    if (isType_gas(down) &&  !isType_solid(self) && down.mat.density < self.mat.density) {
+    if (isType_gas(down) && !isType_solid(self) && down.mat.density < self.mat.density) {
         swap(down, self);
     } else {
-        if (isType_gas(down) &&  !isType_solid(right) && down.mat.density < right.mat.density) {
+        if (isType_gas(down) && !isType_solid(right) && down.mat.density < right.mat.density) {
             swap(down, right);
         } else {
 
@@ -119,9 +119,9 @@ rule_die_off(self, right, down, downright, rand, pos);
 
 void applyLeftRules(
     inout Cell self,
-    inout Cell right,
+    inout Cell left,
     inout Cell down,
-    inout Cell downright,
+    inout Cell downleft,
     vec4 rand,
     ivec2 pos) {
     
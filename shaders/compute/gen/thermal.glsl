@@ -0,0 +1,3 @@
+
+void applyTemperature(inout Cell self, inout Cell right, inout Cell down, inout Cell downright) {
+}
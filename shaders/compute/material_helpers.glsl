@@ -1,3 +1,4 @@
+#pragma once
 #define NUM_MATERIALS 10
 
 Material[NUM_MATERIALS] materials() {
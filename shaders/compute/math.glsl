@@ -1,3 +1,4 @@
+#pragma once
 // Gold Noise ©2015 dcerisano@standard3d.com
 // - based on the Golden Ratio
 // - uniform normalized distribution
@@ -78,6 +79,13 @@ vec4 hash43(uvec3 p)
     //return vec4(hash(x), hash(x), hash(x), hash(x));
 }
 
+// Same as hash43, but every seed produces different values (seed 0 gives the same as hash43)
+vec4 hash43(uvec3 p, uint seed)
+{
+    uint x = p.x*461u + p.y*2131u + p.z*2131u*2131u + seed*0x9e3779b9u;
+    return vec4( hash4i(x) ) / float( 0xffffffffU );
+}
+
 
 float _noise( in vec2 p )
 {
@@ -1,3 +1,4 @@
+#pragma once
 
 
 
@@ -22,6 +23,47 @@ void swap(inout Cell a, inout Cell b) {
     b = tmp;
 }
 
+bool isBoundary(Cell cell) {
+    return cell.mat.type == TYPE_WALL || cell.mat.type == TYPE_NULL;
+}
+
+// Swaps the materials of both cells, the fields stay where they are
+void convert(inout Cell a, inout Cell b) {
+    if (isBoundary(a) || isBoundary(b)) {
+        return;
+    }
+    Material tmp = a.mat;
+    a.mat = b.mat;
+    b.mat = tmp;
+}
+
+// Turns 'dst' into a duplicate of 'src' (material and fields), only the position stays
+void copyCell(inout Cell dst, Cell src) {
+    if (isBoundary(dst) || isBoundary(src)) {
+        return;
+    }
+    dst.mat = src.mat;
+    dst.fields = src.fields;
+}
+
+// Moves 'src' into 'dst' if that is empty (material and fields) and leaves an empty cell behind
+void moveCell(inout Cell src, inout Cell dst) {
+    if (isBoundary(src) || dst.mat != MAT_EMPTY) {
+        return;
+    }
+    dst.mat = src.mat;
+    dst.fields = src.fields;
+    src = newCell(MAT_EMPTY, src.pos);
+}
+
+// Replaces 'dst' with a new cell of the material of 'src'
+void setMaterial(inout Cell dst, Cell src) {
+    if (isBoundary(dst) || isBoundary(src)) {
+        return;
+    }
+    dst = newCell(src.mat, dst.pos);
+}
+
 ivec2 getMargolusOffset(int frame) {
     frame = frame % 4;
     if (frame == 1)
@@ -49,11 +91,14 @@ Cell getCell(ivec2 pos) {
         #endif // SCREEN_IS_BORDER
         return newCell(MAT_NULL, pos);
     };
-    ivec4 data = ivec4(texelFetch(input_data, pos, 0));
-    // data: ___id___  00000000  00000000  00000000
-    int matID = int(data.r);
+    // data: ___id___  field 1  field 2  field 3  (| field 4 ...)
+    vec4 data[NUM_DATA_LAYERS];
+    for (int layer = 0; layer < NUM_DATA_LAYERS; layer++) {
+        data[layer] = texelFetch(input_data, ivec3(pos, layer), 0);
+    }
+    int matID = int(data[0].r);
 
-    return Cell(getMaterialFromID(matID), pos);
+    return Cell(getMaterialFromID(matID), pos, unpackFields(data));
 }
 
 Cell getCell(ivec2 pos, ivec2 offset) {
@@ -108,8 +153,10 @@ void setCell(ivec2 pos, Cell cell) {
     };
     
     //imageStore(output_effects, pos, vec4(cell.mat.emission, 1.0));
-    ivec4 data = ivec4(cell.mat.id, 0, 0, 0);
-    imageStore(output_data, pos, data);
+    vec4 data[NUM_DATA_LAYERS] = packFields(cell.mat.id, cell.fields);
+    for (int layer = 0; layer < NUM_DATA_LAYERS; layer++) {
+        imageStore(output_data, ivec3(pos, layer), data[layer]);
+    }
 
     ivec2[8] neighs = getDiagonalNeighbours(pos);
     Cell[8] neighCells;
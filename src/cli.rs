@@ -0,0 +1,175 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+use colored::Colorize;
+use sandengine_core::RunOptions;
+use sandengine_core::headless::HeadlessOptions;
+
+use crate::config::Config;
+use crate::exit_code;
+
+
+/// The YAML file, if neither a flag nor the config file name one
+pub const DEFAULT_MATERIALS_PATH: &str = "./data/materials.yaml";
+
+
+/// A falling sand simulation engine, whose materials and rules are written in YAML.
+/// Without a command, the simulation runs in a window
+#[derive(Debug, Parser)]
+#[command(name = "sandengine", version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+
+    #[command(flatten)]
+    pub global: GlobalArgs,
+}
+
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the simulation in a window (the default)
+    Run(RunArgs),
+    /// Parses the YAML files and generates the shaders, without running anything
+    Check,
+    /// Writes the generated shaders into a directory
+    Gen {
+        /// The directory the shaders are written to
+        #[arg(default_value = "shaders/compute/gen")]
+        out: PathBuf,
+    },
+    /// Runs the simulation on the CPU, without opening a window
+    Headless(HeadlessArgs),
+    /// Runs the tests of the 'tests' section of the YAML files
+    Test,
+}
+
+
+/// Flags of all commands, they can also be set in `sandengine.toml`
+#[derive(Debug, Args)]
+pub struct GlobalArgs {
+    /// The YAML file with the materials [default: ./data/materials.yaml]
+    #[arg(long, global = true)]
+    pub materials: Option<PathBuf>,
+    /// The config file [default: sandengine.toml, if it exists]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Size of the simulation in cells, e.g. 128x96 [default: the size of the world or 64x64]
+    #[arg(long, global = true, value_parser = parse_size)]
+    pub size: Option<(u32, u32)>,
+    /// Seed for the random values of the simulation [default: 0]
+    #[arg(long, global = true)]
+    pub seed: Option<u32>,
+}
+
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// Image to load the world from
+    #[arg(long)]
+    pub world: Option<PathBuf>,
+    /// Recording to play, it brings its own world and seed
+    #[arg(long, conflicts_with = "world")]
+    pub replay: Option<PathBuf>,
+    /// Size of the window in pixels, the simulation is scaled up to fit into it [default: 640x480]
+    #[arg(long, value_parser = parse_size)]
+    pub window_size: Option<(u32, u32)>,
+    /// Size of a cell in pixels, overrides the window size
+    #[arg(long)]
+    pub scale: Option<f32>,
+    /// Image displayed behind the simulation
+    #[arg(long)]
+    pub background: Option<PathBuf>,
+    /// Limits the frame rate to the refresh rate of the monitor
+    #[arg(long, overrides_with = "no_vsync")]
+    pub vsync: bool,
+    /// Runs as fast as possible (the default)
+    #[arg(long, overrides_with = "vsync")]
+    pub no_vsync: bool,
+}
+
+
+#[derive(Debug, Args)]
+pub struct HeadlessArgs {
+    /// Number of frames to simulate
+    #[arg(long, default_value_t = 100)]
+    pub frames: u32,
+    /// Image to load the world from
+    #[arg(long)]
+    pub world: Option<PathBuf>,
+    /// Recording to play, it brings its own world and seed and is always played to its end
+    #[arg(long, conflicts_with = "world")]
+    pub replay: Option<PathBuf>,
+    /// Directory for the snapshots and the final world
+    #[arg(long, default_value = "out")]
+    pub out: PathBuf,
+    /// Writes a snapshot every n frames (0 only writes the last frame)
+    #[arg(long, default_value_t = 10)]
+    pub every: u32,
+}
+
+
+impl Cli {
+    /// Runs the command and returns the exit code
+    pub fn execute(self) -> i32 {
+        let config = match Config::load(self.global.config.as_deref()) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("{} {:#}", "[sandengine]:".red().bold(), err);
+                return exit_code::USAGE_ERROR;
+            }
+        };
+        let materials_path = self.global.materials.clone()
+            .or(config.materials.clone())
+            .unwrap_or(PathBuf::from(DEFAULT_MATERIALS_PATH));
+
+        match self.command {
+            None => crate::run(&materials_path, run_options(self.run, &self.global, &config)),
+            Some(Command::Run(args)) => crate::run(&materials_path, run_options(args, &self.global, &config)),
+            Some(Command::Check) => crate::check(&materials_path),
+            Some(Command::Gen { out }) => crate::gen(&materials_path, &out),
+            Some(Command::Headless(args)) => crate::run_headless(&materials_path, HeadlessOptions {
+                frames: args.frames,
+                size: self.global.size.or(config.simulation.size),
+                seed: self.global.seed.or(config.seed).unwrap_or(0),
+                world: args.world,
+                replay: args.replay,
+                out: args.out,
+                snapshot_every: args.every,
+            }),
+            Some(Command::Test) => crate::run_tests(&materials_path),
+        }
+    }
+}
+
+
+/// Combines the flags with the config file, the flags take precedence
+fn run_options(args: RunArgs, global: &GlobalArgs, config: &Config) -> RunOptions {
+    let defaults = RunOptions::default();
+    let vsync = match (args.vsync, args.no_vsync) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    };
+    RunOptions {
+        size: global.size.or(config.simulation.size),
+        window_size: args.window_size.or(config.window.size).unwrap_or(defaults.window_size),
+        scale: args.scale.or(config.window.scale),
+        background: args.background.or(config.window.background.clone()),
+        vsync: vsync.or(config.window.vsync).unwrap_or(defaults.vsync),
+        seed: global.seed.or(config.seed).unwrap_or(defaults.seed),
+        world: args.world,
+        replay: args.replay,
+    }
+}
+
+
+/// Parses a size like '128x96'
+fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    let (w, h) = value.split_once('x').ok_or(format!("Expected the size as WxH, got '{}'", value))?;
+    let number = |n: &str| n.parse::<u32>().map_err(|_| format!("Expected a number, got '{}'", n));
+    Ok((number(w)?, number(h)?))
+}
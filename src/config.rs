@@ -0,0 +1,69 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+
+/// The config file, which is read from the working directory if it exists
+pub const CONFIG_PATH: &str = "sandengine.toml";
+
+
+/// Settings of `sandengine.toml`. Everything is optional, command line flags take precedence:
+///
+/// ```toml
+/// materials = "data/materials.yaml"
+/// seed = 42
+///
+/// [simulation]
+/// size = [128, 96]
+///
+/// [window]
+/// size = [1280, 720]
+/// scale = 8.0
+/// background = "data/background.png"
+/// vsync = true
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The YAML file with the materials
+    pub materials: Option<PathBuf>,
+    /// Seed for the random values of the simulation
+    pub seed: Option<u32>,
+    pub simulation: SimulationConfig,
+    pub window: WindowConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationConfig {
+    /// Width and height in cells
+    pub size: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    /// Width and height in pixels
+    pub size: Option<(u32, u32)>,
+    /// Size of a cell in pixels, overrides the size of the window
+    pub scale: Option<f32>,
+    /// Image displayed behind the simulation
+    pub background: Option<PathBuf>,
+    pub vsync: Option<bool>,
+}
+
+impl Config {
+    /// Loads the config file at the path. Without a path, `sandengine.toml` is loaded if it exists
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(CONFIG_PATH).exists() => Path::new(CONFIG_PATH),
+            None => return Ok(Config::default()),
+        };
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read the config file '{}'", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Invalid config file '{}'", path.display()))
+    }
+}
@@ -1,76 +1,176 @@
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
 use colored::Colorize;
+use sandengine_core::RunOptions;
+use sandengine_core::headless::HeadlessOptions;
+use sandengine_core::simulation::ShaderCompileError;
+use sandengine_lang::{GeneratedShaders, ParsingResult};
 
 pub extern crate sandengine_lang;
 
+pub mod config;
+pub mod cli;
+
 //const YAML_DATA: &'static str = include_str!("../data/materials.yaml");
 
-/// Parses the YAML data file(s), builds the compute shader and finally runs the engine
-pub fn run() {
-    let yaml_data = std::fs::read_to_string("./data/materials.yaml").unwrap();
-    let parse_res = sandengine_lang::parse_string(&yaml_data);
-    match parse_res {
+
+/// Exit codes of the command line interface, so that scripts can tell what went wrong
+pub mod exit_code {
+    pub const SUCCESS: i32 = 0;
+    /// The YAML files could not be parsed
+    pub const PARSE_ERROR: i32 = 1;
+    /// Invalid arguments or config file (the same code clap uses)
+    pub const USAGE_ERROR: i32 = 2;
+    /// The shaders could not be generated or the compute shader did not compile
+    pub const SHADER_ERROR: i32 = 3;
+    /// Anything else, e.g. files that could not be read or written
+    pub const RUNTIME_ERROR: i32 = 4;
+    /// Some of the rule tests failed
+    pub const TESTS_FAILED: i32 = 5;
+}
+
+
+/// Parses the YAML data file(s), builds the compute shader and finally runs the engine.
+/// Changes to the files are hot-reloaded while the engine runs. The world is loaded from the image, if given.
+/// A replay brings its own world and is played before the user takes over.
+/// Only returns (with the exit code) if the engine could not be started
+pub fn run(materials_path: &Path, options: RunOptions) -> i32 {
+    let (result, shaders) = match parse_and_generate(materials_path) {
+        Ok(parsed) => parsed,
+        Err(code) => return code,
+    };
+    write_lock(materials_path, &result);
+    match sandengine_core::run(materials_path.to_path_buf(), result, shaders, options) {
+        Ok(()) => exit_code::SUCCESS,
+        Err(err) => {
+            println!("{} {:#}", "[sandengine]:".red().bold(), err);
+            match err.is::<ShaderCompileError>() {
+                true => exit_code::SHADER_ERROR,
+                false => exit_code::RUNTIME_ERROR,
+            }
+        }
+    }
+}
+
+
+/// Parses the YAML data file(s) and generates the shaders, without running anything.
+/// Compiling the compute shader needs a GPU, so that is left to `run`
+pub fn check(materials_path: &Path) -> i32 {
+    match parse_and_generate(materials_path) {
+        Ok(_) => {
+            println!("{} '{}' is valid.", "[sandengine]:".green().bold(), materials_path.display());
+            exit_code::SUCCESS
+        },
+        Err(code) => code,
+    }
+}
+
+
+/// Parses the YAML data file(s) and writes the generated shaders into the directory (see `GeneratedShaders::write_to`)
+pub fn gen(materials_path: &Path, out: &Path) -> i32 {
+    let (result, shaders) = match parse_and_generate(materials_path) {
+        Ok(parsed) => parsed,
+        Err(code) => return code,
+    };
+    write_lock(materials_path, &result);
+    match shaders.write_to(out) {
+        Ok(()) => {
+            println!("{} Wrote the shaders to '{}'", "[sandengine]:".green().bold(), out.display());
+            exit_code::SUCCESS
+        },
+        Err(err) => {
+            println!("{} {:#}", "[sandengine]:".red().bold(), err);
+            exit_code::RUNTIME_ERROR
+        }
+    }
+}
+
+
+/// Parses the YAML data file and runs the simulation without a window (see `HeadlessOptions`)
+pub fn run_headless(materials_path: &Path, options: HeadlessOptions) -> i32 {
+    let result = match parse(materials_path) {
+        Ok(result) => result,
+        Err(code) => return code,
+    };
+
+    match sandengine_core::headless::run_headless(&result, &options) {
+        Ok(()) => {
+            println!("{} Simulated {} frames, written to '{}'", "[sandengine]:".green().bold(), options.frames, options.out.display());
+            exit_code::SUCCESS
+        },
+        Err(err) => {
+            println!("{} {:#}", "[sandengine]:".red().bold(), err);
+            exit_code::RUNTIME_ERROR
+        }
+    }
+}
+
+
+/// Parses the YAML file and runs the tests of its 'tests' section on the CPU
+pub fn run_tests(materials_path: &Path) -> i32 {
+    let result = match parse(materials_path) {
+        Ok(result) => result,
+        Err(code) => return code,
+    };
+
+    let outcomes = sandengine_lang::testing::run_tests(&result);
+    for outcome in outcomes.iter() {
+        println!("{}", outcome);
+    }
+    let failed = outcomes.iter().filter(|o| !o.passed()).count();
+    let summary = format!("{} passed, {} failed", outcomes.len() - failed, failed);
+    match failed {
+        0 => {
+            println!("\n{} {}", "[sandengine]:".green().bold(), summary);
+            exit_code::SUCCESS
+        },
+        _ => {
+            println!("\n{} {}", "[sandengine]:".red().bold(), summary);
+            exit_code::TESTS_FAILED
+        }
+    }
+}
+
+
+/// Parses the YAML file and prints the warnings. Returns the exit code if there were errors
+fn parse(materials_path: &Path) -> Result<ParsingResult, i32> {
+    match sandengine_lang::parse_path(PathBuf::from(materials_path)) {
         Ok(result) => {
             // println!("{}{:#?}", "Rules: ".bold(), result.rules);
             // println!("{}{:#?}", "Types: ".bold(), result.types);
             // println!("{}{:#?}", "Materials: ".bold(), result.materials);
             println!("{}", "[sandengine-lang]: Parsing ok.".green().bold());
-            sandengine_lang::create_glsl_from_parser(&result);
-            
-            build_compute_shaders();
-            sandengine_core::run(result);
+            if !result.report.diagnostics.is_empty() {
+                println!("{}", result.report);
+            }
+            Ok(result)
         },
-        Err(err) => {
-            println!("{} {}", "[sandengine-lang]:".red().bold(), err);
+        Err(report) => {
+            println!("{}\n{}", "[sandengine-lang]:".red().bold(), report);
+            Err(exit_code::PARSE_ERROR)
         }
-    };
+    }
 }
 
 
-/// Processes the GLSL files and resolves the #include statements
-fn build_compute_shaders() {
-    let shaderpath = std::path::PathBuf::from_str("shaders/compute").unwrap();
-    std::fs::read_dir(shaderpath.clone()).unwrap().filter(|f| {
-        if let Ok(file) = f {
-            return file.path().is_file() && file.path().extension().unwrap() == "glsl";
-        } else {
-            false
-        }
-    }).for_each(|file| {
-        let mut had_includes: bool = false;
-
-        let path = file.unwrap().path();
-        let mut contents = std::fs::read_to_string(path.clone()).unwrap();
-        let old_contents = contents.clone();
-        let searchstr = "\n#include \"";
-        let mut start_idx = contents.find(searchstr).unwrap_or(0);
-        while start_idx != 0 {
-            had_includes = true;
-            let incl_path = contents
-                    .split_at(start_idx + searchstr.len()).1
-                    .split("\"").next()
-                    .unwrap();
-            //println!("{}: Include path: {}", path.clone().display(), incl_path);
-            let incl_src = std::fs::read_to_string(path.parent().unwrap().join(incl_path));
-            if let Ok(mut incl_src) = incl_src {
-                incl_src.push_str("\n\n\n");
-                let start_idx = contents.find(searchstr).unwrap();
-                let end_idx = start_idx + searchstr.len() + incl_path.len() + 1;
-
-                let char_before = contents.chars().nth(start_idx - 1).unwrap();
-                if char_before == ' ' || char_before == '/' {
-                    contents.replace_range(start_idx..end_idx, "");
-                } else {
-                    contents.replace_range(start_idx..end_idx, incl_src.as_str());
-                }
-            }
+/// Pins the ids of new materials in the lockfile (see `sandengine_lang::write_lock`).
+/// Failing to write it is only a warning, the ids are the same until materials change
+fn write_lock(materials_path: &Path, result: &ParsingResult) {
+    match sandengine_lang::write_lock(materials_path, result) {
+        Ok(true) => println!("{} Pinned the new material ids in '{}'", "[sandengine]:".green().bold(), materials_path.with_extension("lock").display()),
+        Ok(false) => (),
+        Err(err) => println!("{} {:#}", "[sandengine]:".yellow().bold(), err),
+    }
+}
 
-            start_idx = contents.find(searchstr).unwrap_or(0);
-        }
 
-        if had_includes && old_contents != contents {
-            let path = shaderpath.join("gen").join(path.file_name().unwrap().to_str().unwrap());
-            std::fs::write(path, contents).unwrap();
+/// Parses the YAML file and generates the shaders from it. Returns the exit code if either failed
+fn parse_and_generate(materials_path: &Path) -> Result<(ParsingResult, GeneratedShaders), i32> {
+    let result = parse(materials_path)?;
+    match sandengine_lang::create_glsl_from_parser(&result) {
+        Ok(shaders) => Ok((result, shaders)),
+        Err(err) => {
+            println!("{} {:#}", "[sandengine-lang]:".red().bold(), err);
+            Err(exit_code::SHADER_ERROR)
         }
-    });
-}
\ No newline at end of file
+    }
+}
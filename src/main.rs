@@ -1,5 +1,6 @@
-
+use clap::Parser;
+use sandengine::cli::Cli;
 
 fn main() {
-    sandengine::run();
+    std::process::exit(Cli::parse().execute());
 }
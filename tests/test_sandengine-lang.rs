@@ -1,9 +1,13 @@
-use sandengine_lang::parser::parse_string;
+use sandengine_lang::{parser::{parse_string, report::Severity}, GLSLConvertible, ParseReport};
+
+/// Counts the errors of a specific kind (e.g. "NotFound") inside of the report
+fn count_errors(report: &ParseReport, kind: &str) -> usize {
+    report.errors().filter(|e| e.message.contains(kind)).count()
+}
 
 #[test]
-#[should_panic = "No 'rules' found in input file"]
 fn missing_rules() {
-    let _ = parse_string("
+    let res = parse_string("
     types:
         movable_solid:
             base_rules: [
@@ -18,14 +22,18 @@ fn missing_rules() {
             type: movable_solid
             density: 1.5
             selectable: true
-    ");
+    ", None);
+    let report = res.err().unwrap();
+    assert_eq!(count_errors(&report, "MissingField"), 1);
+    // Both base_rules reference rules which don't exist
+    assert_eq!(count_errors(&report, "NotFound"), 2);
+    assert_eq!(report.errors().count(), 3);
 }
 
 
 #[test]
-#[should_panic = "No 'types' found in input file"]
 fn missing_types() {
-    let _ = parse_string("
+    let res = parse_string("
     rules:
         gravity:
             if: DOWN.density < SELF.density
@@ -42,14 +50,18 @@ fn missing_types() {
             type: movable_solid
             density: 1.5
             selectable: true
-    ");
+    ", None);
+    let report = res.err().unwrap();
+    assert_eq!(count_errors(&report, "MissingField"), 1);
+    assert_eq!(count_errors(&report, "NotRecognized"), 1);
+    // The type of sand can't be found without the types
+    assert_eq!(count_errors(&report, "NotFound"), 1);
 }
 
 
 #[test]
-#[should_panic = "No 'materials' found in input file"]
 fn missing_materials() {
-    let _ = parse_string("
+    let res = parse_string("
     rules:
         gravity:
             if: DOWN.density < SELF.density
@@ -67,7 +79,11 @@ fn missing_materials() {
                 gravity,
                 slide_diagonally
             ]
-    ");
+    ", None);
+    let report = res.err().unwrap();
+    assert_eq!(count_errors(&report, "MissingField"), 1);
+    assert_eq!(count_errors(&report, "NotRecognized"), 1);
+    assert_eq!(report.errors().count(), 2);
 }
 
 
@@ -83,8 +99,13 @@ fn invalid_name() {
             if: DOWNRIGHT.density < SELF.density
             do: swap(SELF, DOWNRIGHT)
             mirrored: true
-    ");
-    assert!(res.err().unwrap().to_string().contains(&"InvalidType"));
+    ", None);
+    let report = res.err().unwrap();
+    assert!(report.to_string().contains(&"InvalidType"));
+    // The missing sections and the invalid 'do' of the second rule are reported as well
+    assert_eq!(count_errors(&report, "InvalidType"), 1);
+    assert_eq!(count_errors(&report, "MissingField"), 2);
+    assert_eq!(count_errors(&report, "NotRecognized"), 1);
 }
 
 #[test]
@@ -99,8 +120,11 @@ fn missing_field() {
             if: DOWNRIGHT.density < SELF.density
             do: swap(SELF, DOWNRIGHT)
             mirrored: true
-    ");
-    assert!(res.err().unwrap().to_string().contains(&"MissingField"));
+    ", None);
+    let report = res.err().unwrap();
+    assert!(report.to_string().contains(&"MissingField"));
+    // 'if', 'types' and 'materials'
+    assert_eq!(count_errors(&report, "MissingField"), 3);
 
     let res = parse_string("
     rules:
@@ -128,8 +152,11 @@ fn missing_field() {
             type: movable_solid
             density: 1.5
             selectable: true
-    ");
-    assert!(res.err().unwrap().to_string().contains(&"MissingField"));
+    ", None);
+    let report = res.err().unwrap();
+    assert!(report.to_string().contains(&"MissingField"));
+    assert_eq!(count_errors(&report, "MissingField"), 1);
+    assert_eq!(count_errors(&report, "NotRecognized"), 1);
 }
 
 
@@ -161,6 +188,1206 @@ fn not_found() {
             type: liquid
             density: 1.5
             selectable: true
-    ");
-    assert!(res.err().unwrap().to_string().contains(&"NotFound"));
-}
\ No newline at end of file
+    ", None);
+    let report = res.err().unwrap();
+    assert!(report.to_string().contains(&"NotFound"));
+    assert_eq!(count_errors(&report, "NotFound"), 1);
+    assert_eq!(count_errors(&report, "NotRecognized"), 1);
+}
+
+#[test]
+fn type_mismatch() {
+    let res = parse_string("
+    rules:
+        gravity:
+            if: DOWN.mat.density < SELF.mat
+            do: SWAP SELF DOWN
+
+
+    types:
+        movable_solid:
+            base_rules: [
+                gravity
+            ]
+
+    
+    materials:
+        sand:
+            color: [1.0, 1.0, 0.0, 1.0]
+            type: movable_solid
+            density: 1.5
+    ", None);
+    assert!(res.err().unwrap().to_string().contains(&"TypeMismatch"));
+}
+
+
+#[test]
+fn material_names_are_not_replaced() {
+    let res = parse_string("
+    rules:
+        fill:
+            if: DOWN.mat == empty_jar and not isType_EMPTY(SELF)
+            do: SET SELF empty_jar
+
+
+    types:
+        container:
+            base_rules: [
+                fill
+            ]
+
+    
+    materials:
+        empty_jar:
+            color: [1.0, 1.0, 0.0, 1.0]
+            type: container
+            density: 1.5
+    ", None).unwrap();
+    let code = res.rules[0].get_glsl_code();
+    assert!(code.contains("down.mat == MAT_empty_jar && !isType_EMPTY(self)"));
+    assert!(code.contains("self = newCell(MAT_empty_jar, pos);"));
+}
+
+
+#[test]
+fn error_location() {
+    let res = parse_string("
+rules:
+    grow:
+        if: isType_EMPTY(SELF) and DOWN.mat == nothing
+        do: SET SELF vine
+types:
+    plant:
+        base_rules: [grow]
+materials:
+    vine:
+        type: plant
+        color: [87, 127, 62]
+        density: 2.5
+", Some("plants.yaml"));
+    let err = res.err().unwrap().to_string();
+    assert!(err.contains("NotFound"));
+    assert!(err.contains("plants.yaml:4:48"));
+    assert!(err.contains("^^^^^^^"));
+}
+
+
+#[test]
+fn multiple_errors() {
+    let res = parse_string("
+rules:
+    grow:
+        if: isType_EMPTY(SELF) and DOWN.mat == nothing
+        do: SET SELF vine
+        probability: often
+    fall:
+        if: DOWN.density < SELF.density
+        do: SWAP SELF BELOW
+types:
+    plant:
+        base_rules: [grow, fall, climb]
+materials:
+    vine:
+        type: plant
+        color: [87, 127, 62]
+    water:
+        type: liquid
+        color: [0, 0, 255]
+        density: 1.0
+", Some("plants.yaml"));
+    let report = res.err().unwrap();
+    assert_eq!(report.errors().count(), 6);
+    assert_eq!(count_errors(&report, "InvalidType"), 1);
+    assert_eq!(count_errors(&report, "MissingField"), 1);
+    // 'nothing', 'BELOW', 'climb' and 'liquid'
+    assert_eq!(count_errors(&report, "NotFound"), 4);
+    assert!(report.warnings().next().is_none());
+
+    let text = report.to_string();
+    assert!(text.contains("plants.yaml:4:48"));
+    assert!(text.contains("plants.yaml:9:13"));
+    assert!(text.contains("6 error(s)"));
+}
+
+
+#[test]
+fn validator_checks() {
+    let yaml = "
+rules:
+    fall:
+        if: DOWN.density < SELF.density
+        do: SWAP SELF DOWN
+    spread:
+        if: isType_EMPTY(LEFT) and isType_EMPTY(RIGHT)
+        do: SWAP SELF LEFT
+        mirrored: false
+    peek:
+        if: isType_EMPTY(DOWNLEFT)
+        do: SET SELF NULL
+    lonely:
+        if: isType_EMPTY(DOWN)
+        do: SET SELF EMPTY
+types:
+    powder:
+        base_rules: [fall, spread, peek]
+materials:
+    sand:
+        type: powder
+        color: [1.0, 1.0, 0]
+        density: 1.5
+        extra_rules: [fly]
+    dirt:
+        type: powder
+        color: [1.0, 1.0, 0]
+        density: 1.5
+";
+    let report = parse_string(yaml, None).err().unwrap();
+    let codes = |severity| report.diagnostics.iter()
+        .filter(|d| d.severity == severity)
+        .filter_map(|d| d.code)
+        .collect::<Vec<_>>();
+    assert_eq!(codes(Severity::Error), vec!["V003", "V004", "V005", "V002"]);
+    assert_eq!(codes(Severity::Warning), vec!["V001", "V006"]);
+
+    // Allowing and denying checks changes their severity
+    let yaml = format!("
+checks:
+    allow: [V001, V002, mixed_directions]
+    warn: [unreceived_cell, V005]
+    deny: [duplicate_density]
+{}", yaml);
+    let report = parse_string(&yaml, None).err().unwrap();
+    let codes = |severity| report.diagnostics.iter()
+        .filter(|d| d.severity == severity)
+        .filter_map(|d| d.code)
+        .collect::<Vec<_>>();
+    assert_eq!(codes(Severity::Error), vec!["V006"]);
+    assert_eq!(codes(Severity::Warning), vec!["V004", "V005"]);
+
+    // EMPTY (density 1.0) is not compared with the other materials
+    let res = parse_string("
+rules:
+    fall:
+        if: DOWN.mat.density < SELF.mat.density
+        do: SWAP SELF DOWN
+types:
+    liquid:
+        base_rules: [fall]
+materials:
+    water:
+        type: liquid
+        color: [0, 0, 255]
+        density: 1.0
+", None).unwrap();
+    assert!(res.report.diagnostics.iter().all(|d| d.code != Some("V006")));
+}
+
+
+#[test]
+fn type_inheritance() {
+    let res = parse_string("
+rules:
+    grow:
+        if: isType_EMPTY(SELF)
+        do: SET SELF vine
+types:
+    plant:
+        inherits: organism
+    organism:
+        inherits: living
+    living:
+        base_rules: [grow]
+materials:
+    vine:
+        type: plant
+        color: [87, 127, 62]
+        density: 2.5
+", None).unwrap();
+    // Parents are ordered before their children, regardless of the order of declaration
+    let names = res.types.iter().map(|t| t.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names[3..], ["living", "organism", "plant"]);
+    let living = res.types.iter().find(|t| t.name == "living").unwrap();
+    assert!(living.get_checker_func().contains("cell.mat.type == TYPE_organism || cell.mat.type == TYPE_plant"));
+    let code = res.rules[0].get_glsl_code();
+    assert!(code.contains("isType_living(self) || isType_organism(self) || isType_plant(self)"));
+
+    let res = parse_string("
+rules:
+    grow:
+        if: isType_EMPTY(SELF)
+        do: SET SELF EMPTY
+types:
+    a:
+        inherits: b
+    b:
+        inherits: c
+    c:
+        inherits: a
+    d:
+        inherits: d
+materials:
+", None);
+    let report = res.err().unwrap();
+    assert_eq!(count_errors(&report, "InheritanceCycle"), 2);
+    let text = report.to_string();
+    assert!(text.contains("a -> b -> c -> a"));
+    assert!(text.contains("d -> d"));
+}
+
+
+#[test]
+fn imports() {
+    let dir = std::env::temp_dir().join("sandengine_test_imports");
+    std::fs::create_dir_all(dir.join("packs")).unwrap();
+    std::fs::write(dir.join("packs").join("liquids.yaml"), "
+pack: wet
+rules:
+    flow:
+        if: RIGHT.density < SELF.density
+        do: SWAP SELF RIGHT
+types:
+    liquid:
+        base_rules: [flow]
+materials:
+    water:
+        type: liquid
+        color: [0, 0, 1.0]
+        density: 1.3
+").unwrap();
+    std::fs::write(dir.join("main.yaml"), "
+imports: [packs/liquids.yaml]
+rules:
+    grow:
+        if: isType_EMPTY(SELF) and DOWN.mat == wet::water
+        do: SET SELF vine
+types:
+    plant:
+        inherits: wet::liquid
+        base_rules: [grow]
+materials:
+    vine:
+        type: plant
+        color: [87, 127, 62]
+        density: 2.5
+").unwrap();
+    let res = sandengine_lang::parse_path(dir.join("main.yaml")).unwrap();
+    // Imported definitions come first
+    let names = res.materials.iter().map(|m| m.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names[3..], ["water", "vine"]);
+    assert!(res.rules[1].get_glsl_code().contains("down.mat == MAT_water"));
+    assert_eq!(res.types.iter().find(|t| t.name == "plant").unwrap().inherits, "liquid");
+
+    // Names defined in multiple files show both locations
+    std::fs::write(dir.join("collision.yaml"), "
+imports: [packs/liquids.yaml]
+rules:
+    grow:
+        if: isType_EMPTY(SELF) and DOWN.mat == dry::water
+        do: SET SELF water
+types:
+    plant:
+        base_rules: [grow]
+materials:
+    water:
+        type: plant
+        color: [87, 127, 62]
+        density: 2.5
+").unwrap();
+    let report = sandengine_lang::parse_path(dir.join("collision.yaml")).err().unwrap();
+    assert_eq!(count_errors(&report, "AlreadyDefined"), 1);
+    assert_eq!(count_errors(&report, "NotFound"), 1);
+    let text = report.to_string();
+    assert!(text.contains("collision.yaml:11:5"));
+    assert!(text.contains("liquids.yaml:11:5"));
+    assert!(text.contains("dry::water"));
+
+    // Different packs can't define the same name either
+    std::fs::write(dir.join("packs").join("oil.yaml"), "
+pack: oily
+materials:
+    water:
+        type: wet::liquid
+        color: [0.2, 0.2, 0.1]
+        density: 1.1
+").unwrap();
+    std::fs::write(dir.join("shared.yaml"), "
+imports: [packs/liquids.yaml, packs/oil.yaml]
+").unwrap();
+    let report = sandengine_lang::parse_path(dir.join("shared.yaml")).err().unwrap();
+    assert_eq!(count_errors(&report, "AlreadyDefined"), 1);
+    let text = report.to_string();
+    assert!(text.contains("oil.yaml:4:5"));
+    assert!(text.contains("liquids.yaml:11:5"));
+}
+
+
+#[test]
+fn custom_fields() {
+    let res = parse_string("
+fields:
+    lifetime: int
+    heat:
+        type: float
+        default: 20.0
+rules:
+    age:
+        if: SELF.lifetime > 0
+        do: INC SELF lifetime -1
+        else:
+            do: SET_FIELD SELF heat 0.5
+types:
+    gas:
+        base_rules: [age]
+materials:
+    smoke:
+        type: gas
+        color: [1.0, 1.0, 1.0]
+        density: 0.1
+        fields:
+            lifetime: 100
+", None).unwrap();
+    // Slot 0 is the material id
+    assert_eq!(res.fields.iter().map(|f| f.slot).collect::<Vec<_>>(), [1, 2]);
+    assert_eq!(res.num_data_layers(), 1);
+    let code = res.rules[0].get_glsl_code();
+    assert!(code.contains("self.fields.lifetime > 0.0"));
+    assert!(code.contains("self.fields.lifetime += -1;"));
+    assert!(code.contains("self.fields.heat = 0.5;"));
+    let smoke = res.materials.iter().find(|m| m.name == "smoke").unwrap();
+    assert_eq!(smoke.fields.len(), 1);
+
+    let res = parse_string("
+fields:
+    lifetime: int
+    density: float
+rules:
+    age:
+        if: SELF.lifetim > 0
+        do: [INC SELF lifetime 0.5, SET_FIELD SELF heat 1]
+types:
+    gas:
+        base_rules: [age]
+materials:
+    smoke:
+        type: gas
+        color: [1.0, 1.0, 1.0]
+        density: 0.1
+        fields:
+            lifetime: 1.5
+", None);
+    let report = res.err().unwrap();
+    // 'density' is already a property of cells
+    assert_eq!(count_errors(&report, "NotRecognized"), 1);
+    // 'SELF.lifetim' and 'heat'
+    assert_eq!(count_errors(&report, "NotFound"), 2);
+    // '0.5' and '1.5' for an int field
+    assert_eq!(count_errors(&report, "InvalidType"), 2);
+
+    // Names that GLSL reserves can't be struct members
+    let res = parse_string("
+fields:
+    in: int
+    out: int
+    uniform: float
+    vec4: float
+    float: float
+    gl_Position: float
+    my__field: int
+    input_heat: float
+rules:
+types:
+materials:
+", None);
+    let report = res.err().unwrap();
+    assert_eq!(count_errors(&report, "NotRecognized"), 7);
+    assert!(!report.to_string().contains("input_heat"));
+}
+
+
+#[test]
+fn actions() {
+    let res = parse_string("
+rules:
+    act:
+        if: isType_EMPTY(DOWN)
+        do:
+            - COPY SELF RIGHT
+            - SET DOWN RIGHT
+            - CONVERT SELF DOWN
+            - SPAWN sand IN DOWN IF EMPTY
+            - MOVE SELF DOWN
+            - CHOOSE:
+                - weight: 3
+                  do: SET SELF sand
+                - do: [SWAP SELF DOWN]
+            - 'CHOOSE [{do: SWAP SELF DOWN}, {do: SET SELF sand}]'
+types:
+    solid:
+        base_rules: [act]
+materials:
+    sand:
+        type: solid
+        color: [1.0, 1.0, 0.0]
+        density: 1.5
+", None).unwrap();
+    let code = res.rules[0].get_glsl_code();
+    assert!(code.contains("copyCell(self, right);"));
+    // SET with a cell instead of a material
+    assert!(code.contains("setMaterial(down, right);"));
+    assert!(code.contains("convert(self, down);"));
+    assert!(code.contains("if (down.mat == MAT_EMPTY) {"));
+    assert!(code.contains("moveCell(self, down);"));
+    // Every CHOOSE has its own random value
+    assert!(code.contains("float choice0 = hash43(uvec3(pos, frame), seed + 2u).x;"));
+    assert!(code.contains("if (choice0 < 0.75) {"));
+    assert!(code.contains("float choice1 = hash43(uvec3(pos, frame), seed + 3u).x;"));
+    assert!(code.contains("if (choice1 < 0.5) {"));
+
+    // MOVE only moves into empty cells
+    let res = parse_string("
+rules:
+    fall:
+        if: SELF.density > DOWN.density
+        do: MOVE SELF DOWN
+types:
+    powder:
+        base_rules: [fall]
+    solid:
+materials:
+    sand:
+        symbol: s
+        type: powder
+        color: [1.0, 1.0, 0.0]
+        density: 1.5
+    water:
+        symbol: w
+        type: solid
+        color: [0.0, 0.0, 1.0]
+        density: 1.2
+tests:
+    falls:
+        before: [s., .., w.]
+        after: [.., s., w.]
+        steps: 4
+        mirror: false
+", None).unwrap();
+    assert!(sandengine_lang::testing::run_tests(&res)[0].passed());
+
+    let res = parse_string("
+rules:
+    act:
+        if: isType_EMPTY(DOWN)
+        do:
+            - COPY SELF UP
+            - CHOOSE:
+                - weight: -1
+                  do: SET SELF sand
+                - weight: 1
+            - CHOOSE: SET SELF sand
+            - CHOOSE [weight
+types:
+    solid:
+        base_rules: [act]
+materials:
+    sand:
+        type: solid
+        color: [1.0, 1.0, 0.0]
+        density: 1.5
+", None);
+    let report = res.err().unwrap();
+    assert_eq!(count_errors(&report, "NotFound"), 1);
+    assert_eq!(count_errors(&report, "MissingField"), 1);
+    assert_eq!(count_errors(&report, "InvalidType"), 3);
+
+    // Unknown materials of SET and SPAWN are errors of the parser, allowing V005 doesn't skip them
+    let res = parse_string("
+checks:
+    allow: [V005]
+rules:
+    act:
+        if: isType_EMPTY(DOWN)
+        do: [SPAWN sand IN DOWN IF water, SET SELF ghost]
+types:
+    solid:
+        base_rules: [act]
+materials:
+    sand:
+        type: solid
+        color: [1.0, 1.0, 0.0]
+        density: 1.5
+", None);
+    let report = res.err().unwrap();
+    assert_eq!(count_errors(&report, "NotFound"), 2);
+    assert!(report.to_string().contains("ghost"));
+}
+
+
+#[test]
+fn reactions() {
+    let res = parse_string("
+reactions:
+    extinguish:
+        reactants: [water, lava]
+        products: [steam, rock]
+        probability: 0.2
+    rust:
+        reactants: [iron, water]
+        products: [rust, water]
+        adjacency: below
+rules:
+types:
+    solid:
+materials:
+    water:
+        type: solid
+        color: [0, 0, 255]
+        density: 1.1
+    lava:
+        type: solid
+        color: [255, 0, 0]
+        density: 2.0
+    steam:
+        type: solid
+        color: [255, 255, 255]
+        density: 0.1
+        selectable: false
+    rock:
+        type: solid
+        color: [50, 50, 50]
+        density: 3.0
+    iron:
+        type: solid
+        color: [100, 100, 100]
+        density: 4.0
+        selectable: false
+    rust:
+        type: solid
+        color: [150, 50, 0]
+        density: 5.0
+", None).unwrap();
+    assert_eq!(res.reactions.len(), 2);
+    let extinguish = res.rules.iter().find(|r| r.name == "reaction_extinguish").unwrap();
+    assert!(extinguish.used);
+    // 3 pairs of cells for each adjacency, in both orders
+    assert_eq!(extinguish.if_conds.len(), 12);
+    let code = extinguish.get_glsl_code();
+    assert!(code.contains("rand.y <= 0.2 && self.mat == MAT_water && right.mat == MAT_lava"));
+    assert!(code.contains("self = newCell(MAT_steam, pos);\n        right = newCell(MAT_rock, pos);"));
+    // Water stays water, so only the iron is replaced
+    let rust = res.rules.iter().find(|r| r.name == "reaction_rust").unwrap();
+    assert_eq!(rust.if_conds.len(), 2);
+    assert!(rust.do_actions.iter().all(|actions| actions.len() == 1));
+    // Iron is never placed
+    let unreachable = res.report.warnings().filter(|w| w.code == Some("V007")).count();
+    assert_eq!(unreachable, 1);
+
+    let res = parse_string("
+reactions:
+    broken:
+        reactants: [water]
+        products: [steam, nothing]
+        adjacency: sideways
+rules:
+types:
+    solid:
+materials:
+    water:
+        type: solid
+        color: [0, 0, 255]
+        density: 1.1
+", None);
+    let report = res.err().unwrap();
+    assert_eq!(count_errors(&report, "InvalidType"), 2);
+    assert_eq!(count_errors(&report, "NotFound"), 2);
+
+    // Findings of the validator point at the reaction, not at a rule
+    let res = parse_string("
+reactions:
+    vanish:
+        reactants: [water, water]
+        products: [NULL, water]
+rules:
+types:
+    solid:
+materials:
+    water:
+        type: solid
+        color: [0, 0, 255]
+        density: 1.1
+", None);
+    let report = res.err().unwrap();
+    let finding = report.errors().find(|e| e.code == Some("V005")).unwrap();
+    assert!(finding.path.as_deref().unwrap().starts_with("reactions/vanish/"));
+}
+
+
+#[test]
+fn temperature() {
+    let res = parse_string("
+rules:
+    boil:
+        if: SELF.temperature > 50
+        do: SWAP SELF RIGHT
+types:
+    solid:
+        base_rules: [boil]
+materials:
+    ice:
+        type: solid
+        color: [200, 220, 255]
+        density: 1.2
+        temperature: -10
+        conductivity: 0.5
+        melting_point: 0
+        melts_into: water
+    water:
+        type: solid
+        color: [0, 0, 255]
+        density: 1.3
+        conductivity: 0.4
+        boiling_point: 100
+        boils_into: steam
+    steam:
+        type: solid
+        color: [200, 200, 200]
+        density: 0.2
+        temperature: 110
+", None).unwrap();
+    // The temperature field is added automatically
+    assert_eq!(res.fields.len(), 1);
+    let field = &res.fields[0];
+    assert_eq!(field.name, "temperature");
+    let ice = res.materials.iter().find(|m| m.name == "ice").unwrap();
+    assert_eq!(ice.field_value(field).as_f32(), -10.0);
+    assert!(ice.get_glsl_code().ends_with(", TYPE_solid, 0.5)\n"));
+
+    let transitions = sandengine_lang::parser::thermal::transitions(&res.materials);
+    let find = |from: &str, to: &str| transitions.iter().find(|t| t.from == from && t.to == to).cloned();
+    assert!(find("ice", "water").unwrap().rising);
+    assert!(find("water", "steam").unwrap().rising);
+    // Melting and boiling are reversed
+    let freeze = find("water", "ice").unwrap();
+    assert!(!freeze.rising);
+    assert_eq!(freeze.point, 0.0);
+    assert!(!find("steam", "water").unwrap().rising);
+    assert_eq!(transitions.len(), 4);
+
+    let code = sandengine_lang::parser::thermal::thermal_glsl(&res.materials, &res.fields);
+    assert!(code.contains("if (cell.fields.temperature >= 100.0) {\n            cell.mat = MAT_steam;\n        } else if (cell.fields.temperature < 0.0) {"));
+
+    let res = parse_string("
+rules:
+types:
+    solid:
+materials:
+    ice:
+        type: solid
+        color: [200, 220, 255]
+        density: 1.2
+        temperature: cold
+        conductivity: 2
+        melting_point: 0
+        melts_into: watr
+    rock:
+        type: solid
+        color: [50, 50, 50]
+        density: 3.0
+        boils_into: rock
+", None);
+    let report = res.err().unwrap();
+    assert_eq!(count_errors(&report, "InvalidType"), 2);
+    assert_eq!(count_errors(&report, "NotFound"), 1);
+    assert_eq!(count_errors(&report, "MissingField"), 1);
+}
+
+
+#[test]
+fn interpreter() {
+    use sandengine_lang::interpreter::{hash43, Interpreter};
+
+    let res = parse_string("
+rules:
+    fall:
+        if: SELF.density > DOWN.density
+        do: SWAP SELF DOWN
+        else:
+            if: SELF.density > DOWNRIGHT.density
+            do: SWAP SELF DOWNRIGHT
+types:
+    movable_solid:
+        base_rules: [fall]
+materials:
+    sand:
+        type: movable_solid
+        color: [255, 255, 0]
+        density: 1.5
+", None).unwrap();
+    let interpreter = Interpreter::new(&res, 0);
+    let sand = interpreter.material_id("sand").unwrap();
+    let mut grid = interpreter.new_grid(8, 8);
+    for x in 2..6 {
+        interpreter.set(&mut grid, x, 0, sand);
+    }
+    let mut other = grid.clone();
+    for _ in 0..100 {
+        interpreter.step(&mut grid);
+        interpreter.step(&mut other);
+    }
+    // Stepping is deterministic and all of the sand ends up on the ground
+    assert_eq!(grid, other);
+    assert_eq!(grid.frame, 101);
+    assert_eq!(grid.cells.iter().filter(|&&id| id == sand).count(), 4);
+    assert!((0..8).all(|x| grid.get(x, 0) == 0));
+    assert_eq!((0..8).filter(|&x| grid.get(x, 7) == sand).count(), 4);
+
+    // Frame 1 has the offset (1, 1), so the blocks start outside of the grid
+    let mut other = interpreter.new_grid(8, 8);
+    let origins = interpreter.block_origins(&other);
+    assert_eq!(origins.len(), 25);
+    assert_eq!(origins[0], (-1, -1));
+    other.frame += 1;
+    assert_eq!(interpreter.block_origins(&other)[0], (0, -1));
+
+    // The seed changes the random values of every block
+    assert_ne!(hash43([0, 0, 2], 0), hash43([0, 0, 2], 1));
+
+    // Changing the seed later is the same as creating the interpreter with it
+    let mut reseeded = Interpreter::new(&res, 0);
+    reseeded.set_seed(7);
+    let seeded = Interpreter::new(&res, 7);
+    let mut a = seeded.new_grid(8, 8);
+    for x in 0..8 {
+        seeded.set(&mut a, x, 0, sand);
+    }
+    let mut b = a.clone();
+    for _ in 0..20 {
+        seeded.step(&mut a);
+        reseeded.step(&mut b);
+    }
+    assert_eq!(a, b);
+}
+
+
+#[test]
+fn interpreter_temperature() {
+    use sandengine_lang::interpreter::Interpreter;
+
+    let res = parse_string("
+rules:
+types:
+    solid:
+materials:
+    ice:
+        type: solid
+        color: [200, 220, 255]
+        density: 1.2
+        temperature: -10
+        conductivity: 1.0
+        melting_point: 0
+        melts_into: water
+    water:
+        type: solid
+        color: [0, 0, 255]
+        density: 1.3
+        conductivity: 1.0
+    lava:
+        type: solid
+        color: [255, 80, 0]
+        density: 3
+        temperature: 1000
+        conductivity: 1.0
+", None).unwrap();
+    let interpreter = Interpreter::new(&res, 0);
+    let [ice, water, lava] = ["ice", "water", "lava"].map(|m| interpreter.material_id(m).unwrap());
+    let temperature = interpreter.field_index("temperature").unwrap();
+    let mut grid = interpreter.new_grid(2, 2);
+    interpreter.set(&mut grid, 0, 0, ice);
+    interpreter.set(&mut grid, 1, 0, ice);
+    interpreter.set(&mut grid, 0, 1, lava);
+    interpreter.set(&mut grid, 1, 1, ice);
+    // Frame 2: only the bottom row is inside of a block (the top row is in a block with the border)
+    interpreter.step(&mut grid);
+    assert_eq!(grid.cells, vec![ice, ice, lava, water]);
+    assert_eq!(grid.cell(1, 1).unwrap().fields[temperature], 495.0);
+    // Frame 3: both columns exchange heat, which melts the remaining ice
+    interpreter.step(&mut grid);
+    assert_eq!(grid.cells, vec![water, water, lava, water]);
+    let total: f32 = (0..4).map(|i| grid.fields[i * grid.num_fields + temperature]).sum();
+    assert_eq!(total, 970.0);
+    assert_eq!(grid.get(0, 1), lava);
+}
+
+
+#[test]
+fn rule_tests() {
+    use sandengine_lang::testing::run_tests;
+
+    let src = "
+rules:
+    fall:
+        if: SELF.density > DOWN.density
+        do: SWAP SELF DOWN
+types:
+    movable_solid:
+        base_rules: [fall]
+    solid:
+materials:
+    sand:
+        symbol: s
+        type: movable_solid
+        color: [255, 255, 0]
+        density: 1.5
+    rock:
+        symbol: r
+        type: solid
+        color: [50, 50, 50]
+        density: 4
+tests:
+    sand_falls:
+        before: |
+            s.
+            ..
+            r.
+        after: |
+            ..
+            s.
+            r.
+        steps: 4
+        mirror: false
+    sand_floats:
+        before: [s, .]
+        after: [s, .]
+        steps: 3
+        seed: 3
+";
+    let res = parse_string(src, None).unwrap();
+    assert_eq!(res.tests.len(), 2);
+    assert_eq!(res.tests[0].before[2], vec![res.materials[4].id as u16, 0]);
+    assert_eq!(res.tests[1].seed, 3);
+    assert_eq!(res.tests[1].mirror, None);
+
+    let outcomes = run_tests(&res);
+    assert!(outcomes[0].passed());
+    assert!(!outcomes[1].passed());
+    assert_eq!(outcomes[1].actual, vec![".", "s"]);
+    // The diff marks the rows that differ
+    assert_eq!(outcomes[1].to_string().matches('<').count(), 2);
+
+    // Unknown symbols, grids of different sizes and symbols used twice
+    let src = src
+        .replace("after: [s, .]", "after: [s., ..]")
+        .replace("r.\n        after", "x.\n        after")
+        .replace("symbol: r", "symbol: s");
+    let report = parse_string(&src, None).err().unwrap();
+    // 'x' doesn't exist and 'r' isn't the symbol of rock anymore
+    assert_eq!(count_errors(&report, "NotFound"), 2);
+    assert_eq!(count_errors(&report, "InvalidType"), 1);
+    assert_eq!(count_errors(&report, "IdCollision"), 1);
+    assert!(report.to_string().contains("materials/sand/symbol"));
+}
+
+
+#[test]
+fn generated_shaders() {
+    use sandengine_lang::create_glsl_from_parser;
+
+    let parse = |color: &str| parse_string(&format!("
+rules:
+    fall:
+        if: SELF.density > DOWN.density
+        do: SWAP SELF DOWN
+types:
+    movable_solid:
+        base_rules: [fall]
+materials:
+    sand:
+        type: movable_solid
+        color: {}
+        density: 1.5
+", color), None).unwrap();
+    // Two results in the same process don't affect each other
+    let yellow = create_glsl_from_parser(&parse("[255, 255, 0]")).unwrap();
+    let red = create_glsl_from_parser(&parse("[255, 0, 0]")).unwrap();
+    assert!(yellow.materials.contains("vec4(1, 1, 0, 1)"));
+    assert!(red.compute.contains("vec4(1, 0, 0, 1)"));
+    assert!(!red.compute.contains("vec4(1, 1, 0, 1)"));
+
+    // All includes are resolved
+    assert!(yellow.compute.contains("void rule_fall ("));
+    assert!(yellow.compute.contains("void main()"));
+    assert!(!yellow.compute.contains("\n#include"));
+
+    let dir = std::env::temp_dir().join(format!("sandengine_gen_{}", std::process::id()));
+    yellow.write_to(&dir).unwrap();
+    assert_eq!(std::fs::read_to_string(dir.join("rules.glsl")).unwrap(), yellow.rules);
+    assert_eq!(std::fs::read_to_string(dir.join("falling_sand.glsl")).unwrap(), yellow.compute);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Handwritten files can be replaced by the ones in a directory, missing ones stay embedded
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("math.glsl"), "#pragma once\nfloat reloaded() { return 1.0; }").unwrap();
+    let mut reloaded = yellow.clone();
+    reloaded.load_compute_files(&dir).unwrap();
+    assert!(reloaded.compute.contains("float reloaded()"));
+    assert!(!reloaded.compute.contains("float gold_noise("));
+    assert!(reloaded.compute.contains("void main()"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+
+#[test]
+fn preprocessor() {
+    use sandengine_lang::shaders::preprocessor::{Preprocessor, ShaderErr};
+
+    let files = |path: &str| match path {
+        "main.glsl" => Some("#version 430\n#include \"lib/a.glsl\"\n#include \"lib/b.glsl\"\nvoid main() {}"),
+        // b.glsl includes a.glsl again, which is only included once
+        "lib/a.glsl" => Some("#pragma once\nfloat a() { return 1.0; }"),
+        "lib/b.glsl" => Some("#ifndef B_GLSL\n#define B_GLSL\n#include \"a.glsl\"\n#include \"../lib/c.glsl\"\n#endif"),
+        "lib/c.glsl" => Some("// guarded\n#ifndef C\n#define C\nfloat c() {\n    return X;\n}\n#endif"),
+        "cycle.glsl" => Some("#include \"cycle2.glsl\""),
+        "cycle2.glsl" => Some("#include \"cycle.glsl\""),
+        "missing.glsl" => Some("\n\n#include \"nothing.glsl\""),
+        _ => None,
+    }.map(String::from);
+
+    let mut preprocessor = Preprocessor::new(files);
+    preprocessor.define("X", "2.0");
+    let shader = preprocessor.process("main.glsl").unwrap();
+    let lines: Vec<&str> = shader.source.lines().collect();
+    // The defines come right after the version
+    assert_eq!(&lines[..3], &["#version 430", "#define X 2.0", "#line 2 0"]);
+    assert_eq!(shader.source.matches("float a()").count(), 1);
+    assert!(shader.source.contains("float c()"));
+    assert!(!shader.source.contains("#include"));
+    assert_eq!(shader.source_map.files, vec!["main.glsl", "lib/a.glsl", "lib/b.glsl", "lib/c.glsl"]);
+
+    // Every line points at its original file and line
+    let line_of = |code: &str| lines.iter().position(|l| l.contains(code)).unwrap() + 1;
+    assert_eq!(shader.source_map.original(line_of("return X")), Some(("lib/c.glsl", 5)));
+    assert_eq!(shader.source_map.original(line_of("void main")), Some(("main.glsl", 4)));
+    assert_eq!(shader.source_map.original(2), None);
+    // The #line directives already fix the line, only the file needs to be looked up
+    assert_eq!(lines[line_of("return X") - 6], "#line 1 3");
+    assert_eq!(shader.source_map.translate("3(5) : error C1008: undefined variable"), "lib/c.glsl:5 : error C1008: undefined variable");
+    assert_eq!(shader.source_map.translate("ERROR: 3:5: 'X' : undeclared identifier"), "ERROR: lib/c.glsl:5: 'X' : undeclared identifier");
+
+    match preprocessor.process("cycle.glsl") {
+        Err(ShaderErr::IncludeCycle { cycle }) => assert_eq!(cycle, "cycle.glsl -> cycle2.glsl -> cycle.glsl"),
+        other => panic!("Expected a cycle, got {:?}", other.map(|s| s.source)),
+    }
+    match preprocessor.process("missing.glsl") {
+        Err(ShaderErr::IncludeNotFound { include, line, .. }) => assert_eq!((include.as_str(), line), ("nothing.glsl", 3)),
+        other => panic!("Expected a missing include, got {:?}", other.map(|s| s.source)),
+    }
+}
+
+
+#[test]
+fn shader_errors() {
+    use sandengine_lang::create_glsl_from_parser;
+
+    let result = parse_string("
+rules:
+    fall:
+        if: SELF.density > DOWN.density
+        do: SWAP SELF DOWN
+        else:
+            if: SELF.density > DOWNRIGHT.density
+            do: SWAP SELF DOWNRIGHT
+types:
+    movable_solid:
+        base_rules: [fall]
+materials:
+    sand:
+        type: movable_solid
+        color: [255, 255, 0]
+        density: 1.5
+reactions:
+    melt:
+        reactants: [sand, WALL]
+        products: [EMPTY, WALL]
+", Some("sand.yaml")).unwrap();
+    let shaders = create_glsl_from_parser(&result).unwrap();
+
+    // Every condition and action has its own lines inside of the function of its rule
+    let lines: Vec<&str> = shaders.rules.lines().collect();
+    let line_of = |code: &str| lines.iter().position(|l| l.contains(code)).unwrap() + 1;
+    assert_eq!(shaders.rule_path(line_of("void rule_fall (")), Some("rules/fall"));
+    assert_eq!(shaders.rule_path(line_of("swap(self, down);")), Some("rules/fall/do"));
+    assert_eq!(shaders.rule_path(line_of("self.mat.density > downright.mat.density")), Some("rules/fall/else/if"));
+    assert_eq!(shaders.rule_path(line_of("void rule_reaction_melt (")), Some("reactions/melt"));
+    assert_eq!(shaders.rule_path(line_of("MAT_sand && right.mat == MAT_WALL")), Some("reactions/melt/reactants"));
+    assert_eq!(shaders.rule_path(line_of("void applyMirroredRules(")), None);
+
+    // Messages of the compiler point at the YAML node or the original file
+    let rules_idx = shaders.source_map.files.iter().position(|f| f == "gen/rules.glsl").unwrap();
+    let log = format!(
+        "ERROR: {}:{}: 'downright' : undeclared identifier\nERROR: 0:60: '' : syntax error\nCompilation failed",
+        rules_idx, line_of("self.mat.density > downright.mat.density"));
+    let diagnostics = shaders.diagnose(&log, &result.report);
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics.iter().all(|d| d.is_error()));
+    assert_eq!(diagnostics[0].path.as_deref(), Some("rules/fall/else/if"));
+    assert_eq!(diagnostics[0].file.as_deref(), Some("sand.yaml"));
+    assert!(diagnostics[0].message.contains("'downright' : undeclared identifier"));
+    assert!(result.report.render(&diagnostics[0]).contains("if: SELF.density > DOWNRIGHT.density"));
+    assert_eq!(diagnostics[1].path, None);
+    assert!(diagnostics[1].message.contains("falling_sand.glsl:60"));
+}
+
+
+#[test]
+fn material_ids() {
+    use sandengine_lang::interpreter::Interpreter;
+    use sandengine_lang::parser::{lock::MaterialLock, parse_string_with_lock};
+
+    let yaml = |materials: &str| format!("
+rules:
+    fall:
+        if: SELF.density > DOWN.density
+        do: SWAP SELF DOWN
+types:
+    movable_solid:
+        base_rules: [fall]
+materials:
+{}", materials);
+    let id_of = |res: &sandengine_lang::ParsingResult, name: &str| res.materials.iter().find(|m| m.name == name).map(|m| m.id);
+
+    let sand = "
+    sand:
+        type: movable_solid
+        color: [255, 255, 0]
+        density: 1.5
+";
+    let water = "
+    water:
+        type: movable_solid
+        color: [0, 0, 255]
+        density: 1.0
+";
+    let glass = "
+    glass:
+        type: movable_solid
+        color: [200, 200, 255]
+        density: 3.0
+        id: 100
+";
+    // Without a lock, the ids are assigned in order after the builtin materials
+    let old = parse_string(&yaml(&format!("{}{}", sand, water)), None).unwrap();
+    assert_eq!(id_of(&old, "sand"), Some(3));
+    assert_eq!(id_of(&old, "water"), Some(4));
+    let mut lock = MaterialLock::default();
+    assert!(lock.update(&old.materials));
+    assert!(!lock.update(&old.materials));
+    assert_eq!(MaterialLock::parse(&lock.to_string()).unwrap(), lock);
+
+    // The lock keeps the ids when a material is inserted in front of the others
+    let inserted = "
+    stone:
+        type: movable_solid
+        color: [100, 100, 100]
+        density: 2.0
+";
+    let new = parse_string_with_lock(&yaml(&format!("{}{}{}{}", inserted, glass, sand, water)), None, &lock).unwrap();
+    assert_eq!(id_of(&new, "sand"), Some(3));
+    assert_eq!(id_of(&new, "water"), Some(4));
+    assert_eq!(id_of(&new, "stone"), Some(5));
+    assert_eq!(id_of(&new, "glass"), Some(100));
+    assert_eq!(new.material(100).unwrap().name, "glass");
+    assert!(new.material(50).is_none());
+
+    // Ids of removed materials are not reused
+    lock.update(&new.materials);
+    let removed = parse_string_with_lock(&yaml(&format!("{}{}", water, inserted.replace("stone", "gravel"))), None, &lock).unwrap();
+    assert_eq!(id_of(&removed, "water"), Some(4));
+    assert_eq!(id_of(&removed, "gravel"), Some(6));
+
+    // Old ids are converted by name, removed materials become EMPTY and unknown ids NULL
+    let table = removed.remap_from(&new);
+    assert_eq!(table[4], 4);
+    assert_eq!(table[3], 0);
+    assert_eq!(table[100], 0);
+    assert_eq!(table[50], 1);
+    assert_eq!(table[1], 1);
+
+    // Sparse ids work in the interpreter, unknown ids are NULL
+    let interpreter = Interpreter::new(&new, 0);
+    let mut grid = interpreter.new_grid(4, 4);
+    interpreter.set(&mut grid, 1, 0, 100);
+    interpreter.set(&mut grid, 2, 0, 50);
+    for _ in 0..20 {
+        interpreter.step(&mut grid);
+    }
+    assert!((0..4).any(|x| grid.get(x, 3) == 100));
+    assert_eq!(grid.get(2, 0), 1);
+
+    // Ids need to be unique (also with the ids pinned in the lock) and can't be builtin ids
+    let report = parse_string_with_lock(&yaml(&format!("{}{}", glass, glass.replace("glass", "crystal"))), None, &lock).err().unwrap();
+    assert_eq!(count_errors(&report, "IdCollision"), 1);
+    assert_eq!(count_errors(&report, "AlreadyDefined"), 0);
+    let text = report.to_string();
+    assert!(text.contains("materials/glass/id"));
+    assert!(text.contains("materials/crystal/id"));
+    let report = parse_string_with_lock(&yaml(&sand.replace("density", "id: 4\n        density")), None, &lock).err().unwrap();
+    assert_eq!(count_errors(&report, "IdCollision"), 1);
+    assert!(report.to_string().contains("materials.lock (pinned to 'water')"));
+    let report = parse_string(&yaml(&format!("{}{}", sand.replace("density", "id: 2\n        density"), water.replace("density", "id: -1\n        density"))), None).err().unwrap();
+    assert_eq!(count_errors(&report, "InvalidType"), 2);
+
+    // Parsing a file only reads its lockfile, 'write_lock' writes it
+    let dir = std::env::temp_dir().join("sandengine_test_material_ids");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("materials.yaml");
+    let _ = std::fs::remove_file(path.with_extension("lock"));
+    std::fs::write(&path, yaml(&format!("{}{}", sand, water))).unwrap();
+    let res = sandengine_lang::parse_path(path.clone()).unwrap();
+    assert!(!path.with_extension("lock").exists());
+    assert!(sandengine_lang::write_lock(&path, &res).unwrap());
+    assert!(!sandengine_lang::write_lock(&path, &res).unwrap());
+    std::fs::write(&path, yaml(&format!("{}{}", inserted, water))).unwrap();
+    let res = sandengine_lang::parse_path(path.clone()).unwrap();
+    assert_eq!(id_of(&res, "water"), Some(4));
+    assert_eq!(id_of(&res, "stone"), Some(5));
+}
+
+
+#[test]
+fn palette() {
+    let yaml = |palette: &str| format!("
+rules:
+    fall:
+        if: SELF.density > DOWN.density
+        do: SWAP SELF DOWN
+types:
+    movable_solid:
+        base_rules: [fall]
+materials:
+    sand:
+        type: movable_solid
+        color: [255, 255, 0]
+        density: 1.5
+palette:
+{}", palette);
+
+    let res = parse_string(&yaml("
+    \"#ffff00\": sand
+    \"#000000\": WALL
+    00FF00: sand
+"), None).unwrap();
+    let colors: Vec<([u8; 3], &str)> = res.palette.iter().map(|p| (p.color, p.material.as_str())).collect();
+    assert_eq!(colors, vec![([255, 255, 0], "sand"), ([0, 0, 0], "WALL"), ([0, 255, 0], "sand")]);
+
+    // Colors need to be hex strings, materials need to exist and every color can only be used once
+    let report = parse_string(&yaml("
+    \"#ffff00\": sand
+    \"#FFFF00\": WALL
+    \"#12345\": sand
+    \"#abcdef\": glass
+    \"#fedcba\": [sand]
+"), None).err().unwrap();
+    assert_eq!(count_errors(&report, "AlreadyDefined"), 1);
+    assert_eq!(count_errors(&report, "InvalidType"), 2);
+    assert_eq!(count_errors(&report, "NotFound"), 1);
+    assert!(report.to_string().contains("palette/#abcdef"));
+}